@@ -0,0 +1,472 @@
+//! ANSI escape sequence handling.
+//!
+//! Many target side loggers, as well as the `RTT_CTRL_TEXT_*` macros of the SEGGER RTT library,
+//! color their output with ANSI escape sequences. [`AnsiParser`] turns lines containing escape
+//! sequences into styled [`Span`]s for display, or strips the sequences from a raw stream, e.g. for
+//! writing to a log file.
+//!
+//! Colors and text attributes (SGR sequences) are supported. Carriage returns, backspaces and
+//! erase line sequences are applied to the line, so that progress output that repeatedly rewrites
+//! a line only shows the final state. Other escape sequences are ignored.
+//!
+//! ## Example
+//!
+//! ```
+//! use probe_rs_rtt::ansi::{AnsiParser, Color};
+//!
+//! let mut parser = AnsiParser::new();
+//!
+//! let spans = parser.parse_line("\x1b[1;31merror:\x1b[0m oops");
+//! assert_eq!(spans[0].text, "error:");
+//! assert_eq!(spans[0].style.fg, Some(Color::Named(1)));
+//! assert!(spans[0].style.bold);
+//! assert_eq!(spans[1].text, " oops");
+//!
+//! let spans = parser.parse_line("10%\r50%\r100%");
+//! assert_eq!(spans[0].text, "100%");
+//!
+//! assert_eq!(parser.strip(b"\x1b[32mok\x1b[0m\n"), b"ok\n");
+//! ```
+
+/// A color set by an escape sequence.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Color {
+    /// One of the 16 basic colors. 0-7 are the normal colors (black, red, green, yellow, blue,
+    /// magenta, cyan, white) and 8-15 the bright versions.
+    Named(u8),
+
+    /// A color from the 256 color palette.
+    Indexed(u8),
+
+    /// A 24-bit RGB color.
+    Rgb(u8, u8, u8),
+}
+
+/// Text style set by escape sequences.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Style {
+    /// Foreground color, or `None` for the default color.
+    pub fg: Option<Color>,
+
+    /// Background color, or `None` for the default color.
+    pub bg: Option<Color>,
+
+    /// Bold or increased intensity.
+    pub bold: bool,
+
+    /// Faint or decreased intensity.
+    pub dim: bool,
+
+    /// Italic text.
+    pub italic: bool,
+
+    /// Underlined text.
+    pub underline: bool,
+
+    /// Foreground and background colors swapped.
+    pub reverse: bool,
+}
+
+/// A piece of text with a single style.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Span {
+    /// Text without any escape sequences.
+    pub text: String,
+
+    /// Style of the text.
+    pub style: Style,
+}
+
+/// Streaming ANSI escape sequence parser.
+///
+/// The style and any incomplete escape sequence carry over from one call to the next, so a color
+/// set on one line applies to the following lines until it is reset.
+#[derive(Clone, Debug, Default)]
+pub struct AnsiParser {
+    state: State,
+    params: String,
+    style: Style,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    Csi,
+    /// Operating system command or other string sequence, terminated by BEL or ST.
+    Str,
+    StrEscape,
+}
+
+/// Effect of a single input character.
+enum Action {
+    Print(char),
+    CarriageReturn,
+    Backspace,
+    EraseLine(u32),
+    Sgr,
+}
+
+impl AnsiParser {
+    /// Creates a new parser with the default style.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current style.
+    pub fn style(&self) -> Style {
+        self.style
+    }
+
+    /// Parses a line of text without the line terminator and returns its contents as styled
+    /// spans. Carriage returns, backspaces and erase line sequences rewrite the line.
+    pub fn parse_line(&mut self, line: &str) -> Vec<Span> {
+        let mut cells: Vec<(char, Style)> = Vec::new();
+        let mut cursor = 0;
+
+        for c in line.chars() {
+            match self.step(c) {
+                Some(Action::Print(c)) => {
+                    if cursor < cells.len() {
+                        cells[cursor] = (c, self.style);
+                    } else {
+                        cells.push((c, self.style));
+                    }
+
+                    cursor += 1;
+                }
+                Some(Action::CarriageReturn) => cursor = 0,
+                Some(Action::Backspace) => cursor = cursor.saturating_sub(1),
+                Some(Action::EraseLine(mode)) => {
+                    let blank = (' ', self.style);
+
+                    match mode {
+                        0 => cells.truncate(cursor),
+                        1 => {
+                            for cell in cells.iter_mut().take(cursor + 1) {
+                                *cell = blank;
+                            }
+                        }
+                        _ => {
+                            cells.clear();
+                            cells.resize(cursor, blank);
+                        }
+                    }
+                }
+                Some(Action::Sgr) | None => {}
+            }
+        }
+
+        let mut spans: Vec<Span> = Vec::new();
+
+        for (c, style) in cells {
+            match spans.last_mut() {
+                Some(span) if span.style == style => span.text.push(c),
+                _ => spans.push(Span {
+                    text: c.to_string(),
+                    style,
+                }),
+            }
+        }
+
+        spans
+    }
+
+    /// Removes escape sequences from a chunk of raw data. All other data, including control
+    /// characters such as carriage returns and bytes that are not ASCII, is passed through as is.
+    pub fn strip(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+
+        for &b in data {
+            // Control characters outside escape sequences are kept, unlike in parse_line
+            if self.state == State::Ground && b != 0x1b && (b as char).is_control() {
+                out.push(b);
+                continue;
+            }
+
+            // Bytes that are not ASCII are passed through as is outside escape sequences. Inside
+            // one, they go through the parser like any other character, which ends a control
+            // sequence as invalid but keeps a string sequence going.
+            if b >= 0x80 {
+                if self.state == State::Ground {
+                    out.push(b);
+                } else {
+                    self.step(b as char);
+                }
+
+                continue;
+            }
+
+            match self.step(b as char) {
+                Some(Action::Print(_)) | Some(Action::CarriageReturn) | Some(Action::Backspace) => {
+                    out.push(b)
+                }
+                Some(Action::EraseLine(_)) | Some(Action::Sgr) | None => {}
+            }
+        }
+
+        out
+    }
+
+    fn step(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                '\x1b' => {
+                    self.state = State::Escape;
+                    None
+                }
+                '\r' => Some(Action::CarriageReturn),
+                '\x08' => Some(Action::Backspace),
+                // Other control characters except tabs and newlines are dropped
+                '\t' | '\n' => Some(Action::Print(c)),
+                c if c.is_control() => None,
+                c => Some(Action::Print(c)),
+            },
+            State::Escape => {
+                self.state = match c {
+                    '[' => {
+                        self.params.clear();
+                        State::Csi
+                    }
+                    ']' | 'P' | 'X' | '^' | '_' => State::Str,
+                    // Intermediate bytes, e.g. character set selection
+                    ' '..='/' => State::Escape,
+                    _ => State::Ground,
+                };
+
+                None
+            }
+            State::Csi => match c {
+                '0'..='9' | ';' | ':' | '<'..='?' | ' '..='/' => {
+                    // Guard against runaway sequences
+                    if self.params.len() < 64 {
+                        self.params.push(c);
+                    }
+
+                    None
+                }
+                '@'..='~' => {
+                    self.state = State::Ground;
+                    self.csi(c)
+                }
+                _ => {
+                    // Invalid sequence
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::Str => {
+                match c {
+                    '\x07' => self.state = State::Ground,
+                    '\x1b' => self.state = State::StrEscape,
+                    _ => {}
+                }
+
+                None
+            }
+            State::StrEscape => {
+                self.state = if c == '\\' { State::Ground } else { State::Str };
+
+                None
+            }
+        }
+    }
+
+    fn csi(&mut self, command: char) -> Option<Action> {
+        // Private sequences, e.g. cursor visibility, are ignored
+        if self.params.starts_with(|c| matches!(c, '<'..='?')) {
+            return None;
+        }
+
+        let params: Vec<u32> = self
+            .params
+            .split(&[';', ':'][..])
+            .map(|p| p.parse().unwrap_or(0))
+            .collect();
+
+        match command {
+            'm' => {
+                self.sgr(&params);
+                Some(Action::Sgr)
+            }
+            'K' => Some(Action::EraseLine(params[0])),
+            _ => None,
+        }
+    }
+
+    fn sgr(&mut self, params: &[u32]) {
+        let style = &mut self.style;
+        let mut iter = params.iter().copied();
+
+        while let Some(p) = iter.next() {
+            match p {
+                0 => *style = Style::default(),
+                1 => style.bold = true,
+                2 => style.dim = true,
+                3 => style.italic = true,
+                4 => style.underline = true,
+                7 => style.reverse = true,
+                22 => {
+                    style.bold = false;
+                    style.dim = false;
+                }
+                23 => style.italic = false,
+                24 => style.underline = false,
+                27 => style.reverse = false,
+                30..=37 => style.fg = Some(Color::Named((p - 30) as u8)),
+                38 => style.fg = extended_color(&mut iter),
+                39 => style.fg = None,
+                40..=47 => style.bg = Some(Color::Named((p - 40) as u8)),
+                48 => style.bg = extended_color(&mut iter),
+                49 => style.bg = None,
+                90..=97 => style.fg = Some(Color::Named((p - 90 + 8) as u8)),
+                100..=107 => style.bg = Some(Color::Named((p - 100 + 8) as u8)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Parses the arguments of a 256 color or RGB color parameter.
+fn extended_color(params: &mut impl Iterator<Item = u32>) -> Option<Color> {
+    match params.next() {
+        Some(5) => params.next().map(|i| Color::Indexed(i as u8)),
+        Some(2) => {
+            let r = params.next()? as u8;
+            let g = params.next()? as u8;
+            let b = params.next()? as u8;
+
+            Some(Color::Rgb(r, g, b))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(spans: &[Span]) -> String {
+        spans.iter().map(|span| span.text.as_str()).collect()
+    }
+
+    #[test]
+    fn sgr_spans() {
+        let mut parser = AnsiParser::new();
+
+        let spans = parser.parse_line("a\x1b[1;4mb\x1b[22;38;5;200mc\x1b[48;2;1;2;3md\x1b[mf");
+        let styles: Vec<Style> = spans.iter().map(|span| span.style).collect();
+
+        assert_eq!(text(&spans), "abcdf");
+        assert_eq!(styles[0], Style::default());
+        assert!(styles[1].bold && styles[1].underline);
+        assert!(!styles[2].bold && styles[2].underline);
+        assert_eq!(styles[2].fg, Some(Color::Indexed(200)));
+        assert_eq!(styles[3].bg, Some(Color::Rgb(1, 2, 3)));
+        assert_eq!(styles[4], Style::default());
+    }
+
+    #[test]
+    fn style_carries_over_lines() {
+        let mut parser = AnsiParser::new();
+
+        parser.parse_line("\x1b[91mred");
+        let spans = parser.parse_line("still red");
+
+        assert_eq!(spans[0].style.fg, Some(Color::Named(9)));
+        assert_eq!(parser.style().fg, Some(Color::Named(9)));
+    }
+
+    #[test]
+    fn carriage_return_rewrites() {
+        let mut parser = AnsiParser::new();
+
+        assert_eq!(text(&parser.parse_line("abcdef\rXY")), "XYcdef");
+        assert_eq!(text(&parser.parse_line("10%\r50%\r100%")), "100%");
+        assert_eq!(text(&parser.parse_line("abc\r")), "abc");
+
+        // The rewritten characters take the new style
+        let spans = parser.parse_line("abc\r\x1b[31mX");
+        assert_eq!(spans[0].text, "X");
+        assert_eq!(spans[0].style.fg, Some(Color::Named(1)));
+        assert_eq!(spans[1].text, "bc");
+        assert_eq!(spans[1].style.fg, None);
+    }
+
+    #[test]
+    fn backspace() {
+        let mut parser = AnsiParser::new();
+
+        assert_eq!(text(&parser.parse_line("abc\x08X")), "abX");
+        assert_eq!(text(&parser.parse_line("abc\x08\x08")), "abc");
+        assert_eq!(text(&parser.parse_line("\x08\x08a")), "a");
+    }
+
+    #[test]
+    fn erase_line() {
+        let mut parser = AnsiParser::new();
+
+        // To the end of the line, the default
+        assert_eq!(text(&parser.parse_line("abcdef\r\x1b[Kxy")), "xy");
+        assert_eq!(text(&parser.parse_line("abcdef\x08\x08\x08\x1b[0K")), "abc");
+
+        // To the start of the line, including the cursor position
+        assert_eq!(
+            text(&parser.parse_line("abcdef\x08\x08\x08\x1b[1K")),
+            "    ef"
+        );
+
+        // The entire line, keeping the cursor position
+        assert_eq!(text(&parser.parse_line("abcdef\x08\x08\x1b[2Kx")), "    x");
+    }
+
+    #[test]
+    fn ignored_sequences() {
+        let mut parser = AnsiParser::new();
+
+        assert_eq!(
+            text(&parser.parse_line("a\x1b]0;title\x07b\x1b]0;t\x1b\\c\x1b[?25ld\x1b(Be\x1b[2Jf")),
+            "abcdef"
+        );
+        assert_eq!(text(&parser.parse_line("a\x00\x07\tb")), "a\tb");
+    }
+
+    #[test]
+    fn strip_sequences() {
+        let mut parser = AnsiParser::new();
+
+        assert_eq!(
+            parser.strip(b"\x1b[1;31merror:\x1b[0m oops\x1b[K\r\n"),
+            b"error: oops\r\n"
+        );
+        assert_eq!(
+            parser.strip(b"a\x08\x07\x00b\t\xc3\xa4"),
+            b"a\x08\x07\x00b\t\xc3\xa4"
+        );
+        assert_eq!(parser.strip(b"\x1b]0;title\x07x"), b"x");
+    }
+
+    #[test]
+    fn strip_across_chunks() {
+        let mut parser = AnsiParser::new();
+
+        assert_eq!(parser.strip(b"a\x1b["), b"a");
+        assert_eq!(parser.strip(b"32"), b"");
+        assert_eq!(parser.strip(b"mb"), b"b");
+        assert_eq!(parser.style().fg, Some(Color::Named(2)));
+    }
+
+    #[test]
+    fn strip_non_ascii_in_sequence() {
+        let mut parser = AnsiParser::new();
+
+        // Ends an escape or control sequence as invalid instead of being skipped
+        assert_eq!(parser.strip(b"\x1b\xc3a"), b"a");
+        assert_eq!(parser.strip(b"\x1b[1\xc3a"), b"a");
+        assert!(!parser.style().bold);
+
+        // Is part of a string sequence
+        assert_eq!(parser.strip(b"\x1b]0;\xc3\xa4\x07b"), b"b");
+    }
+}
@@ -0,0 +1,313 @@
+//! On-disk cache of control block addresses.
+//!
+//! Scanning all of the target RAM for the control block can take a while. The cache stores the
+//! last known control block address for a combination of probe, chip, core and firmware, which can
+//! then be passed to [`AttachOptions::control_block_address`](crate::AttachOptions) on the next
+//! attach. If the address no longer holds a valid control block, attaching falls back to scanning.
+//! [`attach_cached`] does this for one core or for all cores.
+//!
+//! With the `dirs` feature, [`ControlBlockCache::open_default`] opens the cache in the user cache
+//! directory.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use probe_rs::Probe;
+//! use probe_rs_rtt::cache::{CacheKey, ControlBlockCache};
+//! use probe_rs_rtt::{AttachOptions, Rtt};
+//!
+//! let probe = Probe::list_all()[0].open()?;
+//! let session = probe.attach("somechip")?;
+//! let core = session.attach_to_core(0)?;
+//!
+//! let key = CacheKey::new(None, "somechip", 0, None);
+//! let mut cache = ControlBlockCache::open("rtt-cache")?;
+//!
+//! let mut options = AttachOptions::new();
+//! if let Some(ptr) = cache.get(&key) {
+//!     options = options.control_block_address(ptr);
+//! }
+//!
+//! let rtt = Rtt::attach_with_options(core, &session, &options)?;
+//!
+//! cache.insert(&key, rtt.ptr());
+//! cache.save()?;
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use probe_rs::Session;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{AttachOptions, Error, Rtt};
+
+/// Identifies a target and firmware combination in the cache.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CacheKey {
+    probe_serial: String,
+    chip: String,
+    core: usize,
+    elf_hash: Option<u64>,
+}
+
+impl CacheKey {
+    /// Creates a new key. `elf` should be the contents of the firmware ELF file if available, and
+    /// is hashed to tell different builds apart.
+    pub fn new(probe_serial: Option<&str>, chip: &str, core: usize, elf: Option<&[u8]>) -> Self {
+        CacheKey {
+            probe_serial: sanitize(probe_serial.unwrap_or("-")),
+            chip: sanitize(chip),
+            core,
+            elf_hash: elf.map(fnv1a),
+        }
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.probe_serial, self.chip, self.core)?;
+
+        match self.elf_hash {
+            Some(hash) => write!(f, " {:016x}", hash),
+            None => write!(f, " -"),
+        }
+    }
+}
+
+/// On-disk cache of control block addresses.
+///
+/// The cache is a plain text file with one entry per line.
+pub struct ControlBlockCache {
+    path: PathBuf,
+    entries: BTreeMap<String, u32>,
+}
+
+impl ControlBlockCache {
+    /// Opens the cache at the default location in the user cache directory. A missing cache file is
+    /// treated as an empty cache.
+    #[cfg(feature = "dirs")]
+    pub fn open_default() -> io::Result<Self> {
+        let dir = dirs::cache_dir().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "User cache directory not found")
+        })?;
+
+        Self::open(dir.join("probe-rs-rtt").join("control-blocks"))
+    }
+
+    /// Opens the cache at the specified path. A missing cache file is treated as an empty cache.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        // Lines that cannot be parsed are ignored and dropped on the next save
+        let entries = contents.lines().filter_map(parse_line).collect();
+
+        Ok(ControlBlockCache { path, entries })
+    }
+
+    /// Returns the cached control block address for the key, if any.
+    pub fn get(&self, key: &CacheKey) -> Option<u32> {
+        self.entries.get(&key.to_string()).copied()
+    }
+
+    /// Stores the control block address for the key. Call [`save`](ControlBlockCache::save) to
+    /// write the changes to disk.
+    pub fn insert(&mut self, key: &CacheKey, ptr: u32) {
+        self.entries.insert(key.to_string(), ptr);
+    }
+
+    /// Writes the cache to disk, creating the parent directory if needed.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut contents = String::new();
+
+        for (key, ptr) in self.entries.iter() {
+            contents += &format!("{} 0x{:08x}\n", key, ptr);
+        }
+
+        fs::write(&self.path, contents)
+    }
+}
+
+/// Attaches to RTT on a core, or on all cores with [`Rtt::attach_all_cores`] if `core` is `None`,
+/// and returns the instances along with their core numbers.
+///
+/// If a cache is given, the address cached under the key for each core is tried first, and the
+/// addresses found are stored in the cache. Call [`save`](ControlBlockCache::save) to write them to
+/// disk.
+pub fn attach_cached(
+    session: &Session,
+    core: Option<usize>,
+    options: &AttachOptions,
+    mut cache: Option<&mut ControlBlockCache>,
+    key: impl Fn(usize) -> CacheKey,
+) -> Result<Vec<(usize, Rtt)>, Error> {
+    let core_options = |core| match cache.as_ref().and_then(|c| c.get(&key(core))) {
+        Some(ptr) => options.clone().control_block_address(ptr),
+        None => options.clone(),
+    };
+
+    let rtts = match core {
+        Some(core) => {
+            let options = core_options(core);
+            vec![(
+                core,
+                Rtt::attach_with_options(session.attach_to_core(core)?, session, &options)?,
+            )]
+        }
+        None => Rtt::attach_all_cores(session, core_options)?,
+    };
+
+    if let Some(cache) = cache.as_mut() {
+        for (core, rtt) in rtts.iter() {
+            cache.insert(&key(*core), rtt.ptr());
+        }
+    }
+
+    Ok(rtts)
+}
+
+/// Returns the chip name to use in a [`CacheKey`] for a session. If the chip was auto-detected,
+/// there is no name to go by, so the name is made up of a hash of the memory map and flash
+/// algorithms of the detected target instead. This keeps different chips on the same probe apart.
+pub fn chip_name(session: &Session, chip: Option<&str>) -> String {
+    match chip {
+        Some(chip) => chip.to_string(),
+        None => {
+            let mut target = format!("{:?}", session.memory_map());
+
+            for algorithm in session.flash_algorithms() {
+                target += &algorithm.name;
+            }
+
+            format!("auto-{:016x}", fnv1a(target.as_bytes()))
+        }
+    }
+}
+
+/// Parses a cache file line made up of a key and a hexadecimal address separated by a space.
+fn parse_line(line: &str) -> Option<(String, u32)> {
+    let p = line.rfind(' ')?;
+    let ptr = u32::from_str_radix(line[(p + 1)..].trim_start_matches("0x"), 16).ok()?;
+
+    Some((line[..p].to_string(), ptr))
+}
+
+/// Replaces characters that would break the line format.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .collect()
+}
+
+/// 64-bit FNV-1a hash. Used instead of the standard library hasher because the result must be
+/// stable between builds.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(
+            parse_line("123 nrf52 0 - 0x20000100"),
+            Some(("123 nrf52 0 -".to_string(), 0x2000_0100))
+        );
+        assert_eq!(
+            parse_line("- nrf52 1 00000000deadbeef 2000abcd"),
+            Some(("- nrf52 1 00000000deadbeef".to_string(), 0x2000_abcd))
+        );
+        assert_eq!(parse_line(""), None);
+        assert_eq!(parse_line("0x20000100"), None);
+        assert_eq!(parse_line("123 nrf52 0 - 0xnope"), None);
+        assert_eq!(parse_line("123 nrf52 0 - 0x120000000"), None);
+    }
+
+    #[test]
+    fn key_format() {
+        let key = CacheKey::new(Some("12 34"), "some\tchip", 1, None);
+        assert_eq!(key.to_string(), "12_34 some_chip 1 -");
+
+        let key = CacheKey::new(None, "nrf52", 0, Some(b"elf"));
+        assert_eq!(key.to_string(), "- nrf52 0 c2e8f118f04d12c6");
+    }
+
+    #[test]
+    fn sanitize_whitespace() {
+        assert_eq!(sanitize("a b\tc\nd\u{3000}e"), "a_b_c_d_e");
+        assert_eq!(sanitize("nrf52840_xxAA"), "nrf52840_xxAA");
+        assert_eq!(sanitize(""), "");
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        // Reference values for the 64-bit FNV-1a hash
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new();
+        let path = dir.path().join("sub").join("control-blocks");
+
+        let key1 = CacheKey::new(Some("123"), "nrf52", 0, None);
+        let key2 = CacheKey::new(None, "rp2040", 1, Some(b"elf"));
+
+        let mut cache = ControlBlockCache::open(&path).unwrap();
+        assert_eq!(cache.get(&key1), None);
+
+        cache.insert(&key1, 0x2000_0100);
+        cache.insert(&key2, 0x2000_0200);
+        cache.insert(&key1, 0x2000_0300);
+        cache.save().unwrap();
+
+        let cache = ControlBlockCache::open(&path).unwrap();
+        assert_eq!(cache.get(&key1), Some(0x2000_0300));
+        assert_eq!(cache.get(&key2), Some(0x2000_0200));
+        assert_eq!(
+            cache.get(&CacheKey::new(Some("123"), "nrf52", 1, None)),
+            None
+        );
+    }
+
+    #[test]
+    fn load_skips_invalid_lines() {
+        let dir = TempDir::new();
+        let path = dir.path().join("control-blocks");
+
+        fs::write(&path, "garbage\n- nrf52 0 - 0x20000100\n- nrf52 1 - zzz\n").unwrap();
+
+        let cache = ControlBlockCache::open(&path).unwrap();
+        assert_eq!(
+            cache.get(&CacheKey::new(None, "nrf52", 0, None)),
+            Some(0x2000_0100)
+        );
+        assert_eq!(cache.get(&CacheKey::new(None, "nrf52", 1, None)), None);
+
+        cache.save().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "- nrf52 0 - 0x20000100\n"
+        );
+    }
+}
@@ -0,0 +1,765 @@
+//! Recording and replaying RTT sessions.
+//!
+//! A capture file holds the control block address and channel list of each core, followed by the
+//! data read from up channels and written to down channels, in chunks stamped with the host time.
+//! [`CaptureWriter`] writes capture files and [`CaptureReader`] reads them record by record.
+//!
+//! [`Replay`] loads a capture file and provides its channels as [`ReplayChannel`]s, which can be
+//! read like the [`UpChannel`]s of a live target, so anything that reads from an up channel can
+//! also read from a capture. The data is released at the pace it was recorded, or all at once.
+//!
+//! ## File format
+//!
+//! All integers are little endian. The file starts with the magic bytes `RTTCAP`, followed by the
+//! format version as a `u16` (currently 1). The rest of the file is a sequence of records, each
+//! consisting of a record type (`u8`), a payload length (`u32`) and the payload. Readers skip
+//! record types they do not know.
+//!
+//! | Type | Record        | Payload                                                              |
+//! |------|---------------|----------------------------------------------------------------------|
+//! | 1    | Control block | core (`u32`), address (`u32`)                                        |
+//! | 2    | Channel       | core (`u32`), direction (`u8`), number (`u32`), buffer size (`u32`), mode (`u8`), name |
+//! | 3    | Data          | time (`u64`), core (`u32`), direction (`u8`), number (`u32`), data |
+//!
+//! The direction is 0 for up channels and 1 for down channels. The mode is the [`ChannelMode`]
+//! value, or 255 if it is not known. The name is UTF-8 and takes up the rest of the payload, and
+//! is preceded by a `u8` that is 0 if the channel has no name. The time is in microseconds since
+//! the Unix epoch.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use probe_rs_rtt::capture::Replay;
+//!
+//! let file = std::fs::File::open("session.rttcap")?;
+//!
+//! for mut replay in Replay::load(file, Some(1.0))? {
+//!     let mut input = replay.up_channels().take(0).unwrap();
+//!     let mut buf = [0u8; 1024];
+//!
+//!     while !input.is_finished() {
+//!         let count = input.read(&mut buf[..])?;
+//!         print!("{}", String::from_utf8_lossy(&buf[..count]));
+//!     }
+//! }
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{ChannelMode, Channels, DownChannel, Error, RttChannel, UpChannel};
+
+const MAGIC: &[u8; 6] = b"RTTCAP";
+const VERSION: u16 = 1;
+
+const RECORD_CONTROL_BLOCK: u8 = 1;
+const RECORD_CHANNEL: u8 = 2;
+const RECORD_DATA: u8 = 3;
+
+const MODE_UNKNOWN: u8 = 255;
+
+/// Direction of a channel.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    /// Target to host.
+    Up,
+
+    /// Host to target.
+    Down,
+}
+
+/// Control block information for a core.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ControlBlockInfo {
+    /// Number of the core.
+    pub core: usize,
+
+    /// Address of the control block in target memory.
+    pub address: u32,
+}
+
+/// Channel metadata.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChannelInfo {
+    /// Number of the core the channel belongs to.
+    pub core: usize,
+
+    /// Direction of the channel.
+    pub direction: Direction,
+
+    /// Number of the channel.
+    pub number: usize,
+
+    /// Name of the channel or `None` if there is none.
+    pub name: Option<String>,
+
+    /// Buffer size in bytes.
+    pub buffer_size: usize,
+
+    /// Channel mode, if known. Only up channels have a mode.
+    pub mode: Option<ChannelMode>,
+}
+
+impl ChannelInfo {
+    /// Returns the metadata of an up channel, including its current mode.
+    pub fn up(core: usize, channel: &UpChannel) -> Self {
+        ChannelInfo {
+            core,
+            direction: Direction::Up,
+            number: channel.number(),
+            name: channel.name().map(String::from),
+            buffer_size: channel.buffer_size(),
+            mode: channel.mode().ok(),
+        }
+    }
+
+    /// Returns the metadata of a down channel.
+    pub fn down(core: usize, channel: &DownChannel) -> Self {
+        ChannelInfo {
+            core,
+            direction: Direction::Down,
+            number: channel.number(),
+            name: channel.name().map(String::from),
+            buffer_size: channel.buffer_size(),
+            mode: None,
+        }
+    }
+}
+
+/// A chunk of data transferred on a channel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Chunk {
+    /// Host time when the data was read or written.
+    pub time: SystemTime,
+
+    /// Number of the core the channel belongs to.
+    pub core: usize,
+
+    /// Direction of the channel.
+    pub direction: Direction,
+
+    /// Number of the channel.
+    pub number: usize,
+
+    /// The data.
+    pub data: Vec<u8>,
+}
+
+/// A record in a capture file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Record {
+    ControlBlock(ControlBlockInfo),
+    Channel(ChannelInfo),
+    Data(Chunk),
+}
+
+/// Writer for capture files.
+///
+/// Each record is written with a single write call, so wrap the output in a `BufWriter` if
+/// recording performance matters more than having all data on disk in case of a crash.
+pub struct CaptureWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Creates a new writer and writes the file header.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        out.write_all(&header)?;
+
+        Ok(CaptureWriter { out })
+    }
+
+    /// Writes a record.
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        let mut payload = Vec::new();
+
+        let kind = match record {
+            Record::ControlBlock(info) => {
+                payload.extend_from_slice(&(info.core as u32).to_le_bytes());
+                payload.extend_from_slice(&info.address.to_le_bytes());
+
+                RECORD_CONTROL_BLOCK
+            }
+            Record::Channel(info) => {
+                payload.extend_from_slice(&(info.core as u32).to_le_bytes());
+                payload.push(direction_to_u8(info.direction));
+                payload.extend_from_slice(&(info.number as u32).to_le_bytes());
+                payload.extend_from_slice(&(info.buffer_size as u32).to_le_bytes());
+                payload.push(info.mode.map(|m| m as u8).unwrap_or(MODE_UNKNOWN));
+
+                match info.name.as_ref() {
+                    Some(name) => {
+                        payload.push(1);
+                        payload.extend_from_slice(name.as_bytes());
+                    }
+                    None => payload.push(0),
+                }
+
+                RECORD_CHANNEL
+            }
+            Record::Data(chunk) => {
+                let micros = chunk
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_micros() as u64;
+
+                payload.extend_from_slice(&micros.to_le_bytes());
+                payload.extend_from_slice(&(chunk.core as u32).to_le_bytes());
+                payload.push(direction_to_u8(chunk.direction));
+                payload.extend_from_slice(&(chunk.number as u32).to_le_bytes());
+                payload.extend_from_slice(&chunk.data);
+
+                RECORD_DATA
+            }
+        };
+
+        let mut buf = Vec::with_capacity(payload.len() + 5);
+        buf.push(kind);
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        self.out.write_all(&buf)
+    }
+
+    /// Writes a control block record.
+    pub fn control_block(&mut self, core: usize, address: u32) -> io::Result<()> {
+        self.write_record(&Record::ControlBlock(ControlBlockInfo { core, address }))
+    }
+
+    /// Writes a channel record.
+    pub fn channel(&mut self, info: ChannelInfo) -> io::Result<()> {
+        self.write_record(&Record::Channel(info))
+    }
+
+    /// Writes a data record stamped with the current time.
+    pub fn data(
+        &mut self,
+        core: usize,
+        direction: Direction,
+        number: usize,
+        data: &[u8],
+    ) -> io::Result<()> {
+        self.write_record(&Record::Data(Chunk {
+            time: SystemTime::now(),
+            core,
+            direction,
+            number,
+            data: data.to_vec(),
+        }))
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Reader for capture files.
+pub struct CaptureReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Creates a new reader and checks the file header.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; 8];
+        inner.read_exact(&mut header)?;
+
+        if &header[..6] != MAGIC {
+            return Err(invalid_data("Not an RTT capture file."));
+        }
+
+        let version = u16::from_le_bytes([header[6], header[7]]);
+
+        if version != VERSION {
+            return Err(invalid_data(&format!(
+                "Unsupported capture file version {}.",
+                version
+            )));
+        }
+
+        Ok(CaptureReader { inner })
+    }
+
+    /// Reads the next record, or returns `None` at the end of the file. A record that is cut off
+    /// at the end of the file, e.g. because recording was interrupted, is treated as the end of
+    /// the file.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            let mut header = [0u8; 5];
+
+            if !read_full(&mut self.inner, &mut header)? {
+                return Ok(None);
+            }
+
+            let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+            let mut payload = vec![0u8; len];
+
+            if !read_full(&mut self.inner, &mut payload)? {
+                return Ok(None);
+            }
+
+            let mut payload = Payload(&payload);
+
+            let record = match header[0] {
+                RECORD_CONTROL_BLOCK => Record::ControlBlock(ControlBlockInfo {
+                    core: payload.u32()? as usize,
+                    address: payload.u32()?,
+                }),
+                RECORD_CHANNEL => Record::Channel(ChannelInfo {
+                    core: payload.u32()? as usize,
+                    direction: payload.direction()?,
+                    number: payload.u32()? as usize,
+                    buffer_size: payload.u32()? as usize,
+                    mode: match payload.u8()? {
+                        0 => Some(ChannelMode::NoBlockSkip),
+                        1 => Some(ChannelMode::NoBlockTrim),
+                        2 => Some(ChannelMode::BlockIfFull),
+                        _ => None,
+                    },
+                    name: match payload.u8()? {
+                        0 => None,
+                        _ => Some(String::from_utf8_lossy(payload.0).into_owned()),
+                    },
+                }),
+                RECORD_DATA => Record::Data(Chunk {
+                    time: UNIX_EPOCH + Duration::from_micros(payload.u64()?),
+                    core: payload.u32()? as usize,
+                    direction: payload.direction()?,
+                    number: payload.u32()? as usize,
+                    data: payload.0.to_vec(),
+                }),
+                _ => continue,
+            };
+
+            return Ok(Some(record));
+        }
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        self.next_record().transpose()
+    }
+}
+
+/// Cursor over a record payload.
+struct Payload<'a>(&'a [u8]);
+
+impl<'a> Payload<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("Capture file record is too short."));
+        }
+
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn direction(&mut self) -> io::Result<Direction> {
+        match self.u8()? {
+            0 => Ok(Direction::Up),
+            1 => Ok(Direction::Down),
+            _ => Err(invalid_data("Invalid channel direction in capture file.")),
+        }
+    }
+}
+
+/// Fills the buffer completely. Returns `false` if the end of the file was reached first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn direction_to_u8(direction: Direction) -> u8 {
+    match direction {
+        Direction::Up => 0,
+        Direction::Down => 1,
+    }
+}
+
+/// Recorded session of a single core, with the same channel interface as [`Rtt`](crate::Rtt).
+pub struct Replay {
+    core: usize,
+    ptr: Option<u32>,
+    up_channels: Channels<ReplayChannel>,
+    down_channels: Channels<ReplayChannel>,
+}
+
+impl Replay {
+    /// Loads a capture file and returns a replay for each core in it.
+    ///
+    /// With a `speed` of 1.0, data becomes available at the same pace it was recorded, starting
+    /// now. Other values speed up or slow down the replay. With `None`, all data is available
+    /// immediately.
+    pub fn load(reader: impl Read, speed: Option<f64>) -> io::Result<Vec<Replay>> {
+        let mut cores: BTreeMap<usize, (Option<u32>, Vec<ChannelInfo>)> = BTreeMap::new();
+        let mut chunks: Vec<Chunk> = Vec::new();
+
+        for record in CaptureReader::new(reader)? {
+            match record? {
+                Record::ControlBlock(info) => {
+                    cores.entry(info.core).or_default().0 = Some(info.address)
+                }
+                Record::Channel(info) => cores.entry(info.core).or_default().1.push(info),
+                Record::Data(chunk) => chunks.push(chunk),
+            }
+        }
+
+        let clock = Rc::new(Clock {
+            start: Instant::now(),
+            first: chunks.iter().map(|c| c.time).min().unwrap_or(UNIX_EPOCH),
+            speed,
+        });
+
+        let mut replays = Vec::new();
+
+        for (core, (ptr, infos)) in cores {
+            let mut up_channels = Channels::new();
+            let mut down_channels = Channels::new();
+
+            for info in infos {
+                let data = chunks
+                    .iter()
+                    .filter(|c| {
+                        c.core == core && c.direction == info.direction && c.number == info.number
+                    })
+                    .map(|c| (c.time, c.data.clone()))
+                    .collect();
+
+                let channels = match info.direction {
+                    Direction::Up => &mut up_channels,
+                    Direction::Down => &mut down_channels,
+                };
+
+                channels.insert(ReplayChannel {
+                    info,
+                    clock: clock.clone(),
+                    chunks: RefCell::new(data),
+                });
+            }
+
+            replays.push(Replay {
+                core,
+                ptr,
+                up_channels,
+                down_channels,
+            });
+        }
+
+        Ok(replays)
+    }
+
+    /// Returns the number of the core.
+    pub fn core(&self) -> usize {
+        self.core
+    }
+
+    /// Returns the recorded control block address, if any.
+    pub fn ptr(&self) -> Option<u32> {
+        self.ptr
+    }
+
+    /// Gets the up channels of the recorded session.
+    pub fn up_channels(&mut self) -> &mut Channels<ReplayChannel> {
+        &mut self.up_channels
+    }
+
+    /// Gets the down channels of the recorded session. Reading from them returns the data that
+    /// was written to the target.
+    pub fn down_channels(&mut self) -> &mut Channels<ReplayChannel> {
+        &mut self.down_channels
+    }
+}
+
+/// Time base shared by the channels of a replay.
+struct Clock {
+    start: Instant,
+    first: SystemTime,
+    speed: Option<f64>,
+}
+
+impl Clock {
+    /// Returns `true` if data recorded at the time should be available.
+    fn is_due(&self, time: SystemTime) -> bool {
+        let speed = match self.speed {
+            Some(speed) => speed,
+            None => return true,
+        };
+
+        let offset = time.duration_since(self.first).unwrap_or_default();
+
+        self.start.elapsed().as_secs_f64() * speed >= offset.as_secs_f64()
+    }
+}
+
+/// A channel of a recorded session.
+pub struct ReplayChannel {
+    info: ChannelInfo,
+    clock: Rc<Clock>,
+    chunks: RefCell<VecDeque<(SystemTime, Vec<u8>)>>,
+}
+
+impl ReplayChannel {
+    /// Returns the recorded channel metadata.
+    pub fn info(&self) -> &ChannelInfo {
+        &self.info
+    }
+
+    /// Returns the recorded channel mode.
+    pub fn mode(&self) -> Result<ChannelMode, Error> {
+        self.info.mode.ok_or(Error::InvalidChannelMode)
+    }
+
+    /// Returns `true` once all recorded data has been read.
+    pub fn is_finished(&self) -> bool {
+        self.chunks.borrow().is_empty()
+    }
+
+    /// Reads recorded data that is due and returns how many bytes were read.
+    ///
+    /// Like [`UpChannel::read`], this method does not block waiting for data.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut chunks = self.chunks.borrow_mut();
+        let mut total = 0;
+
+        while total < buf.len() {
+            let (time, data) = match chunks.front_mut() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+
+            if !self.clock.is_due(*time) {
+                break;
+            }
+
+            let count = data.len().min(buf.len() - total);
+            buf[total..(total + count)].copy_from_slice(&data[..count]);
+            data.drain(..count);
+            total += count;
+
+            if data.is_empty() {
+                chunks.pop_front();
+            }
+        }
+
+        Ok(total)
+    }
+}
+
+impl RttChannel for ReplayChannel {
+    fn number(&self) -> usize {
+        self.info.number
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.info.name.as_deref()
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.info.buffer_size
+    }
+}
+
+impl io::Read for ReplayChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        ReplayChannel::read(self, buf).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(core: usize, direction: Direction, number: usize, name: Option<&str>) -> Record {
+        Record::Channel(ChannelInfo {
+            core,
+            direction,
+            number,
+            name: name.map(String::from),
+            buffer_size: 1024,
+            mode: match direction {
+                Direction::Up => Some(ChannelMode::BlockIfFull),
+                Direction::Down => None,
+            },
+        })
+    }
+
+    fn data(micros: u64, core: usize, direction: Direction, number: usize, data: &[u8]) -> Record {
+        Record::Data(Chunk {
+            time: UNIX_EPOCH + Duration::from_micros(micros),
+            core,
+            direction,
+            number,
+            data: data.to_vec(),
+        })
+    }
+
+    fn write(records: &[Record]) -> Vec<u8> {
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+
+        writer.into_inner()
+    }
+
+    fn read(file: &[u8]) -> Vec<Record> {
+        CaptureReader::new(file)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let records = vec![
+            Record::ControlBlock(ControlBlockInfo {
+                core: 1,
+                address: 0x2000_0100,
+            }),
+            channel(1, Direction::Up, 0, Some("Terminal")),
+            channel(1, Direction::Down, 0, None),
+            channel(1, Direction::Up, 2, Some("")),
+            data(1_590_000_000_123_456, 1, Direction::Up, 0, b"hello\n"),
+            data(1_590_000_000_223_456, 1, Direction::Down, 0, b""),
+        ];
+
+        assert_eq!(read(&write(&records)), records);
+    }
+
+    #[test]
+    fn record_truncated_at_eof() {
+        let records = vec![
+            channel(0, Direction::Up, 0, Some("Terminal")),
+            data(1, 0, Direction::Up, 0, b"complete"),
+        ];
+
+        let mut file = write(&records);
+        let full = file.len();
+        file.extend(write(&[data(2, 0, Direction::Up, 0, b"cut off")])[8..].iter());
+
+        // Cut off in the payload and in the record header
+        assert_eq!(read(&file[..file.len() - 3]), records);
+        assert_eq!(read(&file[..full + 3]), records);
+    }
+
+    #[test]
+    fn unknown_records_are_skipped() {
+        let mut file = write(&[]);
+        file.extend_from_slice(&[200, 3, 0, 0, 0, 1, 2, 3]);
+        file.extend(write(&[data(1, 0, Direction::Up, 0, b"x")])[8..].iter());
+
+        assert_eq!(read(&file), vec![data(1, 0, Direction::Up, 0, b"x")]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(CaptureReader::new(&b"RTTCAX\x01\x00"[..]).is_err());
+        assert!(CaptureReader::new(&b"RTTCAP\x02\x00"[..]).is_err());
+        assert!(CaptureReader::new(&b"RTT"[..]).is_err());
+
+        // Control block record with a payload too short for its fields
+        let mut file = write(&[]);
+        file.extend_from_slice(&[RECORD_CONTROL_BLOCK, 2, 0, 0, 0, 1, 2]);
+
+        let err = CaptureReader::new(&file[..])
+            .unwrap()
+            .next_record()
+            .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn replay_by_core_and_channel() {
+        let file = write(&[
+            Record::ControlBlock(ControlBlockInfo {
+                core: 0,
+                address: 0x2000_0000,
+            }),
+            channel(0, Direction::Up, 0, Some("Terminal")),
+            channel(0, Direction::Down, 0, Some("Terminal")),
+            channel(1, Direction::Up, 0, None),
+            data(10, 0, Direction::Up, 0, b"abc"),
+            data(20, 1, Direction::Up, 0, b"other core"),
+            data(30, 0, Direction::Down, 0, b"input"),
+            data(40, 0, Direction::Up, 0, b"def"),
+        ]);
+
+        let mut replays = Replay::load(&file[..], None).unwrap();
+        assert_eq!(replays.len(), 2);
+
+        let replay = &mut replays[0];
+        assert_eq!((replay.core(), replay.ptr()), (0, Some(0x2000_0000)));
+
+        let up = replay.up_channels().take(0).unwrap();
+        assert_eq!(up.name(), Some("Terminal"));
+        assert_eq!(up.mode().unwrap(), ChannelMode::BlockIfFull);
+
+        // Chunks are joined, and split to fit the buffer
+        let mut buf = [0u8; 4];
+        assert_eq!(up.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(up.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ef");
+        assert!(up.is_finished());
+        assert_eq!(up.read(&mut buf).unwrap(), 0);
+
+        let down = replay.down_channels().take(0).unwrap();
+        assert!(down.mode().is_err());
+        let mut buf = [0u8; 16];
+        assert_eq!(down.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"input");
+
+        let replay = &mut replays[1];
+        assert_eq!((replay.core(), replay.ptr()), (1, None));
+
+        let up = replay.up_channels().take(0).unwrap();
+        assert_eq!(up.read(&mut buf).unwrap(), 10);
+        assert_eq!(&buf[..10], b"other core");
+    }
+}
@@ -1,5 +1,6 @@
 use probe_rs::{config::MemoryRegion, Core};
-use scroll::{Pread, LE};
+use scroll::{Pread, Pwrite, LE};
+use std::cell::Cell;
 use std::cmp::min;
 use std::io;
 use std::rc::Rc;
@@ -26,6 +27,9 @@ pub(crate) struct Channel {
     name: Option<String>,
     buffer_ptr: u32,
     size: u32,
+    /// Read offset as last seen or written by the host.
+    last_read: Cell<Option<u32>>,
+    events: Cell<ChannelEvents>,
 }
 
 // Chanels must follow this data layout when reading/writing memory in order to be compatible with
@@ -81,9 +85,27 @@ impl Channel {
             name,
             buffer_ptr,
             size: mem.pread_with(Self::O_SIZE, LE).unwrap(),
+            last_read: Cell::new(None),
+            events: Cell::new(ChannelEvents::default()),
         }))
     }
 
+    /// Writes a channel descriptor for a new channel with empty buffers into `mem`.
+    pub(crate) fn write_descriptor(
+        mem: &mut [u8],
+        name_ptr: u32,
+        buffer_ptr: u32,
+        size: u32,
+        mode: ChannelMode,
+    ) {
+        mem.pwrite_with(name_ptr, Self::O_NAME, LE).unwrap();
+        mem.pwrite_with(buffer_ptr, Self::O_BUFFER_PTR, LE).unwrap();
+        mem.pwrite_with(size, Self::O_SIZE, LE).unwrap();
+        mem.pwrite_with(0u32, Self::O_WRITE, LE).unwrap();
+        mem.pwrite_with(0u32, Self::O_READ, LE).unwrap();
+        mem.pwrite_with(mode as u32, Self::O_FLAGS, LE).unwrap();
+    }
+
     pub fn name(&self) -> Option<&str> {
         self.name.as_ref().map(|s| s.as_ref())
     }
@@ -178,9 +200,33 @@ impl UpChannel {
         Ok(())
     }
 
+    /// Returns the events noticed while reading from the channel since the last call, and clears
+    /// them.
+    ///
+    /// Events are only detected when the channel is read or peeked at, so they may be noticed late
+    /// or missed if the channel is not read often enough.
+    pub fn take_events(&self) -> ChannelEvents {
+        self.0.events.take()
+    }
+
     fn read_core(&self, mut buf: &mut [u8]) -> Result<(u32, usize), Error> {
         let (write, mut read) = self.0.read_pointers("up")?;
 
+        let mut events = self.0.events.get();
+
+        // Only the host moves the read offset, so if it changed the target has initialized the
+        // channel again
+        if matches!(self.0.last_read.get(), Some(last) if last != read) {
+            events.reset = true;
+        }
+
+        if self.readable_total(write, read) + 1 >= self.0.size as usize {
+            events.full = true;
+        }
+
+        self.0.events.set(events);
+        self.0.last_read.set(Some(read));
+
         let mut total = 0;
 
         // Read while buffer contains data and output buffer has space (maximum of two iterations)
@@ -221,6 +267,8 @@ impl UpChannel {
             self.0
                 .core
                 .write_8(self.0.ptr + Channel::O_READ as u32, &read.to_le_bytes())?;
+
+            self.0.last_read.set(Some(read));
         }
 
         Ok(total)
@@ -235,6 +283,15 @@ impl UpChannel {
         Ok(self.read_core(buf)?.1)
     }
 
+    /// Calculates total amount of data available for reading
+    fn readable_total(&self, write: u32, read: u32) -> usize {
+        (if read > write {
+            self.0.size - read + write
+        } else {
+            write - read
+        }) as usize
+    }
+
     /// Calculates amount of contiguous data available for reading
     fn readable_contiguous(&self, write: u32, read: u32) -> usize {
         (if read > write {
@@ -399,9 +456,21 @@ fn read_c_string(
         .map(|p| String::from_utf8_lossy(&bytes[..p]).into_owned()))
 }
 
+/// Events noticed on an up channel while reading from it, see [`UpChannel::take_events`].
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct ChannelEvents {
+    /// The target initialized the channel again, most likely because it was reset. Data written
+    /// before that may have been lost.
+    pub reset: bool,
+
+    /// The buffer was found full. Unless the channel is in [`ChannelMode::BlockIfFull`] mode the
+    /// target may have dropped data.
+    pub full: bool,
+}
+
 /// Specifies what to do when a channel doesn't have enough buffer space for a complete write on the
 /// target side.
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 #[repr(u32)]
 pub enum ChannelMode {
     /// Skip writing the data completely if it doesn't fit in its entirety.
@@ -1,13 +1,21 @@
 //! List of RTT channels.
 
-use std::collections::{BTreeMap, btree_map};
-use std::mem;
 use crate::RttChannel;
+use std::collections::{btree_map, BTreeMap};
+use std::fmt;
+use std::iter::FromIterator;
+use std::mem;
+use std::str::FromStr;
 
 /// List of RTT channels.
 pub struct Channels<T: RttChannel>(pub(crate) BTreeMap<usize, T>);
 
 impl<T: RttChannel> Channels<T> {
+    /// Creates a new empty list.
+    pub fn new() -> Self {
+        Channels(BTreeMap::new())
+    }
+
     /// Returns the number of channels on the list.
     pub fn len(&self) -> usize {
         self.0.len()
@@ -19,20 +27,88 @@ impl<T: RttChannel> Channels<T> {
     }
 
     /// Returns a reference to the channel corresponding to the number.
-    pub fn get(&mut self, number: usize) -> Option<&T> {
+    pub fn get(&self, number: usize) -> Option<&T> {
         self.0.get(&number)
     }
 
+    /// Returns a mutable reference to the channel corresponding to the number.
+    pub fn get_mut(&mut self, number: usize) -> Option<&mut T> {
+        self.0.get_mut(&number)
+    }
+
     /// Removes the channel corresponding to the number from the list and returns it.
     pub fn take(&mut self, number: usize) -> Option<T> {
         self.0.remove(&number)
     }
 
+    /// Adds a channel to the list, e.g. to give back a channel previously removed with
+    /// [`take`](Channels::take). If the list already contains a channel with the same number, it
+    /// is replaced and returned.
+    pub fn insert(&mut self, channel: T) -> Option<T> {
+        self.0.insert(channel.number(), channel)
+    }
+
+    /// Returns `true` if the list contains a channel corresponding to the number.
+    pub fn contains(&self, number: usize) -> bool {
+        self.0.contains_key(&number)
+    }
+
+    /// Gets an iterator over the numbers of the channels on the list, sorted by number.
+    pub fn numbers(&self) -> Numbers<'_, T> {
+        Numbers(self.0.keys())
+    }
+
+    /// Returns a reference to the first channel, sorted by number, with a name matching the
+    /// pattern.
+    ///
+    /// The pattern may contain the wildcards `*` (any number of characters) and `?` (any single
+    /// character). A pattern without wildcards must match the name exactly.
+    pub fn find_by_name(&self, pattern: &str) -> Option<&T> {
+        self.0
+            .values()
+            .find(|c| matches!(c.name(), Some(name) if glob_match(pattern, name)))
+    }
+
+    /// Removes the first channel, sorted by number, with a name matching the pattern from the list
+    /// and returns it.
+    ///
+    /// See [`find_by_name`](Channels::find_by_name) for the pattern syntax.
+    pub fn take_by_name(&mut self, pattern: &str) -> Option<T> {
+        let number = self.find_by_name(pattern)?.number();
+
+        self.0.remove(&number)
+    }
+
+    /// Removes the first channel, sorted by number, matching the selector from the list and
+    /// returns it.
+    pub fn take_by_selector(&mut self, selector: &ChannelSelector) -> Option<T> {
+        match selector {
+            ChannelSelector::Number(n) => self.take(*n),
+            ChannelSelector::Name(pattern) => self.take_by_name(pattern),
+        }
+    }
+
+    /// Removes all the channels matching the selector from the list and returns them, sorted by
+    /// number. A name pattern may match multiple channels.
+    pub fn take_all_by_selector(&mut self, selector: &ChannelSelector) -> Vec<T> {
+        match selector {
+            ChannelSelector::Number(n) => self.take(*n).into_iter().collect(),
+            ChannelSelector::Name(pattern) => {
+                std::iter::from_fn(|| self.take_by_name(pattern)).collect()
+            }
+        }
+    }
+
     /// Gets and iterator over the channels on the list, sorted by number.
     pub fn iter(&self) -> Iter<'_, T> {
         Iter(self.0.iter())
     }
 
+    /// Gets a mutable iterator over the channels on the list, sorted by number.
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut(self.0.iter_mut())
+    }
+
     /// Gets and iterator over the channels on the list, sorted by number.
     pub fn drain(&mut self) -> Drain<T> {
         let map = mem::replace(&mut self.0, BTreeMap::new());
@@ -41,6 +117,142 @@ impl<T: RttChannel> Channels<T> {
     }
 }
 
+/// Removes all the channels matching any of the selectors from each of the lists, e.g. the lists of
+/// all cores, and returns them per list in the order of the selectors. If there are no selectors,
+/// all the channels are taken.
+///
+/// Fails with the first selector that matches no channel on any of the lists, so that a mistyped
+/// selector can be reported instead of silently selecting nothing.
+pub fn take_selected<'a, 's, T: RttChannel + 'a>(
+    lists: impl IntoIterator<Item = &'a mut Channels<T>>,
+    selectors: &'s [ChannelSelector],
+) -> Result<Vec<Vec<T>>, &'s ChannelSelector> {
+    let mut lists: Vec<&mut Channels<T>> = lists.into_iter().collect();
+
+    if selectors.is_empty() {
+        return Ok(lists
+            .iter_mut()
+            .map(|list| list.drain().collect())
+            .collect());
+    }
+
+    let mut taken: Vec<Vec<T>> = lists.iter().map(|_| Vec::new()).collect();
+
+    for selector in selectors {
+        let mut found = false;
+
+        for (list, taken) in lists.iter_mut().zip(taken.iter_mut()) {
+            let chans = list.take_all_by_selector(selector);
+            found |= !chans.is_empty();
+            taken.extend(chans);
+        }
+
+        if !found {
+            return Err(selector);
+        }
+    }
+
+    Ok(taken)
+}
+
+/// Selects channels by number or by a name pattern, e.g. from a command line argument.
+///
+/// A string that is a number selects the channel with that number, and anything else is a name
+/// pattern as in [`Channels::find_by_name`]. A string starting with `name:` is always a name
+/// pattern, which selects channels whose names are numbers.
+///
+/// ```
+/// use probe_rs_rtt::ChannelSelector;
+///
+/// assert_eq!("1".parse(), Ok(ChannelSelector::Number(1)));
+/// assert_eq!("log*".parse(), Ok(ChannelSelector::Name("log*".to_string())));
+/// assert_eq!("name:1".parse(), Ok(ChannelSelector::Name("1".to_string())));
+/// assert!("".parse::<ChannelSelector>().is_err());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelSelector {
+    Number(usize),
+    Name(String),
+}
+
+impl FromStr for ChannelSelector {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<ChannelSelector, &'static str> {
+        if let Some(name) = s.strip_prefix("name:") {
+            if name.is_empty() {
+                Err("Channel name must not be empty.")
+            } else {
+                Ok(ChannelSelector::Name(name.to_string()))
+            }
+        } else if s.is_empty() {
+            Err("Channel name must not be empty.")
+        } else if let Ok(n) = s.parse::<usize>() {
+            Ok(ChannelSelector::Number(n))
+        } else {
+            Ok(ChannelSelector::Name(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for ChannelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelSelector::Number(n) => write!(f, "{}", n),
+            ChannelSelector::Name(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+impl<T: RttChannel> Default for Channels<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: RttChannel> FromIterator<T> for Channels<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut channels = Self::new();
+        channels.extend(iter);
+        channels
+    }
+}
+
+impl<T: RttChannel> Extend<T> for Channels<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for channel in iter {
+            self.insert(channel);
+        }
+    }
+}
+
+impl<T: RttChannel> IntoIterator for Channels<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter(self.0.into_iter())
+    }
+}
+
+impl<'a, T: RttChannel> IntoIterator for &'a Channels<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: RttChannel> IntoIterator for &'a mut Channels<T> {
+    type Item = &'a mut T;
+    type IntoIter = IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
 /// An iterator over RTT channels.
 ///
 /// This struct is created by the [`Channels::iter`] method. See its documentation for more.
@@ -54,6 +266,45 @@ impl<'a, T: RttChannel> Iterator for Iter<'a, T> {
     }
 }
 
+/// A mutable iterator over RTT channels.
+///
+/// This struct is created by the [`Channels::iter_mut`] method. See its documentation for more.
+pub struct IterMut<'a, T: RttChannel>(btree_map::IterMut<'a, usize, T>);
+
+impl<'a, T: RttChannel> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, v)| v)
+    }
+}
+
+/// An iterator over the numbers of RTT channels.
+///
+/// This struct is created by the [`Channels::numbers`] method. See its documentation for more.
+pub struct Numbers<'a, T: RttChannel>(btree_map::Keys<'a, usize, T>);
+
+impl<'a, T: RttChannel> Iterator for Numbers<'a, T> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().copied()
+    }
+}
+
+/// An owning iterator over RTT channels.
+///
+/// This struct is created by the `into_iter` method on [`Channels`].
+pub struct IntoIter<T: RttChannel>(btree_map::IntoIter<usize, T>);
+
+impl<T: RttChannel> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, v)| v)
+    }
+}
+
 /// A draining iterator over RTT channels.
 ///
 /// This struct is created by the [`Channels::drain`] method. See its documentation for more.
@@ -66,3 +317,195 @@ impl<'a, T: RttChannel> Iterator for Drain<T> {
         self.0.next().map(|(_, v)| v)
     }
 }
+
+/// Matches a name against a pattern with `*` and `?` wildcards.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+
+    // Position of the last `*` in the pattern and the name position it was tried at
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` match one more character and try again
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_without_wildcards() {
+        assert!(glob_match("defmt", "defmt"));
+        assert!(!glob_match("defmt", "defm"));
+        assert!(!glob_match("defmt", "defmt2"));
+        assert!(!glob_match("defmt", "Defmt"));
+    }
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("*log", "log"));
+        assert!(glob_match("*log", "syslog"));
+        assert!(!glob_match("*log", "logs"));
+
+        assert!(glob_match("log*", "log"));
+        assert!(glob_match("log*", "logger"));
+        assert!(!glob_match("log*", "syslog"));
+
+        assert!(glob_match("a*b", "ab"));
+        assert!(glob_match("a*b", "axxb"));
+        assert!(glob_match("a*b", "abab"));
+        assert!(!glob_match("a*b", "abba "));
+
+        assert!(glob_match("*a*b*", "xxaxbxx"));
+        assert!(glob_match("**", "anything"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_match("log?", "log1"));
+        assert!(!glob_match("log?", "log"));
+        assert!(!glob_match("log?", "log12"));
+        assert!(glob_match("?*", "x"));
+        assert!(glob_match("ch?nn?l", "channel"));
+        assert!(glob_match("??", "\u{e4}\u{f6}"));
+    }
+
+    #[test]
+    fn glob_empty() {
+        assert!(glob_match("", ""));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("", "log"));
+        assert!(!glob_match("log", ""));
+    }
+
+    #[test]
+    fn parse_selector() {
+        assert_eq!("0".parse(), Ok(ChannelSelector::Number(0)));
+        assert_eq!("12".parse(), Ok(ChannelSelector::Number(12)));
+        assert_eq!("-1".parse(), Ok(ChannelSelector::Name("-1".to_string())));
+        assert_eq!(
+            "log?".parse(),
+            Ok(ChannelSelector::Name("log?".to_string()))
+        );
+        assert_eq!(
+            "name:12".parse(),
+            Ok(ChannelSelector::Name("12".to_string()))
+        );
+        assert_eq!(
+            "name:name:x".parse(),
+            Ok(ChannelSelector::Name("name:x".to_string()))
+        );
+        assert!("".parse::<ChannelSelector>().is_err());
+        assert!("name:".parse::<ChannelSelector>().is_err());
+    }
+
+    struct TestChannel(usize, &'static str);
+
+    impl RttChannel for TestChannel {
+        fn number(&self) -> usize {
+            self.0
+        }
+
+        fn name(&self) -> Option<&str> {
+            Some(self.1)
+        }
+
+        fn buffer_size(&self) -> usize {
+            0
+        }
+    }
+
+    fn list(channels: &[(usize, &'static str)]) -> Channels<TestChannel> {
+        channels
+            .iter()
+            .map(|&(number, name)| TestChannel(number, name))
+            .collect()
+    }
+
+    fn numbers(taken: &[Vec<TestChannel>]) -> Vec<Vec<usize>> {
+        taken
+            .iter()
+            .map(|chans| chans.iter().map(|c| c.number()).collect())
+            .collect()
+    }
+
+    fn selectors(s: &[&str]) -> Vec<ChannelSelector> {
+        s.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn take_selected_in_selector_order() {
+        let mut chans = list(&[(0, "Terminal"), (1, "log1"), (2, "defmt"), (3, "log2")]);
+        let sel = selectors(&["defmt", "log*"]);
+
+        let taken = take_selected(Some(&mut chans), &sel).unwrap();
+
+        assert_eq!(numbers(&taken), vec![vec![2, 1, 3]]);
+        assert_eq!(chans.numbers().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn take_selected_without_selectors() {
+        let mut a = list(&[(0, "a"), (1, "b")]);
+        let mut b = list(&[(0, "c")]);
+
+        let taken = take_selected(vec![&mut a, &mut b], &[]).unwrap();
+
+        assert_eq!(numbers(&taken), vec![vec![0, 1], vec![0]]);
+        assert!(a.is_empty() && b.is_empty());
+    }
+
+    #[test]
+    fn take_selected_from_any_list() {
+        let mut a = list(&[(0, "a"), (1, "shared")]);
+        let mut b = list(&[(0, "b"), (1, "shared")]);
+        let sel = selectors(&["a", "shared"]);
+
+        let taken = take_selected(vec![&mut a, &mut b], &sel).unwrap();
+
+        assert_eq!(numbers(&taken), vec![vec![0, 1], vec![1]]);
+    }
+
+    #[test]
+    fn take_selected_unmatched() {
+        let mut a = list(&[(0, "a")]);
+        let mut b = list(&[(0, "b")]);
+        let sel = selectors(&["a", "2", "c*"]);
+
+        assert_eq!(
+            take_selected(vec![&mut a, &mut b], &sel).err(),
+            Some(&ChannelSelector::Number(2))
+        );
+
+        let mut a = list(&[]);
+        assert_eq!(
+            take_selected(Some(&mut a), &sel).err(),
+            Some(&ChannelSelector::Name("a".to_string()))
+        );
+    }
+}
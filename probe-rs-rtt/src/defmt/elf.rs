@@ -0,0 +1,392 @@
+//! Reading the defmt table from the firmware ELF file.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use gimli::Reader as _;
+use object::read::{Object, ObjectSection};
+
+use super::{Level, Location};
+use crate::Error;
+
+/// Name of the static variable the defmt macros create for each log statement. Its DWARF entry
+/// holds the source location.
+const LOG_STATEMENT_VAR: &str = "DEFMT_LOG_STATEMENT";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Encoding {
+    Raw,
+    Rzcobs,
+}
+
+/// What a format string in the table is used for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Tag {
+    /// A log statement with a level.
+    Log(Level),
+    /// A `println!` statement.
+    Println,
+    /// A `Format` implementation derived for a struct or enum.
+    Derived,
+    /// Any other format string or interned string.
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Entry {
+    pub(crate) tag: Tag,
+    pub(crate) format: String,
+}
+
+/// Format strings and source locations read from a firmware ELF file.
+#[derive(Debug)]
+pub struct Table {
+    pub(crate) entries: BTreeMap<u16, Entry>,
+    pub(crate) timestamp: Option<String>,
+    pub(crate) encoding: Encoding,
+    pub(crate) locations: BTreeMap<u16, Location>,
+}
+
+impl Table {
+    /// Reads the defmt table from the contents of an ELF file.
+    ///
+    /// Source locations are read from the debug information if it is present. Returns an error if
+    /// the file does not contain defmt data or uses an unsupported version of defmt.
+    pub fn parse(elf: &[u8]) -> Result<Table, Error> {
+        let file = object::File::parse(elf)
+            .map_err(|err| Error::InvalidDefmtData(format!("Error parsing ELF file: {}", err)))?;
+
+        let section = file.section_by_name(".defmt").ok_or_else(|| {
+            Error::InvalidDefmtData(
+                "No .defmt section found. Is the firmware built with defmt?".to_string(),
+            )
+        })?;
+
+        let mut version = None;
+        let mut encoding = Encoding::Raw;
+        let mut timestamp = None;
+        let mut entries = BTreeMap::new();
+
+        for (_, symbol) in file.symbols() {
+            let name = match symbol.name() {
+                Some(name) => name,
+                None => continue,
+            };
+
+            // The version and encoding markers are not necessarily in the .defmt section
+            if let Some(v) = name.strip_prefix("_defmt_version_ = ") {
+                version = Some(v.to_string());
+                continue;
+            }
+
+            if let Some(e) = name.strip_prefix("_defmt_encoding_ = ") {
+                encoding = match e {
+                    "raw" => Encoding::Raw,
+                    "rzcobs" => Encoding::Rzcobs,
+                    _ => {
+                        return Err(Error::InvalidDefmtData(format!(
+                            "Unsupported encoding '{}'.",
+                            e
+                        )))
+                    }
+                };
+                continue;
+            }
+
+            if symbol.section_index() != Some(section.index()) {
+                continue;
+            }
+
+            let (tag, data) = match parse_symbol(name) {
+                Some(symbol) => symbol,
+                None => continue,
+            };
+
+            let tag = match tag.as_str() {
+                "defmt_timestamp" => {
+                    timestamp = Some(data);
+                    continue;
+                }
+                "defmt_trace" => Tag::Log(Level::Trace),
+                "defmt_debug" => Tag::Log(Level::Debug),
+                "defmt_info" => Tag::Log(Level::Info),
+                "defmt_warn" => Tag::Log(Level::Warn),
+                "defmt_error" => Tag::Log(Level::Error),
+                "defmt_println" => Tag::Println,
+                "defmt_derived" => Tag::Derived,
+                _ => Tag::Other,
+            };
+
+            entries.insert(symbol.address() as u16, Entry { tag, format: data });
+        }
+
+        match version.as_deref() {
+            Some("4") => {}
+            Some(v) => {
+                return Err(Error::InvalidDefmtData(format!(
+                    "Unsupported defmt wire format version {}. Only version 4 is supported.",
+                    v
+                )))
+            }
+            None => {
+                return Err(Error::InvalidDefmtData(
+                    "defmt version marker not found.".to_string(),
+                ))
+            }
+        }
+
+        // Locations are nice to have, so broken or missing debug information is not an error
+        let locations = read_locations(&file).unwrap_or_default();
+
+        Ok(Table {
+            entries,
+            timestamp,
+            encoding,
+            locations,
+        })
+    }
+
+    /// Returns the number of format strings in the table.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the table contains no format strings.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns `true` if source locations were found in the debug information.
+    pub fn has_locations(&self) -> bool {
+        !self.locations.is_empty()
+    }
+}
+
+/// Parses a symbol name of the form
+/// `{"package":"...","tag":"...","data":"...","disambiguator":"...","crate_name":"..."}` and
+/// returns the tag and data fields.
+fn parse_symbol(name: &str) -> Option<(String, String)> {
+    let mut chars = name.strip_prefix('{')?.chars();
+
+    let mut tag = None;
+    let mut data = None;
+
+    loop {
+        let key = parse_json_string(&mut chars)?;
+
+        if chars.next()? != ':' {
+            return None;
+        }
+
+        let value = parse_json_string(&mut chars)?;
+
+        match key.as_str() {
+            "tag" => tag = Some(value),
+            "data" => data = Some(value),
+            _ => {}
+        }
+
+        match chars.next()? {
+            ',' => continue,
+            '}' => break,
+            _ => return None,
+        }
+    }
+
+    Some((tag?, data?))
+}
+
+/// Parses a JSON string literal, including the quotes.
+fn parse_json_string(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+
+    let mut s = String::new();
+
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                'r' => s.push('\r'),
+                'u' => {
+                    let hex: String = chars.take(4).collect();
+                    s.push(std::char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => s.push(c),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+type Reader = gimli::EndianRcSlice<gimli::RunTimeEndian>;
+
+/// Finds the source locations of log statements in the DWARF debug information.
+fn read_locations(file: &object::File) -> Result<BTreeMap<u16, Location>, gimli::Error> {
+    let endian = if file.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    };
+
+    let load_section = |id: gimli::SectionId| -> Result<Reader, gimli::Error> {
+        let data = file
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[][..]));
+
+        Ok(gimli::EndianRcSlice::new(Rc::from(&*data), endian))
+    };
+
+    let load_section_sup = |_| Ok(gimli::EndianRcSlice::new(Rc::from(&[][..]), endian));
+
+    let dwarf = gimli::Dwarf::load(&load_section, &load_section_sup)?;
+
+    let mut locations = BTreeMap::new();
+    let mut units = dwarf.units();
+
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+
+        // Stack of enclosing namespaces and their depth in the tree
+        let mut namespaces: Vec<(isize, String)> = Vec::new();
+        let mut depth = 0;
+
+        while let Some((delta, entry)) = entries.next_dfs()? {
+            depth += delta;
+
+            while matches!(namespaces.last(), Some((d, _)) if *d >= depth) {
+                namespaces.pop();
+            }
+
+            let name = match entry.attr_value(gimli::DW_AT_name)? {
+                Some(name) => dwarf
+                    .attr_string(&unit, name)?
+                    .to_string_lossy()?
+                    .into_owned(),
+                None => continue,
+            };
+
+            if entry.tag() == gimli::DW_TAG_namespace {
+                namespaces.push((depth, name));
+                continue;
+            }
+
+            if entry.tag() != gimli::DW_TAG_variable || name != LOG_STATEMENT_VAR {
+                continue;
+            }
+
+            // The address of the variable is the index of the format string
+            let index = match entry.attr_value(gimli::DW_AT_location)? {
+                Some(gimli::AttributeValue::Exprloc(expr)) => {
+                    let mut reader = expr.0;
+
+                    if reader.read_u8()? != gimli::DW_OP_addr.0 {
+                        continue;
+                    }
+
+                    reader.read_address(unit.encoding().address_size)? as u16
+                }
+                _ => continue,
+            };
+
+            let file_index = match entry.attr_value(gimli::DW_AT_decl_file)? {
+                Some(gimli::AttributeValue::FileIndex(index)) => index,
+                _ => continue,
+            };
+
+            let line = match entry
+                .attr_value(gimli::DW_AT_decl_line)?
+                .and_then(|line| line.udata_value())
+            {
+                Some(line) => line,
+                None => continue,
+            };
+
+            let file = match unit.line_program.as_ref() {
+                Some(program) => {
+                    let header = program.header();
+
+                    match header.file(file_index) {
+                        Some(entry) => {
+                            let path = dwarf.attr_string(&unit, entry.path_name())?;
+                            let path = path.to_string_lossy()?;
+
+                            match entry.directory(header) {
+                                Some(dir) => {
+                                    let dir = dwarf.attr_string(&unit, dir)?;
+
+                                    Path::new(&*dir.to_string_lossy()?)
+                                        .join(&*path)
+                                        .to_string_lossy()
+                                        .into_owned()
+                                }
+                                None => path.into_owned(),
+                            }
+                        }
+                        None => continue,
+                    }
+                }
+                None => continue,
+            };
+
+            let module = namespaces
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect::<Vec<_>>()
+                .join("::");
+
+            locations.insert(index, Location { file, line, module });
+        }
+    }
+
+    Ok(locations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol() {
+        assert_eq!(
+            parse_symbol(
+                r#"{"package":"app","tag":"defmt_info","data":"x={=u8}","disambiguator":"123","crate_name":"app"}"#
+            ),
+            Some(("defmt_info".to_string(), "x={=u8}".to_string()))
+        );
+
+        // Fields in any order
+        assert_eq!(
+            parse_symbol(r#"{"data":"","tag":"defmt_prim"}"#),
+            Some(("defmt_prim".to_string(), "".to_string()))
+        );
+    }
+
+    #[test]
+    fn symbol_escapes() {
+        assert_eq!(
+            parse_symbol(r#"{"tag":"defmt_println","data":"a\"b\\cä\n\t\r\/"}"#),
+            Some(("defmt_println".to_string(), "a\"b\\cä\n\t\r/".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_symbols() {
+        assert_eq!(parse_symbol("main"), None);
+        assert_eq!(parse_symbol("_defmt_version_ = 4"), None);
+        assert_eq!(parse_symbol(r#"{"tag":"defmt_info"}"#), None);
+        assert_eq!(parse_symbol(r#"{"data":"x"}"#), None);
+        assert_eq!(parse_symbol(r#"{"tag":"defmt_info","data":"x""#), None);
+        assert_eq!(parse_symbol(r#"{"tag":"defmt_info";"data":"x"}"#), None);
+        assert_eq!(parse_symbol(r#"{"tag":"defmt_info","data":"x}"#), None);
+        assert_eq!(parse_symbol(r#"{"tag":"defmt_info","data":"\u00"}"#), None);
+        assert_eq!(parse_symbol(r#"{"tag":defmt_info,"data":"x"}"#), None);
+    }
+}
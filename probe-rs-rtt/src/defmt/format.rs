@@ -0,0 +1,827 @@
+//! Decoding and formatting of frame contents.
+
+use defmt_parser::{DisplayHint, Fragment, Parameter, ParserMode, TimePrecision, Type};
+
+use super::elf::{Entry, Table, Tag};
+use super::Frame;
+
+pub(crate) enum DecodeError {
+    /// The data ends before the frame is complete.
+    UnexpectedEof,
+    Malformed(String),
+}
+
+type Result<T> = std::result::Result<T, DecodeError>;
+
+/// Limit for nested `Format` values to avoid unbounded recursion on corrupted data.
+const MAX_DEPTH: usize = 32;
+
+/// Decodes an rzCOBS encoded frame without the terminating zero byte.
+///
+/// rzCOBS is decoded from the end of the frame towards the start. The result may contain up to
+/// seven extra trailing zero bytes, which are ignored by the frame decoder.
+pub(crate) fn rzcobs_decode(data: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut iter = data.iter().rev().copied();
+
+    let truncated = || "rzCOBS frame is truncated.".to_string();
+
+    while let Some(code) = iter.next() {
+        match code {
+            0x00 => return Err("Unexpected zero byte in rzCOBS frame.".to_string()),
+            0x01..=0x7f => {
+                // Each bit, from the most significant, is a zero byte if set
+                for i in (0..7).rev() {
+                    if code & (1 << i) != 0 {
+                        out.push(0);
+                    } else {
+                        out.push(iter.next().ok_or_else(truncated)?);
+                    }
+                }
+            }
+            0x80..=0xfe => {
+                out.push(0);
+
+                for _ in 0..((code & 0x7f) + 7) {
+                    out.push(iter.next().ok_or_else(truncated)?);
+                }
+            }
+            0xff => {
+                for _ in 0..134 {
+                    out.push(iter.next().ok_or_else(truncated)?);
+                }
+            }
+        }
+    }
+
+    out.reverse();
+
+    Ok(out)
+}
+
+/// Decodes a frame from the start of the data. Returns the frame and the number of bytes used.
+pub(crate) fn decode_frame(table: &Table, data: &[u8]) -> Result<(Frame, usize)> {
+    let mut decoder = FrameDecoder {
+        table,
+        data,
+        pos: 0,
+        depth: 0,
+    };
+
+    let index = decoder.u16()?;
+    let entry = decoder.entry(index)?;
+
+    let level = match entry.tag {
+        Tag::Log(level) => Some(level),
+        Tag::Println => None,
+        _ => {
+            return Err(DecodeError::Malformed(format!(
+                "Format string {} is not a log statement.",
+                index
+            )))
+        }
+    };
+
+    let timestamp = match table.timestamp.as_ref() {
+        Some(format) => Some(decoder.format(format)?),
+        None => None,
+    };
+
+    let message = decoder.format(&entry.format)?;
+
+    Ok((
+        Frame {
+            index,
+            level,
+            timestamp,
+            message,
+            location: table.locations.get(&index).cloned(),
+        },
+        decoder.pos,
+    ))
+}
+
+/// A decoded argument value.
+enum Arg {
+    Bool(bool),
+    Char(char),
+    F32(f32),
+    F64(f64),
+    /// Unsigned integer, also used for bitfields.
+    Uint(u128),
+    /// Signed integer with its size in bits.
+    Int(i128, u32),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// Nested value that is already formatted.
+    Formatted(String),
+}
+
+struct FrameDecoder<'a> {
+    table: &'a Table,
+    data: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> FrameDecoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(DecodeError::UnexpectedEof);
+        }
+
+        let bytes = &self.data[self.pos..(self.pos + len)];
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    /// Reads a little endian unsigned integer of `len` bytes.
+    fn uint(&mut self, len: usize) -> Result<u128> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .rev()
+            .fold(0u128, |value, &b| (value << 8) | b as u128))
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    /// Reads a `usize`, which defmt always sends as 32 bits.
+    fn usize(&mut self) -> Result<usize> {
+        Ok(self.uint(4)? as usize)
+    }
+
+    fn int(&mut self, len: usize) -> Result<Arg> {
+        let bits = len as u32 * 8;
+        let value = self.uint(len)?;
+
+        // Sign extend
+        let shift = 128 - bits;
+
+        Ok(Arg::Int(((value << shift) as i128) >> shift, bits))
+    }
+
+    fn entry(&self, index: u16) -> Result<&'a Entry> {
+        self.table.entries.get(&index).ok_or_else(|| {
+            DecodeError::Malformed(format!("Format string {} not found in table.", index))
+        })
+    }
+
+    /// Reads the arguments for a format string and formats it.
+    fn format(&mut self, format: &str) -> Result<String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(DecodeError::Malformed(
+                "Values are nested too deeply.".to_string(),
+            ));
+        }
+
+        self.depth += 1;
+        let result = self.format_inner(format);
+        self.depth -= 1;
+
+        result
+    }
+
+    fn format_inner(&mut self, format: &str) -> Result<String> {
+        let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible)
+            .map_err(|err| DecodeError::Malformed(format!("Invalid format string: {}", err)))?;
+
+        let params: Vec<&Parameter> = fragments
+            .iter()
+            .filter_map(|frag| match frag {
+                Fragment::Parameter(param) => Some(param),
+                Fragment::Literal(_) => None,
+            })
+            .collect();
+
+        // Arguments are sent in order of their index, not in order of appearance
+        let arg_count = params.iter().map(|p| p.index + 1).max().unwrap_or(0);
+        let mut args = Vec::with_capacity(arg_count);
+
+        for index in 0..arg_count {
+            let param = params.iter().find(|p| p.index == index).ok_or_else(|| {
+                DecodeError::Malformed(format!("Argument {} is not used.", index))
+            })?;
+
+            let arg = match &param.ty {
+                Type::BitField(_) => {
+                    let (start, end) = defmt_parser::get_max_bitfield_range(
+                        params.iter().copied().filter(|p| p.index == index),
+                    )
+                    .unwrap();
+
+                    // Only the bytes containing the bitfields are sent
+                    let lowest_byte = start / 8;
+                    let highest_byte = (end - 1) / 8;
+                    let size = match highest_byte - lowest_byte + 1 {
+                        1 => 1,
+                        2 => 2,
+                        3..=4 => 4,
+                        5..=8 => 8,
+                        _ => 16,
+                    };
+
+                    Arg::Uint(self.uint(size)? << (lowest_byte * 8))
+                }
+                ty => self.arg(ty)?,
+            };
+
+            args.push(arg);
+        }
+
+        let mut out = String::new();
+
+        for frag in fragments.iter() {
+            match frag {
+                Fragment::Literal(s) => out += s,
+                Fragment::Parameter(param) => {
+                    display(&mut out, &args[param.index], param);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn arg(&mut self, ty: &Type) -> Result<Arg> {
+        Ok(match ty {
+            Type::Bool => Arg::Bool(self.uint(1)? != 0),
+            Type::Char => {
+                let value = self.uint(4)? as u32;
+
+                Arg::Char(std::char::from_u32(value).ok_or_else(|| {
+                    DecodeError::Malformed(format!("Invalid char value 0x{:x}.", value))
+                })?)
+            }
+            Type::F32 => Arg::F32(f32::from_bits(self.uint(4)? as u32)),
+            Type::F64 => Arg::F64(f64::from_bits(self.uint(8)? as u64)),
+            Type::U8 => Arg::Uint(self.uint(1)?),
+            Type::U16 => Arg::Uint(self.uint(2)?),
+            Type::U32 | Type::Usize => Arg::Uint(self.uint(4)?),
+            Type::U64 => Arg::Uint(self.uint(8)?),
+            Type::U128 => Arg::Uint(self.uint(16)?),
+            Type::I8 => self.int(1)?,
+            Type::I16 => self.int(2)?,
+            Type::I32 | Type::Isize => self.int(4)?,
+            Type::I64 => self.int(8)?,
+            Type::I128 => self.int(16)?,
+            Type::Str => {
+                let len = self.usize()?;
+
+                Arg::Str(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+            }
+            Type::IStr => {
+                let index = self.u16()?;
+
+                Arg::Str(self.entry(index)?.format.clone())
+            }
+            Type::U8Slice => {
+                let len = self.usize()?;
+
+                Arg::Bytes(self.bytes(len)?.to_vec())
+            }
+            Type::U8Array(len) => Arg::Bytes(self.bytes(*len)?.to_vec()),
+            Type::Debug | Type::Display => {
+                // Formatted on the target and terminated by 0xff
+                let len = self.data[self.pos..]
+                    .iter()
+                    .position(|&b| b == 0xff)
+                    .ok_or(DecodeError::UnexpectedEof)?;
+
+                let s = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+                self.pos += 1;
+
+                Arg::Formatted(s)
+            }
+            Type::Format => {
+                let index = self.u16()?;
+                let entry = self.entry(index)?;
+
+                Arg::Formatted(self.format_data(entry)?)
+            }
+            Type::FormatSlice => {
+                let len = self.usize()?;
+                self.format_list(len)?
+            }
+            Type::FormatArray(len) => self.format_list(*len)?,
+            Type::FormatSequence => {
+                // A sequence of write! calls terminated by a zero index
+                let mut s = String::new();
+
+                loop {
+                    let index = self.u16()?;
+
+                    if index == 0 {
+                        break;
+                    }
+
+                    s += &self.format(&self.entry(index)?.format)?;
+                }
+
+                Arg::Formatted(s)
+            }
+            Type::BitField(_) => unreachable!(),
+        })
+    }
+
+    /// Reads the data of a `Format` value whose format string has already been read.
+    fn format_data(&mut self, entry: &Entry) -> Result<String> {
+        // Derived enums list the formats of all variants separated by '|', and send the index of
+        // the variant first
+        if entry.tag == Tag::Derived && entry.format.contains('|') {
+            let variants: Vec<&str> = entry.format.split('|').collect();
+
+            let discriminant = match variants.len() {
+                0..=255 => self.uint(1)?,
+                256..=65535 => self.uint(2)?,
+                _ => self.uint(4)?,
+            } as usize;
+
+            let variant = variants.get(discriminant).ok_or_else(|| {
+                DecodeError::Malformed(format!("Invalid enum discriminant {}.", discriminant))
+            })?;
+
+            return self.format(variant);
+        }
+
+        self.format(&entry.format)
+    }
+
+    /// Reads a list of `Format` values that share the same format string.
+    fn format_list(&mut self, len: usize) -> Result<Arg> {
+        let index = self.u16()?;
+        let entry = self.entry(index)?;
+
+        let mut items = Vec::new();
+
+        for _ in 0..len {
+            items.push(self.format_data(entry)?);
+        }
+
+        Ok(Arg::Formatted(format!("[{}]", items.join(", "))))
+    }
+}
+
+/// Formats an argument according to the display hint of the parameter.
+fn display(out: &mut String, arg: &Arg, param: &Parameter) {
+    let hint = param.hint.as_ref();
+
+    let s = match arg {
+        Arg::Bool(b) => b.to_string(),
+        Arg::Char(c) => match hint {
+            Some(DisplayHint::Debug) => format!("{:?}", c),
+            _ => c.to_string(),
+        },
+        Arg::F32(f) => f.to_string(),
+        Arg::F64(f) => f.to_string(),
+        Arg::Uint(value) => {
+            let value = match &param.ty {
+                Type::BitField(range) => {
+                    let width = range.end - range.start;
+                    let mask = if width >= 128 {
+                        u128::MAX
+                    } else {
+                        (1u128 << width) - 1
+                    };
+
+                    (value >> range.start) & mask
+                }
+                _ => *value,
+            };
+
+            display_uint(value, hint)
+        }
+        Arg::Int(value, bits) => match hint {
+            // Hexadecimal and binary show the two's complement representation
+            Some(DisplayHint::Hexadecimal { .. }) | Some(DisplayHint::Binary { .. }) => {
+                let mask = if *bits == 128 {
+                    u128::MAX
+                } else {
+                    (1u128 << bits) - 1
+                };
+
+                display_uint(*value as u128 & mask, hint)
+            }
+            Some(DisplayHint::NoHint { zero_pad }) => format!("{:01$}", value, zero_pad),
+            _ => value.to_string(),
+        },
+        Arg::Str(s) => match hint {
+            Some(DisplayHint::Debug) => format!("{:?}", s),
+            _ => s.clone(),
+        },
+        Arg::Bytes(bytes) => match hint {
+            Some(DisplayHint::Ascii) => {
+                let escaped: String = bytes
+                    .iter()
+                    .flat_map(|&b| std::ascii::escape_default(b))
+                    .map(char::from)
+                    .collect();
+
+                format!("b\"{}\"", escaped)
+            }
+            _ => {
+                let items: Vec<String> = bytes
+                    .iter()
+                    .map(|&b| display_uint(b as u128, hint))
+                    .collect();
+
+                format!("[{}]", items.join(", "))
+            }
+        },
+        Arg::Formatted(s) => s.clone(),
+    };
+
+    out.push_str(&s);
+}
+
+fn display_uint(value: u128, hint: Option<&DisplayHint>) -> String {
+    match hint {
+        Some(DisplayHint::NoHint { zero_pad }) => format!("{:01$}", value, zero_pad),
+        Some(DisplayHint::Hexadecimal {
+            alternate,
+            uppercase,
+            zero_pad,
+        }) => match (alternate, uppercase) {
+            (false, false) => format!("{:01$x}", value, zero_pad),
+            (false, true) => format!("{:01$X}", value, zero_pad),
+            (true, false) => format!("{:#01$x}", value, zero_pad),
+            (true, true) => format!("{:#01$X}", value, zero_pad),
+        },
+        Some(DisplayHint::Binary {
+            alternate,
+            zero_pad,
+        }) => {
+            if *alternate {
+                format!("{:#01$b}", value, zero_pad)
+            } else {
+                format!("{:01$b}", value, zero_pad)
+            }
+        }
+        Some(DisplayHint::Ascii) if value < 0x80 => std::ascii::escape_default(value as u8)
+            .map(char::from)
+            .collect(),
+        Some(DisplayHint::Seconds(precision)) => {
+            let (per_second, digits) = precision_scale(precision);
+
+            format!("{}.{:02$}", value / per_second, value % per_second, digits)
+        }
+        Some(DisplayHint::Time(precision)) => {
+            let (per_second, digits) = precision_scale(precision);
+            let seconds = value / per_second;
+
+            let mut s = format!(
+                "{:02}:{:02}:{:02}",
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            );
+
+            if digits > 0 {
+                s += &format!(".{:01$}", value % per_second, digits);
+            }
+
+            s
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Returns the number of ticks per second and the number of fractional digits for a precision.
+fn precision_scale(precision: &TimePrecision) -> (u128, usize) {
+    match precision {
+        TimePrecision::Micros => (1_000_000, 6),
+        TimePrecision::Millis => (1_000, 3),
+        TimePrecision::Seconds => (1, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defmt::elf::Encoding;
+    use crate::defmt::Level;
+    use std::collections::BTreeMap;
+
+    const LOG: u16 = 1;
+
+    /// Creates a table with the log statement `format` at index 1 and the other entries.
+    fn table(format: &str, others: &[(u16, Tag, &str)]) -> Table {
+        let mut entries = BTreeMap::new();
+
+        entries.insert(
+            LOG,
+            Entry {
+                tag: Tag::Log(Level::Info),
+                format: format.to_string(),
+            },
+        );
+
+        for &(index, tag, format) in others {
+            entries.insert(
+                index,
+                Entry {
+                    tag,
+                    format: format.to_string(),
+                },
+            );
+        }
+
+        Table {
+            entries,
+            timestamp: None,
+            encoding: Encoding::Raw,
+            locations: BTreeMap::new(),
+        }
+    }
+
+    fn decode(table: &Table, args: &[u8]) -> Result<String> {
+        let mut data = LOG.to_le_bytes().to_vec();
+        data.extend_from_slice(args);
+
+        let (frame, len) = decode_frame(table, &data)?;
+        assert_eq!(len, data.len());
+
+        Ok(frame.message)
+    }
+
+    fn message(format: &str, args: &[u8]) -> String {
+        match decode(&table(format, &[]), args) {
+            Ok(message) => message,
+            Err(DecodeError::UnexpectedEof) => panic!("unexpected end of frame"),
+            Err(DecodeError::Malformed(err)) => panic!("malformed frame: {}", err),
+        }
+    }
+
+    fn malformed(table: &Table, args: &[u8]) -> String {
+        match decode(table, args) {
+            Err(DecodeError::Malformed(err)) => err,
+            _ => panic!("expected a malformed frame"),
+        }
+    }
+
+    fn concat(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    #[test]
+    fn rzcobs() {
+        // Each code byte marks the zero bytes of the up to seven bytes before it
+        assert_eq!(rzcobs_decode(&[0x7f]).unwrap(), vec![0; 7]);
+        assert_eq!(
+            rzcobs_decode(&[1, 2, 0x7a]).unwrap(),
+            vec![1, 0, 2, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            rzcobs_decode(&[1, 2, 3, 4, 5, 6, 7, 0x80]).unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7, 0]
+        );
+        assert_eq!(
+            rzcobs_decode(&[1, 2, 3, 4, 5, 6, 7, 8, 0x81]).unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 0]
+        );
+
+        // Multiple groups are decoded in order
+        assert_eq!(
+            rzcobs_decode(&[1, 2, 3, 4, 5, 6, 7, 0x80, 9, 0x7e]).unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7, 0, 9, 0, 0, 0, 0, 0, 0]
+        );
+
+        // 134 bytes without a zero
+        let long: Vec<u8> = (1..=134).collect();
+        let mut encoded = long.clone();
+        encoded.push(0xff);
+        assert_eq!(rzcobs_decode(&encoded).unwrap(), long);
+
+        assert_eq!(rzcobs_decode(&[]).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn rzcobs_errors() {
+        assert!(rzcobs_decode(&[1, 0, 0x7f]).unwrap_err().contains("zero"));
+        assert!(rzcobs_decode(&[0x7c]).unwrap_err().contains("truncated"));
+        assert!(rzcobs_decode(&[1, 0x80]).unwrap_err().contains("truncated"));
+        assert!(rzcobs_decode(&[1, 0xff]).unwrap_err().contains("truncated"));
+    }
+
+    #[test]
+    fn bool_char_float() {
+        assert_eq!(message("{=bool} {=bool}", &[1, 0]), "true false");
+        assert_eq!(
+            message("{=char} {=char:?}", &[0xe4, 0, 0, 0, b'a', 0, 0, 0]),
+            "ä 'a'"
+        );
+        assert_eq!(
+            message(
+                "{=f32} {=f64}",
+                &concat(&[&1.5f32.to_le_bytes(), &(-0.25f64).to_le_bytes()])
+            ),
+            "1.5 -0.25"
+        );
+
+        assert!(malformed(&table("{=char}", &[]), &[0x00, 0xd8, 0, 0]).contains("char"));
+    }
+
+    #[test]
+    fn unsigned() {
+        let args = concat(&[
+            &[200],
+            &60000u16.to_le_bytes(),
+            &4_000_000_000u32.to_le_bytes(),
+            &7u32.to_le_bytes(),
+            &u64::MAX.to_le_bytes(),
+            &u128::MAX.to_le_bytes(),
+        ]);
+
+        assert_eq!(
+            message("{=u8} {=u16} {=u32} {=usize} {=u64} {=u128}", &args),
+            format!("200 60000 4000000000 7 {} {}", u64::MAX, u128::MAX)
+        );
+
+        assert_eq!(
+            message(
+                "{=u8:x} {=u8:#X} {=u8:08b} {=u8:#b} {=u8:03}",
+                &[0xab, 0xab, 5, 5, 7]
+            ),
+            "ab 0xAB 00000101 0b101 007"
+        );
+        assert_eq!(message("{=u8:a} {=u8:a}", b"a\n"), "a \\n");
+    }
+
+    #[test]
+    fn signed() {
+        let args = concat(&[
+            &[0x80],
+            &(-2i16).to_le_bytes(),
+            &(-3i32).to_le_bytes(),
+            &(-4i32).to_le_bytes(),
+            &i64::MIN.to_le_bytes(),
+            &i128::MIN.to_le_bytes(),
+        ]);
+
+        assert_eq!(
+            message("{=i8} {=i16} {=i32} {=isize} {=i64} {=i128}", &args),
+            format!("-128 -2 -3 -4 {} {}", i64::MIN, i128::MIN)
+        );
+
+        // Hexadecimal and binary show the two's complement representation
+        assert_eq!(
+            message(
+                "{=i8:x} {=i16:#x} {=i8:b} {=i128:x}",
+                &concat(&[&[0xff, 0xfe, 0xff, 0x81], &(-1i128).to_le_bytes()])
+            ),
+            format!("ff 0xfffe 10000001 {}", "f".repeat(32))
+        );
+        assert_eq!(message("{=i32:05}", &(-42i32).to_le_bytes()), "-0042");
+    }
+
+    #[test]
+    fn strings_and_bytes() {
+        assert_eq!(
+            message(
+                "{=str} {=str:?}",
+                &[2, 0, 0, 0, b'h', b'i', 1, 0, 0, 0, b'"']
+            ),
+            "hi \"\\\"\""
+        );
+        assert_eq!(
+            decode(&table("{=istr}", &[(5, Tag::Other, "interned")]), &[5, 0]).ok(),
+            Some("interned".to_string())
+        );
+
+        assert_eq!(message("{=[u8]}", &[3, 0, 0, 0, 1, 2, 3]), "[1, 2, 3]");
+        assert_eq!(message("{=[u8]:x}", &[2, 0, 0, 0, 0x0a, 0xff]), "[a, ff]");
+        assert_eq!(
+            message("{=[u8]:a}", &[2, 0, 0, 0, b'a', 0xff]),
+            "b\"a\\xff\""
+        );
+        assert_eq!(message("{=[u8; 2]} {=u8}", &[5, 6, 7]), "[5, 6] 7");
+    }
+
+    #[test]
+    fn debug_and_display() {
+        // Formatted on the target and terminated by 0xff
+        assert_eq!(
+            message(
+                "{=__internal_Debug} {=__internal_Display} {=u8}",
+                &concat(&[b"Some(1)", &[0xff], b"ok", &[0xff], &[9]])
+            ),
+            "Some(1) ok 9"
+        );
+        assert_eq!(message("[{=__internal_Display}]", &[0xff]), "[]");
+
+        assert!(matches!(
+            decode(&table("{=__internal_Debug}", &[]), b"unterminated"),
+            Err(DecodeError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn bitfields() {
+        // Only the bytes containing the bitfields are sent
+        assert_eq!(message("{0=4..12:x} {0=0..4}", &[0xab, 0x0c]), "ca 11");
+        assert_eq!(message("{0=8..16:x}", &[0x5a]), "5a");
+        assert_eq!(message("{0=0..1} {0=31..32}", &[1, 0, 0, 0x80]), "1 1");
+        assert_eq!(message("{0=0..64:x}", &[0xff; 8]), "f".repeat(16));
+
+        // The full width of a u128 must not overflow the mask
+        assert_eq!(message("{0=0..128:x}", &[0xff; 16]), "f".repeat(32));
+        assert_eq!(message("{0=120..128:x}", &[0x34]), "34");
+    }
+
+    #[test]
+    fn argument_order() {
+        // Arguments are sent in order of their index
+        assert_eq!(
+            message("{1=u8} {0=u16} {1=u8:x}", &[0x34, 0x12, 0xab]),
+            "171 4660 ab"
+        );
+        assert!(malformed(&table("{1=u8}", &[]), &[0]).contains("not used"));
+    }
+
+    #[test]
+    fn format_values() {
+        let table = table(
+            "{=?} {=[?]} {=[?; 2]}",
+            &[
+                (2, Tag::Derived, "x={=u8}"),
+                (3, Tag::Derived, "None|Some({=u8})"),
+            ],
+        );
+
+        let args = concat(&[&[2, 0, 1], &[2, 0, 0, 0, 3, 0, 1, 7, 0], &[2, 0, 2, 3]]);
+
+        assert_eq!(
+            decode(&table, &args).ok(),
+            Some("x=1 [Some(7), None] [x=2, x=3]".to_string())
+        );
+
+        assert!(malformed(&table, &[3, 0, 2]).contains("discriminant"));
+        assert!(malformed(&table, &[9, 0]).contains("not found"));
+    }
+
+    #[test]
+    fn format_sequence() {
+        let table = table(
+            "<{=__internal_FormatSequence}>",
+            &[(2, Tag::Other, "a{=u8}"), (3, Tag::Other, "b")],
+        );
+
+        assert_eq!(
+            decode(&table, &[2, 0, 1, 3, 0, 2, 0, 5, 0, 0]).ok(),
+            Some("<a1ba5>".to_string())
+        );
+        assert_eq!(decode(&table, &[0, 0]).ok(), Some("<>".to_string()));
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let table = table("{=?}", &[(2, Tag::Derived, "({=?})")]);
+
+        assert!(malformed(&table, &[2, 0].repeat(MAX_DEPTH + 1)).contains("nested"));
+    }
+
+    #[test]
+    fn timestamp_and_level() {
+        let mut table = table("{=u8}", &[(2, Tag::Println, "hello"), (3, Tag::Other, "x")]);
+        table.timestamp = Some("{=u32:tms}".to_string());
+
+        let data = concat(&[&[1, 0], &3_723_004u32.to_le_bytes(), &[5]]);
+        let (frame, _) = decode_frame(&table, &data).ok().unwrap();
+
+        assert_eq!(frame.index, 1);
+        assert_eq!(frame.level, Some(Level::Info));
+        assert_eq!(frame.timestamp.as_deref(), Some("01:02:03.004"));
+        assert_eq!(frame.message, "5");
+
+        let data = concat(&[&[2, 0], &1500u32.to_le_bytes()]);
+        let (frame, _) = decode_frame(&table, &data).ok().unwrap();
+
+        assert_eq!(frame.level, None);
+        assert_eq!(frame.timestamp.as_deref(), Some("00:00:01.500"));
+        assert_eq!(frame.message, "hello");
+
+        let data = concat(&[&[3, 0], &0u32.to_le_bytes()]);
+        assert!(matches!(
+            decode_frame(&table, &data),
+            Err(DecodeError::Malformed(err)) if err.contains("not a log statement")
+        ));
+
+        assert_eq!(message("{=u32:ms}", &1234u32.to_le_bytes()), "1.234");
+        assert_eq!(message("{=u64:us}", &5u64.to_le_bytes()), "0.000005");
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(matches!(
+            decode(&table("{=u32}", &[]), &[1, 2]),
+            Err(DecodeError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            decode_frame(&table("", &[]), &[1]),
+            Err(DecodeError::UnexpectedEof)
+        ));
+    }
+}
@@ -0,0 +1,459 @@
+//! Decoding of [defmt](https://github.com/knurling-rs/defmt) log frames.
+//!
+//! defmt sends log messages as compact binary frames that refer to format strings stored in the
+//! firmware ELF file. [`Table`] reads the format strings and source locations from the ELF file,
+//! and [`Decoder`] turns the raw data from an up channel into [`Frame`]s. [`DefmtReader`] wraps an
+//! up channel (or any other [`Read`] source) and outputs the decoded frames as lines of text.
+//!
+//! Wire format version 4 (defmt 0.3) is supported, with both the rzCOBS and raw encodings.
+//!
+//! This module is only available with the `defmt` feature.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use probe_rs::Probe;
+//! use probe_rs_rtt::defmt::{Decoder, Table};
+//! use probe_rs_rtt::Rtt;
+//! use std::sync::Arc;
+//!
+//! let table = Arc::new(Table::parse(&std::fs::read("firmware.elf")?)?);
+//!
+//! let probe = Probe::list_all()[0].open()?;
+//! let session = probe.attach("somechip")?;
+//! let core = session.attach_to_core(0)?;
+//! let mut rtt = Rtt::attach(core, &session)?;
+//!
+//! let input = rtt.up_channels().take_by_name("defmt").unwrap();
+//! let mut decoder = Decoder::new(table);
+//! let mut buf = [0u8; 1024];
+//!
+//! loop {
+//!     let count = input.read(&mut buf[..])?;
+//!     decoder.received(&buf[..count]);
+//!
+//!     while let Some(frame) = decoder.next_frame()? {
+//!         println!("{}", frame);
+//!     }
+//! }
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use crate::Error;
+
+mod elf;
+mod format;
+
+pub use elf::Table;
+
+/// Name of the up channel defmt logs to by default.
+pub const CHANNEL_NAME: &str = "defmt";
+
+/// Log level of a frame.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Returns the name of the level in upper case.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Source location of a log statement.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Location {
+    /// Path of the source file.
+    pub file: String,
+
+    /// Line number in the source file.
+    pub line: u64,
+
+    /// Module path of the log statement.
+    pub module: String,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} @ {}:{}", self.module, self.file, self.line)
+    }
+}
+
+/// A decoded log frame.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// Index of the format string in the table.
+    pub index: u16,
+
+    /// Log level, or `None` for `println!` frames.
+    pub level: Option<Level>,
+
+    /// Formatted timestamp, if the firmware defines one.
+    pub timestamp: Option<String>,
+
+    /// Formatted message.
+    pub message: String,
+
+    /// Source location of the log statement, if available in the ELF file.
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for Frame {
+    /// Formats the frame as `timestamp LEVEL message`, leaving out the parts that are not present.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(timestamp) = self.timestamp.as_ref() {
+            write!(f, "{} ", timestamp)?;
+        }
+
+        if let Some(level) = self.level {
+            write!(f, "{:<5} ", level)?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Streaming decoder that turns raw up channel data into frames.
+pub struct Decoder {
+    table: Arc<Table>,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    /// Creates a new decoder using the format strings in the table.
+    pub fn new(table: Arc<Table>) -> Self {
+        Decoder {
+            table,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns the table used by the decoder.
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    /// Adds data received from the target to the decoder.
+    pub fn received(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Decodes the next complete frame, or returns `None` if there is none yet.
+    ///
+    /// A malformed frame is dropped and returns an error, after which decoding can continue. With
+    /// the raw encoding frame boundaries cannot be recovered, so all buffered data is dropped as
+    /// well.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if self.table.encoding == elf::Encoding::Raw {
+            return match format::decode_frame(&self.table, &self.buf) {
+                Ok((frame, len)) => {
+                    self.buf.drain(..len);
+                    Ok(Some(frame))
+                }
+                Err(format::DecodeError::UnexpectedEof) => Ok(None),
+                Err(format::DecodeError::Malformed(err)) => {
+                    self.buf.clear();
+                    Err(Error::MalformedDefmtFrame(err))
+                }
+            };
+        }
+
+        loop {
+            let end = match self.buf.iter().position(|&b| b == 0) {
+                Some(end) => end,
+                None => return Ok(None),
+            };
+
+            let encoded: Vec<u8> = self.buf.drain(..=end).take(end).collect();
+
+            // Empty frames are separators, e.g. the one sent at the start of a session
+            if encoded.is_empty() {
+                continue;
+            }
+
+            let data = format::rzcobs_decode(&encoded).map_err(Error::MalformedDefmtFrame)?;
+
+            return match format::decode_frame(&self.table, &data) {
+                Ok((frame, _)) => Ok(Some(frame)),
+                Err(format::DecodeError::UnexpectedEof) => Err(Error::MalformedDefmtFrame(
+                    "Frame ends in the middle of a value.".to_string(),
+                )),
+                Err(format::DecodeError::Malformed(err)) => Err(Error::MalformedDefmtFrame(err)),
+            };
+        }
+    }
+}
+
+/// Reader that decodes defmt frames from an up channel and outputs them as lines of text.
+///
+/// Each frame is output as `timestamp LEVEL message`, optionally followed by a line with the
+/// source location. Malformed frames are reported inline and skipped.
+pub struct DefmtReader<R: Read> {
+    inner: R,
+    decoder: Decoder,
+    read_buf: Box<[u8]>,
+    out: Vec<u8>,
+    show_location: bool,
+    colors: bool,
+}
+
+impl<R: Read> DefmtReader<R> {
+    /// Creates a new reader that shows source locations and does not use colors.
+    pub fn new(inner: R, table: Arc<Table>) -> Self {
+        DefmtReader {
+            inner,
+            decoder: Decoder::new(table),
+            read_buf: vec![0u8; 1024].into_boxed_slice(),
+            out: Vec::new(),
+            show_location: true,
+            colors: false,
+        }
+    }
+
+    /// Sets whether the source location is output after each frame.
+    pub fn show_location(mut self, show_location: bool) -> Self {
+        self.show_location = show_location;
+        self
+    }
+
+    /// Sets whether the log level is colored with ANSI escape sequences.
+    pub fn colors(mut self, colors: bool) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    /// Returns a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns the underlying reader. Any buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn write_frame(&mut self, frame: &Frame) {
+        let mut line = String::new();
+
+        if let Some(timestamp) = frame.timestamp.as_ref() {
+            line += timestamp;
+            line.push(' ');
+        }
+
+        if let Some(level) = frame.level {
+            if self.colors {
+                line += &format!("\x1b[{}m{:<5}\x1b[0m ", level_color(level), level);
+            } else {
+                line += &format!("{:<5} ", level);
+            }
+        }
+
+        line += &frame.message;
+        line.push('\n');
+
+        if let (true, Some(location)) = (self.show_location, frame.location.as_ref()) {
+            if self.colors {
+                line += &format!("\x1b[2m└─ {}\x1b[0m\n", location);
+            } else {
+                line += &format!("└─ {}\n", location);
+            }
+        }
+
+        self.out.extend_from_slice(line.as_bytes());
+    }
+}
+
+impl<R: Read> Read for DefmtReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.out.is_empty() {
+            let count = self.inner.read(&mut self.read_buf)?;
+            self.decoder.received(&self.read_buf[..count]);
+
+            loop {
+                match self.decoder.next_frame() {
+                    Ok(Some(frame)) => self.write_frame(&frame),
+                    Ok(None) => break,
+                    Err(err) => self
+                        .out
+                        .extend_from_slice(format!("({})\n", err).as_bytes()),
+                }
+            }
+        }
+
+        let count = self.out.len().min(buf.len());
+
+        buf[..count].copy_from_slice(&self.out[..count]);
+        self.out.drain(..count);
+
+        Ok(count)
+    }
+}
+
+/// Returns the SGR color code for a log level.
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Trace => "2",
+        Level::Debug => "34",
+        Level::Info => "32",
+        Level::Warn => "33",
+        Level::Error => "31",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::elf::{Encoding, Entry, Tag};
+    use super::*;
+    use crate::test_util::ScriptedReader;
+    use std::collections::BTreeMap;
+
+    fn table(encoding: Encoding) -> Arc<Table> {
+        let mut entries = BTreeMap::new();
+
+        entries.insert(
+            1,
+            Entry {
+                tag: Tag::Log(Level::Info),
+                format: "x={=u8}".to_string(),
+            },
+        );
+
+        let mut locations = BTreeMap::new();
+
+        locations.insert(
+            1,
+            Location {
+                file: "src/main.rs".to_string(),
+                line: 3,
+                module: "app".to_string(),
+            },
+        );
+
+        Arc::new(Table {
+            entries,
+            timestamp: None,
+            encoding,
+            locations,
+        })
+    }
+
+    fn messages(decoder: &mut Decoder) -> Vec<Result<String, String>> {
+        let mut result = Vec::new();
+
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => result.push(Ok(frame.to_string())),
+                Ok(None) => return result,
+                Err(err) => result.push(Err(err.to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn raw_frames() {
+        let mut decoder = Decoder::new(table(Encoding::Raw));
+
+        decoder.received(&[1]);
+        assert!(messages(&mut decoder).is_empty());
+
+        decoder.received(&[0, 5, 1, 0]);
+        assert_eq!(messages(&mut decoder), vec![Ok("INFO  x=5".to_string())]);
+
+        decoder.received(&[6]);
+        assert_eq!(messages(&mut decoder), vec![Ok("INFO  x=6".to_string())]);
+    }
+
+    #[test]
+    fn raw_resync_after_malformed_frame() {
+        let mut decoder = Decoder::new(table(Encoding::Raw));
+
+        // The boundary of the next frame is lost along with the malformed one
+        decoder.received(&[9, 0, 1, 0, 5]);
+        let result = messages(&mut decoder);
+        assert_eq!(result.len(), 1);
+        assert!(result[0].is_err());
+
+        decoder.received(&[1, 0, 7]);
+        assert_eq!(messages(&mut decoder), vec![Ok("INFO  x=7".to_string())]);
+    }
+
+    #[test]
+    fn rzcobs_frames() {
+        let mut decoder = Decoder::new(table(Encoding::Rzcobs));
+
+        // [1, 0, 5] and [1, 0, 6] with separators, split in the middle of a frame
+        decoder.received(&[0, 1, 5, 0x7a, 0, 0, 1]);
+        assert_eq!(messages(&mut decoder), vec![Ok("INFO  x=5".to_string())]);
+
+        decoder.received(&[6, 0x7a, 0]);
+        assert_eq!(messages(&mut decoder), vec![Ok("INFO  x=6".to_string())]);
+    }
+
+    #[test]
+    fn rzcobs_resync_after_malformed_frame() {
+        let mut decoder = Decoder::new(table(Encoding::Rzcobs));
+
+        // A truncated rzCOBS frame, a frame ending in the middle of a value and a valid frame
+        decoder.received(&[0x7c, 0, 1, 0x7f, 0, 1, 7, 0x7a, 0]);
+
+        let result = messages(&mut decoder);
+        assert_eq!(result.len(), 3);
+        assert!(result[0].as_ref().unwrap_err().contains("truncated"));
+        assert!(result[1].is_err());
+        assert_eq!(result[2], Ok("INFO  x=7".to_string()));
+    }
+
+    #[test]
+    fn reader_lines() {
+        let input = ScriptedReader::new(&[&[1, 5, 0x7a], &[0, 0x7c, 0, 1, 6, 0x7a, 0]]);
+        let mut reader = DefmtReader::new(input, table(Encoding::Rzcobs));
+
+        // Nothing is output until a frame is complete
+        let mut out = String::new();
+        assert_eq!(reader.read_to_string(&mut out).unwrap(), 0);
+        reader.read_to_string(&mut out).unwrap();
+
+        assert_eq!(
+            out,
+            "INFO  x=5\n└─ app @ src/main.rs:3\n\
+             (Malformed defmt frame: rzCOBS frame is truncated.)\n\
+             INFO  x=6\n└─ app @ src/main.rs:3\n"
+        );
+    }
+
+    #[test]
+    fn reader_colors_without_location() {
+        let input = ScriptedReader::new(&[&[1, 0, 5]]);
+        let mut reader = DefmtReader::new(input, table(Encoding::Raw))
+            .show_location(false)
+            .colors(true);
+
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+
+        assert_eq!(out, "\x1b[32mINFO \x1b[0m x=5\n");
+    }
+}
@@ -0,0 +1,599 @@
+//! Expect-style scripted interaction with a target, for hardware-in-the-loop tests.
+//!
+//! [`Expect`] waits for patterns in the data from an up channel (or any other [`Read`] source) and
+//! sends data to a down channel (or any other [`Write`] sink). Patterns are byte regexes, so the
+//! data does not need to be valid UTF-8. Exit patterns fail the interaction as soon as they match a
+//! line of the data, e.g. to stop at a panic message instead of waiting for a timeout.
+//!
+//! [`Script`] runs a list of steps parsed from a simple script format with a step per line:
+//!
+//! ```text
+//! # Fail as soon as the firmware panics
+//! exit-on /panicked at/
+//!
+//! expect /ready/ 5s
+//! send "start\n"
+//! expect /result: \d+/ 500ms
+//! ```
+//!
+//! Patterns are delimited by slashes, with `\/` standing for a slash in the pattern. The timeout of
+//! `expect` can be given in `ms`, `s` or `min` and defaults to the timeout of the [`Expect`], which
+//! is [`DEFAULT_TIMEOUT`] unless changed. Strings are delimited by double quotes and support the
+//! escapes `\n`, `\r`, `\t`, `\0`, `\\`, `\"` and `\xNN`.
+//!
+//! This module is only available with the `expect` feature.
+//!
+//! ## Example
+//!
+//! ```
+//! use probe_rs_rtt::expect::{Expect, Script};
+//!
+//! // Any reader and writer work, on a target these would be an up channel and a down channel
+//! let input = &b"booting\nready\nresult: 42\n"[..];
+//! let mut expect = Expect::new(input, Vec::new());
+//!
+//! let script = Script::parse("expect /ready/ 1s\nsend \"start\\n\"\nexpect /result: \\d+/")?;
+//! script.run(&mut expect)?;
+//!
+//! let (_, output) = expect.into_inner();
+//! assert_eq!(output, b"start\n");
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+pub use regex::bytes::Regex;
+
+use crate::Error;
+
+/// Default timeout for waiting for patterns and for space in the output.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits for patterns in the data from a reader and sends data to a writer.
+///
+/// Reading and writing never block, so the reader and writer are polled until a pattern matches or
+/// the timeout passes. The reader returning 0 bytes means that no data is available yet, not the
+/// end of the stream.
+pub struct Expect<R: Read, W: Write> {
+    input: R,
+    output: W,
+    timeout: Duration,
+    /// Data received but not yet consumed by a match.
+    buf: Vec<u8>,
+    /// Received line that exit patterns are matched against.
+    line: Vec<u8>,
+    exit_patterns: Vec<Regex>,
+    echo: Option<Box<dyn Write>>,
+}
+
+/// The data matched by [`Expect::expect`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Match {
+    /// Data received before the match, which was skipped.
+    pub before: Vec<u8>,
+
+    /// Data matched by each group of the pattern, with the whole match first. Groups that did not
+    /// participate in the match are `None`.
+    pub groups: Vec<Option<Vec<u8>>>,
+}
+
+impl<R: Read, W: Write> Expect<R, W> {
+    /// Creates a new `Expect` reading from `input` and writing to `output`.
+    pub fn new(input: R, output: W) -> Self {
+        Expect {
+            input,
+            output,
+            timeout: DEFAULT_TIMEOUT,
+            buf: Vec::new(),
+            line: Vec::new(),
+            exit_patterns: Vec::new(),
+            echo: None,
+        }
+    }
+
+    /// Sets the timeout for sending data, and for script steps that wait for a pattern without a
+    /// timeout of their own. The default is [`DEFAULT_TIMEOUT`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Writes all the data received to `echo` as well, e.g. to show the output of the target while
+    /// a script runs.
+    pub fn echo(mut self, echo: impl Write + 'static) -> Self {
+        self.echo = Some(Box::new(echo));
+        self
+    }
+
+    /// Adds a pattern that fails the interaction with [`Error::ExitPatternMatched`] as soon as it
+    /// matches a line of the received data. Data received before the pattern was added is not
+    /// checked.
+    pub fn exit_on(&mut self, pattern: Regex) {
+        self.exit_patterns.push(pattern);
+    }
+
+    /// Waits until the pattern matches the received data and returns the match. The data up to the
+    /// end of the match is consumed, so the next call starts matching after it.
+    pub fn expect(&mut self, pattern: &Regex, timeout: Duration) -> Result<Match, Error> {
+        let start = Instant::now();
+        let mut received = true;
+
+        loop {
+            if received {
+                if let Some(captures) = pattern.captures(&self.buf) {
+                    let whole = captures.get(0).unwrap();
+
+                    let m = Match {
+                        before: self.buf[..whole.start()].to_vec(),
+                        groups: captures
+                            .iter()
+                            .map(|group| group.map(|group| group.as_bytes().to_vec()))
+                            .collect(),
+                    };
+
+                    self.buf.drain(..whole.end());
+
+                    return Ok(m);
+                }
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(Error::ExpectTimeout(delimited(pattern)));
+            }
+
+            received = self.poll()? > 0;
+        }
+    }
+
+    /// Sends all of the data, waiting for space in the output for up to the timeout. Data is still
+    /// received while waiting, so that the target is not blocked on a full up channel meanwhile.
+    pub fn send(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        let start = Instant::now();
+
+        while !data.is_empty() {
+            let count = self.output.write(data)?;
+            data = &data[count..];
+
+            if count == 0 {
+                if start.elapsed() >= self.timeout {
+                    return Err(Error::ExpectTimeout("space in the output".to_string()));
+                }
+
+                self.poll()?;
+            }
+        }
+
+        self.output.flush()?;
+
+        Ok(())
+    }
+
+    /// Runs a script step.
+    pub fn run_step(&mut self, step: &Step) -> Result<(), Error> {
+        match step {
+            Step::Expect { pattern, timeout } => {
+                self.expect(pattern, timeout.unwrap_or(self.timeout))?;
+            }
+            Step::Send(data) => self.send(data)?,
+            Step::ExitOn(pattern) => self.exit_on(pattern.clone()),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the reader and the writer.
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+
+    /// Reads any new data and checks it against the exit patterns. Returns the number of bytes
+    /// read.
+    fn poll(&mut self) -> Result<usize, Error> {
+        let mut data = [0u8; 1024];
+        let count = self.input.read(&mut data)?;
+        let data = &data[..count];
+
+        if let Some(echo) = self.echo.as_mut() {
+            echo.write_all(data)?;
+            echo.flush()?;
+        }
+
+        self.buf.extend_from_slice(data);
+
+        for piece in data.split_inclusive(|&b| b == b'\n') {
+            self.line.extend_from_slice(piece);
+
+            if self.exit_patterns.iter().any(|p| p.is_match(&self.line)) {
+                let line = String::from_utf8_lossy(&self.line);
+
+                return Err(Error::ExitPatternMatched(line.trim_end().to_string()));
+            }
+
+            if self.line.ends_with(b"\n") {
+                self.line.clear();
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+/// A step of a [`Script`].
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// Waits for a pattern. Without a timeout the timeout of the [`Expect`] is used.
+    Expect {
+        pattern: Regex,
+        timeout: Option<Duration>,
+    },
+
+    /// Sends data.
+    Send(Vec<u8>),
+
+    /// Adds an exit pattern.
+    ExitOn(Regex),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Expect { pattern, timeout } => {
+                write!(f, "expect {}", delimited(pattern))?;
+
+                if let Some(timeout) = timeout {
+                    write!(f, " {}ms", timeout.as_millis())?;
+                }
+
+                Ok(())
+            }
+            Step::Send(data) => write!(f, "send {:?}", String::from_utf8_lossy(data)),
+            Step::ExitOn(pattern) => write!(f, "exit-on {}", delimited(pattern)),
+        }
+    }
+}
+
+/// A list of steps parsed from a script, see the [module documentation](self) for the format.
+#[derive(Clone, Debug)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+impl Script {
+    /// Parses a script.
+    pub fn parse(text: &str) -> Result<Script, Error> {
+        let mut steps = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let step = parse_step(line).map_err(|err| Error::InvalidScript(index + 1, err))?;
+
+            steps.push(step);
+        }
+
+        Ok(Script { steps })
+    }
+
+    /// Returns the steps of the script.
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Runs the steps of the script in order, stopping at the first one that fails.
+    pub fn run<R: Read, W: Write>(&self, expect: &mut Expect<R, W>) -> Result<(), Error> {
+        for step in self.steps.iter() {
+            expect.run_step(step)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_step(line: &str) -> Result<Step, String> {
+    let (command, args) = match line.find(char::is_whitespace) {
+        Some(p) => (&line[..p], line[p..].trim_start()),
+        None => (line, ""),
+    };
+
+    let step = match command {
+        "expect" => {
+            let (pattern, rest) = parse_pattern(args)?;
+
+            let timeout = if rest.is_empty() {
+                None
+            } else {
+                Some(parse_timeout(rest)?)
+            };
+
+            Step::Expect { pattern, timeout }
+        }
+        "send" => {
+            let (data, rest) = parse_string(args)?;
+            expect_end(rest)?;
+
+            Step::Send(data)
+        }
+        "exit-on" => {
+            let (pattern, rest) = parse_pattern(args)?;
+            expect_end(rest)?;
+
+            Step::ExitOn(pattern)
+        }
+        _ => return Err(format!("unknown command '{}'", command)),
+    };
+
+    Ok(step)
+}
+
+fn expect_end(rest: &str) -> Result<(), String> {
+    if rest.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("unexpected '{}'", rest))
+    }
+}
+
+/// Returns a pattern delimited by slashes, as written in scripts.
+fn delimited(pattern: &Regex) -> String {
+    format!("/{}/", pattern.as_str().replace('/', "\\/"))
+}
+
+/// Parses a pattern delimited by slashes and returns it with the rest of the input.
+fn parse_pattern(s: &str) -> Result<(Regex, &str), String> {
+    let mut chars = s.char_indices();
+
+    if !matches!(chars.next(), Some((_, '/'))) {
+        return Err("expected a pattern delimited by slashes".to_string());
+    }
+
+    let mut pattern = String::new();
+
+    loop {
+        match chars.next() {
+            Some((_, '\\')) => match chars.next() {
+                Some((_, '/')) => pattern.push('/'),
+                Some((_, c)) => {
+                    pattern.push('\\');
+                    pattern.push(c);
+                }
+                None => break,
+            },
+            Some((p, '/')) => {
+                if pattern.is_empty() {
+                    return Err("pattern must not be empty".to_string());
+                }
+
+                let regex = Regex::new(&pattern).map_err(|err| err.to_string())?;
+
+                return Ok((regex, s[(p + 1)..].trim_start()));
+            }
+            Some((_, c)) => pattern.push(c),
+            None => break,
+        }
+    }
+
+    Err("unterminated pattern".to_string())
+}
+
+/// Parses a string delimited by double quotes and returns it with the rest of the input.
+fn parse_string(s: &str) -> Result<(Vec<u8>, &str), String> {
+    let mut chars = s.char_indices();
+
+    if !matches!(chars.next(), Some((_, '"'))) {
+        return Err("expected a string delimited by double quotes".to_string());
+    }
+
+    let mut data = Vec::new();
+    let mut utf8 = [0u8; 4];
+
+    loop {
+        match chars.next() {
+            Some((_, '\\')) => match chars.next() {
+                Some((_, 'n')) => data.push(b'\n'),
+                Some((_, 'r')) => data.push(b'\r'),
+                Some((_, 't')) => data.push(b'\t'),
+                Some((_, '0')) => data.push(0),
+                Some((_, '\\')) => data.push(b'\\'),
+                Some((_, '"')) => data.push(b'"'),
+                Some((p, 'x')) => {
+                    let byte = s
+                        .get((p + 1)..(p + 3))
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                        .ok_or_else(|| "expected two hex digits after \\x".to_string())?;
+
+                    data.push(byte);
+                    chars.next();
+                    chars.next();
+                }
+                Some((_, c)) => return Err(format!("unknown escape '\\{}'", c)),
+                None => break,
+            },
+            Some((p, '"')) => return Ok((data, s[(p + 1)..].trim_start())),
+            Some((_, c)) => data.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes()),
+            None => break,
+        }
+    }
+
+    Err("unterminated string".to_string())
+}
+
+/// Parses a timeout such as `500ms`, `5s` or `2min`. A number without a unit is in seconds.
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+
+    let (number, unit) = s.split_at(split);
+
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("invalid timeout '{}'", s))?;
+
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "s" | "" => number,
+        "min" => number * 60.0,
+        _ => return Err(format!("invalid timeout unit in '{}'", s)),
+    };
+
+    // Converting a number of seconds that does not fit in a Duration panics
+    if seconds >= u64::MAX as f64 {
+        return Err(format!("timeout '{}' is too long", s));
+    }
+
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ScriptedReader;
+
+    fn expect(chunks: &[&[u8]]) -> Expect<ScriptedReader, Vec<u8>> {
+        Expect::new(ScriptedReader::new(chunks), Vec::new()).timeout(Duration::from_millis(20))
+    }
+
+    fn parse_error(line: &str) -> String {
+        match parse_step(line) {
+            Err(err) => err,
+            Ok(step) => panic!("'{}' parsed as '{}'", line, step),
+        }
+    }
+
+    #[test]
+    fn parse_script() {
+        let script = Script::parse(
+            "# comment\n\n  exit-on /panicked at/\nexpect /a\\/b \\d+/ 1.5s\nexpect /x/\n\
+             send \"q\\\"\\\\\\x7f\\0\\r\\n\\tä\"\n",
+        )
+        .unwrap();
+
+        let steps: Vec<String> = script.steps().iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(
+            steps,
+            [
+                "exit-on /panicked at/",
+                "expect /a\\/b \\d+/ 1500ms",
+                "expect /x/",
+                "send \"q\\\"\\\\\\u{7f}\\0\\r\\n\\tä\"",
+            ]
+        );
+
+        match &script.steps()[3] {
+            Step::Send(data) => assert_eq!(data, b"q\"\\\x7f\0\r\n\t\xc3\xa4"),
+            step => panic!("unexpected step '{}'", step),
+        }
+    }
+
+    #[test]
+    fn parse_timeouts() {
+        for &(s, millis) in &[
+            ("250ms", 250),
+            ("2s", 2000),
+            ("3", 3000),
+            ("0.5s", 500),
+            ("2min", 120_000),
+            ("1 s", 1000),
+        ] {
+            assert_eq!(
+                parse_timeout(s).unwrap(),
+                Duration::from_millis(millis),
+                "{}",
+                s
+            );
+        }
+
+        for s in &[
+            "",
+            "s",
+            "1h",
+            "-1s",
+            "1e3s",
+            "1e400",
+            "99999999999999999999min",
+        ] {
+            assert!(parse_timeout(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_error("wait /x/"), "unknown command 'wait'");
+        assert_eq!(
+            parse_error("expect x"),
+            "expected a pattern delimited by slashes"
+        );
+        assert_eq!(parse_error("expect //"), "pattern must not be empty");
+        assert_eq!(parse_error("expect /abc\\/"), "unterminated pattern");
+        assert_eq!(parse_error("exit-on /x/ 1s"), "unexpected '1s'");
+        assert_eq!(parse_error("send \"abc"), "unterminated string");
+        assert_eq!(parse_error("send \"\\q\""), "unknown escape '\\q'");
+        assert_eq!(
+            parse_error("send \"\\x4\""),
+            "expected two hex digits after \\x"
+        );
+        assert_eq!(parse_error("send \"a\" \"b\""), "unexpected '\"b\"'");
+        assert!(parse_error("expect /(/").contains("unclosed group"));
+
+        match Script::parse("expect /x/\n\n# comment\nsend x") {
+            Err(Error::InvalidScript(line, _)) => assert_eq!(line, 4),
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn expect_across_reads() {
+        let mut expect = expect(&[b"boot", b"ing\nresult: 4", b"2\nresult: 7\n"]);
+        let pattern = Regex::new(r"result: (\d+)\n").unwrap();
+
+        let m = expect.expect(&pattern, Duration::from_secs(1)).unwrap();
+        assert_eq!(m.before, b"booting\n");
+        assert_eq!(
+            m.groups,
+            [Some(b"result: 42\n".to_vec()), Some(b"42".to_vec())]
+        );
+
+        // Matching continues after the previous match
+        let m = expect.expect(&pattern, Duration::from_secs(1)).unwrap();
+        assert_eq!(m.before, b"");
+        assert_eq!(m.groups[1], Some(b"7".to_vec()));
+
+        match expect.expect(&pattern, Duration::from_millis(10)) {
+            Err(Error::ExpectTimeout(pattern)) => assert_eq!(pattern, "/result: (\\d+)\\n/"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn exit_pattern_split_across_reads() {
+        let mut expect = expect(&[b"ok\npan", b"icked at src/main.rs\nready\n"]);
+        expect.exit_on(Regex::new("^panicked").unwrap());
+
+        match expect.expect(&Regex::new("ready").unwrap(), Duration::from_secs(1)) {
+            Err(Error::ExitPatternMatched(line)) => {
+                assert_eq!(line, "panicked at src/main.rs")
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn run_script() {
+        let mut expect = expect(&[b"ready\n", b"", b"echo: go\n"]);
+
+        Script::parse("expect /ready/\nsend \"go\\n\"\nexpect /echo: go/ 1s")
+            .unwrap()
+            .run(&mut expect)
+            .unwrap();
+
+        assert_eq!(expect.into_inner().1, b"go\n");
+    }
+}
@@ -0,0 +1,689 @@
+//! Packet framing for binary channels.
+//!
+//! RTT channels are plain byte streams, so binary protocols need a framing layer to find the
+//! packet boundaries again on the host. [`FrameReader`] splits the data from an up channel (or any
+//! other [`Read`] source) into whole frames, and [`FrameWriter`] frames packets written to a down
+//! channel (or any other [`Write`] sink). The supported framings are:
+//!
+//! - [`Framing::Cobs`]: Consistent Overhead Byte Stuffing with a zero byte after each frame.
+//! - [`Framing::Slip`]: SLIP (RFC 1055) with an `END` byte before and after each frame.
+//! - [`Framing::Varint`]: each frame is prefixed with its length as an unsigned LEB128 varint.
+//!
+//! Corrupted frames are dropped and counted, and the reader resynchronizes on the next frame
+//! boundary. With the varint framing there are no boundary markers, so the reader skips data one
+//! byte at a time until the length prefix looks valid again.
+//!
+//! ## Example
+//!
+//! ```
+//! use probe_rs_rtt::framing::{FrameReader, Framing};
+//!
+//! let mut data = Framing::Cobs.encode(b"\x01\x00\x02");
+//!
+//! // A corrupted frame, followed by a good one
+//! data.extend_from_slice(b"\x05\x01\x00");
+//! data.extend(Framing::Cobs.encode(b"hello"));
+//!
+//! let mut reader = FrameReader::new(&data[..], Framing::Cobs);
+//!
+//! assert_eq!(reader.next_frame()?, Some(b"\x01\x00\x02".to_vec()));
+//! assert_eq!(reader.next_frame()?, Some(b"hello".to_vec()));
+//! assert_eq!(reader.next_frame()?, None);
+//! assert_eq!(reader.stats().bad_frames, 1);
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::str;
+
+/// Default maximum size of a decoded frame.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4096;
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+/// Maximum length of a varint length prefix. Lengths are limited to 32 bits.
+const VARINT_MAX_LEN: usize = 5;
+
+/// Framing used to delimit packets in a byte stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Framing {
+    /// COBS encoded frames, each followed by a zero byte.
+    Cobs,
+
+    /// SLIP encoded frames, delimited by `END` (0xC0) bytes. Empty frames are skipped, as they
+    /// cannot be told apart from consecutive delimiters.
+    Slip,
+
+    /// Frames prefixed with their length as an unsigned LEB128 varint.
+    Varint,
+}
+
+impl Framing {
+    /// Encodes a packet as a single frame, including the delimiters.
+    pub fn encode(self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+
+        match self {
+            Framing::Cobs => {
+                let mut code_pos = 0;
+                out.push(0);
+
+                for &b in data {
+                    if b != 0 {
+                        out.push(b);
+                    }
+
+                    let code = out.len() - code_pos;
+
+                    if b == 0 || code == 0xff {
+                        out[code_pos] = code as u8;
+                        code_pos = out.len();
+                        out.push(0);
+                    }
+                }
+
+                out[code_pos] = (out.len() - code_pos) as u8;
+                out.push(0);
+            }
+            Framing::Slip => {
+                out.push(SLIP_END);
+
+                for &b in data {
+                    match b {
+                        SLIP_END => out.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+                        SLIP_ESC => out.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+                        b => out.push(b),
+                    }
+                }
+
+                out.push(SLIP_END);
+            }
+            Framing::Varint => {
+                let mut len = data.len();
+
+                loop {
+                    if len < 0x80 {
+                        out.push(len as u8);
+                        break;
+                    }
+
+                    out.push((len as u8 & 0x7f) | 0x80);
+                    len >>= 7;
+                }
+
+                out.extend_from_slice(data);
+            }
+        }
+
+        out
+    }
+}
+
+impl str::FromStr for Framing {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Framing, &'static str> {
+        match s {
+            "cobs" => Ok(Framing::Cobs),
+            "slip" => Ok(Framing::Slip),
+            "varint" => Ok(Framing::Varint),
+            _ => Err("Invalid framing. Valid framings are 'cobs', 'slip' and 'varint'."),
+        }
+    }
+}
+
+/// Frame counters of a [`FrameReader`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FrameStats {
+    /// Number of frames received successfully.
+    pub frames: u64,
+
+    /// Number of frames dropped because they were corrupted or too large.
+    pub bad_frames: u64,
+}
+
+/// Reader that splits the data from an up channel into frames.
+///
+/// The reader never blocks waiting for data. Frames only become available once they are complete.
+pub struct FrameReader<R: Read> {
+    inner: R,
+    framing: Framing,
+    max_frame_size: usize,
+    read_buf: Box<[u8]>,
+    /// Data of the frame that is not yet complete. Still encoded for COBS and varint, already
+    /// decoded for SLIP.
+    buf: Vec<u8>,
+    /// Set after a SLIP escape byte.
+    escape: bool,
+    /// Set when the current frame is corrupted and the rest of it is skipped.
+    discard: bool,
+    frames: VecDeque<Vec<u8>>,
+    stats: FrameStats,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Creates a new frame reader with the default maximum frame size.
+    pub fn new(inner: R, framing: Framing) -> Self {
+        FrameReader {
+            inner,
+            framing,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            read_buf: vec![0u8; 1024].into_boxed_slice(),
+            buf: Vec::new(),
+            escape: false,
+            discard: false,
+            frames: VecDeque::new(),
+            stats: FrameStats::default(),
+        }
+    }
+
+    /// Sets the maximum size of a decoded frame. Larger frames are dropped and counted as bad.
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// Returns the framing used by the reader.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Returns the frame counters.
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Returns a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the underlying reader. Any buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads any new data from the underlying reader and returns the next complete frame, or
+    /// `None` if there is none yet.
+    pub fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.frames.is_empty() {
+            self.poll()?;
+        }
+
+        Ok(self.frames.pop_front())
+    }
+
+    /// Reads any new data from the underlying reader and decodes the complete frames in it.
+    /// Returns the number of bytes read.
+    pub fn poll(&mut self) -> io::Result<usize> {
+        let count = self.inner.read(&mut self.read_buf)?;
+
+        for i in 0..count {
+            let b = self.read_buf[i];
+
+            match self.framing {
+                Framing::Cobs => self.cobs_byte(b),
+                Framing::Slip => self.slip_byte(b),
+                Framing::Varint => self.buf.push(b),
+            }
+        }
+
+        if self.framing == Framing::Varint {
+            self.varint_frames();
+        }
+
+        Ok(count)
+    }
+
+    fn cobs_byte(&mut self, b: u8) {
+        if b != 0 {
+            // Encoding adds at most one byte per 254 bytes of data, plus the first code byte
+            if self.buf.len() > self.max_frame_size + self.max_frame_size / 254 {
+                self.bad_frame();
+            }
+
+            if !self.discard {
+                self.buf.push(b);
+            }
+
+            return;
+        }
+
+        if !self.discard && !self.buf.is_empty() {
+            match cobs_decode(&self.buf) {
+                Some(frame) if frame.len() <= self.max_frame_size => self.push_frame(frame),
+                _ => self.stats.bad_frames += 1,
+            }
+        }
+
+        self.buf.clear();
+        self.discard = false;
+    }
+
+    fn slip_byte(&mut self, b: u8) {
+        if b == SLIP_END {
+            if self.escape {
+                self.stats.bad_frames += 1;
+            } else if !self.discard && !self.buf.is_empty() {
+                let frame = std::mem::take(&mut self.buf);
+                self.push_frame(frame);
+            }
+
+            self.buf.clear();
+            self.escape = false;
+            self.discard = false;
+            return;
+        }
+
+        if self.discard {
+            return;
+        }
+
+        let b = if self.escape {
+            self.escape = false;
+
+            match b {
+                SLIP_ESC_END => SLIP_END,
+                SLIP_ESC_ESC => SLIP_ESC,
+                _ => return self.bad_frame(),
+            }
+        } else if b == SLIP_ESC {
+            self.escape = true;
+            return;
+        } else {
+            b
+        };
+
+        if self.buf.len() >= self.max_frame_size {
+            return self.bad_frame();
+        }
+
+        self.buf.push(b);
+    }
+
+    fn varint_frames(&mut self) {
+        let mut pos = 0;
+
+        loop {
+            let mut len = 0usize;
+            let mut prefix_len = None;
+
+            for (i, &b) in self.buf[pos..].iter().take(VARINT_MAX_LEN).enumerate() {
+                len |= ((b & 0x7f) as usize) << (7 * i);
+
+                if b & 0x80 == 0 {
+                    prefix_len = Some(i + 1);
+                    break;
+                }
+            }
+
+            let prefix_len = match prefix_len {
+                Some(prefix_len) if len <= self.max_frame_size => prefix_len,
+                None if self.buf.len() - pos < VARINT_MAX_LEN => break,
+                _ => {
+                    // Not a valid length prefix, skip a byte and try again from the next one
+                    if !self.discard {
+                        self.stats.bad_frames += 1;
+                        self.discard = true;
+                    }
+
+                    pos += 1;
+                    continue;
+                }
+            };
+
+            let start = pos + prefix_len;
+
+            if self.buf.len() - start < len {
+                break;
+            }
+
+            let frame = self.buf[start..(start + len)].to_vec();
+            self.push_frame(frame);
+            self.discard = false;
+            pos = start + len;
+        }
+
+        self.buf.drain(..pos);
+    }
+
+    /// Drops the current frame and skips data until the next frame boundary.
+    fn bad_frame(&mut self) {
+        if !self.discard {
+            self.stats.bad_frames += 1;
+            self.discard = true;
+        }
+
+        self.buf.clear();
+    }
+
+    fn push_frame(&mut self, frame: Vec<u8>) {
+        self.stats.frames += 1;
+        self.frames.push_back(frame);
+    }
+}
+
+/// Decodes a COBS encoded frame without the terminating zero byte.
+fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let code = data[pos] as usize;
+        let end = pos + code;
+
+        if code == 0 || end > data.len() {
+            return None;
+        }
+
+        out.extend_from_slice(&data[(pos + 1)..end]);
+        pos = end;
+
+        // A zero byte is implied after each block, except for full blocks and the last one
+        if code < 0xff && pos < data.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
+
+/// Writer that frames packets written to a down channel.
+///
+/// Down channel writes do not block and may only write part of the data if the buffer is full.
+/// Framed data that could not be written yet is kept in the writer and written by later calls to
+/// [`FrameWriter::write_frame`] or [`FrameWriter::poll`], so frames are never cut off.
+pub struct FrameWriter<W: Write> {
+    inner: W,
+    framing: Framing,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Creates a new frame writer.
+    pub fn new(inner: W, framing: Framing) -> Self {
+        FrameWriter {
+            inner,
+            framing,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Returns the framing used by the writer.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the underlying writer. Any pending data is lost.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Returns the number of bytes that are waiting to be written.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Frames a packet and writes as much of the pending data as possible. Returns the number of
+    /// bytes written.
+    pub fn write_frame(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.pending.extend(self.framing.encode(data));
+        self.poll()
+    }
+
+    /// Writes as much of the pending data as possible. Returns the number of bytes written.
+    pub fn poll(&mut self) -> io::Result<usize> {
+        let mut total = 0;
+
+        while !self.pending.is_empty() {
+            let count = self.inner.write(&self.pending)?;
+
+            if count == 0 {
+                break;
+            }
+
+            self.pending.drain(..count);
+            total += count;
+        }
+
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ScriptedReader;
+
+    /// Writer that accepts at most `size` bytes per call, and nothing once `capacity` is reached.
+    struct Limited {
+        data: Vec<u8>,
+        size: usize,
+        capacity: usize,
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let count = self
+                .size
+                .min(buf.len())
+                .min(self.capacity - self.data.len());
+            self.data.extend_from_slice(&buf[..count]);
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn decode(reader: &mut FrameReader<ScriptedReader>) -> Vec<Vec<u8>> {
+        while reader.poll().unwrap() > 0 {}
+
+        let mut frames = Vec::new();
+
+        while let Some(frame) = reader.next_frame().unwrap() {
+            frames.push(frame);
+        }
+
+        frames
+    }
+
+    fn decode_chunked(framing: Framing, data: &[u8], size: usize) -> Vec<Vec<u8>> {
+        decode(&mut FrameReader::new(
+            ScriptedReader::split(data, size),
+            framing,
+        ))
+    }
+
+    fn packets() -> Vec<Vec<u8>> {
+        vec![
+            b"a".to_vec(),
+            vec![0],
+            vec![0, 0, 1, 0],
+            vec![SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC_ESC],
+            (1..=254).collect(),
+            (1..=255).cycle().take(600).collect(),
+            (0..=255).cycle().take(1000).collect(),
+        ]
+    }
+
+    #[test]
+    fn cobs_encoding() {
+        assert_eq!(Framing::Cobs.encode(b""), [1, 0]);
+        assert_eq!(Framing::Cobs.encode(&[0]), [1, 1, 0]);
+        assert_eq!(
+            Framing::Cobs.encode(&[0x11, 0x22, 0, 0x33]),
+            [3, 0x11, 0x22, 2, 0x33, 0]
+        );
+
+        let block: Vec<u8> = (1..=254).collect();
+        let mut encoded = vec![0xff];
+        encoded.extend_from_slice(&block);
+        encoded.extend_from_slice(&[1, 0]);
+        assert_eq!(Framing::Cobs.encode(&block), encoded);
+    }
+
+    #[test]
+    fn slip_and_varint_encoding() {
+        assert_eq!(
+            Framing::Slip.encode(&[1, SLIP_END, SLIP_ESC]),
+            [
+                SLIP_END,
+                1,
+                SLIP_ESC,
+                SLIP_ESC_END,
+                SLIP_ESC,
+                SLIP_ESC_ESC,
+                SLIP_END
+            ]
+        );
+
+        assert_eq!(Framing::Varint.encode(b"ab"), [2, b'a', b'b']);
+        assert_eq!(&Framing::Varint.encode(&[7; 300])[..3], [0xac, 0x02, 7]);
+    }
+
+    #[test]
+    fn round_trip_split_across_reads() {
+        for &framing in &[Framing::Cobs, Framing::Slip, Framing::Varint] {
+            let mut packets = packets();
+
+            // Empty frames are only kept by COBS and varint
+            if framing != Framing::Slip {
+                packets.push(Vec::new());
+            }
+
+            let data: Vec<u8> = packets.iter().flat_map(|p| framing.encode(p)).collect();
+
+            for &size in &[1, 3, 254, 1024] {
+                let frames = decode_chunked(framing, &data, size);
+                assert_eq!(frames, packets, "{:?} in chunks of {}", framing, size);
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_frame_is_kept() {
+        for &framing in &[Framing::Cobs, Framing::Slip, Framing::Varint] {
+            let encoded = framing.encode(b"split frame");
+            let (first, second) = encoded.split_at(5);
+
+            let mut reader = FrameReader::new(ScriptedReader::new(&[first]), framing);
+            assert_eq!(reader.next_frame().unwrap(), None);
+
+            reader.get_mut().push(second);
+            assert_eq!(reader.next_frame().unwrap(), Some(b"split frame".to_vec()));
+            assert_eq!(reader.stats().frames, 1);
+        }
+    }
+
+    #[test]
+    fn cobs_resync() {
+        let mut data = vec![0x05, 1, 2, 0];
+        data.extend(Framing::Cobs.encode(b"ok"));
+
+        let mut reader = FrameReader::new(ScriptedReader::split(&data, 2), Framing::Cobs);
+        assert_eq!(decode(&mut reader), [b"ok".to_vec()]);
+        assert_eq!(
+            reader.stats(),
+            FrameStats {
+                frames: 1,
+                bad_frames: 1
+            }
+        );
+    }
+
+    #[test]
+    fn slip_resync() {
+        // Invalid escape, then an escape right before the end of a frame
+        let mut data = vec![SLIP_END, 1, SLIP_ESC, 2, 3, SLIP_END, 4, SLIP_ESC, SLIP_END];
+        data.extend(Framing::Slip.encode(b"ok"));
+
+        let mut reader = FrameReader::new(ScriptedReader::split(&data, 1), Framing::Slip);
+        assert_eq!(decode(&mut reader), [b"ok".to_vec()]);
+        assert_eq!(
+            reader.stats(),
+            FrameStats {
+                frames: 1,
+                bad_frames: 2
+            }
+        );
+    }
+
+    #[test]
+    fn varint_resync() {
+        let mut data = vec![0xff; 6];
+        data.extend(Framing::Varint.encode(b"ok"));
+
+        // Without boundary markers, garbage bytes may look like the prefix of a large frame,
+        // which the maximum frame size rules out here
+        for &size in &[1, 1024] {
+            let mut reader = FrameReader::new(ScriptedReader::split(&data, size), Framing::Varint)
+                .max_frame_size(16);
+            assert_eq!(decode(&mut reader), [b"ok".to_vec()]);
+            assert_eq!(
+                reader.stats(),
+                FrameStats {
+                    frames: 1,
+                    bad_frames: 1
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn max_frame_size() {
+        // Varint frames cannot be skipped reliably, see varint_resync
+        for &framing in &[Framing::Cobs, Framing::Slip] {
+            let mut data = framing.encode(&[1; 600]);
+            data.extend(framing.encode(&[2; 4]));
+
+            let mut reader =
+                FrameReader::new(ScriptedReader::split(&data, 7), framing).max_frame_size(4);
+            assert_eq!(decode(&mut reader), [vec![2; 4]], "{:?}", framing);
+            assert_eq!(reader.stats().bad_frames, 1, "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn writer_keeps_frames_whole() {
+        let sink = Limited {
+            data: Vec::new(),
+            size: 3,
+            capacity: 4,
+        };
+
+        let mut writer = FrameWriter::new(sink, Framing::Slip);
+        assert_eq!(writer.write_frame(b"hello").unwrap(), 4);
+        assert_eq!(writer.pending(), 3);
+
+        writer.get_mut().capacity = 100;
+        assert_eq!(writer.write_frame(b"x").unwrap(), 6);
+        assert_eq!(writer.pending(), 0);
+        assert_eq!(writer.poll().unwrap(), 0);
+
+        let data = writer.into_inner().data;
+        let frames = decode_chunked(Framing::Slip, &data, 1024);
+        assert_eq!(frames, [b"hello".to_vec(), b"x".to_vec()]);
+    }
+}
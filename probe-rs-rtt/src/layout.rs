@@ -0,0 +1,343 @@
+use scroll::{Pwrite, LE};
+
+use crate::channel::Channel;
+use crate::{ChannelMode, Error, Rtt};
+
+/// Describes a control block to be created in target memory with [`Rtt::create_at`].
+///
+/// The control block is placed at the start of the memory area, followed by the channel names and
+/// then the channel buffers, each aligned to 4 bytes. Use
+/// [`buffer_addresses`](ControlBlockLayout::buffer_addresses) to find out where the buffers end up.
+#[derive(Clone, Debug)]
+pub struct ControlBlockLayout {
+    pub(crate) control_block_id: Vec<u8>,
+    pub(crate) up_channels: Vec<ChannelLayout>,
+    pub(crate) down_channels: Vec<ChannelLayout>,
+}
+
+impl ControlBlockLayout {
+    /// Creates a new layout with no channels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the ID string the control block is identified by. The default is `"SEGGER RTT"`. The
+    /// ID must be between 1 and 16 bytes long.
+    pub fn control_block_id(mut self, id: impl AsRef<[u8]>) -> Self {
+        self.control_block_id = id.as_ref().to_vec();
+        self
+    }
+
+    /// Adds an up (target to host) channel. Channels are numbered in the order they are added.
+    pub fn up_channel(mut self, channel: ChannelLayout) -> Self {
+        self.up_channels.push(channel);
+        self
+    }
+
+    /// Adds a down (host to target) channel. Channels are numbered in the order they are added.
+    pub fn down_channel(mut self, channel: ChannelLayout) -> Self {
+        self.down_channels.push(channel);
+        self
+    }
+
+    /// Returns the total size in bytes of the target memory used by the control block, channel
+    /// names and buffers.
+    pub fn size(&self) -> Result<u32, Error> {
+        Ok(self.place(0)?.size)
+    }
+
+    /// Returns the addresses of the up and down channel buffers if the control block is created at
+    /// `ptr`.
+    pub fn buffer_addresses(&self, ptr: u32) -> Result<(Vec<u32>, Vec<u32>), Error> {
+        let mut placement = self.place(ptr)?;
+        let down = placement.buffer_ptrs.split_off(self.up_channels.len());
+
+        Ok((placement.buffer_ptrs, down))
+    }
+
+    pub(crate) fn channels(&self) -> impl Iterator<Item = &ChannelLayout> {
+        self.up_channels.iter().chain(self.down_channels.iter())
+    }
+
+    /// Calculates the addresses of the channel names and buffers. Up channels come first in the
+    /// returned lists.
+    pub(crate) fn place(&self, ptr: u32) -> Result<Placement, Error> {
+        let overflow = || Error::InvalidLayout("Layout extends past the end of the address space.");
+
+        // The buffers are aligned relative to the control block, and the target accesses the
+        // control block with word accesses
+        if ptr & 3 != 0 {
+            return Err(Error::InvalidLayout(
+                "Control block address must be aligned to 4 bytes.",
+            ));
+        }
+
+        let mut offset = Rtt::O_CHANNEL_ARRAYS
+            + (self.up_channels.len() + self.down_channels.len()) * Channel::SIZE;
+
+        let mut name_ptrs = Vec::new();
+
+        for chan in self.channels() {
+            match chan.name.as_ref() {
+                Some(name) => {
+                    if name.as_bytes().contains(&0) {
+                        return Err(Error::InvalidLayout(
+                            "Channel names must not contain null bytes.",
+                        ));
+                    }
+
+                    name_ptrs.push(offset);
+                    offset += name.len() + 1;
+                }
+                None => name_ptrs.push(0),
+            }
+        }
+
+        let data_size = offset;
+
+        let mut buffer_ptrs = Vec::new();
+
+        for chan in self.channels() {
+            if chan.buffer_size < 2 {
+                return Err(Error::InvalidLayout(
+                    "Channel buffers must be at least 2 bytes long.",
+                ));
+            }
+
+            offset = align4(offset);
+            buffer_ptrs.push(offset);
+            offset += chan.buffer_size as usize;
+        }
+
+        let to_ptr = |offset: usize| {
+            if (offset as u64) + (ptr as u64) > u32::MAX as u64 {
+                Err(overflow())
+            } else {
+                Ok(ptr + offset as u32)
+            }
+        };
+
+        Ok(Placement {
+            name_ptrs: name_ptrs
+                .into_iter()
+                .map(|o| if o == 0 { Ok(0) } else { to_ptr(o) })
+                .collect::<Result<_, _>>()?,
+            buffer_ptrs: buffer_ptrs
+                .into_iter()
+                .map(to_ptr)
+                .collect::<Result<_, _>>()?,
+            data_size,
+            size: to_ptr(offset)? - ptr,
+        })
+    }
+
+    /// Builds the contents of the control block and channel names to write to target memory when
+    /// creating the control block at `ptr` with the padded ID `id`.
+    pub(crate) fn image(&self, id: &[u8; 16], ptr: u32) -> Result<(Placement, Vec<u8>), Error> {
+        let placement = self.place(ptr)?;
+
+        let mut mem = vec![0u8; placement.data_size];
+
+        mem[Rtt::O_ID..(Rtt::O_ID + id.len())].copy_from_slice(id);
+        mem.pwrite_with(self.up_channels.len() as u32, Rtt::O_MAX_UP_CHANNELS, LE)
+            .unwrap();
+        mem.pwrite_with(
+            self.down_channels.len() as u32,
+            Rtt::O_MAX_DOWN_CHANNELS,
+            LE,
+        )
+        .unwrap();
+
+        for (i, chan) in self.channels().enumerate() {
+            let offset = Rtt::O_CHANNEL_ARRAYS + i * Channel::SIZE;
+            let name_ptr = placement.name_ptrs[i];
+
+            Channel::write_descriptor(
+                &mut mem[offset..],
+                name_ptr,
+                placement.buffer_ptrs[i],
+                chan.buffer_size,
+                chan.mode,
+            );
+
+            if let Some(name) = chan.name.as_ref() {
+                let name_offset = (name_ptr - ptr) as usize;
+                mem[name_offset..(name_offset + name.len())].copy_from_slice(name.as_bytes());
+            }
+        }
+
+        Ok((placement, mem))
+    }
+
+    /// Splits an image built with [`image`](ControlBlockLayout::image) into the writes to do in
+    /// order. Everything after the ID is written first.
+    pub(crate) fn writes(ptr: u32, mem: &[u8]) -> [(u32, &[u8]); 2] {
+        [
+            (
+                ptr + Rtt::O_MAX_UP_CHANNELS as u32,
+                &mem[Rtt::O_MAX_UP_CHANNELS..],
+            ),
+            (
+                ptr + Rtt::O_ID as u32,
+                &mem[Rtt::O_ID..Rtt::O_MAX_UP_CHANNELS],
+            ),
+        ]
+    }
+}
+
+impl Default for ControlBlockLayout {
+    fn default() -> Self {
+        ControlBlockLayout {
+            control_block_id: b"SEGGER RTT".to_vec(),
+            up_channels: Vec::new(),
+            down_channels: Vec::new(),
+        }
+    }
+}
+
+/// Describes a channel in a [`ControlBlockLayout`].
+#[derive(Clone, Debug)]
+pub struct ChannelLayout {
+    pub(crate) name: Option<String>,
+    pub(crate) buffer_size: u32,
+    pub(crate) mode: ChannelMode,
+}
+
+impl ChannelLayout {
+    /// Creates a new unnamed channel with a buffer of the specified size in bytes. Note that the
+    /// usable size is one byte less due to how the ring buffer is implemented.
+    pub fn new(buffer_size: u32) -> Self {
+        ChannelLayout {
+            name: None,
+            buffer_size,
+            mode: ChannelMode::NoBlockSkip,
+        }
+    }
+
+    /// Sets the name of the channel.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the initial mode of the channel. The default is [`ChannelMode::NoBlockSkip`].
+    pub fn mode(mut self, mode: ChannelMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+/// Addresses of the parts of a control block created from a layout.
+pub(crate) struct Placement {
+    /// Channel name addresses, or 0 for unnamed channels.
+    pub(crate) name_ptrs: Vec<u32>,
+    pub(crate) buffer_ptrs: Vec<u32>,
+    /// Size of the control block and names, which are the parts written by the host.
+    pub(crate) data_size: usize,
+    pub(crate) size: u32,
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scroll::Pread;
+
+    const PTR: u32 = 0x2000_0000;
+
+    fn layout() -> ControlBlockLayout {
+        ControlBlockLayout::new()
+            .up_channel(ChannelLayout::new(64).name("Terminal"))
+            .up_channel(ChannelLayout::new(10))
+            .down_channel(
+                ChannelLayout::new(16)
+                    .name("Down")
+                    .mode(ChannelMode::BlockIfFull),
+            )
+    }
+
+    fn id() -> [u8; 16] {
+        *b"SEGGER RTT\0\0\0\0\0\0"
+    }
+
+    #[test]
+    fn place_names_and_buffers() {
+        let placement = layout().place(PTR).unwrap();
+
+        // 24 byte header and three 24 byte descriptors, followed by the null terminated names
+        assert_eq!(placement.name_ptrs, vec![PTR + 96, 0, PTR + 105]);
+        assert_eq!(placement.data_size, 110);
+
+        // Buffers are aligned to 4 bytes
+        assert_eq!(placement.buffer_ptrs, vec![PTR + 112, PTR + 176, PTR + 188]);
+        assert_eq!(placement.size, 204);
+
+        assert_eq!(layout().size().unwrap(), 204);
+        assert_eq!(
+            layout().buffer_addresses(PTR).unwrap(),
+            (vec![PTR + 112, PTR + 176], vec![PTR + 188])
+        );
+    }
+
+    #[test]
+    fn image_descriptors() {
+        let (_, mem) = layout().image(&id(), PTR).unwrap();
+
+        assert_eq!(mem.len(), 110);
+        assert_eq!(&mem[0..16], &id());
+        assert_eq!(mem.pread_with::<u32>(16, LE).unwrap(), 2);
+        assert_eq!(mem.pread_with::<u32>(20, LE).unwrap(), 1);
+
+        // name, buffer, size, write, read, flags
+        let descriptor = |i: usize| -> Vec<u32> {
+            (0..6)
+                .map(|f| mem.pread_with::<u32>(24 + i * 24 + f * 4, LE).unwrap())
+                .collect()
+        };
+
+        assert_eq!(descriptor(0), vec![PTR + 96, PTR + 112, 64, 0, 0, 0]);
+        assert_eq!(descriptor(1), vec![0, PTR + 176, 10, 0, 0, 0]);
+        assert_eq!(descriptor(2), vec![PTR + 105, PTR + 188, 16, 0, 0, 2]);
+
+        assert_eq!(&mem[96..105], b"Terminal\0");
+        assert_eq!(&mem[105..110], b"Down\0");
+    }
+
+    #[test]
+    fn id_written_last() {
+        let (_, mem) = layout().image(&id(), PTR).unwrap();
+        let writes = ControlBlockLayout::writes(PTR, &mem);
+
+        assert_eq!(writes[0], (PTR + 16, &mem[16..]));
+        assert_eq!(writes[1], (PTR, &id()[..]));
+    }
+
+    #[test]
+    fn place_errors() {
+        let err = |layout: ControlBlockLayout, ptr: u32| match layout.place(ptr) {
+            Err(Error::InvalidLayout(msg)) => msg,
+            _ => panic!("expected an invalid layout"),
+        };
+
+        assert!(err(layout(), PTR + 2).contains("aligned"));
+        assert!(err(layout(), 0xffff_ff80).contains("address space"));
+        assert!(err(
+            ControlBlockLayout::new().up_channel(ChannelLayout::new(1)),
+            PTR
+        )
+        .contains("2 bytes"));
+        assert!(err(
+            ControlBlockLayout::new().up_channel(ChannelLayout::new(16).name("a\0b")),
+            PTR
+        )
+        .contains("null"));
+
+        // The end address of the layout must fit in the address space as well
+        let size = layout().size().unwrap();
+        assert!(err(layout(), 0u32.wrapping_sub(size)).contains("address space"));
+        assert!(layout().place(0u32.wrapping_sub(size + 4)).is_ok());
+    }
+}
@@ -37,17 +37,60 @@
 
 use thiserror::Error;
 
+pub mod ansi;
+pub use ansi::AnsiParser;
+
+pub mod cache;
+
+pub mod capture;
+
 mod channel;
 pub use channel::*;
 
 pub mod channels;
-pub use channels::Channels;
+pub use channels::{ChannelSelector, Channels};
+
+#[cfg(feature = "defmt")]
+pub mod defmt;
+
+#[cfg(feature = "expect")]
+pub mod expect;
+
+pub mod framing;
+pub use framing::{FrameReader, FrameWriter, Framing};
+
+mod layout;
+pub use layout::*;
+
+pub mod lines;
+pub use lines::{Line, LineReader};
+
+pub mod pcapng;
 
 mod rtt;
 pub use rtt::*;
 
+#[cfg(feature = "symbols")]
+pub mod symbols;
+
+#[cfg(feature = "systemview")]
+pub mod systemview;
+
+pub mod terminal;
+pub use terminal::{VirtualTerminal, VirtualTerminals};
+
+#[cfg(test)]
+mod test_util;
+
+pub mod text;
+pub use text::{DecodeFallback, TextDecoder};
+
 /// Error type for RTT operations.
+///
+/// Some variants are only available with the feature they belong to, so the enum is
+/// non-exhaustive to keep the features additive.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// RTT control block not found in target memory. Make sure RTT is initialized on the target.
     #[error(
@@ -63,10 +106,62 @@ pub enum Error {
     #[error("Control block corrupted: {0}")]
     ControlBlockCorrupted(String),
 
+    /// The control block ID specified in the attach options is not between 1 and 16 bytes long. The
+    /// data contains the length of the ID.
+    #[error("Control block ID must be between 1 and 16 bytes long, but is {0} bytes.")]
+    InvalidControlBlockId(usize),
+
+    /// The control block layout passed to [`Rtt::create_at`] is invalid. The data contains a
+    /// description of the problem.
+    #[error("Invalid control block layout: {0}")]
+    InvalidLayout(&'static str),
+
     /// The target flags contain an invalid channel mode.
     #[error("The target flags contain an invalid channel mode.")]
     InvalidChannelMode,
 
+    /// The firmware ELF file does not contain valid defmt data. The data contains a detailed error.
+    #[cfg(feature = "defmt")]
+    #[error("Invalid defmt data: {0}")]
+    InvalidDefmtData(String),
+
+    /// A defmt frame could not be decoded. The data contains a detailed error.
+    #[cfg(feature = "defmt")]
+    #[error("Malformed defmt frame: {0}")]
+    MalformedDefmtFrame(String),
+
+    /// The firmware ELF file does not contain usable symbols or debug information. The data
+    /// contains a detailed error.
+    #[cfg(feature = "symbols")]
+    #[error("Invalid debug info: {0}")]
+    InvalidDebugInfo(String),
+
+    /// SystemView data could not be decoded. The data contains a detailed error.
+    #[cfg(feature = "systemview")]
+    #[error("Malformed SystemView data: {0}")]
+    MalformedSystemViewData(String),
+
+    /// No data matching a pattern arrived, or no space became available to send data, before the
+    /// timeout. The data describes what was waited for.
+    #[cfg(feature = "expect")]
+    #[error("Timed out waiting for {0}.")]
+    ExpectTimeout(String),
+
+    /// An exit pattern matched the received data. The data contains the line that matched.
+    #[cfg(feature = "expect")]
+    #[error("Exit pattern matched: {0}")]
+    ExitPatternMatched(String),
+
+    /// An expect script could not be parsed. The data contains the line number and a detailed
+    /// error.
+    #[cfg(feature = "expect")]
+    #[error("Invalid script on line {0}: {1}")]
+    InvalidScript(usize, String),
+
+    /// Wraps I/O errors, e.g. from the readers and writers an expect script is run on.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
     /// Wraps errors propagated up from probe-rs.
     #[error("Error communicating with probe: {0}")]
     Probe(#[from] probe_rs::Error),
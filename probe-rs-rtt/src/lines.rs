@@ -0,0 +1,419 @@
+//! Line based reading from up channels.
+//!
+//! [`LineReader`] splits the data from an up channel (or any other [`Read`] source) into complete
+//! lines and stamps each line with the host time its first and last bytes arrived.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use probe_rs::Probe;
+//! use probe_rs_rtt::{LineReader, Rtt};
+//! use std::time::Duration;
+//!
+//! let probe = Probe::list_all()[0].open()?;
+//! let session = probe.attach("somechip")?;
+//! let core = session.attach_to_core(0)?;
+//! let mut rtt = Rtt::attach(core, &session)?;
+//!
+//! let input = rtt.up_channels().take(0).unwrap();
+//! let mut lines = LineReader::new(input).idle_timeout(Duration::from_millis(500));
+//!
+//! loop {
+//!     while let Some(line) = lines.next_line()? {
+//!         println!("{:?}: {}", line.first, String::from_utf8_lossy(&line.text));
+//!     }
+//! }
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A line read by a [`LineReader`].
+#[derive(Clone, Debug)]
+pub struct Line {
+    /// Contents of the line without the line terminator (`\n` or `\r\n`).
+    pub text: Vec<u8>,
+
+    /// Host time when the first byte of the line arrived.
+    pub first: SystemTime,
+
+    /// Host time when the last byte of the line arrived.
+    pub last: SystemTime,
+
+    /// `true` if the line was terminated by a newline, `false` if it was a partial line flushed
+    /// after the idle timeout.
+    pub complete: bool,
+}
+
+/// Buffered line reader for up channels.
+///
+/// The reader never blocks waiting for data. Lines only become available once they are complete,
+/// or once no new data has arrived for the idle timeout if one is set, in which case the partial
+/// line is flushed as is.
+///
+/// The reader also implements [`BufRead`], which only ever exposes complete (or flushed) lines. An
+/// empty result means that no complete line is available yet, not the end of the stream, so e.g.
+/// [`BufRead::lines`] can be called again on every poll.
+pub struct LineReader<R: Read> {
+    inner: R,
+    idle_timeout: Option<Duration>,
+    read_buf: Box<[u8]>,
+    buf: Vec<u8>,
+    segments: VecDeque<Segment>,
+}
+
+/// Bookkeeping for a line in the buffer.
+struct Segment {
+    len: usize,
+    first: SystemTime,
+    last: SystemTime,
+    last_instant: Instant,
+    complete: bool,
+    flushed: bool,
+}
+
+impl Segment {
+    fn is_ready(&self) -> bool {
+        self.complete || self.flushed
+    }
+}
+
+impl<R: Read> LineReader<R> {
+    /// Creates a new line reader without an idle timeout.
+    pub fn new(inner: R) -> Self {
+        LineReader {
+            inner,
+            idle_timeout: None,
+            read_buf: vec![0u8; 1024].into_boxed_slice(),
+            buf: Vec::new(),
+            segments: VecDeque::new(),
+        }
+    }
+
+    /// Sets the time after which a partial line is flushed if no new data arrives.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Returns a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the underlying reader. Any buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns the data received so far for the line that is not yet complete.
+    pub fn partial(&self) -> &[u8] {
+        match self.segments.back() {
+            Some(seg) if !seg.is_ready() => &self.buf[(self.buf.len() - seg.len)..],
+            _ => &[],
+        }
+    }
+
+    /// Reads any new data from the underlying reader and returns the next complete line, or
+    /// `None` if there is none yet.
+    pub fn next_line(&mut self) -> io::Result<Option<Line>> {
+        if !self.has_ready() {
+            self.poll()?;
+        }
+
+        let seg = match self.segments.front() {
+            Some(seg) if seg.is_ready() => self.segments.pop_front().unwrap(),
+            _ => return Ok(None),
+        };
+
+        let mut text: Vec<u8> = self.buf.drain(..seg.len).collect();
+
+        if seg.complete {
+            text.pop();
+
+            if text.last() == Some(&b'\r') {
+                text.pop();
+            }
+        }
+
+        Ok(Some(Line {
+            text,
+            first: seg.first,
+            last: seg.last,
+            complete: seg.complete,
+        }))
+    }
+
+    /// Reads any new data from the underlying reader into the buffer and flushes the partial line
+    /// if the idle timeout has passed. Returns the number of bytes read.
+    pub fn poll(&mut self) -> io::Result<usize> {
+        let count = self.inner.read(&mut self.read_buf)?;
+
+        if count > 0 {
+            let now = SystemTime::now();
+            let now_instant = Instant::now();
+
+            for piece in self.read_buf[..count].split_inclusive(|&b| b == b'\n') {
+                match self.segments.back_mut() {
+                    Some(seg) if !seg.is_ready() => {
+                        seg.len += piece.len();
+                        seg.last = now;
+                        seg.last_instant = now_instant;
+                        seg.complete = piece.ends_with(b"\n");
+                    }
+                    _ => self.segments.push_back(Segment {
+                        len: piece.len(),
+                        first: now,
+                        last: now,
+                        last_instant: now_instant,
+                        complete: piece.ends_with(b"\n"),
+                        flushed: false,
+                    }),
+                }
+            }
+
+            self.buf.extend_from_slice(&self.read_buf[..count]);
+        } else if let (Some(timeout), Some(seg)) = (self.idle_timeout, self.segments.back_mut()) {
+            if !seg.is_ready() && seg.last_instant.elapsed() >= timeout {
+                seg.flushed = true;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn has_ready(&self) -> bool {
+        matches!(self.segments.front(), Some(seg) if seg.is_ready())
+    }
+
+    fn ready_len(&self) -> usize {
+        self.segments
+            .iter()
+            .take_while(|seg| seg.is_ready())
+            .map(|seg| seg.len)
+            .sum()
+    }
+}
+
+impl<R: Read> Read for LineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let count = available.len().min(buf.len());
+
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+
+        Ok(count)
+    }
+}
+
+impl<R: Read> BufRead for LineReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if !self.has_ready() {
+            self.poll()?;
+        }
+
+        let len = self.ready_len();
+
+        Ok(&self.buf[..len])
+    }
+
+    fn consume(&mut self, mut amt: usize) {
+        amt = amt.min(self.ready_len());
+        self.buf.drain(..amt);
+
+        while amt > 0 {
+            let seg = self.segments.front_mut().unwrap();
+
+            if amt >= seg.len {
+                amt -= seg.len;
+                self.segments.pop_front();
+            } else {
+                seg.len -= amt;
+                amt = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ScriptedReader;
+    use std::thread;
+
+    fn read_lines<R: Read>(lines: &mut LineReader<R>, polls: usize) -> Vec<(Vec<u8>, bool)> {
+        let mut result = Vec::new();
+
+        for _ in 0..polls {
+            while let Some(line) = lines.next_line().unwrap() {
+                result.push((line.text, line.complete));
+            }
+        }
+
+        result
+    }
+
+    #[test]
+    fn lines_across_chunks() {
+        let mut lines = LineReader::new(ScriptedReader::new(&[
+            b"fir",
+            b"st\nsec",
+            b"ond\nthird\nfou",
+        ]));
+
+        assert!(lines.next_line().unwrap().is_none());
+        assert_eq!(lines.partial(), b"fir");
+
+        assert_eq!(
+            read_lines(&mut lines, 3),
+            vec![
+                (b"first".to_vec(), true),
+                (b"second".to_vec(), true),
+                (b"third".to_vec(), true),
+            ]
+        );
+        assert_eq!(lines.partial(), b"fou");
+    }
+
+    #[test]
+    fn lines_split_into_single_bytes() {
+        let data = b"one\ntwo\n\nthree\n";
+        let mut lines = LineReader::new(ScriptedReader::split(data, 1));
+
+        assert_eq!(
+            read_lines(&mut lines, data.len()),
+            vec![
+                (b"one".to_vec(), true),
+                (b"two".to_vec(), true),
+                (b"".to_vec(), true),
+                (b"three".to_vec(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn crlf() {
+        let mut lines = LineReader::new(ScriptedReader::new(&[b"a\r\nb\r", b"\n\rc\r\n\r"]));
+
+        assert_eq!(
+            read_lines(&mut lines, 2),
+            vec![
+                (b"a".to_vec(), true),
+                (b"b".to_vec(), true),
+                (b"\rc".to_vec(), true),
+            ]
+        );
+        assert_eq!(lines.partial(), b"\r");
+    }
+
+    #[test]
+    fn idle_timeout_flushes_partial_line() {
+        let mut lines = LineReader::new(ScriptedReader::new(&[b"done\npartial"]))
+            .idle_timeout(Duration::from_millis(20));
+
+        let line = lines.next_line().unwrap().unwrap();
+        assert_eq!((line.text, line.complete), (b"done".to_vec(), true));
+
+        // Not flushed before the timeout has passed
+        assert!(lines.next_line().unwrap().is_none());
+        assert_eq!(lines.partial(), b"partial");
+
+        thread::sleep(Duration::from_millis(30));
+
+        let line = lines.next_line().unwrap().unwrap();
+        assert_eq!((line.text, line.complete), (b"partial".to_vec(), false));
+        assert_eq!(lines.partial(), b"");
+        assert!(lines.next_line().unwrap().is_none());
+    }
+
+    #[test]
+    fn data_after_flush_starts_new_line() {
+        let mut reader = ScriptedReader::new(&[b"ab"]);
+        reader.push(b"");
+        reader.push(b"c\n");
+
+        let mut lines = LineReader::new(reader).idle_timeout(Duration::from_millis(0));
+
+        assert_eq!(
+            read_lines(&mut lines, 3),
+            vec![(b"ab".to_vec(), false), (b"c".to_vec(), true)]
+        );
+    }
+
+    #[test]
+    fn no_flush_without_timeout() {
+        let mut lines = LineReader::new(ScriptedReader::new(&[b"partial"]));
+
+        assert!(read_lines(&mut lines, 3).is_empty());
+        assert_eq!(lines.partial(), b"partial");
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut lines = LineReader::new(ScriptedReader::new(&[b"a", b"b\nc\n"]));
+
+        let before = SystemTime::now();
+        assert!(lines.next_line().unwrap().is_none());
+        let between = SystemTime::now();
+
+        thread::sleep(Duration::from_millis(10));
+
+        let line1 = lines.next_line().unwrap().unwrap();
+        let after = SystemTime::now();
+        let line2 = lines.next_line().unwrap().unwrap();
+
+        assert_eq!(line1.text, b"ab");
+        assert!(before <= line1.first && line1.first <= between);
+        assert!(between < line1.last && line1.last <= after);
+
+        // Both lines were completed by the same read
+        assert_eq!(line2.text, b"c");
+        assert_eq!(line2.first, line1.last);
+        assert_eq!(line2.last, line1.last);
+    }
+
+    #[test]
+    fn buf_read() {
+        let mut lines = LineReader::new(ScriptedReader::new(&[b"one\ntw", b"o\nthr"]));
+
+        // Only complete lines are exposed
+        assert_eq!(lines.fill_buf().unwrap(), b"one\n");
+        lines.consume(2);
+        assert_eq!(lines.fill_buf().unwrap(), b"e\n");
+        lines.consume(2);
+
+        let mut line = String::new();
+        assert_eq!(lines.read_line(&mut line).unwrap(), 4);
+        assert_eq!(line, "two\n");
+
+        // No complete line available is an empty read, not an error
+        assert_eq!(lines.read_line(&mut line).unwrap(), 0);
+        assert_eq!(lines.partial(), b"thr");
+
+        // Consuming more than is available stops at the partial line
+        lines.consume(100);
+        assert_eq!(lines.partial(), b"thr");
+    }
+
+    #[test]
+    fn read_and_lines() {
+        let mut lines = LineReader::new(ScriptedReader::new(&[b"a\nb\nc"]));
+
+        let mut buf = [0u8; 3];
+        assert_eq!(lines.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"a\nb");
+
+        let rest: Vec<String> = (&mut lines).lines().map(|l| l.unwrap()).collect();
+        assert_eq!(rest, vec![""]);
+        assert_eq!(lines.partial(), b"c");
+    }
+}
@@ -0,0 +1,408 @@
+//! Export of RTT traffic as pcapng files, for inspecting binary protocols with Wireshark.
+//!
+//! Each channel is written as an interface of its own, named after the core, direction and number
+//! of the channel, e.g. `core0-up1`, with the channel name as the interface description. Data is
+//! written as one packet per chunk read from or written to a channel, stamped with the host time
+//! and with the direction stored in the packet flags (inbound for up channels, outbound for down
+//! channels).
+//!
+//! The interfaces use the `USER0` link type by default, so a dissector can be assigned to them in
+//! Wireshark under "DLT_USER" in the protocol preferences.
+//!
+//! ## Example
+//!
+//! Converting a capture file recorded with [`CaptureWriter`](crate::capture::CaptureWriter):
+//!
+//! ```no_run
+//! use std::fs::File;
+//!
+//! let input = File::open("session.rttcap")?;
+//! let output = File::create("session.pcapng")?;
+//!
+//! probe_rs_rtt::pcapng::convert(input, output)?;
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::capture::{CaptureReader, ChannelInfo, Chunk, Direction, Record};
+
+/// Link type for private use, the default for all interfaces.
+pub const LINKTYPE_USER0: u16 = 147;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_END: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_DESCRIPTION: u16 = 3;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const FLAGS_INBOUND: u32 = 1;
+const FLAGS_OUTBOUND: u32 = 2;
+
+/// Writer for pcapng files.
+///
+/// Interfaces are added with [`PcapngWriter::interface`] before data is written on them. Data on a
+/// channel without an interface creates one without a description.
+pub struct PcapngWriter<W: Write> {
+    out: W,
+    link_type: u16,
+    interfaces: HashMap<(usize, Direction, usize), u32>,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Creates a new writer and writes the section header.
+    pub fn new(out: W) -> io::Result<Self> {
+        let mut writer = PcapngWriter {
+            out,
+            link_type: LINKTYPE_USER0,
+            interfaces: HashMap::new(),
+        };
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is not known in advance
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut body, OPT_SHB_USERAPPL, b"probe-rs-rtt");
+        push_option(&mut body, OPT_END, &[]);
+
+        writer.write_block(BLOCK_SECTION_HEADER, &body)?;
+
+        Ok(writer)
+    }
+
+    /// Sets the link type of interfaces added after this. The default is [`LINKTYPE_USER0`].
+    pub fn link_type(mut self, link_type: u16) -> Self {
+        self.link_type = link_type;
+        self
+    }
+
+    /// Adds an interface for a channel. Does nothing if the channel already has one.
+    pub fn interface(&mut self, info: &ChannelInfo) -> io::Result<()> {
+        self.interface_id(info.core, info.direction, info.number, info.name.as_deref())
+            .map(|_| ())
+    }
+
+    /// Writes a chunk of data as a packet on the interface of its channel.
+    pub fn write_chunk(&mut self, chunk: &Chunk) -> io::Result<()> {
+        let id = self.interface_id(chunk.core, chunk.direction, chunk.number, None)?;
+
+        let micros = chunk
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let flags = match chunk.direction {
+            Direction::Up => FLAGS_INBOUND,
+            Direction::Down => FLAGS_OUTBOUND,
+        };
+
+        let mut body = Vec::with_capacity(chunk.data.len() + 40);
+        body.extend_from_slice(&id.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(chunk.data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(chunk.data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&chunk.data);
+        pad(&mut body);
+        push_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut body, OPT_END, &[]);
+
+        self.write_block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    /// Writes data transferred on a channel as a packet stamped with the current time.
+    pub fn data(
+        &mut self,
+        core: usize,
+        direction: Direction,
+        number: usize,
+        data: &[u8],
+    ) -> io::Result<()> {
+        self.write_chunk(&Chunk {
+            time: SystemTime::now(),
+            core,
+            direction,
+            number,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Writes a record read from a capture file. Channel records add interfaces and data records
+    /// are written as packets. Other records are ignored.
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        match record {
+            Record::Channel(info) => self.interface(info),
+            Record::Data(chunk) => self.write_chunk(chunk),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Returns the ID of the interface of a channel, writing the interface description first if
+    /// the channel does not have one yet.
+    fn interface_id(
+        &mut self,
+        core: usize,
+        direction: Direction,
+        number: usize,
+        description: Option<&str>,
+    ) -> io::Result<u32> {
+        if let Some(&id) = self.interfaces.get(&(core, direction, number)) {
+            return Ok(id);
+        }
+
+        let id = self.interfaces.len() as u32;
+
+        let name = format!(
+            "core{}-{}{}",
+            core,
+            match direction {
+                Direction::Up => "up",
+                Direction::Down => "down",
+            },
+            number
+        );
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&self.link_type.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut body, OPT_IF_NAME, name.as_bytes());
+
+        if let Some(description) = description {
+            push_option(&mut body, OPT_IF_DESCRIPTION, description.as_bytes());
+        }
+
+        push_option(&mut body, OPT_END, &[]);
+
+        self.write_block(BLOCK_INTERFACE_DESCRIPTION, &body)?;
+        self.interfaces.insert((core, direction, number), id);
+
+        Ok(id)
+    }
+
+    fn write_block(&mut self, kind: u32, body: &[u8]) -> io::Result<()> {
+        let len = (body.len() + 12) as u32;
+
+        let mut buf = Vec::with_capacity(len as usize);
+        buf.extend_from_slice(&kind.to_le_bytes());
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(body);
+        buf.extend_from_slice(&len.to_le_bytes());
+
+        self.out.write_all(&buf)
+    }
+}
+
+/// Converts a capture file to a pcapng file and returns the output writer.
+pub fn convert<R: Read, W: Write>(input: R, output: W) -> io::Result<W> {
+    let mut writer = PcapngWriter::new(output)?;
+
+    for record in CaptureReader::new(input)? {
+        writer.write_record(&record?)?;
+    }
+
+    writer.flush()?;
+
+    Ok(writer.into_inner())
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+/// Pads a block body to a multiple of 4 bytes.
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::CaptureWriter;
+    use crate::ChannelMode;
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    fn u16_at(buf: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes(buf[pos..(pos + 2)].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(buf[pos..(pos + 4)].try_into().unwrap())
+    }
+
+    /// Splits a file into blocks, checking the block lengths and padding.
+    fn blocks(file: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut pos = 0;
+
+        while pos < file.len() {
+            let len = u32_at(file, pos + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(file, pos + len - 4) as usize, len);
+
+            blocks.push((u32_at(file, pos), &file[(pos + 8)..(pos + len - 4)]));
+            pos += len;
+        }
+
+        assert_eq!(pos, file.len());
+        blocks
+    }
+
+    /// Parses the options at the end of a block body, checking that they are terminated.
+    fn options(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+        let mut options = Vec::new();
+
+        loop {
+            let code = u16_at(buf, 0);
+            let len = u16_at(buf, 2) as usize;
+
+            if code == OPT_END {
+                assert_eq!(buf.len(), 4);
+                return options;
+            }
+
+            options.push((code, &buf[4..(4 + len)]));
+            buf = &buf[(4 + ((len + 3) & !3))..];
+        }
+    }
+
+    #[test]
+    fn block_layout() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap().link_type(148);
+
+        let info = ChannelInfo {
+            core: 1,
+            direction: Direction::Up,
+            number: 2,
+            name: Some("Binary".into()),
+            buffer_size: 1024,
+            mode: Some(ChannelMode::NoBlockSkip),
+        };
+
+        writer.interface(&info).unwrap();
+        writer.interface(&info).unwrap();
+
+        let time = UNIX_EPOCH + Duration::from_micros(0x1_2345_6789);
+
+        for (number, direction, data) in &[
+            (2, Direction::Up, &b"abcde"[..]),
+            (0, Direction::Down, &b"wxyz"[..]),
+        ] {
+            writer
+                .write_chunk(&Chunk {
+                    time,
+                    core: 1,
+                    direction: *direction,
+                    number: *number,
+                    data: data.to_vec(),
+                })
+                .unwrap();
+        }
+
+        let file = writer.into_inner();
+        let blocks = blocks(&file);
+        let kinds: Vec<u32> = blocks.iter().map(|b| b.0).collect();
+
+        // Interfaces are only written once
+        assert_eq!(
+            kinds,
+            [
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET
+            ]
+        );
+
+        let shb = blocks[0].1;
+        assert_eq!(u32_at(shb, 0), BYTE_ORDER_MAGIC);
+        assert_eq!((u16_at(shb, 4), u16_at(shb, 6)), (1, 0));
+        assert_eq!(
+            options(&shb[16..]),
+            [(OPT_SHB_USERAPPL, &b"probe-rs-rtt"[..])]
+        );
+
+        let idb = blocks[1].1;
+        assert_eq!(u16_at(idb, 0), 148);
+        assert_eq!(
+            options(&idb[8..]),
+            [
+                (OPT_IF_NAME, &b"core1-up2"[..]),
+                (OPT_IF_DESCRIPTION, &b"Binary"[..])
+            ]
+        );
+
+        let epb = blocks[2].1;
+        assert_eq!(u32_at(epb, 0), 0);
+        assert_eq!((u32_at(epb, 4), u32_at(epb, 8)), (1, 0x2345_6789));
+        assert_eq!((u32_at(epb, 12), u32_at(epb, 16)), (5, 5));
+        assert_eq!(&epb[20..25], b"abcde");
+        assert_eq!(
+            options(&epb[28..]),
+            [(OPT_EPB_FLAGS, &FLAGS_INBOUND.to_le_bytes()[..])]
+        );
+
+        // Data on a channel without an interface adds one without a description
+        assert_eq!(
+            options(&blocks[3].1[8..]),
+            [(OPT_IF_NAME, &b"core1-down0"[..])]
+        );
+
+        let epb = blocks[4].1;
+        assert_eq!(u32_at(epb, 0), 1);
+        assert_eq!(&epb[20..24], b"wxyz");
+        assert_eq!(
+            options(&epb[24..]),
+            [(OPT_EPB_FLAGS, &FLAGS_OUTBOUND.to_le_bytes()[..])]
+        );
+    }
+
+    #[test]
+    fn convert_capture() {
+        let mut capture = CaptureWriter::new(Vec::new()).unwrap();
+        capture.control_block(0, 0x2000_0000).unwrap();
+        capture.data(0, Direction::Up, 0, b"hello").unwrap();
+        capture.data(0, Direction::Up, 0, b"again").unwrap();
+
+        let file = convert(&capture.into_inner()[..], Vec::new()).unwrap();
+        let kinds: Vec<u32> = blocks(&file).iter().map(|b| b.0).collect();
+
+        assert_eq!(
+            kinds,
+            [
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET
+            ]
+        );
+    }
+}
@@ -1,18 +1,24 @@
-use probe_rs::{config::MemoryRegion, Core, Session};
+use probe_rs::{
+    config::{MemoryRegion, RamRegion},
+    Core, Session,
+};
 use scroll::{Pread, LE};
 use std::collections::BTreeMap;
+use std::ops::Range;
 use std::rc::Rc;
 
 use crate::channel::*;
-use crate::{Channels, Error};
+use crate::{Channels, ControlBlockLayout, Error};
 
 /// The RTT interface.
 ///
-/// Use [`Rtt::attach`] to attach to a probe-rs `Core` and detect channels.
+/// Use [`Rtt::attach`] to attach to a probe-rs `Core` and detect channels, or
+/// [`Rtt::attach_with_options`] to customize how the control block is detected.
 pub struct Rtt {
     ptr: u32,
     up_channels: Channels<UpChannel>,
     down_channels: Channels<DownChannel>,
+    core_was_halted: Option<bool>,
 }
 
 // Rtt must follow this data layout when reading/writing memory in order to be compatible with the
@@ -35,19 +41,20 @@ impl Rtt {
     const MIN_SIZE: usize = Self::O_CHANNEL_ARRAYS;
 
     // Offsets of fields in target memory in bytes
-    const O_ID: usize = 0;
-    const O_MAX_UP_CHANNELS: usize = 16;
-    const O_MAX_DOWN_CHANNELS: usize = 20;
-    const O_CHANNEL_ARRAYS: usize = 24;
+    pub(crate) const O_ID: usize = 0;
+    pub(crate) const O_MAX_UP_CHANNELS: usize = 16;
+    pub(crate) const O_MAX_DOWN_CHANNELS: usize = 20;
+    pub(crate) const O_CHANNEL_ARRAYS: usize = 24;
 
     fn from(
         core: &Rc<Core>,
         memory_map: &[MemoryRegion],
+        id: &[u8; 16],
         ptr: u32,
         mem: &[u8],
     ) -> Result<Option<Rtt>, Error> {
         // Validate that the control block starts with the ID bytes
-        if mem[Self::O_ID..(Self::O_ID + Self::RTT_ID.len())] != Self::RTT_ID {
+        if mem[Self::O_ID..(Self::O_ID + id.len())] != id[..] {
             return Ok(None);
         }
 
@@ -58,7 +65,7 @@ impl Rtt {
 
         // Validate that the entire control block fits within the region
         if Self::O_CHANNEL_ARRAYS + (max_up_channels + max_down_channels) * Channel::SIZE
-            >= mem.len()
+            > mem.len()
         {
             return Ok(None);
         }
@@ -91,6 +98,7 @@ impl Rtt {
             ptr,
             up_channels: Channels(up_channels),
             down_channels: Channels(down_channels),
+            core_was_halted: None,
         }))
     }
 
@@ -100,9 +108,168 @@ impl Rtt {
     /// `core` can be e.g. an owned `Core` or a shared `Rc<Core>`. The session is only borrowed
     /// temporarily during detection.
     pub fn attach(core: impl Into<Rc<Core>>, session: &Session) -> Result<Rtt, Error> {
-        let core = core.into();
-        let memory_map: &[MemoryRegion] = &*session.memory_map();
+        Self::attach_with_options(core, session, &AttachOptions::default())
+    }
+
+    /// Attempts to detect an RTT control block in the core memory using the specified options and
+    /// returns an instance if a valid control block was found.
+    ///
+    /// See [`AttachOptions`] for the available options.
+    pub fn attach_with_options(
+        core: impl Into<Rc<Core>>,
+        session: &Session,
+        options: &AttachOptions,
+    ) -> Result<Rtt, Error> {
+        let mut candidates = Self::find_candidates(&core.into(), session, options)?;
+
+        match candidates.len() {
+            0 => Err(Error::ControlBlockNotFound),
+            1 => Ok(candidates.remove(0)),
+            _ => Err(Error::MultipleControlBlocksFound(
+                candidates.iter().map(|rtt| rtt.ptr).collect(),
+            )),
+        }
+    }
+
+    /// Returns an instance for every valid control block found with the options. A control block
+    /// at the address hint, if any, is returned on its own without scanning.
+    fn find_candidates(
+        core: &Rc<Core>,
+        session: &Session,
+        options: &AttachOptions,
+    ) -> Result<Vec<Rtt>, Error> {
+        let id = pad_control_block_id(&options.control_block_id)?;
+
+        // Extra regions are treated like RAM so they're both scanned and considered valid
+        // locations for channel names.
+        let mut memory_map = session.memory_map();
+        memory_map.extend(options.scan_regions.iter().map(|range| {
+            MemoryRegion::Ram(RamRegion {
+                range: range.clone(),
+                is_boot_memory: false,
+            })
+        }));
+        let memory_map: &[MemoryRegion] = &memory_map;
+
+        let core_was_halted = if options.halt_core {
+            let halted = core.core_halted()?;
+
+            if !halted {
+                core.halt()?;
+            }
+
+            Some(halted)
+        } else {
+            None
+        };
+
+        // Try the address hint first, if any, and fall back to scanning if it doesn't hold a valid
+        // control block anymore.
+        let result = match options.control_block_address {
+            Some(ptr) => match Self::try_address(core, memory_map, &id, ptr) {
+                Ok(Some(rtt)) => Ok(vec![rtt]),
+                Ok(None) => Self::scan(core, memory_map, &id),
+                Err(err) => Err(err),
+            },
+            None => Self::scan(core, memory_map, &id),
+        };
+
+        // Leave the core in the state it was found in, even if scanning failed. A scan error takes
+        // precedence over an error resuming the core.
+        let resumed = if core_was_halted == Some(false) {
+            core.run()
+        } else {
+            Ok(())
+        };
+
+        let mut candidates = result?;
+        resumed?;
+
+        for rtt in candidates.iter_mut() {
+            rtt.core_was_halted = core_was_halted;
+        }
+
+        Ok(candidates)
+    }
+
+    /// Attempts to detect an RTT control block on every core of the session, and returns an
+    /// instance for each core a valid control block was found on along with the core number.
+    ///
+    /// `options` is called with each core number to get the options to use for that core. Each
+    /// control block is given to one core only, as cores that share RAM (e.g. on the RP2040) see
+    /// each other's control blocks. The blocks are assigned by repeatedly giving a core the only
+    /// block it found that no other core has been given yet, in core order, so a single shared
+    /// control block goes to the lowest numbered core. If every remaining core found several, the
+    /// lowest numbered one is given the one at the lowest address, so two shared control blocks
+    /// go to cores 0 and 1 in address order. Cores left without a control block are skipped.
+    ///
+    /// Use a different control block ID or scan region per core in the options to pick the block
+    /// of each core explicitly. If no control block is found on any core,
+    /// [`Error::ControlBlockNotFound`] is returned.
+    pub fn attach_all_cores(
+        session: &Session,
+        options: impl Fn(usize) -> AttachOptions,
+    ) -> Result<Vec<(usize, Rtt)>, Error> {
+        let mut candidates = Vec::new();
+
+        for n in 0..session.list_cores().len() {
+            let core = Rc::new(session.attach_to_core(n)?);
+
+            candidates.push(Self::find_candidates(&core, session, &options(n))?);
+        }
+
+        assign_control_blocks(candidates, |rtt| rtt.ptr)
+    }
+
+    fn try_address(
+        core: &Rc<Core>,
+        memory_map: &[MemoryRegion],
+        id: &[u8; 16],
+        ptr: u32,
+    ) -> Result<Option<Rtt>, Error> {
+        // The control block must be entirely within a RAM region
+        let range = memory_map
+            .iter()
+            .filter_map(|r| match r {
+                MemoryRegion::Ram(r) => Some(&r.range),
+                _ => None,
+            })
+            .find(|r| r.contains(&ptr));
 
+        let available = match range {
+            Some(range) => (range.end - ptr) as usize,
+            None => return Ok(None),
+        };
+
+        if available < Self::MIN_SIZE {
+            return Ok(None);
+        }
+
+        // Read the header first to find out the size of the entire control block
+        let mut mem = vec![0u8; Self::MIN_SIZE];
+        core.read_8(ptr, mem.as_mut())?;
+
+        let max_up_channels = mem.pread_with::<u32>(Self::O_MAX_UP_CHANNELS, LE).unwrap() as usize;
+        let max_down_channels = mem
+            .pread_with::<u32>(Self::O_MAX_DOWN_CHANNELS, LE)
+            .unwrap() as usize;
+
+        let size = Self::O_CHANNEL_ARRAYS + (max_up_channels + max_down_channels) * Channel::SIZE;
+        if size > available {
+            return Ok(None);
+        }
+
+        mem.resize(size, 0);
+        core.read_8(ptr, mem.as_mut())?;
+
+        Rtt::from(core, memory_map, id, ptr, &mem)
+    }
+
+    fn scan(
+        core: &Rc<Core>,
+        memory_map: &[MemoryRegion],
+        id: &[u8; 16],
+    ) -> Result<Vec<Rtt>, Error> {
         let mut mem: Vec<u8> = Vec::new();
         let mut instances: Vec<Rtt> = Vec::new();
 
@@ -110,16 +277,22 @@ impl Rtt {
             if let MemoryRegion::Ram(ram) = region {
                 let range = &ram.range;
 
+                if range.is_empty() {
+                    continue;
+                }
+
                 mem.resize((range.end - range.start) as usize, 0);
                 core.read_8(range.start, mem.as_mut())?;
 
-                for offset in 0..(mem.len() - Self::MIN_SIZE) {
-                    if let Some(rtt) = Rtt::from(
-                        &core,
-                        memory_map,
-                        range.start + offset as u32,
-                        &mem[offset..],
-                    )? {
+                for offset in 0..mem.len().saturating_sub(Self::MIN_SIZE) {
+                    let ptr = range.start + offset as u32;
+
+                    // User specified regions may overlap with the memory map
+                    if instances.iter().any(|i| i.ptr == ptr) {
+                        continue;
+                    }
+
+                    if let Some(rtt) = Rtt::from(core, memory_map, id, ptr, &mem[offset..])? {
                         instances.push(rtt);
 
                         if instances.len() > 5 {
@@ -130,17 +303,41 @@ impl Rtt {
             }
         }
 
-        if instances.len() == 0 {
-            return Err(Error::ControlBlockNotFound);
-        }
+        Ok(instances)
+    }
 
-        if instances.len() > 1 {
-            return Err(Error::MultipleControlBlocksFound(
-                instances.into_iter().map(|i| i.ptr).collect(),
-            ));
+    /// Creates a new control block in target memory at `ptr` according to the layout, and returns
+    /// an instance for it.
+    ///
+    /// This can be used with firmware that doesn't initialize RTT itself, in which case the
+    /// firmware only needs to know the buffer addresses (see
+    /// [`ControlBlockLayout::buffer_addresses`]). The caller must make sure that
+    /// [`ControlBlockLayout::size`] bytes of RAM starting at `ptr` are available. `ptr` must be
+    /// aligned to 4 bytes. The ID is written last so that the control block cannot be found before
+    /// it's complete.
+    pub fn create_at(
+        core: impl Into<Rc<Core>>,
+        ptr: u32,
+        layout: &ControlBlockLayout,
+    ) -> Result<Rtt, Error> {
+        let core = core.into();
+        let id = pad_control_block_id(&layout.control_block_id)?;
+        let (placement, mem) = layout.image(&id, ptr)?;
+
+        for (write_ptr, data) in ControlBlockLayout::writes(ptr, &mem).iter() {
+            core.write_8(*write_ptr, data)?;
         }
 
-        Ok(instances.remove(0))
+        // Read the channels back like a detected control block. Only the created area is
+        // considered valid memory for channel names.
+        let memory_map = [MemoryRegion::Ram(RamRegion {
+            range: ptr..(ptr + placement.size),
+            is_boot_memory: false,
+        })];
+
+        Rtt::from(&core, &memory_map, &id, ptr, &mem)?.ok_or_else(|| {
+            Error::ControlBlockCorrupted("created control block could not be read back".into())
+        })
     }
 
     /// Returns the memory address of the control block in target memory.
@@ -148,6 +345,12 @@ impl Rtt {
         self.ptr
     }
 
+    /// Returns whether the core was already halted when attaching, or `None` if halting the core
+    /// was not requested with [`AttachOptions::halt_core`].
+    pub fn core_was_halted(&self) -> Option<bool> {
+        self.core_was_halted
+    }
+
     /// Gets the detected up channels.
     pub fn up_channels(&mut self) -> &mut Channels<UpChannel> {
         &mut self.up_channels
@@ -158,3 +361,233 @@ impl Rtt {
         &mut self.down_channels
     }
 }
+
+/// Options for detecting the RTT control block in [`Rtt::attach_with_options`].
+#[derive(Clone, Debug)]
+pub struct AttachOptions {
+    control_block_id: Vec<u8>,
+    scan_regions: Vec<Range<u32>>,
+    halt_core: bool,
+    control_block_address: Option<u32>,
+}
+
+impl AttachOptions {
+    /// Creates a new set of options with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the ID string the control block is identified by. The default is `"SEGGER RTT"`.
+    ///
+    /// This is useful if the firmware writes the ID under a project-specific string at runtime to
+    /// avoid false matches in e.g. flash images. The ID must be between 1 and 16 bytes long, and is
+    /// padded with null bytes to 16 bytes when matching.
+    pub fn control_block_id(mut self, id: impl AsRef<[u8]>) -> Self {
+        self.control_block_id = id.as_ref().to_vec();
+        self
+    }
+
+    /// Adds a memory region to scan for the control block in addition to the RAM regions in the
+    /// target memory map.
+    ///
+    /// This is useful for targets with e.g. CCM RAM or external SDRAM, or targets with an
+    /// incomplete memory map. Channel names located in the region are also resolved.
+    pub fn scan_region(mut self, range: Range<u32>) -> Self {
+        self.scan_regions.push(range);
+        self
+    }
+
+    /// Sets whether to halt the core while scanning for the control block and parsing the channels.
+    /// The default is `false`.
+    ///
+    /// Scanning while the target is running may catch a half-initialized control block, which can
+    /// cause channels to be missed. If the core was already halted it is left halted, otherwise it
+    /// is resumed after attaching. See [`Rtt::core_was_halted`].
+    pub fn halt_core(mut self, halt: bool) -> Self {
+        self.halt_core = halt;
+        self
+    }
+
+    /// Sets an address where the control block is expected to be, e.g. the address found by a
+    /// previous attach. See the [`cache`](crate::cache) module for a way to persist it.
+    ///
+    /// The address is checked for a valid control block first, and memory is scanned as usual only
+    /// if it doesn't contain one.
+    pub fn control_block_address(mut self, ptr: u32) -> Self {
+        self.control_block_address = Some(ptr);
+        self
+    }
+}
+
+impl Default for AttachOptions {
+    fn default() -> Self {
+        AttachOptions {
+            control_block_id: Rtt::RTT_ID
+                .iter()
+                .copied()
+                .take_while(|&b| b != 0)
+                .collect(),
+            scan_regions: Vec::new(),
+            halt_core: false,
+            control_block_address: None,
+        }
+    }
+}
+
+/// Parses a memory region for [`AttachOptions::scan_region`] given as `START..END` or
+/// `START+SIZE`, e.g. from a command line argument. Addresses are decimal, or hexadecimal with a
+/// `0x` prefix.
+///
+/// ```
+/// use probe_rs_rtt::parse_region;
+///
+/// assert_eq!(parse_region("0x20000000..0x20010000"), Ok(0x2000_0000..0x2001_0000));
+/// assert_eq!(parse_region("0x10000000+65536"), Ok(0x1000_0000..0x1001_0000));
+/// assert!(parse_region("0x20000000").is_err());
+/// ```
+pub fn parse_region(s: &str) -> Result<Range<u32>, String> {
+    let parse_u32 = |s: &str| {
+        let s = s.trim();
+
+        if s.starts_with("0x") || s.starts_with("0X") {
+            u32::from_str_radix(&s[2..], 16)
+        } else {
+            s.parse::<u32>()
+        }
+        .map_err(|_| format!("Invalid address: '{}'", s))
+    };
+
+    let range = if let Some(p) = s.find("..") {
+        parse_u32(&s[..p])?..parse_u32(&s[(p + 2)..])?
+    } else if let Some(p) = s.find('+') {
+        let start = parse_u32(&s[..p])?;
+        let size = parse_u32(&s[(p + 1)..])?;
+
+        let end = start
+            .checked_add(size)
+            .ok_or_else(|| "Region extends past the end of the address space.".to_string())?;
+
+        start..end
+    } else {
+        return Err("Expected a region in the form START..END or START+SIZE.".to_string());
+    };
+
+    if range.start >= range.end {
+        return Err("Region must not be empty.".to_string());
+    }
+
+    Ok(range)
+}
+
+fn pad_control_block_id(id: &[u8]) -> Result<[u8; 16], Error> {
+    if id.is_empty() || id.len() > 16 {
+        return Err(Error::InvalidControlBlockId(id.len()));
+    }
+
+    let mut padded = [0u8; 16];
+    padded[..id.len()].copy_from_slice(id);
+
+    Ok(padded)
+}
+
+/// Gives each core at most one of the control blocks it found, identified by `ptr`. A core with a
+/// single unassigned control block is given it first, in core order. When every core left has
+/// several to choose from, the lowest numbered one is given the block at the lowest address.
+fn assign_control_blocks<T>(
+    mut candidates: Vec<Vec<T>>,
+    ptr: impl Fn(&T) -> u32,
+) -> Result<Vec<(usize, T)>, Error> {
+    let mut assigned: Vec<Option<T>> = candidates.iter().map(|_| None).collect();
+
+    loop {
+        let next = candidates
+            .iter()
+            .position(|c| c.len() == 1)
+            .or_else(|| candidates.iter().position(|c| !c.is_empty()));
+
+        let n = match next {
+            Some(n) => n,
+            None => break,
+        };
+
+        let lowest = (0..candidates[n].len())
+            .min_by_key(|&i| ptr(&candidates[n][i]))
+            .unwrap();
+        let block = candidates[n].remove(lowest);
+        candidates[n].clear();
+        let block_ptr = ptr(&block);
+
+        for other in candidates.iter_mut() {
+            other.retain(|candidate| ptr(candidate) != block_ptr);
+        }
+
+        assigned[n] = Some(block);
+    }
+
+    let instances: Vec<(usize, T)> = assigned
+        .into_iter()
+        .enumerate()
+        .filter_map(|(n, block)| block.map(|block| (n, block)))
+        .collect();
+
+    if instances.is_empty() {
+        return Err(Error::ControlBlockNotFound);
+    }
+
+    Ok(instances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assign(candidates: &[&[u32]]) -> Result<Vec<(usize, u32)>, Error> {
+        assign_control_blocks(candidates.iter().map(|c| c.to_vec()).collect(), |&ptr| ptr)
+    }
+
+    #[test]
+    fn assign_single_shared_block_to_first_core() {
+        assert_eq!(assign(&[&[0x100], &[0x100]]).unwrap(), vec![(0, 0x100)]);
+    }
+
+    #[test]
+    fn assign_unique_blocks_first() {
+        // Core 1 only sees one block, so core 0 gets the other one even though it's higher
+        assert_eq!(
+            assign(&[&[0x100, 0x200], &[0x100]]).unwrap(),
+            vec![(0, 0x200), (1, 0x100)]
+        );
+    }
+
+    #[test]
+    fn assign_shared_blocks_by_address() {
+        assert_eq!(
+            assign(&[&[0x200, 0x100], &[0x100, 0x200]]).unwrap(),
+            vec![(0, 0x100), (1, 0x200)]
+        );
+    }
+
+    #[test]
+    fn assign_skips_cores_without_blocks() {
+        assert_eq!(
+            assign(&[&[], &[0x100], &[0x100, 0x300]]).unwrap(),
+            vec![(1, 0x100), (2, 0x300)]
+        );
+    }
+
+    #[test]
+    fn assign_more_cores_than_blocks() {
+        assert_eq!(
+            assign(&[&[0x100, 0x200], &[0x100, 0x200], &[0x100, 0x200]]).unwrap(),
+            vec![(0, 0x100), (1, 0x200)]
+        );
+    }
+
+    #[test]
+    fn assign_no_blocks() {
+        assert!(matches!(
+            assign(&[&[], &[]]),
+            Err(Error::ControlBlockNotFound)
+        ));
+    }
+}
@@ -0,0 +1,685 @@
+//! Symbolizing code addresses in output using the debug information in the firmware ELF file.
+//!
+//! Firmware often prints raw code addresses, e.g. the program counter and the unwound frames when
+//! it panics or hits a fault. [`Symbols`] reads the function symbols and the DWARF line tables
+//! from the ELF file and looks up the function and source location of an address, and
+//! [`SymbolizeReader`] wraps an up channel (or any other [`Read`] source) and annotates the
+//! addresses it finds in the text:
+//!
+//! ```text
+//! PC=0x08001234 <app::main at src/main.rs:42>
+//! ```
+//!
+//! Addresses are recognised as hexadecimal numbers with a `0x` prefix that fall inside a code
+//! section of the ELF file. Other numbers are left as they are.
+//!
+//! This module is only available with the `symbols` feature.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use probe_rs::Probe;
+//! use probe_rs_rtt::symbols::{SymbolizeReader, Symbols};
+//! use probe_rs_rtt::Rtt;
+//! use std::io::Read;
+//! use std::sync::Arc;
+//!
+//! let symbols = Arc::new(Symbols::parse(&std::fs::read("firmware.elf")?)?);
+//!
+//! let probe = Probe::list_all()[0].open()?;
+//! let session = probe.attach("somechip")?;
+//! let core = session.attach_to_core(0)?;
+//! let mut rtt = Rtt::attach(core, &session)?;
+//!
+//! let input = rtt.up_channels().take(0).unwrap();
+//! let mut reader = SymbolizeReader::new(input, symbols);
+//! let mut buf = [0u8; 1024];
+//!
+//! loop {
+//!     let count = reader.read(&mut buf[..])?;
+//!     print!("{}", String::from_utf8_lossy(&buf[..count]));
+//! }
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use gimli::Reader as _;
+use object::read::{Object, ObjectSection};
+use object::target_lexicon::Architecture;
+use object::{SectionKind, SymbolKind};
+
+use crate::Error;
+
+/// Maximum number of hex digits in an address, enough for 64-bit targets.
+const MAX_DIGITS: usize = 16;
+
+/// The function and source location of a code address.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    /// Demangled name of the function, if a symbol covers the address.
+    pub function: Option<String>,
+
+    /// Source file, if the line tables cover the address.
+    pub file: Option<String>,
+
+    /// Line number in the source file. Zero or missing if the code is not attributable to a line.
+    pub line: Option<u64>,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let function = self.function.as_deref().unwrap_or("??");
+
+        match (self.file.as_ref(), self.line) {
+            (Some(file), Some(line)) => write!(f, "{} at {}:{}", function, file, line),
+            (Some(file), None) => write!(f, "{} at {}", function, file),
+            _ => write!(f, "{}", function),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Function {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+/// A row of the line tables. The row covers the addresses up to the next row.
+#[derive(Debug)]
+struct Row {
+    address: u64,
+    /// Index into `Symbols::files`, or `None` for the end of a sequence.
+    file: Option<usize>,
+    line: u64,
+}
+
+/// Function symbols and line tables read from a firmware ELF file.
+#[derive(Debug)]
+pub struct Symbols {
+    code: Vec<Range<u64>>,
+    /// Sorted by address.
+    functions: Vec<Function>,
+    /// Sorted by address.
+    rows: Vec<Row>,
+    files: Vec<String>,
+    /// Whether bit 0 of addresses is the Thumb bit.
+    thumb: bool,
+}
+
+impl Symbols {
+    /// Reads the function symbols and line tables from the contents of an ELF file.
+    ///
+    /// Returns an error if the file cannot be parsed or contains neither symbols nor debug
+    /// information, e.g. because it has been stripped.
+    pub fn parse(elf: &[u8]) -> Result<Symbols, Error> {
+        let file = object::File::parse(elf)
+            .map_err(|err| Error::InvalidDebugInfo(format!("Error parsing ELF file: {}", err)))?;
+
+        let thumb = matches!(file.architecture(), Architecture::Arm(_));
+
+        let code = file
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text && section.size() > 0)
+            .map(|section| section.address()..section.address() + section.size())
+            .collect();
+
+        let mut functions: Vec<Function> = file
+            .symbols()
+            .filter(|(_, symbol)| symbol.kind() == SymbolKind::Text)
+            .filter_map(|(_, symbol)| {
+                let name = symbol.name()?;
+                let address = if thumb {
+                    symbol.address() & !1
+                } else {
+                    symbol.address()
+                };
+
+                Some(Function {
+                    address,
+                    size: symbol.size(),
+                    name: format!("{:#}", rustc_demangle::demangle(name)),
+                })
+            })
+            .collect();
+
+        functions.sort_by_key(|function| function.address);
+
+        let mut symbols = Symbols {
+            code,
+            functions,
+            rows: Vec::new(),
+            files: Vec::new(),
+            thumb,
+        };
+
+        symbols.read_lines(&file).map_err(|err| {
+            Error::InvalidDebugInfo(format!("Error reading line tables: {}", err))
+        })?;
+
+        if symbols.functions.is_empty() && symbols.rows.is_empty() {
+            return Err(Error::InvalidDebugInfo(
+                "No symbols or debug information found. Is the ELF file stripped?".to_string(),
+            ));
+        }
+
+        Ok(symbols)
+    }
+
+    /// Returns `true` if source locations were found in the debug information.
+    pub fn has_lines(&self) -> bool {
+        !self.rows.is_empty()
+    }
+
+    /// Looks up the function and source location of a code address. Returns `None` if the address
+    /// is not in a code section or nothing is known about it.
+    pub fn lookup(&self, address: u64) -> Option<Symbol> {
+        let address = if self.thumb { address & !1 } else { address };
+
+        if !self.is_code(address) {
+            return None;
+        }
+
+        let function = self.functions[..upper_bound(&self.functions, address, |f| f.address)]
+            .iter()
+            .rev()
+            .find(|f| f.address == address || (f.address < address && address - f.address < f.size))
+            .map(|f| f.name.clone());
+
+        let row = match upper_bound(&self.rows, address, |row| row.address) {
+            0 => None,
+            index => Some(&self.rows[index - 1]),
+        };
+
+        let (file, line) = match row {
+            Some(Row {
+                file: Some(file),
+                line,
+                ..
+            }) => (
+                Some(self.files[*file].clone()),
+                Some(*line).filter(|&line| line > 0),
+            ),
+            _ => (None, None),
+        };
+
+        if function.is_none() && file.is_none() {
+            return None;
+        }
+
+        Some(Symbol {
+            function,
+            file,
+            line,
+        })
+    }
+
+    /// Annotates the code addresses in a piece of text with their function and source location.
+    pub fn annotate(&self, text: &str) -> String {
+        let mut out = Vec::new();
+        self.annotate_bytes(text.as_bytes(), &mut out);
+
+        // Annotations are only inserted after ASCII digits, so the text stays valid UTF-8
+        String::from_utf8(out).unwrap()
+    }
+
+    fn annotate_bytes(&self, text: &[u8], out: &mut Vec<u8>) {
+        let mut copied = 0;
+        let mut pos = 0;
+
+        while pos + 2 < text.len() {
+            // A letter may precede the prefix, e.g. the end of an ANSI color sequence
+            let is_prefix = text[pos] == b'0'
+                && matches!(text[pos + 1], b'x' | b'X')
+                && (pos == 0 || !text[pos - 1].is_ascii_digit());
+
+            if !is_prefix {
+                pos += 1;
+                continue;
+            }
+
+            let digits = text[pos + 2..]
+                .iter()
+                .take_while(|b| b.is_ascii_hexdigit())
+                .count();
+            let end = pos + 2 + digits;
+
+            if digits > 0
+                && digits <= MAX_DIGITS
+                && !matches!(text.get(end), Some(&b) if is_word(b))
+            {
+                let hex = std::str::from_utf8(&text[pos + 2..end]).unwrap();
+                let address = u64::from_str_radix(hex, 16).unwrap();
+
+                if let Some(symbol) = self.lookup(address) {
+                    out.extend_from_slice(&text[copied..end]);
+                    write!(out, " <{}>", symbol).unwrap();
+                    copied = end;
+                }
+            }
+
+            pos = end;
+        }
+
+        out.extend_from_slice(&text[copied..]);
+    }
+
+    fn is_code(&self, address: u64) -> bool {
+        self.code.iter().any(|range| range.contains(&address))
+    }
+
+    /// Reads the rows of the DWARF line tables that are in code sections. Sequences for functions
+    /// that were removed by the linker are left at address zero, and are skipped.
+    fn read_lines(&mut self, file: &object::File) -> Result<(), gimli::Error> {
+        let endian = if file.is_little_endian() {
+            gimli::RunTimeEndian::Little
+        } else {
+            gimli::RunTimeEndian::Big
+        };
+
+        let load_section = |id: gimli::SectionId| -> Result<Reader, gimli::Error> {
+            let data = file
+                .section_by_name(id.name())
+                .and_then(|section| section.uncompressed_data().ok())
+                .unwrap_or(Cow::Borrowed(&[][..]));
+
+            Ok(gimli::EndianRcSlice::new(Rc::from(&*data), endian))
+        };
+
+        let load_section_sup = |_| Ok(gimli::EndianRcSlice::new(Rc::from(&[][..]), endian));
+
+        let dwarf = gimli::Dwarf::load(&load_section, &load_section_sup)?;
+
+        let mut file_indices: HashMap<String, usize> = HashMap::new();
+        let mut units = dwarf.units();
+
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => continue,
+            };
+
+            // Indices into `files` by the file numbers of this unit
+            let mut unit_files: HashMap<u64, Option<usize>> = HashMap::new();
+            let mut in_sequence = false;
+            let mut in_code = false;
+            let mut rows = program.rows();
+
+            while let Some((header, row)) = rows.next_row()? {
+                if !in_sequence {
+                    in_sequence = true;
+                    in_code = self.is_code(row.address());
+                }
+
+                if !in_code {
+                    in_sequence = !row.end_sequence();
+                    continue;
+                }
+
+                if row.end_sequence() {
+                    in_sequence = false;
+
+                    self.rows.push(Row {
+                        address: row.address(),
+                        file: None,
+                        line: 0,
+                    });
+
+                    continue;
+                }
+
+                let file_index = match unit_files.get(&row.file_index()) {
+                    Some(index) => *index,
+                    None => {
+                        let path = match row.file(header) {
+                            Some(entry) => Some(file_path(&dwarf, &unit, header, entry)?),
+                            None => None,
+                        };
+
+                        let index = path.map(|path| {
+                            let next = self.files.len();
+                            let index = *file_indices.entry(path.clone()).or_insert(next);
+
+                            if index == next {
+                                self.files.push(path);
+                            }
+
+                            index
+                        });
+
+                        unit_files.insert(row.file_index(), index);
+                        index
+                    }
+                };
+
+                self.rows.push(Row {
+                    address: row.address(),
+                    file: file_index,
+                    line: row.line().unwrap_or(0),
+                });
+            }
+        }
+
+        // The end of a sequence sorts before a row at the same address, so that it does not hide
+        // the start of the next sequence
+        self.rows
+            .sort_by_key(|row| (row.address, row.file.is_some()));
+
+        Ok(())
+    }
+}
+
+type Reader = gimli::EndianRcSlice<gimli::RunTimeEndian>;
+
+/// Builds the path of a file in a line table from its directory and name.
+fn file_path(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &gimli::Unit<Reader>,
+    header: &gimli::LineProgramHeader<Reader>,
+    entry: &gimli::FileEntry<Reader>,
+) -> Result<String, gimli::Error> {
+    let name = dwarf.attr_string(unit, entry.path_name())?;
+    let name = name.to_string_lossy()?;
+
+    Ok(match entry.directory(header) {
+        Some(directory) => {
+            let directory = dwarf.attr_string(unit, directory)?;
+            Path::new(&*directory.to_string_lossy()?)
+                .join(&*name)
+                .to_string_lossy()
+                .into_owned()
+        }
+        None => name.into_owned(),
+    })
+}
+
+/// Returns the number of items with a key less than or equal to the value. The items must be
+/// sorted by the key.
+fn upper_bound<T>(items: &[T], value: u64, key: impl Fn(&T) -> u64) -> usize {
+    let (mut low, mut high) = (0, items.len());
+
+    while low < high {
+        let mid = (low + high) / 2;
+
+        if key(&items[mid]) <= value {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    low
+}
+
+fn is_word(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// Reader that annotates the code addresses in the text from an up channel with their function
+/// and source location.
+///
+/// An address may be split between reads, so a word at the end of the data read is held back
+/// until more data arrives or the inner reader has no more data available.
+pub struct SymbolizeReader<R: Read> {
+    inner: R,
+    symbols: Arc<Symbols>,
+    read_buf: Box<[u8]>,
+    held: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl<R: Read> SymbolizeReader<R> {
+    pub fn new(inner: R, symbols: Arc<Symbols>) -> Self {
+        SymbolizeReader {
+            inner,
+            symbols,
+            read_buf: vec![0u8; 1024].into_boxed_slice(),
+            held: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    /// Returns a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns the underlying reader. Any buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for SymbolizeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.out.is_empty() {
+            let count = self.inner.read(&mut self.read_buf)?;
+            self.held.extend_from_slice(&self.read_buf[..count]);
+
+            let split = if count == 0 {
+                self.held.len()
+            } else {
+                let word = self.held.iter().rev().take_while(|&&b| is_word(b)).count();
+                self.held.len() - word
+            };
+
+            let text: Vec<u8> = self.held.drain(..split).collect();
+            self.symbols.annotate_bytes(&text, &mut self.out);
+        }
+
+        let count = self.out.len().min(buf.len());
+
+        buf[..count].copy_from_slice(&self.out[..count]);
+        self.out.drain(..count);
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ScriptedReader;
+
+    fn function(address: u64, size: u64, name: &str) -> Function {
+        Function {
+            address,
+            size,
+            name: name.to_string(),
+        }
+    }
+
+    fn row(address: u64, file: Option<usize>, line: u64) -> Row {
+        Row {
+            address,
+            file,
+            line,
+        }
+    }
+
+    /// Symbols of a Thumb firmware with code in flash at 0x1000..0x2000, and in RAM.
+    fn symbols() -> Symbols {
+        Symbols {
+            code: vec![0x1000..0x2000, 0x2000_0000..0x2000_0100],
+            functions: vec![
+                function(0x1000, 0x100, "app::main"),
+                function(0x1100, 0x10, "app::helper"),
+                function(0x1200, 0, "label"),
+                function(0x2000_0000, 0x20, "app::ram_fn"),
+            ],
+            rows: vec![
+                row(0x1000, Some(0), 10),
+                row(0x1010, Some(0), 12),
+                row(0x1100, Some(1), 0),
+                row(0x1110, None, 0),
+            ],
+            files: vec!["src/main.rs".to_string(), "src/helper.rs".to_string()],
+            thumb: true,
+        }
+    }
+
+    /// Reads everything from the chunks, polling once per chunk and once more for the end of the
+    /// data, as no output is returned while a word is held back.
+    fn symbolize(chunks: &[&[u8]]) -> String {
+        let mut reader = SymbolizeReader::new(ScriptedReader::new(chunks), Arc::new(symbols()));
+        let mut out = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        for _ in 0..=chunks.len() {
+            let count = reader.read(&mut buf).unwrap();
+            out.extend_from_slice(&buf[..count]);
+        }
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = symbols();
+        let lookup = |address| symbols.lookup(address).map(|symbol| symbol.to_string());
+
+        assert_eq!(lookup(0x1000).unwrap(), "app::main at src/main.rs:10");
+        assert_eq!(lookup(0x1013).unwrap(), "app::main at src/main.rs:12");
+        assert_eq!(lookup(0x10ff).unwrap(), "app::main at src/main.rs:12");
+
+        // Line 0 is not attributable to a line
+        assert_eq!(lookup(0x1104).unwrap(), "app::helper at src/helper.rs");
+
+        // Past the end of a sequence, and a symbol without a size
+        assert_eq!(lookup(0x1200).unwrap(), "label");
+        assert_eq!(lookup(0x1180), None);
+        assert_eq!(lookup(0x1202), None);
+
+        // Code in another section
+        assert_eq!(lookup(0x2000_0010).unwrap(), "app::ram_fn");
+
+        // Outside of code
+        assert_eq!(lookup(0x0fff), None);
+        assert_eq!(lookup(0x2000), None);
+    }
+
+    #[test]
+    fn lookup_thumb_bit() {
+        let mut symbols = symbols();
+
+        assert_eq!(symbols.lookup(0x1011), symbols.lookup(0x1010));
+        assert_eq!(symbols.lookup(0x1201).unwrap().function.unwrap(), "label");
+
+        symbols.thumb = false;
+        assert_eq!(symbols.lookup(0x1201), None);
+    }
+
+    #[test]
+    fn display_unknown_function() {
+        let symbol = Symbol {
+            function: None,
+            file: Some("src/lib.rs".to_string()),
+            line: Some(3),
+        };
+
+        assert_eq!(symbol.to_string(), "?? at src/lib.rs:3");
+    }
+
+    #[test]
+    fn annotate_addresses() {
+        let symbols = symbols();
+
+        assert_eq!(
+            symbols.annotate("PC=0x1001 LR=0X00001101\n"),
+            "PC=0x1001 <app::main at src/main.rs:10> LR=0X00001101 <app::helper at src/helper.rs>\n"
+        );
+
+        // After an ANSI color sequence, at the end and next to non-ASCII text
+        assert_eq!(
+            symbols.annotate("\x1b[31m0x1200\x1b[0m é0x1200"),
+            "\x1b[31m0x1200 <label>\x1b[0m é0x1200 <label>"
+        );
+    }
+
+    #[test]
+    fn annotate_ignores_other_numbers() {
+        let symbols = symbols();
+
+        for text in [
+            "0x3000",
+            "0x",
+            "0x1000g",
+            "0x1000_0",
+            "10x1000",
+            "0x00000000000001000",
+            "1000",
+        ] {
+            assert_eq!(symbols.annotate(text), text);
+        }
+    }
+
+    #[test]
+    fn address_split_across_reads() {
+        assert_eq!(
+            symbolize(&[b"PC=0x", b"10", b"00 ", b"next\n"]),
+            "PC=0x1000 <app::main at src/main.rs:10> next\n"
+        );
+
+        // The held back word is only annotated once it is known to be complete
+        assert_eq!(symbolize(&[b"0x1000", b"g\n"]), "0x1000g\n");
+        assert_eq!(
+            symbolize(&[b"at 0x1000"]),
+            "at 0x1000 <app::main at src/main.rs:10>"
+        );
+    }
+
+    #[test]
+    fn small_read_buffer() {
+        let mut reader =
+            SymbolizeReader::new(ScriptedReader::new(&[b"0x1200\n"]), Arc::new(symbols()));
+        let mut out = Vec::new();
+        let mut buf = [0u8; 3];
+
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                count => out.extend_from_slice(&buf[..count]),
+            }
+        }
+
+        assert_eq!(out, b"0x1200 <label>\n");
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(
+            Symbols::parse(b"not an elf file"),
+            Err(Error::InvalidDebugInfo(_))
+        ));
+    }
+
+    #[test]
+    fn parse_own_executable() {
+        let elf = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let symbols = Symbols::parse(&elf).unwrap();
+
+        assert!(symbols.has_lines());
+
+        let function = symbols
+            .functions
+            .iter()
+            .find(|f| f.name == "probe_rs_rtt::symbols::tests::parse_own_executable")
+            .unwrap();
+        let symbol = symbols.lookup(function.address).unwrap();
+
+        assert_eq!(symbol.function.as_deref(), Some(&*function.name));
+        assert!(symbol.file.unwrap().ends_with("symbols.rs"));
+        assert!(symbol.line.is_some());
+    }
+}
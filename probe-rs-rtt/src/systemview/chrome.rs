@@ -0,0 +1,474 @@
+//! Export of events in the Chrome trace event format.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use serde_json::{json, Value};
+
+use super::{Event, EventKind, PrintLevel};
+
+const PID: u32 = 1;
+
+// Tracks that are not tasks. Task tracks use the task ID, which is an address and does not collide
+// with these.
+const IDLE_TID: u32 = 0;
+const ISR_TID: u32 = 1;
+const TIMER_TID: u32 = 2;
+
+/// Writer that exports events as a Chrome trace JSON file, for viewing in `chrome://tracing` or
+/// Perfetto.
+///
+/// Task execution is shown as slices on one track per task, and interrupt handlers and software
+/// timer callbacks on a track each. Other events are shown as instant events on the track of the
+/// context they happened in.
+///
+/// Events are written as they are received. As the closing bracket of the trace is optional, the
+/// file can be viewed even if [`ChromeTrace::finish`] is never called, e.g. because recording was
+/// interrupted.
+pub struct ChromeTrace<W: Write> {
+    out: W,
+    first: bool,
+    /// Track of the task or idle slice that is currently open.
+    running: Option<u32>,
+    isr_depth: usize,
+    timer_depth: usize,
+    /// Interrupt names from the system description.
+    isr_names: BTreeMap<u32, String>,
+    task_names: BTreeMap<u32, String>,
+    marker_names: BTreeMap<u32, String>,
+}
+
+impl<W: Write> ChromeTrace<W> {
+    /// Creates a new writer and writes the start of the trace.
+    pub fn new(out: W) -> io::Result<Self> {
+        let mut trace = ChromeTrace {
+            out,
+            first: true,
+            running: None,
+            isr_depth: 0,
+            timer_depth: 0,
+            isr_names: BTreeMap::new(),
+            task_names: BTreeMap::new(),
+            marker_names: BTreeMap::new(),
+        };
+
+        trace.out.write_all(b"[")?;
+
+        trace.record(json!({
+            "ph": "M",
+            "name": "process_name",
+            "pid": PID,
+            "args": { "name": "SystemView" },
+        }))?;
+
+        for &(tid, name) in &[
+            (IDLE_TID, "Idle"),
+            (ISR_TID, "Interrupts"),
+            (TIMER_TID, "Timers"),
+        ] {
+            trace.thread_name(tid, name)?;
+        }
+
+        Ok(trace)
+    }
+
+    /// Writes an event to the trace.
+    pub fn write_event(&mut self, event: &Event) -> io::Result<()> {
+        // Without a known timestamp frequency, cycles are shown as microseconds
+        let ts = match event.seconds {
+            Some(seconds) => seconds * 1_000_000.0,
+            None => event.timestamp as f64,
+        };
+
+        match &event.kind {
+            EventKind::TaskStartExec { task } => {
+                self.stop_running(ts)?;
+
+                let name = self.task_name(*task);
+                self.slice("B", ts, *task, &name)?;
+                self.running = Some(*task);
+            }
+            EventKind::TaskStopExec => self.stop_running(ts)?,
+            EventKind::Idle => {
+                self.stop_running(ts)?;
+
+                self.slice("B", ts, IDLE_TID, "Idle")?;
+                self.running = Some(IDLE_TID);
+            }
+            EventKind::IsrEnter { isr } => {
+                let name = match self.isr_names.get(isr) {
+                    Some(name) => name.clone(),
+                    None => format!("ISR {}", isr),
+                };
+
+                self.slice("B", ts, ISR_TID, &name)?;
+                self.isr_depth += 1;
+            }
+            EventKind::IsrExit | EventKind::IsrToScheduler if self.isr_depth > 0 => {
+                self.slice("E", ts, ISR_TID, "")?;
+                self.isr_depth -= 1;
+            }
+            EventKind::TimerEnter { timer } => {
+                self.slice("B", ts, TIMER_TID, &format!("Timer 0x{:08x}", timer))?;
+                self.timer_depth += 1;
+            }
+            EventKind::TimerExit if self.timer_depth > 0 => {
+                self.slice("E", ts, TIMER_TID, "")?;
+                self.timer_depth -= 1;
+            }
+            EventKind::TaskCreate { task } => self.instant(ts, *task, "Created", json!({}))?,
+            EventKind::TaskTerminate { task } => {
+                self.instant(ts, *task, "Terminated", json!({}))?
+            }
+            EventKind::TaskStartReady { task } => self.instant(ts, *task, "Ready", json!({}))?,
+            EventKind::TaskStopReady { task, cause } => {
+                self.instant(ts, *task, "Blocked", json!({ "cause": cause }))?
+            }
+            EventKind::TaskInfo {
+                task,
+                priority,
+                name,
+            } => {
+                self.task_names.insert(*task, name.clone());
+                self.thread_name(*task, name)?;
+
+                // Higher priority tasks are shown first
+                self.record(json!({
+                    "ph": "M",
+                    "name": "thread_sort_index",
+                    "pid": PID,
+                    "tid": task,
+                    "args": { "sort_index": -(*priority as i64) },
+                }))?;
+            }
+            EventKind::SystemDescription(desc) => {
+                // Interrupt names are given as I#<number>=<name>
+                for item in desc.split(',') {
+                    let mut parts = item.trim().splitn(2, '=');
+
+                    if let (Some(key), Some(name)) = (parts.next(), parts.next()) {
+                        if let Some(Ok(isr)) = key.strip_prefix("I#").map(str::parse) {
+                            self.isr_names.insert(isr, name.to_string());
+                        }
+                    }
+                }
+            }
+            EventKind::MarkStart { marker } | EventKind::MarkStop { marker } => {
+                let ph = match event.kind {
+                    EventKind::MarkStart { .. } => "b",
+                    _ => "e",
+                };
+
+                let name = self.marker_name(*marker);
+
+                self.record(json!({
+                    "ph": ph,
+                    "cat": "marker",
+                    "id": marker,
+                    "name": name,
+                    "ts": ts,
+                    "pid": PID,
+                    "tid": self.current_tid(),
+                }))?;
+            }
+            EventKind::Mark { marker } => {
+                let name = self.marker_name(*marker);
+                self.instant(ts, self.current_tid(), &name, json!({}))?;
+            }
+            EventKind::NameMarker { marker, name } => {
+                self.marker_names.insert(*marker, name.clone());
+            }
+            EventKind::Print { level, message } => {
+                let level = match level {
+                    PrintLevel::Log => "log",
+                    PrintLevel::Warning => "warning",
+                    PrintLevel::Error => "error",
+                };
+
+                self.instant(ts, self.current_tid(), message, json!({ "level": level }))?;
+            }
+            EventKind::User { event, params } => self.instant(
+                ts,
+                self.current_tid(),
+                &format!("Event {}", event),
+                json!({ "params": params }),
+            )?,
+            EventKind::Overflow { dropped } => self.record(json!({
+                "ph": "i",
+                "s": "g",
+                "name": "Overflow",
+                "ts": ts,
+                "pid": PID,
+                "args": { "dropped": dropped },
+            }))?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Writes the end of the trace and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(b"\n]\n")?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+
+    /// Returns the track that instant events are shown on.
+    fn current_tid(&self) -> u32 {
+        if self.isr_depth > 0 {
+            ISR_TID
+        } else if self.timer_depth > 0 {
+            TIMER_TID
+        } else {
+            self.running.unwrap_or(IDLE_TID)
+        }
+    }
+
+    fn task_name(&self, task: u32) -> String {
+        match self.task_names.get(&task) {
+            Some(name) => name.clone(),
+            None => format!("Task 0x{:08x}", task),
+        }
+    }
+
+    fn marker_name(&self, marker: u32) -> String {
+        match self.marker_names.get(&marker) {
+            Some(name) => name.clone(),
+            None => format!("Marker {}", marker),
+        }
+    }
+
+    fn stop_running(&mut self, ts: f64) -> io::Result<()> {
+        if let Some(tid) = self.running.take() {
+            self.slice("E", ts, tid, "")?;
+        }
+
+        Ok(())
+    }
+
+    fn slice(&mut self, ph: &str, ts: f64, tid: u32, name: &str) -> io::Result<()> {
+        self.record(json!({
+            "ph": ph,
+            "name": name,
+            "ts": ts,
+            "pid": PID,
+            "tid": tid,
+        }))
+    }
+
+    fn instant(&mut self, ts: f64, tid: u32, name: &str, args: Value) -> io::Result<()> {
+        self.record(json!({
+            "ph": "i",
+            "s": "t",
+            "name": name,
+            "ts": ts,
+            "pid": PID,
+            "tid": tid,
+            "args": args,
+        }))
+    }
+
+    fn thread_name(&mut self, tid: u32, name: &str) -> io::Result<()> {
+        self.record(json!({
+            "ph": "M",
+            "name": "thread_name",
+            "pid": PID,
+            "tid": tid,
+            "args": { "name": name },
+        }))
+    }
+
+    fn record(&mut self, value: Value) -> io::Result<()> {
+        let separator: &[u8] = if self.first { b"\n" } else { b",\n" };
+        self.out.write_all(separator)?;
+        self.first = false;
+
+        serde_json::to_writer(&mut self.out, &value)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systemview::tests::{decode_all, RECORDING};
+    use crate::systemview::Decoder;
+
+    fn export(events: &[Event], finish: bool) -> Vec<Value> {
+        let mut trace = ChromeTrace::new(Vec::new()).unwrap();
+
+        for event in events {
+            trace.write_event(event).unwrap();
+        }
+
+        let out = if finish {
+            trace.finish().unwrap()
+        } else {
+            let mut out = trace.out;
+            out.extend_from_slice(b"]");
+            out
+        };
+
+        // Leave out the metadata written at the start
+        let mut records: Vec<Value> = serde_json::from_slice(&out).unwrap();
+
+        records.split_off(4)
+    }
+
+    fn event(timestamp: u64, kind: EventKind) -> Event {
+        Event {
+            timestamp,
+            seconds: None,
+            kind,
+        }
+    }
+
+    #[test]
+    fn header() {
+        let out = ChromeTrace::new(Vec::new()).unwrap().finish().unwrap();
+        let records: Vec<Value> = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(records[0]["name"], "process_name");
+        assert_eq!(records[0]["args"]["name"], "SystemView");
+
+        let threads: Vec<(u64, &str)> = records[1..]
+            .iter()
+            .map(|r| {
+                (
+                    r["tid"].as_u64().unwrap(),
+                    r["args"]["name"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(threads, vec![(0, "Idle"), (1, "Interrupts"), (2, "Timers")]);
+    }
+
+    #[test]
+    fn export_recording() {
+        let mut decoder = Decoder::new();
+        decoder.received(RECORDING);
+
+        let events = decode_all(&mut decoder);
+        let records = export(&events, true);
+
+        let task = 0x2000_0100u64;
+        let summary: Vec<(&str, &str, f64, u64)> = records
+            .iter()
+            .map(|r| {
+                (
+                    r["ph"].as_str().unwrap(),
+                    r["name"].as_str().unwrap(),
+                    r["ts"].as_f64().unwrap_or(-1.0),
+                    r["tid"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+
+        // The timestamps are in microseconds at 1 MHz
+        assert_eq!(
+            summary,
+            vec![
+                ("M", "thread_name", -1.0, task),
+                ("M", "thread_sort_index", -1.0, task),
+                ("B", "main", 100.0, task),
+                ("B", "SysTick", 150.0, ISR_TID as u64),
+                ("E", "", 160.0, ISR_TID as u64),
+                ("i", "x=-1", 165.0, task),
+                ("E", "", 185.0, task),
+                ("B", "Idle", 185.0, IDLE_TID as u64),
+                ("i", "Event 40", 200.0, IDLE_TID as u64),
+            ]
+        );
+
+        assert_eq!(records[0]["args"]["name"], "main");
+        assert_eq!(records[1]["args"]["sort_index"], -3);
+        assert_eq!(records[5]["args"]["level"], "warning");
+        assert_eq!(records[8]["args"]["params"], json!([1, 300]));
+    }
+
+    #[test]
+    fn unfinished_trace_is_valid() {
+        let events = [event(10, EventKind::Idle)];
+
+        assert_eq!(export(&events, false)[0]["name"], "Idle");
+    }
+
+    #[test]
+    fn nested_contexts() {
+        let records = export(
+            &[
+                event(1, EventKind::TaskStartExec { task: 0x100 }),
+                event(2, EventKind::TimerEnter { timer: 0x200 }),
+                event(3, EventKind::IsrEnter { isr: 5 }),
+                event(4, EventKind::Mark { marker: 1 }),
+                event(5, EventKind::IsrToScheduler),
+                event(6, EventKind::TaskCreate { task: 0x300 }),
+                event(7, EventKind::TimerExit),
+                // Exits without a matching enter are ignored
+                event(8, EventKind::TimerExit),
+                event(9, EventKind::IsrExit),
+                event(10, EventKind::TaskStartExec { task: 0x300 }),
+            ],
+            true,
+        );
+
+        let summary: Vec<(&str, &str, u64)> = records
+            .iter()
+            .map(|r| {
+                (
+                    r["ph"].as_str().unwrap(),
+                    r["name"].as_str().unwrap(),
+                    r["tid"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("B", "Task 0x00000100", 0x100),
+                ("B", "Timer 0x00000200", TIMER_TID as u64),
+                ("B", "ISR 5", ISR_TID as u64),
+                ("i", "Marker 1", ISR_TID as u64),
+                ("E", "", ISR_TID as u64),
+                ("i", "Created", 0x300),
+                ("E", "", TIMER_TID as u64),
+                // Switching tasks ends the slice of the previous one
+                ("E", "", 0x100),
+                ("B", "Task 0x00000300", 0x300),
+            ]
+        );
+    }
+
+    #[test]
+    fn markers_and_overflow() {
+        let records = export(
+            &[
+                event(
+                    1,
+                    EventKind::NameMarker {
+                        marker: 4,
+                        name: "work".to_string(),
+                    },
+                ),
+                event(2, EventKind::MarkStart { marker: 4 }),
+                event(3, EventKind::MarkStop { marker: 4 }),
+                event(4, EventKind::Overflow { dropped: 12 }),
+            ],
+            true,
+        );
+
+        assert_eq!(records[0]["ph"], "b");
+        assert_eq!(records[0]["name"], "work");
+        assert_eq!(records[0]["id"], 4);
+        assert_eq!(records[1]["ph"], "e");
+        assert_eq!(records[1]["id"], 4);
+
+        assert_eq!(records[2]["ph"], "i");
+        assert_eq!(records[2]["s"], "g");
+        assert_eq!(records[2]["args"]["dropped"], 12);
+    }
+}
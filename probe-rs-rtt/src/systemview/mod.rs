@@ -0,0 +1,996 @@
+//! Decoding of [SEGGER SystemView](https://www.segger.com/products/development-tools/systemview/)
+//! event streams.
+//!
+//! SystemView records RTOS and application events on the target and sends them over a dedicated
+//! up channel, named "SysView" by default. [`Decoder`] turns the raw data from the channel (or
+//! from a recorded capture) into [`Event`]s such as task switches, interrupt entry and exit, and
+//! user events. [`ChromeTrace`] writes the events as a Chrome trace JSON file that can be viewed
+//! in `chrome://tracing` or [Perfetto](https://ui.perfetto.dev).
+//!
+//! The packet format of SystemView version 3 target code is supported. Recording usually only
+//! starts once the host sends [`COMMAND_START`] on the "SysView" down channel, unless the firmware
+//! calls `SEGGER_SYSVIEW_Start` itself.
+//!
+//! This module is only available with the `systemview` feature.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use probe_rs::Probe;
+//! use probe_rs_rtt::systemview::{self, ChromeTrace, Decoder};
+//! use probe_rs_rtt::Rtt;
+//!
+//! let probe = Probe::list_all()[0].open()?;
+//! let session = probe.attach("somechip")?;
+//! let core = session.attach_to_core(0)?;
+//! let mut rtt = Rtt::attach(core, &session)?;
+//!
+//! let input = rtt.up_channels().take_by_name(systemview::CHANNEL_NAME).unwrap();
+//! let output = rtt.down_channels().take_by_name(systemview::CHANNEL_NAME).unwrap();
+//! output.write(&[systemview::COMMAND_START])?;
+//!
+//! let mut decoder = Decoder::new();
+//! let mut trace = ChromeTrace::new(std::fs::File::create("trace.json")?)?;
+//! let mut buf = [0u8; 1024];
+//!
+//! loop {
+//!     let count = input.read(&mut buf[..])?;
+//!     decoder.received(&buf[..count]);
+//!
+//!     while let Some(event) = decoder.next_event()? {
+//!         println!("{}", event.kind);
+//!         trace.write_event(&event)?;
+//!     }
+//! }
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::fmt;
+
+use crate::Error;
+
+mod chrome;
+
+pub use chrome::ChromeTrace;
+
+/// Name of the channels SystemView uses by default.
+pub const CHANNEL_NAME: &str = "SysView";
+
+/// Command that starts recording when sent on the SystemView down channel.
+pub const COMMAND_START: u8 = 1;
+
+/// Command that stops recording when sent on the SystemView down channel.
+pub const COMMAND_STOP: u8 = 2;
+
+// Event IDs. Events below EVTID_INIT have a fixed format and no length field.
+const EVTID_NOP: u32 = 0;
+const EVTID_OVERFLOW: u32 = 1;
+const EVTID_ISR_ENTER: u32 = 2;
+const EVTID_ISR_EXIT: u32 = 3;
+const EVTID_TASK_START_EXEC: u32 = 4;
+const EVTID_TASK_STOP_EXEC: u32 = 5;
+const EVTID_TASK_START_READY: u32 = 6;
+const EVTID_TASK_STOP_READY: u32 = 7;
+const EVTID_TASK_CREATE: u32 = 8;
+const EVTID_TASK_INFO: u32 = 9;
+const EVTID_TRACE_START: u32 = 10;
+const EVTID_TRACE_STOP: u32 = 11;
+const EVTID_SYSTIME_CYCLES: u32 = 12;
+const EVTID_SYSTIME_US: u32 = 13;
+const EVTID_SYSDESC: u32 = 14;
+const EVTID_MARK_START: u32 = 15;
+const EVTID_MARK_STOP: u32 = 16;
+const EVTID_IDLE: u32 = 17;
+const EVTID_ISR_TO_SCHEDULER: u32 = 18;
+const EVTID_TIMER_ENTER: u32 = 19;
+const EVTID_TIMER_EXIT: u32 = 20;
+const EVTID_STACK_INFO: u32 = 21;
+const EVTID_MODULEDESC: u32 = 22;
+const EVTID_INIT: u32 = 24;
+const EVTID_NAME_RESOURCE: u32 = 25;
+const EVTID_PRINT_FORMATTED: u32 = 26;
+const EVTID_NUMMODULES: u32 = 27;
+const EVTID_END_CALL: u32 = 28;
+const EVTID_TASK_TERMINATE: u32 = 29;
+const EVTID_EX: u32 = 31;
+
+// Sub IDs of extended events.
+const EVTID_EX_MARK: u32 = 0;
+const EVTID_EX_NAME_MARKER: u32 = 1;
+
+/// Level of a message printed with the `SEGGER_SYSVIEW_Print*` functions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PrintLevel {
+    Log,
+    Warning,
+    Error,
+}
+
+/// System information sent by the target when recording starts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SystemInfo {
+    /// Frequency of the timestamp counter in Hz.
+    pub timestamp_frequency: u32,
+
+    /// CPU frequency in Hz.
+    pub cpu_frequency: u32,
+
+    /// Base address used to shrink IDs (such as task IDs) sent by the target.
+    pub ram_base: u32,
+
+    /// Number of bits IDs are shifted right by when shrinking them.
+    pub id_shift: u32,
+}
+
+/// Kind and parameters of an event.
+///
+/// Task, timer and resource IDs are usually the addresses of the corresponding objects. They are
+/// restored from the shrunk form the target sends once the [`EventKind::Init`] event has been
+/// received.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    /// Events were dropped on the target because the channel buffer was full.
+    Overflow { dropped: u32 },
+
+    /// An interrupt handler was entered.
+    IsrEnter { isr: u32 },
+
+    /// An interrupt handler returned.
+    IsrExit,
+
+    /// An interrupt handler returned to the scheduler.
+    IsrToScheduler,
+
+    /// A task started or resumed executing.
+    TaskStartExec { task: u32 },
+
+    /// The executing task stopped executing.
+    TaskStopExec,
+
+    /// A task became ready to run.
+    TaskStartReady { task: u32 },
+
+    /// A task stopped being ready to run, e.g. because it blocked. The cause is RTOS specific.
+    TaskStopReady { task: u32, cause: u32 },
+
+    /// A task was created.
+    TaskCreate { task: u32 },
+
+    /// A task was terminated.
+    TaskTerminate { task: u32 },
+
+    /// Name and priority of a task.
+    TaskInfo {
+        task: u32,
+        priority: u32,
+        name: String,
+    },
+
+    /// Stack of a task.
+    StackInfo {
+        task: u32,
+        base: u32,
+        size: u32,
+        usage: u32,
+    },
+
+    /// Recording started.
+    TraceStart,
+
+    /// Recording stopped.
+    TraceStop,
+
+    /// System time in timestamp counter cycles.
+    SystemTimeCycles(u32),
+
+    /// System time in microseconds.
+    SystemTimeUs(u64),
+
+    /// System description, a list of `N=value` pairs separated by commas.
+    SystemDescription(String),
+
+    /// A performance marker started.
+    MarkStart { marker: u32 },
+
+    /// A performance marker stopped.
+    MarkStop { marker: u32 },
+
+    /// A single performance marker.
+    Mark { marker: u32 },
+
+    /// Name of a performance marker.
+    NameMarker { marker: u32, name: String },
+
+    /// The system went idle.
+    Idle,
+
+    /// A software timer callback was entered.
+    TimerEnter { timer: u32 },
+
+    /// A software timer callback returned.
+    TimerExit,
+
+    /// Description of an event module.
+    ModuleDescription {
+        module: u32,
+        event_offset: u32,
+        description: String,
+    },
+
+    /// Number of event modules.
+    ModuleCount(u32),
+
+    /// Recording was initialized.
+    Init(SystemInfo),
+
+    /// Name of a resource, such as an interrupt or a queue.
+    NameResource { resource: u32, name: String },
+
+    /// A message printed with one of the `SEGGER_SYSVIEW_Print*` functions.
+    Print { level: PrintLevel, message: String },
+
+    /// An API call returned. `event` is the event ID of the call.
+    EndCall { event: u32, result: Option<u32> },
+
+    /// A user or RTOS module event with its raw parameters.
+    User { event: u32, params: Vec<u32> },
+}
+
+/// A decoded event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// Timestamp in timestamp counter cycles since the start of the stream.
+    pub timestamp: u64,
+
+    /// Timestamp in seconds, if the timestamp frequency is known.
+    pub seconds: Option<f64>,
+
+    /// Kind and parameters of the event.
+    pub kind: EventKind,
+}
+
+impl fmt::Display for Event {
+    /// Formats the event as `[seconds] description`, or `[cycles] description` if the timestamp
+    /// frequency is not known.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.seconds {
+            Some(seconds) => write!(f, "[{:.6}] {}", seconds, self.kind),
+            None => write!(f, "[{}] {}", self.timestamp, self.kind),
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventKind::Overflow { dropped } => write!(f, "Overflow, {} events dropped", dropped),
+            EventKind::IsrEnter { isr } => write!(f, "ISR {} enter", isr),
+            EventKind::IsrExit => write!(f, "ISR exit"),
+            EventKind::IsrToScheduler => write!(f, "ISR exit to scheduler"),
+            EventKind::TaskStartExec { task } => write!(f, "Task 0x{:08x} start exec", task),
+            EventKind::TaskStopExec => write!(f, "Task stop exec"),
+            EventKind::TaskStartReady { task } => write!(f, "Task 0x{:08x} ready", task),
+            EventKind::TaskStopReady { task, cause } => {
+                write!(f, "Task 0x{:08x} blocked, cause {}", task, cause)
+            }
+            EventKind::TaskCreate { task } => write!(f, "Task 0x{:08x} created", task),
+            EventKind::TaskTerminate { task } => write!(f, "Task 0x{:08x} terminated", task),
+            EventKind::TaskInfo {
+                task,
+                priority,
+                name,
+            } => write!(
+                f,
+                "Task 0x{:08x} is '{}', priority {}",
+                task, name, priority
+            ),
+            EventKind::StackInfo {
+                task,
+                base,
+                size,
+                usage,
+            } => write!(
+                f,
+                "Task 0x{:08x} stack at 0x{:08x}, size {}, used {}",
+                task, base, size, usage
+            ),
+            EventKind::TraceStart => write!(f, "Trace start"),
+            EventKind::TraceStop => write!(f, "Trace stop"),
+            EventKind::SystemTimeCycles(cycles) => write!(f, "System time {} cycles", cycles),
+            EventKind::SystemTimeUs(us) => write!(f, "System time {} us", us),
+            EventKind::SystemDescription(desc) => write!(f, "System description '{}'", desc),
+            EventKind::MarkStart { marker } => write!(f, "Marker {} start", marker),
+            EventKind::MarkStop { marker } => write!(f, "Marker {} stop", marker),
+            EventKind::Mark { marker } => write!(f, "Marker {}", marker),
+            EventKind::NameMarker { marker, name } => {
+                write!(f, "Marker {} is '{}'", marker, name)
+            }
+            EventKind::Idle => write!(f, "Idle"),
+            EventKind::TimerEnter { timer } => write!(f, "Timer 0x{:08x} enter", timer),
+            EventKind::TimerExit => write!(f, "Timer exit"),
+            EventKind::ModuleDescription {
+                module,
+                event_offset,
+                description,
+            } => write!(
+                f,
+                "Module {} at event offset {} is '{}'",
+                module, event_offset, description
+            ),
+            EventKind::ModuleCount(count) => write!(f, "{} modules", count),
+            EventKind::Init(info) => write!(
+                f,
+                "Init, timestamp {} Hz, CPU {} Hz, RAM base 0x{:08x}",
+                info.timestamp_frequency, info.cpu_frequency, info.ram_base
+            ),
+            EventKind::NameResource { resource, name } => {
+                write!(f, "Resource 0x{:08x} is '{}'", resource, name)
+            }
+            EventKind::Print { level, message } => match level {
+                PrintLevel::Log => write!(f, "{}", message),
+                PrintLevel::Warning => write!(f, "Warning: {}", message),
+                PrintLevel::Error => write!(f, "Error: {}", message),
+            },
+            EventKind::EndCall { event, result } => match result {
+                Some(result) => write!(f, "Call {} returned {}", event, result),
+                None => write!(f, "Call {} returned", event),
+            },
+            EventKind::User { event, params } => write!(f, "Event {} {:?}", event, params),
+        }
+    }
+}
+
+/// Error while parsing a packet.
+enum ParseError {
+    /// The data ends before the packet is complete.
+    UnexpectedEof,
+    Malformed(String),
+}
+
+/// Streaming decoder that turns raw SystemView data into events.
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    timestamp: u64,
+    info: Option<SystemInfo>,
+}
+
+impl Decoder {
+    /// Creates a new decoder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the system information, once the [`EventKind::Init`] event has been received.
+    pub fn system_info(&self) -> Option<SystemInfo> {
+        self.info
+    }
+
+    /// Adds data received from the target to the decoder.
+    pub fn received(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Decodes the next complete event, or returns `None` if there is none yet.
+    ///
+    /// The stream does not contain markers that packet boundaries could be recovered from, so if
+    /// a malformed packet is found, all buffered data is dropped and an error is returned.
+    pub fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        // Zero bytes are sent to synchronize the stream when recording starts
+        let start = self.buf.iter().position(|&b| b != EVTID_NOP as u8);
+        self.buf.drain(..start.unwrap_or(self.buf.len()));
+
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let mut packet = Packet {
+            data: &self.buf,
+            pos: 0,
+        };
+
+        match packet.event(self.info) {
+            Ok((delta, kind)) => {
+                let len = packet.pos;
+                self.buf.drain(..len);
+                self.timestamp += delta as u64;
+
+                if let EventKind::Init(info) = kind {
+                    self.info = Some(info);
+                }
+
+                let seconds = match self.info {
+                    Some(info) if info.timestamp_frequency > 0 => {
+                        Some(self.timestamp as f64 / info.timestamp_frequency as f64)
+                    }
+                    _ => None,
+                };
+
+                Ok(Some(Event {
+                    timestamp: self.timestamp,
+                    seconds,
+                    kind,
+                }))
+            }
+            Err(ParseError::UnexpectedEof) => Ok(None),
+            Err(ParseError::Malformed(err)) => {
+                self.buf.clear();
+                Err(Error::MalformedSystemViewData(err))
+            }
+        }
+    }
+}
+
+struct Packet<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Packet<'a> {
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        let b = *self.data.get(self.pos).ok_or(ParseError::UnexpectedEof)?;
+        self.pos += 1;
+
+        Ok(b)
+    }
+
+    /// Reads a variable length encoded 32-bit value.
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        let mut value = 0u32;
+
+        for i in 0..5 {
+            let b = self.u8()?;
+            value |= ((b & 0x7f) as u32) << (7 * i);
+
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(ParseError::Malformed(
+            "Variable length value is too long.".to_string(),
+        ))
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        let len = match self.u8()? {
+            255 => self.u8()? as usize | ((self.u8()? as usize) << 8),
+            len => len as usize,
+        };
+
+        let end = self.pos + len;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or(ParseError::UnexpectedEof)?;
+        self.pos = end;
+
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// Parses a packet and returns its timestamp delta and event.
+    fn event(&mut self, info: Option<SystemInfo>) -> Result<(u32, EventKind), ParseError> {
+        let id = self.u32()?;
+
+        let kind = if id < EVTID_INIT {
+            self.fixed_event(id, info)?
+        } else {
+            let len = self.u32()? as usize;
+            let end = self.pos + len;
+
+            if end > self.data.len() {
+                return Err(ParseError::UnexpectedEof);
+            }
+
+            let mut payload = Packet {
+                data: &self.data[..end],
+                pos: self.pos,
+            };
+
+            // A payload that does not match the expected format is not fatal, as the length
+            // is known
+            let kind = payload
+                .variable_event(id, info)
+                .unwrap_or_else(|_| EventKind::User {
+                    event: id,
+                    params: Vec::new(),
+                });
+
+            self.pos = end;
+
+            kind
+        };
+
+        let delta = self.u32()?;
+
+        Ok((delta, kind))
+    }
+
+    fn fixed_event(&mut self, id: u32, info: Option<SystemInfo>) -> Result<EventKind, ParseError> {
+        let expand = |id: u32| expand_id(id, info);
+
+        Ok(match id {
+            EVTID_OVERFLOW => EventKind::Overflow {
+                dropped: self.u32()?,
+            },
+            EVTID_ISR_ENTER => EventKind::IsrEnter { isr: self.u32()? },
+            EVTID_ISR_EXIT => EventKind::IsrExit,
+            EVTID_TASK_START_EXEC => EventKind::TaskStartExec {
+                task: expand(self.u32()?),
+            },
+            EVTID_TASK_STOP_EXEC => EventKind::TaskStopExec,
+            EVTID_TASK_START_READY => EventKind::TaskStartReady {
+                task: expand(self.u32()?),
+            },
+            EVTID_TASK_STOP_READY => EventKind::TaskStopReady {
+                task: expand(self.u32()?),
+                cause: self.u32()?,
+            },
+            EVTID_TASK_CREATE => EventKind::TaskCreate {
+                task: expand(self.u32()?),
+            },
+            EVTID_TASK_INFO => EventKind::TaskInfo {
+                task: expand(self.u32()?),
+                priority: self.u32()?,
+                name: self.string()?,
+            },
+            EVTID_TRACE_START => EventKind::TraceStart,
+            EVTID_TRACE_STOP => EventKind::TraceStop,
+            EVTID_SYSTIME_CYCLES => EventKind::SystemTimeCycles(self.u32()?),
+            EVTID_SYSTIME_US => {
+                let low = self.u32()? as u64;
+                let high = self.u32()? as u64;
+
+                EventKind::SystemTimeUs(low | (high << 32))
+            }
+            EVTID_SYSDESC => EventKind::SystemDescription(self.string()?),
+            EVTID_MARK_START => EventKind::MarkStart {
+                marker: self.u32()?,
+            },
+            EVTID_MARK_STOP => EventKind::MarkStop {
+                marker: self.u32()?,
+            },
+            EVTID_IDLE => EventKind::Idle,
+            EVTID_ISR_TO_SCHEDULER => EventKind::IsrToScheduler,
+            EVTID_TIMER_ENTER => EventKind::TimerEnter {
+                timer: expand(self.u32()?),
+            },
+            EVTID_TIMER_EXIT => EventKind::TimerExit,
+            EVTID_STACK_INFO => EventKind::StackInfo {
+                task: expand(self.u32()?),
+                base: self.u32()?,
+                size: self.u32()?,
+                usage: self.u32()?,
+            },
+            EVTID_MODULEDESC => EventKind::ModuleDescription {
+                module: self.u32()?,
+                event_offset: self.u32()?,
+                description: self.string()?,
+            },
+            _ => return Err(ParseError::Malformed(format!("Unknown event ID {}.", id))),
+        })
+    }
+
+    fn variable_event(
+        &mut self,
+        id: u32,
+        info: Option<SystemInfo>,
+    ) -> Result<EventKind, ParseError> {
+        Ok(match id {
+            EVTID_INIT => EventKind::Init(SystemInfo {
+                timestamp_frequency: self.u32()?,
+                cpu_frequency: self.u32()?,
+                ram_base: self.u32()?,
+                id_shift: self.u32()?,
+            }),
+            EVTID_NAME_RESOURCE => EventKind::NameResource {
+                resource: expand_id(self.u32()?, info),
+                name: self.string()?,
+            },
+            EVTID_PRINT_FORMATTED => {
+                let format = self.string()?;
+                let options = self.u32()?;
+                let count = self.u32()?;
+
+                let args = (0..count)
+                    .map(|_| self.u32())
+                    .collect::<Result<Vec<u32>, ParseError>>()?;
+
+                let level = match options & 0x3 {
+                    1 => PrintLevel::Warning,
+                    2 => PrintLevel::Error,
+                    _ => PrintLevel::Log,
+                };
+
+                EventKind::Print {
+                    level,
+                    message: format_message(&format, &args),
+                }
+            }
+            EVTID_NUMMODULES => EventKind::ModuleCount(self.u32()?),
+            EVTID_END_CALL => EventKind::EndCall {
+                event: self.u32()?,
+                result: self.params()?.first().copied(),
+            },
+            EVTID_TASK_TERMINATE => EventKind::TaskTerminate {
+                task: expand_id(self.u32()?, info),
+            },
+            EVTID_EX => match self.u32()? {
+                EVTID_EX_MARK => EventKind::Mark {
+                    marker: self.u32()?,
+                },
+                EVTID_EX_NAME_MARKER => EventKind::NameMarker {
+                    marker: self.u32()?,
+                    name: self.string()?,
+                },
+                _ => EventKind::User {
+                    event: id,
+                    params: self.params()?,
+                },
+            },
+            _ => EventKind::User {
+                event: id,
+                params: self.params()?,
+            },
+        })
+    }
+
+    /// Reads variable length encoded values until the end of the payload.
+    fn params(&mut self) -> Result<Vec<u32>, ParseError> {
+        let mut params = Vec::new();
+
+        while self.pos < self.data.len() {
+            params.push(self.u32()?);
+        }
+
+        Ok(params)
+    }
+}
+
+/// Restores an ID shrunk by the target.
+fn expand_id(id: u32, info: Option<SystemInfo>) -> u32 {
+    match info {
+        Some(info) => (id << info.id_shift).wrapping_add(info.ram_base),
+        None => id,
+    }
+}
+
+/// Formats a message sent by `SEGGER_SYSVIEW_PrintfHost` and friends. The target sends the format
+/// string and the arguments as 32-bit values, so only integer and character conversions are
+/// supported.
+fn format_message(format: &str, args: &[u32]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter().copied();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let mut left = false;
+        let mut zero = false;
+        let mut width = 0;
+
+        loop {
+            match chars.peek() {
+                Some('-') => left = true,
+                Some('0') => zero = true,
+                Some('+') | Some(' ') | Some('#') => {}
+                _ => break,
+            }
+
+            chars.next();
+        }
+
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            width = width * 10 + digit as usize;
+            chars.next();
+        }
+
+        // Length modifiers make no difference with 32-bit arguments
+        while let Some('l') | Some('h') = chars.peek() {
+            chars.next();
+        }
+
+        let value = match chars.next() {
+            Some('%') => {
+                out.push('%');
+                continue;
+            }
+            Some(conv) => match (conv, args.next()) {
+                ('d', Some(arg)) | ('i', Some(arg)) => (arg as i32).to_string(),
+                ('u', Some(arg)) => arg.to_string(),
+                ('x', Some(arg)) => format!("{:x}", arg),
+                ('X', Some(arg)) => format!("{:X}", arg),
+                ('p', Some(arg)) => format!("0x{:08x}", arg),
+                ('c', Some(arg)) => std::char::from_u32(arg).unwrap_or('?').to_string(),
+                (conv, _) => format!("%{}", conv),
+            },
+            None => {
+                out.push('%');
+                break;
+            }
+        };
+
+        let pad = width.saturating_sub(value.chars().count());
+
+        if left {
+            out += &value;
+            out += &" ".repeat(pad);
+        } else if zero && value.starts_with('-') {
+            out.push('-');
+            out += &"0".repeat(pad);
+            out += &value[1..];
+        } else {
+            out += &(if zero { "0" } else { " " }).repeat(pad);
+            out += &value;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A short recording of a target with one task and an interrupt.
+    #[rustfmt::skip]
+    pub(super) const RECORDING: &[u8] = &[
+        // Synchronization
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // Init: 1 MHz timestamps, 64 MHz CPU, RAM at 0x20000000, IDs shifted by 2
+        0x18, 0x0d, 0xc0, 0x84, 0x3d, 0x80, 0xa0, 0xc2, 0x1e, 0x80, 0x80, 0x80, 0x80, 0x02, 0x02,
+        0x00,
+        // System description "N=App,I#15=SysTick"
+        0x0e, 0x12, 0x4e, 0x3d, 0x41, 0x70, 0x70, 0x2c, 0x49, 0x23, 0x31, 0x35, 0x3d, 0x53, 0x79,
+        0x73, 0x54, 0x69, 0x63, 0x6b, 0x00,
+        // Task info: task 0x40, priority 3, "main"
+        0x09, 0x40, 0x03, 0x04, 0x6d, 0x61, 0x69, 0x6e, 0x00,
+        // Task 0x40 start exec, 100 cycles later
+        0x04, 0x40, 0x64,
+        // ISR 15 enter
+        0x02, 0x0f, 0x32,
+        // ISR exit
+        0x03, 0x0a,
+        // Warning "x=%d" with -1
+        0x1a, 0x0c, 0x04, 0x78, 0x3d, 0x25, 0x64, 0x01, 0x01, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x05,
+        // Task stop exec
+        0x05, 0x14,
+        // Idle
+        0x11, 0x00,
+        // User event 40 with 1 and 300
+        0x28, 0x03, 0x01, 0xac, 0x02, 0x0f,
+    ];
+
+    pub(super) fn decode_all(decoder: &mut Decoder) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        while let Some(event) = decoder.next_event().unwrap() {
+            events.push(event);
+        }
+
+        events
+    }
+
+    const INFO: SystemInfo = SystemInfo {
+        timestamp_frequency: 1_000_000,
+        cpu_frequency: 64_000_000,
+        ram_base: 0x2000_0000,
+        id_shift: 2,
+    };
+
+    fn expected() -> Vec<(u64, EventKind)> {
+        vec![
+            (0, EventKind::Init(INFO)),
+            (
+                0,
+                EventKind::SystemDescription("N=App,I#15=SysTick".to_string()),
+            ),
+            (
+                0,
+                EventKind::TaskInfo {
+                    task: 0x2000_0100,
+                    priority: 3,
+                    name: "main".to_string(),
+                },
+            ),
+            (100, EventKind::TaskStartExec { task: 0x2000_0100 }),
+            (150, EventKind::IsrEnter { isr: 15 }),
+            (160, EventKind::IsrExit),
+            (
+                165,
+                EventKind::Print {
+                    level: PrintLevel::Warning,
+                    message: "x=-1".to_string(),
+                },
+            ),
+            (185, EventKind::TaskStopExec),
+            (185, EventKind::Idle),
+            (
+                200,
+                EventKind::User {
+                    event: 40,
+                    params: vec![1, 300],
+                },
+            ),
+        ]
+    }
+
+    fn kinds(events: &[Event]) -> Vec<(u64, EventKind)> {
+        events
+            .iter()
+            .map(|event| (event.timestamp, event.kind.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn decode_recording() {
+        let mut decoder = Decoder::new();
+        decoder.received(RECORDING);
+
+        let events = decode_all(&mut decoder);
+
+        assert_eq!(kinds(&events), expected());
+        assert_eq!(decoder.system_info(), Some(INFO));
+        assert_eq!(events[3].seconds, Some(0.0001));
+        assert_eq!(
+            events[3].to_string(),
+            "[0.000100] Task 0x20000100 start exec"
+        );
+    }
+
+    #[test]
+    fn decode_recording_byte_by_byte() {
+        let mut decoder = Decoder::new();
+        let mut events = Vec::new();
+
+        for &b in RECORDING {
+            decoder.received(&[b]);
+            events.extend(decode_all(&mut decoder));
+        }
+
+        assert_eq!(kinds(&events), expected());
+    }
+
+    #[test]
+    fn ids_before_init() {
+        let mut decoder = Decoder::new();
+        decoder.received(&[0x04, 0x40, 0x64]);
+
+        let events = decode_all(&mut decoder);
+
+        assert_eq!(events[0].kind, EventKind::TaskStartExec { task: 0x40 });
+        assert_eq!(events[0].seconds, None);
+        assert_eq!(events[0].to_string(), "[100] Task 0x00000040 start exec");
+    }
+
+    #[test]
+    fn fixed_events() {
+        let mut decoder = Decoder::new();
+        decoder.received(&[
+            // Overflow, 300 dropped
+            0x01, 0xac, 0x02, 0x00, // System time 0x100000001 us
+            0x0d, 0x01, 0x01, 0x00, // Task 5 blocked, cause 2
+            0x07, 0x05, 0x02, 0x00, // Stack info
+            0x15, 0x05, 0x10, 0x80, 0x01, 0x20, 0x00, // Marker 7 start and stop
+            0x0f, 0x07, 0x00, 0x10, 0x07, 0x00,
+        ]);
+
+        assert_eq!(
+            decode_all(&mut decoder)
+                .into_iter()
+                .map(|event| event.kind)
+                .collect::<Vec<_>>(),
+            vec![
+                EventKind::Overflow { dropped: 300 },
+                EventKind::SystemTimeUs(0x1_0000_0001),
+                EventKind::TaskStopReady { task: 5, cause: 2 },
+                EventKind::StackInfo {
+                    task: 5,
+                    base: 0x10,
+                    size: 0x80,
+                    usage: 0x20,
+                },
+                EventKind::MarkStart { marker: 7 },
+                EventKind::MarkStop { marker: 7 },
+            ]
+        );
+    }
+
+    #[test]
+    fn variable_events() {
+        // Resource 5 with a 300 byte name, which needs a 304 byte payload
+        let mut name = vec![0x19, 0xb0, 0x02, 0x05, 0xff, 0x2c, 0x01];
+        name.extend_from_slice(&[b'a'; 300]);
+        name.push(0x00);
+
+        let mut decoder = Decoder::new();
+        decoder.received(&[
+            // Mark 3, name marker 3 "m"
+            0x1f, 0x02, 0x00, 0x03, 0x00, 0x1f, 0x04, 0x01, 0x03, 0x01, b'm', 0x00,
+            // End call 40 with result 1, without result
+            0x1c, 0x02, 0x28, 0x01, 0x00, 0x1c, 0x01, 0x28, 0x00,
+            // Init with a truncated payload
+            0x18, 0x01, 0x80, 0x00,
+        ]);
+        decoder.received(&name[..]);
+
+        let kinds: Vec<EventKind> = decode_all(&mut decoder)
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Mark { marker: 3 },
+                EventKind::NameMarker {
+                    marker: 3,
+                    name: "m".to_string(),
+                },
+                EventKind::EndCall {
+                    event: 40,
+                    result: Some(1),
+                },
+                EventKind::EndCall {
+                    event: 40,
+                    result: None,
+                },
+                // Payloads that don't match are kept as the length is known
+                EventKind::User {
+                    event: 24,
+                    params: Vec::new(),
+                },
+                EventKind::NameResource {
+                    resource: 5,
+                    name: "a".repeat(300),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let mut decoder = Decoder::new();
+
+        // Unknown fixed event, and the rest of the buffer is dropped
+        decoder.received(&[0x17, 0x00, 0x11, 0x00]);
+        assert!(decoder.next_event().is_err());
+        assert_eq!(decoder.next_event().unwrap(), None);
+
+        // Variable length value that is too long
+        decoder.received(&[0x02, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+        assert!(decoder.next_event().is_err());
+
+        decoder.received(&[0x11, 0x05]);
+        assert_eq!(decoder.next_event().unwrap().unwrap().kind, EventKind::Idle);
+    }
+
+    #[test]
+    fn printf() {
+        assert_eq!(
+            format_message(
+                "%d|%5d|%-4u|%04x|%X|%p|%c|%%|%ld|%05d",
+                &[
+                    -3i32 as u32,
+                    42,
+                    7,
+                    0xab,
+                    0xab,
+                    0x100,
+                    'z' as u32,
+                    9,
+                    -5i32 as u32
+                ]
+            ),
+            "-3|   42|7   |00ab|AB|0x00000100|z|%|9|-0005"
+        );
+
+        // Missing arguments and unsupported conversions are left as is
+        assert_eq!(format_message("%d %s %", &[]), "%d %s %");
+    }
+}
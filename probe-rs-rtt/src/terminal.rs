@@ -0,0 +1,267 @@
+//! SEGGER virtual terminal demultiplexing.
+//!
+//! The SEGGER RTT target library can multiplex up to 16 virtual terminals on up channel 0 with
+//! `SEGGER_RTT_SetTerminal` and `SEGGER_RTT_TerminalOut`. Switching terminals is signaled in the
+//! data stream by the byte `0xFF` followed by the terminal ID as an ASCII character (`'0'`-`'9'`,
+//! `'A'`-`'F'`). Output goes to terminal 0 until the first switch.
+//!
+//! [`VirtualTerminals`] splits a channel into a separate [`VirtualTerminal`] stream for each
+//! terminal.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use probe_rs::Probe;
+//! use probe_rs_rtt::{Rtt, VirtualTerminals};
+//! use std::io::Read;
+//!
+//! let probe = Probe::list_all()[0].open()?;
+//! let session = probe.attach("somechip")?;
+//! let core = session.attach_to_core(0)?;
+//! let mut rtt = Rtt::attach(core, &session)?;
+//!
+//! let terminals = VirtualTerminals::new(rtt.up_channels().take(0).unwrap());
+//! let mut terminal = terminals.terminal(1).unwrap();
+//!
+//! let mut buf = [0u8; 1024];
+//! let count = terminal.read(&mut buf[..])?;
+//!
+//! println!("Terminal 1: {:?}", &buf[..count]);
+//!
+//! # Ok::<(), Box<std::error::Error>>(())
+//! ```
+
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+use crate::UpChannel;
+
+/// Number of virtual terminals supported by the protocol.
+pub const NUM_TERMINALS: u8 = 16;
+
+const ESCAPE: u8 = 0xff;
+
+/// Demultiplexer that splits a channel into virtual terminals.
+///
+/// Data is only buffered for terminals that currently have an open [`VirtualTerminal`] handle.
+/// Data for other terminals is discarded, but the terminals are still reported by
+/// [`terminals`](VirtualTerminals::terminals).
+pub struct VirtualTerminals<R: Read = UpChannel> {
+    demux: Rc<RefCell<Demux<R>>>,
+}
+
+/// A single virtual terminal stream obtained from [`VirtualTerminals::terminal`].
+///
+/// Reading from a terminal reads any new data from the underlying channel and returns the data
+/// for this terminal. Dropping the handle closes the terminal so that it can be opened again.
+pub struct VirtualTerminal<R: Read = UpChannel> {
+    demux: Rc<RefCell<Demux<R>>>,
+    id: u8,
+}
+
+struct Demux<R> {
+    inner: R,
+    read_buf: Box<[u8]>,
+    current: u8,
+    escape: bool,
+    seen: u16,
+    buffers: Vec<Option<Vec<u8>>>,
+}
+
+impl<R: Read> VirtualTerminals<R> {
+    /// Creates a new demultiplexer for the channel.
+    pub fn new(inner: R) -> Self {
+        VirtualTerminals {
+            demux: Rc::new(RefCell::new(Demux {
+                inner,
+                read_buf: vec![0u8; 1024].into_boxed_slice(),
+                current: 0,
+                escape: false,
+                seen: 1,
+                buffers: vec![None; NUM_TERMINALS as usize],
+            })),
+        }
+    }
+
+    /// Opens the terminal with the specified ID. Returns `None` if the ID is out of range or the
+    /// terminal is already open.
+    pub fn terminal(&self, id: u8) -> Option<VirtualTerminal<R>> {
+        let mut demux = self.demux.borrow_mut();
+        let buffer = demux.buffers.get_mut(id as usize)?;
+
+        if buffer.is_some() {
+            return None;
+        }
+
+        *buffer = Some(Vec::new());
+
+        Some(VirtualTerminal {
+            demux: self.demux.clone(),
+            id,
+        })
+    }
+
+    /// Reads any new data from the underlying channel and distributes it to the open terminals.
+    /// Returns the number of bytes read.
+    pub fn poll(&self) -> io::Result<usize> {
+        self.demux.borrow_mut().poll()
+    }
+
+    /// Returns the IDs of the terminals that have been switched to so far. Terminal 0 is always
+    /// included.
+    pub fn terminals(&self) -> Vec<u8> {
+        let seen = self.demux.borrow().seen;
+
+        (0..NUM_TERMINALS)
+            .filter(|id| seen & (1 << id) != 0)
+            .collect()
+    }
+
+    /// Returns the ID of the terminal that output currently goes to.
+    pub fn current(&self) -> u8 {
+        self.demux.borrow().current
+    }
+}
+
+impl<R: Read> Clone for VirtualTerminals<R> {
+    fn clone(&self) -> Self {
+        VirtualTerminals {
+            demux: self.demux.clone(),
+        }
+    }
+}
+
+impl<R: Read> VirtualTerminal<R> {
+    /// Returns the ID of the terminal.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+}
+
+impl<R: Read> Read for VirtualTerminal<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut demux = self.demux.borrow_mut();
+
+        demux.poll()?;
+
+        let buffer = demux.buffers[self.id as usize].as_mut().unwrap();
+        let count = buffer.len().min(buf.len());
+
+        buf[..count].copy_from_slice(&buffer[..count]);
+        buffer.drain(..count);
+
+        Ok(count)
+    }
+}
+
+impl<R: Read> Drop for VirtualTerminal<R> {
+    fn drop(&mut self) {
+        self.demux.borrow_mut().buffers[self.id as usize] = None;
+    }
+}
+
+impl<R: Read> Demux<R> {
+    fn poll(&mut self) -> io::Result<usize> {
+        let count = self.inner.read(&mut self.read_buf)?;
+
+        for i in 0..count {
+            let b = self.read_buf[i];
+
+            if self.escape {
+                self.escape = false;
+
+                match terminal_id(b) {
+                    Some(id) => {
+                        self.current = id;
+                        self.seen |= 1 << id;
+                    }
+                    None => {
+                        // Not a terminal switch, pass both bytes through
+                        self.push(ESCAPE);
+                        self.push(b);
+                    }
+                }
+            } else if b == ESCAPE {
+                self.escape = true;
+            } else {
+                self.push(b);
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn push(&mut self, b: u8) {
+        if let Some(buffer) = self.buffers[self.current as usize].as_mut() {
+            buffer.push(b);
+        }
+    }
+}
+
+fn terminal_id(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ScriptedReader;
+
+    fn terminals(chunks: &[&[u8]]) -> VirtualTerminals<ScriptedReader> {
+        VirtualTerminals::new(ScriptedReader::new(chunks))
+    }
+
+    fn read_all(terminal: &mut VirtualTerminal<ScriptedReader>) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let count = terminal.read(&mut buf).unwrap();
+        buf[..count].to_vec()
+    }
+
+    #[test]
+    fn switch_split_between_reads() {
+        let terminals = terminals(&[b"zero\xff", b"1one\xff", b"0back\xffF", b"last"]);
+        let mut zero = terminals.terminal(0).unwrap();
+        let mut one = terminals.terminal(1).unwrap();
+
+        while terminals.poll().unwrap() > 0 {}
+
+        assert_eq!(read_all(&mut zero), b"zeroback");
+        assert_eq!(read_all(&mut one), b"one");
+        assert_eq!(terminals.current(), 15);
+        assert_eq!(terminals.terminals(), [0, 1, 15]);
+    }
+
+    #[test]
+    fn escape_without_switch_passes_through() {
+        let terminals = terminals(&[b"a\xff", b"Gb\xff\xff3c"]);
+        let mut zero = terminals.terminal(0).unwrap();
+        let mut three = terminals.terminal(3).unwrap();
+
+        assert_eq!(read_all(&mut zero), b"a");
+        // The byte after an escape is never a switch itself
+        assert_eq!(read_all(&mut zero), b"\xffGb\xff\xff3c");
+        assert_eq!(read_all(&mut three), b"");
+        assert_eq!(terminals.terminals(), [0]);
+    }
+
+    #[test]
+    fn closed_terminals_discard_data() {
+        let terminals = terminals(&[b"\xff2dropped", b"kept"]);
+
+        let two = terminals.terminal(2).unwrap();
+        assert!(terminals.terminal(2).is_none());
+        assert!(terminals.terminal(NUM_TERMINALS).is_none());
+        drop(two);
+
+        terminals.poll().unwrap();
+
+        let mut two = terminals.terminal(2).unwrap();
+        assert_eq!(two.id(), 2);
+        assert_eq!(read_all(&mut two), b"kept");
+    }
+}
@@ -0,0 +1,85 @@
+//! Helpers shared by the unit tests.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Reader that returns scripted chunks of data, one chunk per read, like a channel that is read
+/// while the target is still writing. Returns no data once all the chunks have been read.
+pub(crate) struct ScriptedReader {
+    chunks: VecDeque<Vec<u8>>,
+}
+
+impl ScriptedReader {
+    /// Creates a reader that returns the chunks in order.
+    pub fn new(chunks: &[&[u8]]) -> Self {
+        ScriptedReader {
+            chunks: chunks.iter().map(|chunk| chunk.to_vec()).collect(),
+        }
+    }
+
+    /// Creates a reader that returns the data in chunks of `size` bytes.
+    pub fn split(data: &[u8], size: usize) -> Self {
+        ScriptedReader {
+            chunks: data.chunks(size).map(|chunk| chunk.to_vec()).collect(),
+        }
+    }
+
+    /// Queues a chunk to be returned after the ones already queued.
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.chunks.push_back(chunk.to_vec());
+    }
+}
+
+impl Read for ScriptedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = match self.chunks.pop_front() {
+            Some(chunk) => chunk,
+            None => return Ok(0),
+        };
+
+        // A chunk that does not fit is returned over multiple reads
+        if chunk.len() > buf.len() {
+            self.chunks.push_front(chunk.split_off(buf.len()));
+        }
+
+        buf[..chunk.len()].copy_from_slice(&chunk);
+
+        Ok(chunk.len())
+    }
+}
+
+/// Directory under the system temporary directory that is removed when dropped.
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a new empty directory with a name that is unique within the test run.
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "probe-rs-rtt-test-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        fs::create_dir_all(&path).unwrap();
+
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
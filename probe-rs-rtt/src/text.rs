@@ -0,0 +1,191 @@
+//! Streaming text decoding.
+//!
+//! Data read from a channel is split into chunks arbitrarily, so a multi-byte UTF-8 character may
+//! be split between two reads. [`TextDecoder`] buffers incomplete sequences between calls so that
+//! they are decoded correctly once the rest of the character arrives.
+//!
+//! ## Example
+//!
+//! ```
+//! use probe_rs_rtt::{DecodeFallback, TextDecoder};
+//!
+//! let mut decoder = TextDecoder::new(DecodeFallback::Escape);
+//!
+//! // "ä" is split between two chunks
+//! assert_eq!(decoder.decode(b"\xc3"), "");
+//! assert_eq!(decoder.decode(b"\xa4 \xff"), "ä \\xff");
+//! ```
+
+use std::str;
+
+/// Specifies how [`TextDecoder`] handles input that is not valid UTF-8.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DecodeFallback {
+    /// Replace each invalid sequence with the replacement character `U+FFFD`.
+    #[default]
+    Replace,
+
+    /// Escape each invalid byte as `\xNN`.
+    Escape,
+
+    /// Decode each invalid byte as Latin-1 (ISO 8859-1).
+    Latin1,
+}
+
+impl str::FromStr for DecodeFallback {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<DecodeFallback, &'static str> {
+        match s {
+            "replace" => Ok(DecodeFallback::Replace),
+            "escape" => Ok(DecodeFallback::Escape),
+            "latin1" => Ok(DecodeFallback::Latin1),
+            _ => Err("Invalid decode mode. Valid modes are 'replace', 'escape' and 'latin1'."),
+        }
+    }
+}
+
+/// Streaming UTF-8 decoder that handles characters split between chunks.
+#[derive(Clone, Debug)]
+pub struct TextDecoder {
+    fallback: DecodeFallback,
+    pending: Vec<u8>,
+}
+
+impl TextDecoder {
+    /// Creates a new decoder that handles invalid input as specified.
+    pub fn new(fallback: DecodeFallback) -> Self {
+        TextDecoder {
+            fallback,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Decodes a chunk of data. An incomplete sequence at the end of the chunk is kept and decoded
+    /// along with the next chunk.
+    pub fn decode(&mut self, data: &[u8]) -> String {
+        let mut out = String::new();
+        self.decode_to(data, &mut out);
+        out
+    }
+
+    /// Decodes a chunk of data and appends the result to `out`. See
+    /// [`decode`](TextDecoder::decode).
+    pub fn decode_to(&mut self, data: &[u8], out: &mut String) {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(data);
+
+        let mut rest = &input[..];
+
+        loop {
+            match str::from_utf8(rest) {
+                Ok(valid) => {
+                    out.push_str(valid);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, after_valid) = rest.split_at(err.valid_up_to());
+                    out.push_str(str::from_utf8(valid).unwrap());
+
+                    match err.error_len() {
+                        Some(len) => {
+                            self.push_invalid(&after_valid[..len], out);
+                            rest = &after_valid[len..];
+                        }
+                        None => {
+                            // Incomplete sequence at the end of the input
+                            self.pending.extend_from_slice(after_valid);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decodes any buffered incomplete sequence as invalid input. Call this when no more data is
+    /// expected to complete it, e.g. at the end of a line or stream.
+    pub fn finish(&mut self) -> String {
+        let pending = std::mem::take(&mut self.pending);
+        let mut out = String::new();
+
+        if !pending.is_empty() {
+            self.push_invalid(&pending, &mut out);
+        }
+
+        out
+    }
+
+    fn push_invalid(&self, bytes: &[u8], out: &mut String) {
+        match self.fallback {
+            DecodeFallback::Replace => out.push(char::REPLACEMENT_CHARACTER),
+            DecodeFallback::Escape => {
+                for b in bytes {
+                    out.push_str(&format!("\\x{:02x}", b));
+                }
+            }
+            DecodeFallback::Latin1 => out.extend(bytes.iter().map(|&b| char::from(b))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_split(fallback: DecodeFallback, data: &[u8], at: usize) -> String {
+        let mut decoder = TextDecoder::new(fallback);
+        let mut out = decoder.decode(&data[..at]);
+        decoder.decode_to(&data[at..], &mut out);
+        out.push_str(&decoder.finish());
+        out
+    }
+
+    #[test]
+    fn characters_split_between_chunks() {
+        let text = "a ä € 𝄞 z";
+
+        for at in 0..=text.len() {
+            assert_eq!(
+                decode_split(DecodeFallback::Replace, text.as_bytes(), at),
+                text,
+                "split at {}",
+                at
+            );
+        }
+    }
+
+    #[test]
+    fn one_byte_at_a_time() {
+        let mut decoder = TextDecoder::new(DecodeFallback::Escape);
+        let out: String = "€𝄞".bytes().map(|b| decoder.decode(&[b])).collect();
+
+        assert_eq!(out, "€𝄞");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn fallbacks() {
+        let data = b"a\xff\xe2\x82b\xe4";
+
+        for &(fallback, expected) in &[
+            (DecodeFallback::Replace, "a\u{fffd}\u{fffd}b\u{fffd}"),
+            (DecodeFallback::Escape, "a\\xff\\xe2\\x82b\\xe4"),
+            (DecodeFallback::Latin1, "a\u{ff}\u{e2}\u{82}b\u{e4}"),
+        ] {
+            for at in 0..=data.len() {
+                assert_eq!(decode_split(fallback, data, at), expected, "{:?}", fallback);
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_sequence_is_kept_until_finish() {
+        let mut decoder = TextDecoder::new(DecodeFallback::Escape);
+
+        assert_eq!(decoder.decode(b"ok\xf0\x9d"), "ok");
+        assert_eq!(decoder.finish(), "\\xf0\\x9d");
+        assert_eq!(decoder.finish(), "");
+        assert_eq!(decoder.decode(b"\x84\x9e"), "\\x84\\x9e");
+    }
+}
@@ -0,0 +1,374 @@
+//! Conditions for exiting when running unattended, e.g. in CI.
+
+use probe_rs_rtt::expect::Regex;
+use std::time::{Duration, Instant};
+
+/// Exit code when a timeout passes, same as with the `timeout` command.
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Pattern of the line firmware prints to exit with a code.
+const MARKER_PATTERN: &str = r"^\s*EXIT:(-?\d+)\s*$";
+
+/// Why and with which code to exit.
+pub struct Exit {
+    pub code: i32,
+    pub reason: String,
+}
+
+/// Checks the output and the time passed against the exit conditions.
+pub struct ExitConditions {
+    patterns: Vec<Regex>,
+    pattern_code: i32,
+    marker: Option<Regex>,
+    timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_bytes: Option<u64>,
+    start: Instant,
+    last_data: Instant,
+    bytes: u64,
+}
+
+impl ExitConditions {
+    pub fn new() -> Self {
+        ExitConditions {
+            patterns: Vec::new(),
+            pattern_code: 0,
+            marker: None,
+            timeout: None,
+            idle_timeout: None,
+            max_bytes: None,
+            start: Instant::now(),
+            last_data: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Exits with the code when a line of output matches one of the patterns. Partial lines are
+    /// matched as well once no more data arrives for them, so that e.g. a prompt can be waited
+    /// for.
+    pub fn patterns(mut self, patterns: Vec<Regex>, code: i32) -> Self {
+        self.patterns = patterns;
+        self.pattern_code = code;
+        self
+    }
+
+    /// Exits with the code given by the firmware in an `EXIT:<code>` line.
+    pub fn marker(mut self, marker: bool) -> Self {
+        self.marker = if marker {
+            Some(Regex::new(MARKER_PATTERN).unwrap())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Exits with [`TIMEOUT_EXIT_CODE`] once the time has passed.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Exits with [`TIMEOUT_EXIT_CODE`] once no data has been received for the time.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Exits with 0 once the number of bytes has been received.
+    pub fn max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Returns whether there are any conditions.
+    pub fn is_active(&self) -> bool {
+        self.checks_lines()
+            || self.timeout.is_some()
+            || self.idle_timeout.is_some()
+            || self.max_bytes.is_some()
+    }
+
+    /// Returns whether lines of output need to be checked.
+    pub fn checks_lines(&self) -> bool {
+        !self.patterns.is_empty() || self.marker.is_some()
+    }
+
+    /// Restarts the idle timeout, so that e.g. the time taken to attach is not counted.
+    pub fn reset_idle(&mut self) {
+        self.last_data = Instant::now();
+    }
+
+    /// Counts data received from a source.
+    pub fn received(&mut self, count: usize) -> Option<Exit> {
+        if count == 0 {
+            return None;
+        }
+
+        self.bytes += count as u64;
+        self.last_data = Instant::now();
+
+        match self.max_bytes {
+            Some(max) if self.bytes >= max => Some(Exit {
+                code: 0,
+                reason: format!("received {} bytes", self.bytes),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Checks a line of output, without the line terminator. Lines that are not complete yet are
+    /// only checked against the patterns, and should only be checked once no more data arrives for
+    /// them, as anchors match their end.
+    pub fn check_line(&self, line: &[u8], complete: bool) -> Option<Exit> {
+        if let Some(pattern) = self.patterns.iter().find(|p| p.is_match(line)) {
+            return Some(Exit {
+                code: self.pattern_code,
+                reason: format!("output matched /{}/", pattern),
+            });
+        }
+
+        let captures = match self.marker.as_ref() {
+            Some(marker) if complete => marker.captures(line)?,
+            _ => return None,
+        };
+
+        let code = std::str::from_utf8(&captures[1]).ok()?.parse().ok()?;
+
+        Some(Exit {
+            code,
+            reason: format!("target exited with code {}", code),
+        })
+    }
+
+    /// Checks the timeouts.
+    pub fn poll(&self) -> Option<Exit> {
+        if let Some(timeout) = self.timeout {
+            if self.start.elapsed() >= timeout {
+                return Some(Exit {
+                    code: TIMEOUT_EXIT_CODE,
+                    reason: format!("timed out after {}", humantime::format_duration(timeout)),
+                });
+            }
+        }
+
+        if let Some(timeout) = self.idle_timeout {
+            if self.last_data.elapsed() >= timeout {
+                return Some(Exit {
+                    code: TIMEOUT_EXIT_CODE,
+                    reason: format!(
+                        "no data received for {}",
+                        humantime::format_duration(timeout)
+                    ),
+                });
+            }
+        }
+
+        None
+    }
+}
+
+/// Tracks the current line of output from a source for checking it against the exit conditions.
+///
+/// Complete lines are checked as they arrive. A partial line is checked once no more data has
+/// arrived for the idle timeout, so that e.g. `PASS$` does not match the start of `PASSED`.
+pub struct ExitLine {
+    line: Vec<u8>,
+    idle_timeout: Duration,
+    last_data: Instant,
+    /// Whether the partial line has been checked since it last grew.
+    checked: bool,
+}
+
+impl ExitLine {
+    pub fn new(idle_timeout: Duration) -> Self {
+        ExitLine {
+            line: Vec::new(),
+            idle_timeout,
+            last_data: Instant::now(),
+            checked: true,
+        }
+    }
+
+    /// Adds output and checks each line it completes. Call this with empty output as well, to
+    /// check the partial line at the end once it has been idle for long enough.
+    pub fn output(&mut self, exit: &ExitConditions, data: &[u8]) -> Option<Exit> {
+        if !exit.checks_lines() {
+            return None;
+        }
+
+        for piece in data.split_inclusive(|&b| b == b'\n') {
+            self.line.extend_from_slice(piece);
+
+            if let Some(line) = self.line.strip_suffix(b"\n") {
+                let result = exit.check_line(line.strip_suffix(b"\r").unwrap_or(line), true);
+                self.line.clear();
+
+                if result.is_some() {
+                    return result;
+                }
+            }
+        }
+
+        if !data.is_empty() {
+            self.last_data = Instant::now();
+            self.checked = self.line.is_empty();
+        }
+
+        if !self.checked && self.last_data.elapsed() >= self.idle_timeout {
+            self.checked = true;
+            return exit.check_line(&self.line, false);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const IDLE: Duration = Duration::from_millis(20);
+
+    fn patterns(patterns: &[&str], code: i32) -> ExitConditions {
+        ExitConditions::new().patterns(
+            patterns.iter().map(|p| Regex::new(p).unwrap()).collect(),
+            code,
+        )
+    }
+
+    fn code(exit: Option<Exit>) -> Option<i32> {
+        exit.map(|exit| exit.code)
+    }
+
+    #[test]
+    fn inactive() {
+        let exit = ExitConditions::new();
+
+        assert!(!exit.is_active());
+        assert!(!exit.checks_lines());
+        assert!(code(exit.check_line(b"EXIT:1", true)).is_none());
+        assert!(code(ExitLine::new(IDLE).output(&exit, b"EXIT:1\n")).is_none());
+    }
+
+    #[test]
+    fn marker() {
+        let exit = ExitConditions::new().marker(true);
+
+        assert!(exit.is_active());
+        assert_eq!(code(exit.check_line(b"EXIT:0", true)), Some(0));
+        assert_eq!(code(exit.check_line(b"  EXIT:3 \t", true)), Some(3));
+        assert_eq!(code(exit.check_line(b"EXIT:-2", true)), Some(-2));
+
+        assert_eq!(code(exit.check_line(b"EXIT:", true)), None);
+        assert_eq!(code(exit.check_line(b"EXIT:x", true)), None);
+        assert_eq!(code(exit.check_line(b"EXIT:1 done", true)), None);
+        assert_eq!(code(exit.check_line(b"not EXIT:1", true)), None);
+        assert_eq!(code(exit.check_line(b"EXIT:99999999999", true)), None);
+
+        // A partial line may still continue
+        assert_eq!(code(exit.check_line(b"EXIT:1", false)), None);
+    }
+
+    #[test]
+    fn pattern_code() {
+        let exit = patterns(&["PANIC", "^FAIL$"], 2).marker(true);
+
+        assert_eq!(code(exit.check_line(b"a PANIC b", true)), Some(2));
+        assert_eq!(code(exit.check_line(b"FAIL", false)), Some(2));
+        assert_eq!(code(exit.check_line(b"FAILED", true)), None);
+
+        // Patterns are checked before the marker
+        let exit = patterns(&["EXIT"], 5).marker(true);
+        assert_eq!(code(exit.check_line(b"EXIT:1", true)), Some(5));
+    }
+
+    #[test]
+    fn lines_split_across_output() {
+        let exit = ExitConditions::new().marker(true);
+        let mut line = ExitLine::new(IDLE);
+
+        assert_eq!(code(line.output(&exit, b"log\nEX")), None);
+        assert_eq!(code(line.output(&exit, b"IT:")), None);
+        assert_eq!(code(line.output(&exit, b"-7\r\nmore")), Some(-7));
+    }
+
+    #[test]
+    fn partial_line_checked_when_idle() {
+        let exit = patterns(&["PASS$"], 0);
+        let mut line = ExitLine::new(IDLE);
+
+        // Not checked while more of the line may still arrive
+        assert_eq!(code(line.output(&exit, b"PASS")), None);
+        assert_eq!(code(line.output(&exit, b"")), None);
+        assert_eq!(code(line.output(&exit, b"ED\n")), None);
+
+        assert_eq!(code(line.output(&exit, b"> PASS")), None);
+        thread::sleep(IDLE);
+        assert_eq!(code(line.output(&exit, b"")), Some(0));
+
+        // Only checked once
+        assert_eq!(code(line.output(&exit, b"")), None);
+    }
+
+    #[test]
+    fn partial_line_checked_again_after_growing() {
+        let exit = patterns(&["^> $"], 0);
+        let mut line = ExitLine::new(IDLE);
+
+        line.output(&exit, b">");
+        thread::sleep(IDLE);
+        assert_eq!(code(line.output(&exit, b"")), None);
+
+        line.output(&exit, b" ");
+        thread::sleep(IDLE);
+        assert_eq!(code(line.output(&exit, b"")), Some(0));
+    }
+
+    #[test]
+    fn max_bytes() {
+        let mut exit = ExitConditions::new().max_bytes(Some(10));
+
+        assert!(exit.is_active());
+        assert!(!exit.checks_lines());
+        assert_eq!(code(exit.received(0)), None);
+        assert_eq!(code(exit.received(6)), None);
+
+        let exited = exit.received(4).unwrap();
+        assert_eq!(exited.code, 0);
+        assert_eq!(exited.reason, "received 10 bytes");
+    }
+
+    #[test]
+    fn timeout() {
+        let exit = ExitConditions::new().timeout(Some(IDLE));
+
+        assert!(exit.is_active());
+        assert!(exit.poll().is_none());
+
+        thread::sleep(IDLE);
+        let exited = exit.poll().unwrap();
+        assert_eq!(exited.code, TIMEOUT_EXIT_CODE);
+        assert_eq!(exited.reason, "timed out after 20ms");
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let mut exit = ExitConditions::new().idle_timeout(Some(IDLE * 4));
+
+        thread::sleep(IDLE * 2);
+        exit.received(1);
+        thread::sleep(IDLE * 2);
+        assert!(exit.poll().is_none());
+
+        // No data received does not count
+        exit.received(0);
+        thread::sleep(IDLE * 2);
+        let exited = exit.poll().unwrap();
+        assert_eq!(exited.code, TIMEOUT_EXIT_CODE);
+        assert_eq!(exited.reason, "no data received for 80ms");
+
+        exit.reset_idle();
+        assert!(exit.poll().is_none());
+    }
+}
@@ -0,0 +1,148 @@
+//! Records for the JSON lines output format.
+//!
+//! Each record is a JSON object on a line of its own, with a `type` field telling what the record
+//! is about and a `time` field with the host time in RFC 3339 format:
+//!
+//! - `probes`: the available probes.
+//! - `attach`: a control block was found, with the channels on it.
+//! - `data`: a line of output from an up channel, as `text` if it is valid UTF-8 or base64 encoded
+//!   `data` otherwise. `complete` is `false` for a partial line output after an idle timeout.
+//! - `reset`: the target initialized an up channel again, most likely because it was reset.
+//! - `overflow`: an up channel in a non-blocking mode was found full, so data may have been lost.
+//! - `error`: an error occurred, with the `message` also printed to stderr.
+//! - `exit`: an exit condition was met, with the exit `code` and the `reason`.
+
+use probe_rs::DebugProbeInfo;
+use probe_rs_rtt::{Channels, Line, Rtt, RttChannel};
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// The channel, or virtual terminal on a channel, that a record is about.
+pub struct Source {
+    pub core: usize,
+    pub channel: usize,
+    pub name: Option<String>,
+    pub terminal: Option<u8>,
+}
+
+impl Source {
+    fn record(&self, kind: &str, time: SystemTime) -> Map<String, Value> {
+        let mut record = new_record(kind, time);
+
+        record.insert("core".into(), json!(self.core));
+        record.insert("channel".into(), json!(self.channel));
+        record.insert("name".into(), json!(self.name));
+
+        if let Some(terminal) = self.terminal {
+            record.insert("terminal".into(), json!(terminal));
+        }
+
+        record
+    }
+}
+
+/// Writes a record to stdout.
+pub fn print(record: &Value) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    writeln!(stdout, "{}", record)?;
+    stdout.flush()
+}
+
+pub fn probes(probes: &[DebugProbeInfo]) -> Value {
+    let mut record = new_record("probes", SystemTime::now());
+
+    let probes = probes
+        .iter()
+        .enumerate()
+        .map(|(number, probe)| {
+            json!({
+                "number": number,
+                "identifier": probe.identifier,
+                "vendor_id": probe.vendor_id,
+                "product_id": probe.product_id,
+                "serial_number": probe.serial_number,
+            })
+        })
+        .collect();
+
+    record.insert("probes".into(), Value::Array(probes));
+
+    Value::Object(record)
+}
+
+/// Describes the control block and the channels that have not been taken from it yet.
+pub fn attach(core: usize, rtt: &mut Rtt) -> Value {
+    let mut record = new_record("attach", SystemTime::now());
+
+    record.insert("core".into(), json!(core));
+    record.insert("control_block".into(), json!(rtt.ptr()));
+    record.insert("core_halted".into(), json!(rtt.core_was_halted()));
+    record.insert("up_channels".into(), channels(rtt.up_channels()));
+    record.insert("down_channels".into(), channels(rtt.down_channels()));
+
+    Value::Object(record)
+}
+
+/// Describes a line of output. `text` is the line converted to text, if it has been already.
+pub fn data(source: &Source, line: &Line, text: Option<String>) -> Value {
+    let mut record = source.record("data", line.first);
+
+    match text.or_else(|| String::from_utf8(line.text.clone()).ok()) {
+        Some(text) => record.insert("text".into(), json!(text)),
+        None => record.insert("data".into(), json!(base64::encode(&line.text))),
+    };
+
+    record.insert("complete".into(), json!(line.complete));
+
+    Value::Object(record)
+}
+
+/// Describes an event on a channel, such as `reset` or `overflow`.
+pub fn event(source: &Source, kind: &str) -> Value {
+    Value::Object(source.record(kind, SystemTime::now()))
+}
+
+pub fn error(message: &str) -> Value {
+    let mut record = new_record("error", SystemTime::now());
+
+    record.insert("message".into(), json!(message));
+
+    Value::Object(record)
+}
+
+pub fn exit(code: i32, reason: &str) -> Value {
+    let mut record = new_record("exit", SystemTime::now());
+
+    record.insert("code".into(), json!(code));
+    record.insert("reason".into(), json!(reason));
+
+    Value::Object(record)
+}
+
+fn new_record(kind: &str, time: SystemTime) -> Map<String, Value> {
+    let mut record = Map::new();
+
+    record.insert("type".into(), json!(kind));
+    record.insert(
+        "time".into(),
+        json!(humantime::format_rfc3339_micros(time).to_string()),
+    );
+
+    record
+}
+
+fn channels(channels: &Channels<impl RttChannel>) -> Value {
+    channels
+        .iter()
+        .map(|chan| {
+            json!({
+                "number": chan.number(),
+                "name": chan.name(),
+                "buffer_size": chan.buffer_size(),
+            })
+        })
+        .collect()
+}
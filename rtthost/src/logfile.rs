@@ -0,0 +1,381 @@
+//! Log files with rotation by size or age.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// When and how to rotate log files.
+#[derive(Clone, Debug, Default)]
+pub struct Rotation {
+    /// Size in bytes after which the file is rotated.
+    pub max_size: Option<u64>,
+
+    /// Time after which the file is rotated.
+    pub max_age: Option<Duration>,
+
+    /// Whether to compress rotated files with gzip.
+    pub gzip: bool,
+}
+
+/// A log file that is rotated once it grows too large or too old.
+///
+/// The file is only rotated at the start of a line, so that lines are never split between files,
+/// unless a line without an end makes the file grow to twice the maximum size. Rotated files are renamed with a sequence number before the extension, e.g. `log.1.txt` for
+/// `log.txt`, and compressed in the background if enabled. The header, if any, is written at the
+/// start of every file. Dropping the log file waits for the compression to finish, so that no
+/// truncated `.gz` files are left behind on exit.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    header: Option<String>,
+    file: File,
+    size: u64,
+    opened: Instant,
+    line_start: bool,
+    /// Number of the next rotated file.
+    sequence: usize,
+    /// Threads compressing rotated files.
+    compressing: Vec<JoinHandle<()>>,
+}
+
+impl LogFile {
+    /// Creates the log file. An existing file is rotated away first.
+    pub fn create(path: &Path, rotation: Rotation, header: Option<String>) -> io::Result<LogFile> {
+        let mut sequence = 1;
+        let mut compressing = Vec::new();
+
+        if path.exists() {
+            compressing.extend(archive(path, &mut sequence, rotation.gzip)?);
+        }
+
+        let mut log = LogFile {
+            path: path.to_path_buf(),
+            rotation,
+            header,
+            file: File::create(path)?,
+            size: 0,
+            opened: Instant::now(),
+            line_start: true,
+            sequence,
+            compressing,
+        };
+
+        log.write_header()?;
+
+        Ok(log)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if let Some(header) = self.header.as_ref() {
+            self.file.write_all(header.as_bytes())?;
+            self.size += header.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the file is so large that it must be rotated even in the middle of a line.
+    fn over_hard_limit(&self) -> bool {
+        matches!(self.rotation.max_size, Some(max) if self.size >= max.saturating_mul(2))
+    }
+
+    fn needs_rotation(&self) -> bool {
+        matches!(self.rotation.max_size, Some(max) if self.size >= max)
+            || matches!(self.rotation.max_age, Some(max) if self.opened.elapsed() >= max)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let compressing = archive(&self.path, &mut self.sequence, self.rotation.gzip)?;
+        self.compressing.extend(compressing);
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+
+        self.write_header()
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if (self.line_start && self.needs_rotation()) || self.over_hard_limit() {
+            self.rotate()?;
+        }
+
+        let count = self.file.write(buf)?;
+
+        if count > 0 {
+            self.size += count as u64;
+            self.line_start = buf[count - 1] == b'\n';
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        for thread in self.compressing.drain(..) {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Renames a log file with the next free sequence number and compresses it in the background if
+/// enabled. Returns the thread compressing it.
+fn archive(path: &Path, sequence: &mut usize, gzip: bool) -> io::Result<Option<JoinHandle<()>>> {
+    let rotated = loop {
+        let mut name = path.file_stem().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", sequence));
+
+        if let Some(ext) = path.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+
+        let rotated = path.with_file_name(name);
+
+        let mut gz_path = rotated.clone().into_os_string();
+        gz_path.push(".gz");
+
+        *sequence += 1;
+
+        // Rotated files from earlier runs are kept
+        if !rotated.exists() && !Path::new(&gz_path).exists() {
+            break rotated;
+        }
+    };
+
+    fs::rename(path, &rotated)?;
+
+    if !gzip {
+        return Ok(None);
+    }
+
+    Ok(Some(thread::spawn(move || {
+        if let Err(err) = compress(&rotated) {
+            eprintln!("Error compressing {}: {}", rotated.display(), err);
+        }
+    })))
+}
+
+/// Compresses a file to a `.gz` file next to it and removes the original.
+fn compress(path: &Path) -> io::Result<()> {
+    let mut gz_path = path.to_path_buf().into_os_string();
+    gz_path.push(".gz");
+
+    let mut input = File::open(path)?;
+    let mut output = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+
+    io::copy(&mut input, &mut output)?;
+    output.finish()?;
+
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A temporary directory that is removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+            let path = std::env::temp_dir().join(format!(
+                "rtthost-logfile-test-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&path).unwrap();
+
+            TempDir(path)
+        }
+
+        fn read(&self, name: &str) -> String {
+            fs::read_to_string(self.0.join(name)).unwrap()
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut files: Vec<String> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            files.sort();
+
+            files
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn by_size(max: u64) -> Rotation {
+        Rotation {
+            max_size: Some(max),
+            ..Rotation::default()
+        }
+    }
+
+    #[test]
+    fn no_rotation() {
+        let dir = TempDir::new();
+        let mut log = LogFile::create(&dir.0.join("log.txt"), Rotation::default(), None).unwrap();
+
+        for _ in 0..100 {
+            log.write_all(b"line\n").unwrap();
+        }
+
+        drop(log);
+        assert_eq!(dir.files(), ["log.txt"]);
+        assert_eq!(dir.read("log.txt").len(), 500);
+    }
+
+    #[test]
+    fn rotate_by_size_with_header() {
+        let dir = TempDir::new();
+        let header = Some("# header\n".to_string());
+        let mut log = LogFile::create(&dir.0.join("log.txt"), by_size(16), header).unwrap();
+
+        log.write_all(b"one\n").unwrap();
+        log.write_all(b"two\n").unwrap();
+        log.write_all(b"three\n").unwrap();
+        log.write_all(b"four\n").unwrap();
+
+        drop(log);
+        assert_eq!(dir.files(), ["log.1.txt", "log.txt"]);
+        assert_eq!(dir.read("log.1.txt"), "# header\none\ntwo\n");
+        assert_eq!(dir.read("log.txt"), "# header\nthree\nfour\n");
+    }
+
+    #[test]
+    fn rotate_at_line_start() {
+        let dir = TempDir::new();
+        let mut log = LogFile::create(&dir.0.join("log"), by_size(4), None).unwrap();
+
+        // The line is over the size limit, but kept in one file
+        log.write_all(b"abc").unwrap();
+        log.write_all(b"def").unwrap();
+        log.write_all(b"\n").unwrap();
+        log.write_all(b"gh\n").unwrap();
+
+        drop(log);
+        assert_eq!(dir.files(), ["log", "log.1"]);
+        assert_eq!(dir.read("log.1"), "abcdef\n");
+        assert_eq!(dir.read("log"), "gh\n");
+    }
+
+    #[test]
+    fn rotate_mid_line_at_hard_limit() {
+        let dir = TempDir::new();
+        let mut log = LogFile::create(&dir.0.join("log.txt"), by_size(4), None).unwrap();
+
+        for _ in 0..10 {
+            log.write_all(b"abc").unwrap();
+        }
+
+        drop(log);
+        assert_eq!(
+            dir.files(),
+            ["log.1.txt", "log.2.txt", "log.3.txt", "log.txt"]
+        );
+        assert_eq!(dir.read("log.1.txt"), "abcabcabc");
+        assert_eq!(dir.read("log.txt"), "abc");
+    }
+
+    #[test]
+    fn sequence_skips_existing_files() {
+        let dir = TempDir::new();
+        let path = dir.0.join("log.txt");
+
+        fs::write(&path, "previous\n").unwrap();
+        fs::write(dir.0.join("log.1.txt"), "older\n").unwrap();
+        fs::write(dir.0.join("log.2.txt.gz"), "").unwrap();
+
+        let mut log = LogFile::create(&path, by_size(6), Some("h\n".to_string())).unwrap();
+        log.write_all(b"new\n").unwrap();
+        log.write_all(b"newer\n").unwrap();
+
+        drop(log);
+        assert_eq!(
+            dir.files(),
+            [
+                "log.1.txt",
+                "log.2.txt.gz",
+                "log.3.txt",
+                "log.4.txt",
+                "log.txt"
+            ]
+        );
+        assert_eq!(dir.read("log.1.txt"), "older\n");
+        assert_eq!(dir.read("log.3.txt"), "previous\n");
+        assert_eq!(dir.read("log.4.txt"), "h\nnew\n");
+        assert_eq!(dir.read("log.txt"), "h\nnewer\n");
+    }
+
+    #[test]
+    fn rotate_by_age() {
+        let dir = TempDir::new();
+        let rotation = Rotation {
+            max_age: Some(Duration::from_millis(20)),
+            ..Rotation::default()
+        };
+        let mut log = LogFile::create(&dir.0.join("log.txt"), rotation, None).unwrap();
+
+        log.write_all(b"a").unwrap();
+        thread::sleep(Duration::from_millis(30));
+
+        // Not in the middle of a line
+        log.write_all(b"b\n").unwrap();
+        log.write_all(b"c\n").unwrap();
+
+        drop(log);
+        assert_eq!(dir.read("log.1.txt"), "ab\n");
+        assert_eq!(dir.read("log.txt"), "c\n");
+    }
+
+    #[test]
+    fn gzip() {
+        let dir = TempDir::new();
+        let rotation = Rotation {
+            max_size: Some(1),
+            gzip: true,
+            ..Rotation::default()
+        };
+        let mut log = LogFile::create(&dir.0.join("log.txt"), rotation, None).unwrap();
+
+        log.write_all(b"one\n").unwrap();
+        log.write_all(b"two\n").unwrap();
+
+        // Waits for the compression to finish
+        drop(log);
+        assert_eq!(dir.files(), ["log.1.txt.gz", "log.txt"]);
+
+        let mut text = String::new();
+        GzDecoder::new(File::open(dir.0.join("log.1.txt.gz")).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "one\n");
+    }
+}
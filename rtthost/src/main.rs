@@ -1,11 +1,65 @@
+mod exit;
+mod json;
+mod logfile;
+#[cfg(unix)]
+mod pty;
+mod server;
+
 use probe_rs::{config::TargetSelector, DebugProbeInfo, Probe};
-use probe_rs_rtt::{Channels, Rtt, RttChannel};
+use probe_rs_rtt::cache::{self, CacheKey, ControlBlockCache};
+use probe_rs_rtt::capture::{CaptureWriter, ChannelInfo, Direction};
+use probe_rs_rtt::defmt::{self, DefmtReader, Table};
+use probe_rs_rtt::expect::{Expect, Regex, Script, Step};
+use probe_rs_rtt::pcapng::{self, PcapngWriter};
+use probe_rs_rtt::symbols::{SymbolizeReader, Symbols};
+use probe_rs_rtt::systemview::{self, ChromeTrace};
+use probe_rs_rtt::terminal::NUM_TERMINALS;
+use probe_rs_rtt::{
+    channels, parse_region, AnsiParser, AttachOptions, ChannelMode, ChannelSelector, Channels,
+    DecodeFallback, DownChannel, Line, LineReader, Rtt, RttChannel, TextDecoder, UpChannel,
+    VirtualTerminals,
+};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::prelude::*;
 use std::io::{stdin, stdout};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use structopt::StructOpt;
 
+use exit::{Exit, ExitConditions, ExitLine};
+use logfile::{LogFile, Rotation};
+#[cfg(unix)]
+use pty::Pty;
+use server::Server;
+
+/// ANSI colours of up channels with --color: cyan, yellow, magenta, green, blue and red.
+const CHANNEL_COLORS: [u8; 6] = [36, 33, 35, 32, 34, 31];
+
+/// Time after which a partial line is output when output is interleaved by line, or checked
+/// against the exit conditions.
+const LINE_IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Prints an error message to stderr, and also as an error record to stdout if the output format is
+/// JSON lines.
+macro_rules! report_error {
+    ($json:expr, $($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        eprintln!("{}", message);
+
+        if $json {
+            json::print(&json::error(message.trim_start())).ok();
+        }
+    }};
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum ProbeInfo {
     Number(usize),
@@ -26,6 +80,132 @@ impl std::str::FromStr for ProbeInfo {
     }
 }
 
+/// Up channels to output, and the file to write them to instead of stdout.
+#[derive(Debug, PartialEq, Eq)]
+struct UpSelector {
+    /// `None` selects all channels.
+    channel: Option<ChannelSelector>,
+    file: Option<PathBuf>,
+}
+
+impl std::str::FromStr for UpSelector {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<UpSelector, &'static str> {
+        let (channel, file) = match s.find('=') {
+            Some(p) if p + 1 == s.len() => return Err("File name must not be empty."),
+            Some(p) => (&s[..p], Some(PathBuf::from(&s[(p + 1)..]))),
+            None => (s, None),
+        };
+
+        let channel = if channel == "all" {
+            None
+        } else {
+            Some(channel.parse()?)
+        };
+
+        Ok(UpSelector { channel, file })
+    }
+}
+
+impl std::fmt::Display for UpSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.channel.as_ref() {
+            Some(channel) => channel.fmt(f),
+            None => write!(f, "all"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ChannelPrefix {
+    Name,
+    Number,
+}
+
+impl std::str::FromStr for ChannelPrefix {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<ChannelPrefix, &'static str> {
+        match s {
+            "name" => Ok(ChannelPrefix::Name),
+            "number" => Ok(ChannelPrefix::Number),
+            _ => Err("Invalid prefix. Expected 'name' or 'number'."),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum TimestampFormat {
+    Iso,
+    Relative,
+}
+
+impl std::str::FromStr for TimestampFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<TimestampFormat, &'static str> {
+        match s {
+            "iso" => Ok(TimestampFormat::Iso),
+            "relative" => Ok(TimestampFormat::Relative),
+            _ => Err("Invalid timestamp format. Expected 'iso' or 'relative'."),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum OutputFormat {
+    Text,
+    Jsonl,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<OutputFormat, &'static str> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            _ => Err("Invalid format. Expected 'text' or 'jsonl'."),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TerminalSelector {
+    Number(u8),
+    All,
+}
+
+impl std::str::FromStr for TerminalSelector {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<TerminalSelector, &'static str> {
+        if s == "all" {
+            return Ok(TerminalSelector::All);
+        }
+
+        match s.parse::<u8>() {
+            Ok(n) if n < NUM_TERMINALS => Ok(TerminalSelector::Number(n)),
+            _ => Err("Invalid terminal. Expected a number from 0 to 15 or 'all'."),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(
+        about = "Run an expect script against an up channel and a down channel, and exit with 0 if it passes or 1 if it fails. The up channel defaults to 0 and can be selected with a single --up, and the down channel with --down."
+    )]
+    Expect {
+        #[structopt(
+            parse(from_os_str),
+            help = "Script with a step per line: expect /REGEX/ [TIMEOUT], send \"STRING\" or exit-on /REGEX/. The timeout defaults to 10s, and exit-on fails the script as soon as a line of output matches."
+        )]
+        script: PathBuf,
+    },
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "rtthost",
@@ -50,19 +230,259 @@ struct Opts {
     #[structopt(short, long, help = "List RTT channels and exit.")]
     list: bool,
 
+    #[structopt(
+        long = "rtt-id",
+        help = "Control block ID string to scan for (up to 16 bytes). Defaults to \"SEGGER RTT\"."
+    )]
+    rtt_id: Option<String>,
+
+    #[structopt(
+        long = "scan-region",
+        parse(try_from_str = parse_region),
+        number_of_values = 1,
+        help = "Additional memory region to scan for the control block, as START..END or START+SIZE. Can be given multiple times."
+    )]
+    scan_regions: Vec<Range<u32>>,
+
+    #[structopt(
+        long,
+        help = "Halt the core while scanning for the control block. The core is resumed afterwards unless it was already halted."
+    )]
+    halt: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Path to the firmware ELF file. Used to tell firmware builds apart in the control block cache, to decode defmt logs, and to annotate code addresses in the output with the function and source location."
+    )]
+    elf: Option<PathBuf>,
+
+    #[structopt(
+        long = "no-symbolize",
+        help = "Do not annotate code addresses in the output with the function and source location from the ELF file. Channels written to a file with --up CHANNEL=FILE and SystemView output are never annotated."
+    )]
+    no_symbolize: bool,
+
+    #[structopt(
+        long,
+        help = "Cache the control block address on disk and try it first on the next run."
+    )]
+    cache: bool,
+
+    #[structopt(
+        long,
+        conflicts_with = "all-cores",
+        help = "Number of the core to attach to. Default is 0."
+    )]
+    core: Option<usize>,
+
+    #[structopt(
+        long = "all-cores",
+        help = "Attach to RTT on all cores. Output is prefixed with the core number."
+    )]
+    all_cores: bool,
+
     #[structopt(
         short,
         long,
-        help = "Number of up channel to output. Defaults to 0 if it exists."
+        number_of_values = 1,
+        help = "Number or name of an up channel to output, or 'all'. Names may contain * and ? wildcards, and names that are numbers can be given as name:NAME. The channel is written to a file instead of stdout if given as CHANNEL=FILE. Can be given multiple times. Defaults to 0 if it exists, or to 'defmt' with --defmt."
+    )]
+    up: Vec<UpSelector>,
+
+    #[structopt(
+        long,
+        help = "Prefix each line of output with the name or number of its up channel: name or number. Channels without a name are shown by number."
+    )]
+    prefix: Option<ChannelPrefix>,
+
+    #[structopt(
+        long,
+        help = "Show the output of each up channel in a different colour."
+    )]
+    color: bool,
+
+    #[structopt(
+        long = "log-dir",
+        parse(from_os_str),
+        conflicts_with_all = &["server", "pty"],
+        help = "Write each up channel to its own file in the directory instead of stdout. Each file starts with a header describing the session."
+    )]
+    log_dir: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Prefix each line of output with the host time: iso for the ISO 8601 time in UTC, or relative for the seconds since the start."
+    )]
+    timestamps: Option<TimestampFormat>,
+
+    #[structopt(
+        long = "rotate-size",
+        parse(try_from_str = parse_size),
+        help = "Rotate output files once they reach the size in bytes. K, M and G suffixes can be used. Files are only rotated at the start of a line."
+    )]
+    rotate_size: Option<u64>,
+
+    #[structopt(
+        long = "rotate-interval",
+        parse(try_from_str = humantime::parse_duration),
+        help = "Rotate output files after the given time, e.g. 1h or 30min. Files are only rotated at the start of a line."
+    )]
+    rotate_interval: Option<Duration>,
+
+    #[structopt(long, help = "Compress rotated output files with gzip.")]
+    gzip: bool,
+
+    #[structopt(
+        long,
+        help = "Output format: text (the default), or jsonl for a JSON object per line of output and per event such as attaching or a target reset, for use by other programs. Also applies to --list and --probe list. Up channels written to files are still written as text."
     )]
-    up: Option<usize>,
+    format: Option<OutputFormat>,
 
     #[structopt(
         short,
         long,
-        help = "Number of down channel for keyboard input. Defaults to 0 if it exists."
+        help = "Number or name of down channel for keyboard input. Names may contain * and ? wildcards, and names that are numbers can be given as name:NAME. Defaults to 0 if it exists."
+    )]
+    down: Option<ChannelSelector>,
+
+    #[structopt(
+        long,
+        help = "Decode output as UTF-8, handling invalid input with the given mode (replace, escape or latin1). By default output is passed through as is."
+    )]
+    decode: Option<DecodeFallback>,
+
+    #[structopt(
+        long,
+        help = "Split the up channel into SEGGER virtual terminals and output only the specified terminal, or 'all' to output all terminals prefixed with the terminal number."
+    )]
+    terminal: Option<TerminalSelector>,
+
+    #[structopt(
+        long = "strip-ansi",
+        help = "Remove ANSI escape sequences such as colors from the output."
+    )]
+    strip_ansi: bool,
+
+    #[structopt(
+        long,
+        requires = "elf",
+        conflicts_with = "terminal",
+        help = "Decode defmt log frames from the up channel using the format strings in the ELF file."
+    )]
+    defmt: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["terminal", "defmt"],
+        help = "Start SystemView recording and output the decoded events of the up channel, which defaults to 'SysView'."
+    )]
+    systemview: bool,
+
+    #[structopt(
+        long = "chrome-trace",
+        parse(from_os_str),
+        requires = "systemview",
+        conflicts_with = "all-cores",
+        help = "Write the SystemView events to a Chrome trace JSON file, for viewing in chrome://tracing or Perfetto."
+    )]
+    chrome_trace: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Record the session to a capture file, which can be replayed with rttui --replay."
+    )]
+    record: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Write the traffic of the session to a pcapng file, with an interface per channel, for viewing in Wireshark."
+    )]
+    pcapng: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        requires = "pcapng",
+        help = "Convert a capture file recorded with --record to the pcapng file given with --pcapng instead of attaching to a target."
+    )]
+    convert: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["up", "down", "terminal", "defmt", "systemview", "all-cores"],
+        help = "Serve the channels over TCP on the given address, e.g. localhost:19021, instead of using stdin and stdout. Clients receive the data of an up channel, and their input goes to the down channel with the same number. Clients select a channel by sending $$SEGGER_TELNET_ConfigStr=RTTCh;<number>$$ and start out on channel 0. Telnet clients are supported."
+    )]
+    server: Option<String>,
+
+    #[structopt(
+        long = "port-per-channel",
+        requires = "server",
+        help = "Listen on a port per up channel, numbered from the server port plus the channel number, instead of letting clients select a channel."
+    )]
+    port_per_channel: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["down", "terminal", "defmt", "systemview", "all-cores", "server"],
+        help = "Create a pseudo-terminal for each up channel, or only the ones given with --up, and print their paths. Data from the up channel can be read from the terminal, and data written to the terminal goes to the down channel with the same number. Only supported on Unix."
+    )]
+    pty: bool,
+
+    #[structopt(
+        long = "pty-link",
+        parse(from_os_str),
+        requires = "pty",
+        help = "Create symbolic links to the pseudo-terminals, named by the given path followed by the channel number, e.g. /tmp/rtt0."
+    )]
+    pty_link: Option<PathBuf>,
+
+    #[structopt(
+        long = "exit-on",
+        parse(try_from_str = Regex::new),
+        number_of_values = 1,
+        help = "Exit when a line of output matches the regex, with the code given by --exit-code. Can be given multiple times."
+    )]
+    exit_on: Vec<Regex>,
+
+    #[structopt(
+        long = "exit-code",
+        requires = "exit-on",
+        help = "Exit code when --exit-on matches. Defaults to 0."
+    )]
+    exit_code: Option<i32>,
+
+    #[structopt(
+        long = "exit-marker",
+        help = "Exit when the target outputs a line EXIT:<code>, with the code as the exit code."
+    )]
+    exit_marker: bool,
+
+    #[structopt(
+        long,
+        parse(try_from_str = humantime::parse_duration),
+        help = "Exit with code 124 after the given time, e.g. 30s or 5min."
+    )]
+    timeout: Option<Duration>,
+
+    #[structopt(
+        long = "idle-timeout",
+        parse(try_from_str = humantime::parse_duration),
+        help = "Exit with code 124 when no output has been received for the given time."
+    )]
+    idle_timeout: Option<Duration>,
+
+    #[structopt(
+        long = "max-bytes",
+        parse(try_from_str = parse_size),
+        help = "Exit with code 0 once the given number of bytes of output has been received. K, M and G suffixes can be used."
     )]
-    down: Option<usize>,
+    max_bytes: Option<u64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
 fn main() {
@@ -73,15 +493,80 @@ fn main() {
 
 fn run() -> i32 {
     let opts = Opts::from_args();
+    let json = opts.format == Some(OutputFormat::Jsonl);
+
+    if json
+        && (opts.prefix.is_some()
+            || opts.color
+            || opts.timestamps.is_some()
+            || opts.log_dir.is_some()
+            || opts.server.is_some()
+            || opts.pty)
+    {
+        eprintln!("Error: --format jsonl cannot be used with --prefix, --color, --timestamps, --log-dir, --server or --pty.");
+        return 1;
+    }
+
+    let script = match opts.command.as_ref() {
+        Some(Command::Expect { script }) => {
+            let result = std::fs::read_to_string(script)
+                .map_err(|err| err.to_string())
+                .and_then(|text| Script::parse(&text).map_err(|err| err.to_string()));
+
+            match result {
+                Ok(script) => Some(script),
+                Err(err) => {
+                    eprintln!("Error reading script {}: {}", script.display(), err);
+                    return 1;
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut exit = ExitConditions::new()
+        .patterns(opts.exit_on.clone(), opts.exit_code.unwrap_or(0))
+        .marker(opts.exit_marker)
+        .timeout(opts.timeout)
+        .idle_timeout(opts.idle_timeout)
+        .max_bytes(opts.max_bytes);
+
+    if exit.is_active() && (opts.server.is_some() || opts.pty || script.is_some()) {
+        eprintln!("Error: exit conditions cannot be used with --server, --pty or expect.");
+        return 1;
+    }
+
+    // Expect scripts run against a single up channel on a single core
+    if script.is_some() && (opts.up.len() > 1 || opts.all_cores) {
+        eprintln!("Error: expect cannot be used with more than one --up or with --all-cores.");
+        return 1;
+    }
+
+    if let (Some(input), Some(output)) = (opts.convert.as_ref(), opts.pcapng.as_ref()) {
+        let result =
+            File::open(input).and_then(|input| pcapng::convert(input, File::create(output)?));
+
+        return match result {
+            Ok(_) => 0,
+            Err(err) => {
+                report_error!(json, "Error converting capture file: {}", err);
+                1
+            }
+        };
+    }
 
     let probes = Probe::list_all();
 
     if probes.len() == 0 {
-        eprintln!("No debug probes available. Make sure your probe is plugged in, supported and up-to-date.");
+        report_error!(json, "No debug probes available. Make sure your probe is plugged in, supported and up-to-date.");
         return 1;
     }
 
     let probe_number = match opts.probe {
+        ProbeInfo::List if json => {
+            json::print(&json::probes(&probes)).ok();
+            return 0;
+        }
         ProbeInfo::List => {
             list_probes(std::io::stdout(), &probes);
             return 0;
@@ -90,7 +575,7 @@ fn run() -> i32 {
     };
 
     if probe_number >= probes.len() {
-        eprintln!("Probe {} does not exist.", probe_number);
+        report_error!(json, "Probe {} does not exist.", probe_number);
         list_probes(std::io::stderr(), &probes);
         return 1;
     }
@@ -98,7 +583,7 @@ fn run() -> i32 {
     let probe = match probes[probe_number].open() {
         Ok(probe) => probe,
         Err(err) => {
-            eprintln!("Error opening probe: {}", err);
+            report_error!(json, "Error opening probe: {}", err);
             return 1;
         }
     };
@@ -112,7 +597,7 @@ fn run() -> i32 {
     let session = match probe.attach(target_selector) {
         Ok(session) => session,
         Err(err) => {
-            eprintln!("Error creating debug session: {}", err);
+            report_error!(json, "Error creating debug session: {}", err);
 
             if opts.chip.is_none() {
                 if let probe_rs::Error::ChipNotFound(_) = err {
@@ -124,85 +609,666 @@ fn run() -> i32 {
         }
     };
 
-    let core = match session.attach_to_core(0) {
-        Ok(core) => core,
+    let mut attach_options = AttachOptions::new();
+
+    if let Some(rtt_id) = opts.rtt_id.as_ref() {
+        attach_options = attach_options.control_block_id(rtt_id);
+    }
+
+    for range in opts.scan_regions.iter() {
+        attach_options = attach_options.scan_region(range.clone());
+    }
+
+    attach_options = attach_options.halt_core(opts.halt);
+
+    let elf = match opts.elf.as_ref().map(std::fs::read).transpose() {
+        Ok(elf) => elf,
         Err(err) => {
-            eprintln!("Error attaching to core 0: {}", err);
+            report_error!(json, "Error reading ELF file: {}", err);
             return 1;
         }
     };
 
-    eprintln!("Attaching to RTT...");
+    let defmt_table = match (opts.defmt, elf.as_ref()) {
+        (true, Some(elf)) => match Table::parse(elf) {
+            Ok(table) => Some(Arc::new(table)),
+            Err(err) => {
+                report_error!(json, "Error reading defmt data from ELF file: {}", err);
+                return 1;
+            }
+        },
+        _ => None,
+    };
 
-    let mut rtt = match Rtt::attach(core, &session) {
-        Ok(rtt) => rtt,
-        Err(err) => {
-            eprintln!("Error attaching to RTT: {}", err);
-            return 1;
+    // Symbols are nice to have, so the output is shown without them if they cannot be read
+    let symbols = match (opts.no_symbolize, elf.as_ref()) {
+        (false, Some(elf)) => match Symbols::parse(elf) {
+            Ok(symbols) => Some(Arc::new(symbols)),
+            Err(err) => {
+                eprintln!("Warning: code addresses will not be symbolized: {}", err);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let mut cache = if opts.cache {
+        match ControlBlockCache::open_default() {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                eprintln!("Warning: control block cache disabled: {}", err);
+                None
+            }
         }
+    } else {
+        None
     };
 
-    if opts.list {
-        println!("Up channels:");
-        list_channels(rtt.up_channels());
+    let probe_serial = probes[probe_number].serial_number.clone();
+    let chip_name = cache::chip_name(&session, opts.chip.as_deref());
+    let cache_key = |core| CacheKey::new(probe_serial.as_deref(), &chip_name, core, elf.as_deref());
 
-        println!("Down channels:");
-        list_channels(rtt.down_channels());
+    eprintln!("Attaching to RTT...");
 
-        return 0;
+    let core = if opts.all_cores {
+        None
+    } else {
+        Some(opts.core.unwrap_or(0))
+    };
+
+    let mut rtts =
+        match cache::attach_cached(&session, core, &attach_options, cache.as_mut(), cache_key) {
+            Ok(rtts) => rtts,
+            Err(err) => {
+                report_error!(json, "Error attaching to RTT: {}", err);
+                return 1;
+            }
+        };
+
+    if let Some(cache) = cache.as_ref() {
+        if let Err(err) = cache.save() {
+            eprintln!("Warning: failed to save control block cache: {}", err);
+        }
     }
 
-    let up_channel = if let Some(up) = opts.up {
-        let chan = rtt.up_channels().take(up);
+    if json {
+        for (core, rtt) in rtts.iter_mut() {
+            json::print(&json::attach(*core, rtt)).ok();
+        }
+    }
 
-        if chan.is_none() {
-            eprintln!("Error: up channel {} does not exist.", up);
-            return 1;
+    if opts.list {
+        if json {
+            return 0;
         }
 
-        chan
-    } else {
-        rtt.up_channels().take(0)
-    };
+        for (core, rtt) in rtts.iter_mut() {
+            if opts.all_cores {
+                println!("Core {}:", core);
+            }
 
-    let down_channel = if let Some(down) = opts.down {
-        let chan = rtt.down_channels().take(down);
+            println!("Up channels:");
+            list_channels(rtt.up_channels());
 
-        if chan.is_none() {
-            eprintln!("Error: up channel {} does not exist.", down);
-            return 1;
+            println!("Down channels:");
+            list_channels(rtt.down_channels());
         }
 
-        chan
-    } else {
-        rtt.down_channels().take(0)
-    };
+        return 0;
+    }
 
-    let stdin = down_channel.as_ref().map(|_| stdin_channel());
+    let mut recorder = Recorder::default();
 
-    eprintln!("Found control block at 0x{:08x}", rtt.ptr());
+    if let Some(path) = opts.record.as_ref() {
+        match start_capture(path, &mut rtts) {
+            Ok(capture) => recorder.capture = Some(capture),
+            Err(err) => {
+                report_error!(json, "Error creating capture file: {}", err);
+                return 1;
+            }
+        }
+    }
 
-    let mut up_buf = [0u8; 1024];
-    let mut down_buf = vec![];
+    if let Some(path) = opts.pcapng.as_ref() {
+        match start_pcapng(path, &mut rtts) {
+            Ok(pcapng) => recorder.pcapng = Some(pcapng),
+            Err(err) => {
+                report_error!(json, "Error creating pcapng file: {}", err);
+                return 1;
+            }
+        }
+    }
 
-    loop {
-        if let Some(up_channel) = up_channel.as_ref() {
-            let count = match up_channel.read(up_buf.as_mut()) {
-                Ok(count) => count,
+    let recorder = if recorder.is_active() {
+        Some(Rc::new(RefCell::new(recorder)))
+    } else {
+        None
+    };
+
+    if let Some(script) = script.as_ref() {
+        let (core, rtt) = &mut rtts[0];
+
+        return run_expect(
+            script,
+            opts.up.first(),
+            opts.down.as_ref(),
+            *core,
+            rtt,
+            recorder.as_ref(),
+        );
+    }
+
+    if opts.pty {
+        let (core, rtt) = &mut rtts[0];
+
+        return run_pty(
+            &opts.up,
+            opts.pty_link.as_deref(),
+            *core,
+            rtt,
+            recorder.as_ref(),
+        );
+    }
+
+    if let Some(addr) = opts.server.as_ref() {
+        let (core, rtt) = &mut rtts[0];
+
+        let server = if opts.port_per_channel {
+            let numbers: Vec<usize> = rtt.up_channels().numbers().collect();
+            Server::bind_per_channel(addr, &numbers)
+        } else {
+            Server::bind(addr, 0)
+        };
+
+        return match server {
+            Ok(server) => run_server(server, *core, rtt, recorder.as_ref()),
+            Err(err) => {
+                report_error!(json, "Error starting server: {}", err);
+                1
+            }
+        };
+    }
+
+    // Up channels to output, with the file to write to instead of stdout and whether the file was
+    // given with CHANNEL=FILE to get the channel data as is
+    let mut up_channels = Vec::new();
+    let default_up = [UpSelector {
+        channel: Some(if opts.defmt {
+            ChannelSelector::Name(defmt::CHANNEL_NAME.to_string())
+        } else if opts.systemview {
+            ChannelSelector::Name(systemview::CHANNEL_NAME.to_string())
+        } else {
+            ChannelSelector::Number(0)
+        }),
+        file: None,
+    }];
+    let up: &[UpSelector] = if opts.up.is_empty() {
+        &default_up
+    } else {
+        &opts.up
+    };
+    let up_required = !opts.up.is_empty() || opts.defmt || opts.systemview;
+
+    let rotation = Rotation {
+        max_size: opts.rotate_size,
+        max_age: opts.rotate_interval,
+        gzip: opts.gzip,
+    };
+
+    // Channels written to the same file share it
+    let mut files: BTreeMap<&Path, Rc<RefCell<LogFile>>> = BTreeMap::new();
+
+    for selector in up {
+        let file = match selector.file.as_deref() {
+            Some(path) => match files.get(path) {
+                Some(file) => Some(file.clone()),
+                None => match LogFile::create(path, rotation.clone(), None) {
+                    Ok(file) => {
+                        let file = Rc::new(RefCell::new(file));
+                        files.insert(path, file.clone());
+                        Some(file)
+                    }
+                    Err(err) => {
+                        report_error!(
+                            json,
+                            "Error creating output file {}: {}",
+                            path.display(),
+                            err
+                        );
+                        return 1;
+                    }
+                },
+            },
+            None => None,
+        };
+
+        let chans = match channels::take_selected(
+            rtts.iter_mut().map(|(_, rtt)| rtt.up_channels()),
+            selector.channel.as_slice(),
+        ) {
+            Ok(chans) => chans,
+            Err(_) if !up_required => continue,
+            Err(selector) => {
+                if opts.all_cores {
+                    report_error!(
+                        json,
+                        "Error: up channel {} does not exist on any core.",
+                        selector
+                    );
+                } else {
+                    report_error!(json, "Error: up channel {} does not exist.", selector);
+                }
+                return 1;
+            }
+        };
+
+        for ((core, _), chans) in rtts.iter().zip(chans) {
+            for chan in chans {
+                up_channels.push((*core, chan, file.clone(), selector.file.is_some()));
+            }
+        }
+    }
+
+    if let Some(dir) = opts.log_dir.as_ref() {
+        if let Err(err) = std::fs::create_dir_all(dir) {
+            report_error!(
+                json,
+                "Error creating log directory {}: {}",
+                dir.display(),
+                err
+            );
+            return 1;
+        }
+
+        let started = humantime::format_rfc3339_millis(SystemTime::now());
+
+        for (core, chan, file, _) in up_channels.iter_mut() {
+            if file.is_some() {
+                continue;
+            }
+
+            let mut name = if opts.all_cores {
+                format!("core{}-up{}", core, chan.number())
+            } else {
+                format!("up{}", chan.number())
+            };
+
+            if let Some(chan_name) = chan.name() {
+                name.push('-');
+                name.extend(chan_name.chars().map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                }));
+            }
+
+            let path = dir.join(format!("{}.log", name));
+
+            let ptr = rtts
+                .iter()
+                .find(|(c, _)| c == core)
+                .map(|(_, rtt)| rtt.ptr())
+                .unwrap_or_default();
+
+            let probe = &probes[probe_number];
+
+            let header = format!(
+                "# rtthost session started at {}\n\
+                 # Probe: {} ({})\n\
+                 # Chip: {}\n\
+                 # Control block: 0x{:08x} on core {}\n\
+                 # Channel: up {} {}\n",
+                started,
+                probe.identifier,
+                probe.serial_number.as_deref().unwrap_or("no serial number"),
+                opts.chip.as_deref().unwrap_or("auto-detected"),
+                ptr,
+                core,
+                chan.number(),
+                chan.name().unwrap_or("(no name)"),
+            );
+
+            match LogFile::create(&path, rotation.clone(), Some(header)) {
+                Ok(log) => *file = Some(Rc::new(RefCell::new(log))),
                 Err(err) => {
-                    eprintln!("\nError reading from RTT: {}", err);
+                    report_error!(json, "Error creating log file {}: {}", path.display(), err);
+                    return 1;
+                }
+            }
+        }
+    }
+
+    // Keyboard input always goes to the first core
+    let down_channel = if let Some(down) = opts.down.as_ref() {
+        let chan = rtts[0].1.down_channels().take_by_selector(down);
+
+        if chan.is_none() {
+            report_error!(json, "Error: down channel {} does not exist.", down);
+            return 1;
+        }
+
+        chan
+    } else {
+        rtts[0].1.down_channels().take(0)
+    };
+
+    let stdin = down_channel.as_ref().map(|_| stdin_channel());
+
+    // SystemView only starts recording once the host asks for it
+    if opts.systemview {
+        for (_, rtt) in rtts.iter_mut() {
+            if let Some(chan) = rtt.down_channels().take_by_name(systemview::CHANNEL_NAME) {
+                if let Err(err) = chan.write(&[systemview::COMMAND_START]) {
+                    report_error!(json, "Error starting SystemView recording: {}", err);
                     return 1;
                 }
+            }
+        }
+    }
+
+    let mut chrome_trace = match opts
+        .chrome_trace
+        .as_ref()
+        .map(|path| File::create(path).and_then(ChromeTrace::new))
+        .transpose()
+    {
+        Ok(trace) => trace,
+        Err(err) => {
+            report_error!(json, "Error creating Chrome trace file: {}", err);
+            return 1;
+        }
+    };
+
+    for (core, rtt) in rtts.iter() {
+        if opts.all_cores {
+            eprintln!(
+                "Found control block at 0x{:08x} on core {}",
+                rtt.ptr(),
+                core
+            );
+        } else {
+            eprintln!("Found control block at 0x{:08x}", rtt.ptr());
+        }
+
+        if rtt.core_was_halted() == Some(true) {
+            eprintln!(
+                "Note: core {} was already halted and has been left halted.",
+                core
+            );
+        }
+    }
+
+    // Output from multiple sources on stdout is interleaved by line
+    let stdout_sources = up_channels
+        .iter()
+        .filter(|(_, _, file, _)| file.is_none())
+        .count();
+    let by_line = stdout_sources > 1 || opts.terminal == Some(TerminalSelector::All);
+
+    // Output is prefixed with the core number in multi-core mode, the channel if requested, and
+    // the terminal number if all virtual terminals are shown
+    let mut up_states = Vec::new();
+    let mut json_states = Vec::new();
+
+    for (index, (core, chan, file, raw_file)) in up_channels.into_iter().enumerate() {
+        let mut prefix = if opts.all_cores {
+            format!("[core {}] ", core)
+        } else {
+            String::new()
+        };
+
+        match (opts.prefix, chan.name()) {
+            (Some(ChannelPrefix::Name), Some(name)) => prefix += &format!("[{}] ", name),
+            (Some(_), _) => prefix += &format!("[{}] ", chan.number()),
+            (None, _) => {}
+        }
+
+        let number = chan.number();
+        let name = chan.name().map(str::to_string);
+
+        let json_output = json && file.is_none();
+
+        let chan: Box<dyn Read> = if json_output {
+            Box::new(EventReporter {
+                source: json::Source {
+                    core,
+                    channel: number,
+                    name: name.clone(),
+                    terminal: None,
+                },
+                blocking: matches!(chan.mode(), Ok(ChannelMode::BlockIfFull)),
+                inner: chan,
+            })
+        } else {
+            Box::new(chan)
+        };
+
+        let chan = recording(core, number, chan, recorder.as_ref());
+
+        let color = if opts.color && file.is_none() {
+            Some(CHANNEL_COLORS[index % CHANNEL_COLORS.len()])
+        } else {
+            None
+        };
+
+        let symbols = symbols
+            .as_ref()
+            .filter(|_| symbolizes(raw_file, opts.systemview))
+            .cloned();
+
+        let mut add_source = |source: Box<dyn Read>, prefix: String, terminal: Option<u8>| {
+            let source: Box<dyn Read> = match symbols.as_ref() {
+                Some(symbols) => Box::new(SymbolizeReader::new(source, symbols.clone())),
+                None => source,
             };
 
-            match stdout().write_all(&up_buf[..count]) {
-                Ok(_) => {
-                    stdout().flush().ok();
+            if json_output {
+                json_states.push(JsonState {
+                    lines: LineReader::new(source).idle_timeout(LINE_IDLE_TIMEOUT),
+                    source: json::Source {
+                        core,
+                        channel: number,
+                        name: name.clone(),
+                        terminal,
+                    },
+                    ansi: if opts.strip_ansi {
+                        Some(AnsiParser::new())
+                    } else {
+                        None
+                    },
+                    decoder: opts.decode.map(TextDecoder::new),
+                });
+
+                return;
+            }
+
+            let by_line = by_line && file.is_none();
+
+            up_states.push(UpState {
+                source: if by_line {
+                    Box::new(LineReader::new(source).idle_timeout(LINE_IDLE_TIMEOUT))
+                } else {
+                    source
+                },
+                output: file.clone(),
+                by_line,
+                prefix,
+                color,
+                line_start: true,
+                ansi: if opts.strip_ansi {
+                    Some(AnsiParser::new())
+                } else {
+                    None
+                },
+                decoder: opts.decode.map(TextDecoder::new),
+                exit_line: ExitLine::new(LINE_IDLE_TIMEOUT),
+            });
+        };
+
+        if opts.systemview {
+            add_source(
+                Box::new(SystemViewReader {
+                    inner: chan,
+                    decoder: systemview::Decoder::new(),
+                    trace: chrome_trace.take(),
+                    out: Vec::new(),
+                }),
+                prefix,
+                None,
+            );
+            continue;
+        }
+
+        if let Some(table) = defmt_table.as_ref() {
+            add_source(
+                Box::new(DefmtReader::new(chan, table.clone())),
+                prefix,
+                None,
+            );
+            continue;
+        }
+
+        match opts.terminal {
+            None => add_source(Box::new(chan), prefix, None),
+            Some(TerminalSelector::Number(n)) => {
+                let terminal = VirtualTerminals::new(chan).terminal(n).unwrap();
+
+                add_source(Box::new(terminal), prefix, Some(n));
+            }
+            Some(TerminalSelector::All) => {
+                let terminals = VirtualTerminals::new(chan);
+
+                for n in 0..NUM_TERMINALS {
+                    let terminal = terminals.terminal(n).unwrap();
+
+                    add_source(
+                        Box::new(terminal),
+                        format!("{}[terminal {}] ", prefix, n),
+                        Some(n),
+                    );
                 }
-                Err(err) => {
+            }
+        }
+    }
+
+    let mut up_buf = [0u8; 1024];
+    let mut down_buf = vec![];
+
+    // Source whose last output to stdout did not end with a complete line
+    let mut stdout_partial: Option<usize> = None;
+
+    let timestamps = opts.timestamps.map(|format| Timestamps {
+        format,
+        start: Instant::now(),
+    });
+
+    exit.reset_idle();
+
+    loop {
+        if let Some(exited) = exit.poll() {
+            return exit_with(json, exited);
+        }
+
+        for (index, state) in up_states.iter_mut().enumerate() {
+            loop {
+                let count = match state.source.read(up_buf.as_mut()) {
+                    Ok(count) => count,
+                    Err(err) => {
+                        report_error!(json, "\nError reading from RTT: {}", err);
+                        return 1;
+                    }
+                };
+
+                let data = state.convert(&up_buf[..count]);
+
+                if let Some(file) = state.output.as_ref() {
+                    let mut file = file.borrow_mut();
+
+                    if let Err(err) = write_prefixed(
+                        &mut *file,
+                        &state.prefix,
+                        timestamps.as_ref(),
+                        None,
+                        &mut state.line_start,
+                        &data,
+                    ) {
+                        report_error!(json, "\nError writing to output file: {}", err);
+                        return 1;
+                    }
+                } else if !data.is_empty() {
+                    let stdout = stdout();
+                    let mut stdout = stdout.lock();
+
+                    // Another source broke the line, so start a new one
+                    if stdout_partial.is_some() && stdout_partial != Some(index) {
+                        stdout.write_all(b"\n").ok();
+                    }
+
+                    if !state.line_start && stdout_partial != Some(index) {
+                        state.line_start = true;
+                    }
+
+                    match write_prefixed(
+                        &mut stdout,
+                        &state.prefix,
+                        timestamps.as_ref(),
+                        state.color,
+                        &mut state.line_start,
+                        &data,
+                    ) {
+                        Ok(_) => {
+                            stdout.flush().ok();
+                        }
+                        Err(err) => {
+                            report_error!(json, "Error writing to stdout: {}", err);
+                            return 1;
+                        }
+                    }
+
+                    stdout_partial = if state.line_start { None } else { Some(index) };
+                }
+
+                let exited = exit
+                    .received(count)
+                    .or_else(|| state.exit_line.output(&exit, &data));
+
+                if let Some(exited) = exited {
+                    return exit_with(json, exited);
+                }
+
+                // Line by line sources are read until the end of a line so that a long line is not
+                // broken up by other sources
+                if !state.by_line || count == 0 || up_buf[count - 1] == b'\n' {
+                    break;
+                }
+            }
+        }
+
+        for state in json_states.iter_mut() {
+            loop {
+                let line = match state.lines.next_line() {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(err) => {
+                        report_error!(json, "Error reading from RTT: {}", err);
+                        return 1;
+                    }
+                };
+
+                if let Err(err) = json::print(&state.record(&line)) {
                     eprintln!("Error writing to stdout: {}", err);
                     return 1;
                 }
+
+                let exited = exit
+                    .received(line.text.len() + usize::from(line.complete))
+                    .or_else(|| exit.check_line(&line.text, line.complete));
+
+                if let Some(exited) = exited {
+                    return exit_with(json, exited);
+                }
             }
         }
 
@@ -211,23 +1277,703 @@ fn run() -> i32 {
                 down_buf.extend_from_slice(bytes.as_slice());
             }
 
-            if !down_buf.is_empty() {
-                let count = match down_channel.write(down_buf.as_mut()) {
+            if let Err(err) = write_down(rtts[0].0, down_channel, &mut down_buf, recorder.as_ref())
+            {
+                report_error!(json, "\n{}", err);
+                return 1;
+            }
+        }
+    }
+}
+
+/// Serves the channels of a core to TCP clients until an error occurs.
+fn run_server(
+    mut server: Server,
+    core: usize,
+    rtt: &mut Rtt,
+    recorder: Option<&Rc<RefCell<Recorder>>>,
+) -> i32 {
+    let mut up_channels: Vec<(usize, Box<dyn Read>)> = rtt
+        .up_channels()
+        .drain()
+        .map(|chan| {
+            (
+                chan.number(),
+                recording(core, chan.number(), Box::new(chan), recorder),
+            )
+        })
+        .collect();
+
+    let mut down_channels: BTreeMap<usize, (DownChannel, Vec<u8>)> = rtt
+        .down_channels()
+        .drain()
+        .map(|chan| (chan.number(), (chan, Vec::new())))
+        .collect();
+
+    let mut up_buf = [0u8; 1024];
+
+    loop {
+        for (number, source) in up_channels.iter_mut() {
+            let count = match source.read(up_buf.as_mut()) {
+                Ok(count) => count,
+                Err(err) => {
+                    eprintln!("Error reading from RTT: {}", err);
+                    return 1;
+                }
+            };
+
+            if count > 0 {
+                server.broadcast(*number, &up_buf[..count]);
+            }
+        }
+
+        // Input for channels without a down channel is discarded
+        for (number, data) in server.poll() {
+            if let Some((_, down_buf)) = down_channels.get_mut(&number) {
+                down_buf.extend_from_slice(&data);
+            }
+        }
+
+        for (down_channel, down_buf) in down_channels.values_mut() {
+            if let Err(err) = write_down(core, down_channel, down_buf, recorder) {
+                eprintln!("{}", err);
+                return 1;
+            }
+        }
+    }
+}
+
+/// Bridges up channels and their down channels to pseudo-terminals until an error occurs.
+#[cfg(unix)]
+fn run_pty(
+    up: &[UpSelector],
+    link: Option<&Path>,
+    core: usize,
+    rtt: &mut Rtt,
+    recorder: Option<&Rc<RefCell<Recorder>>>,
+) -> i32 {
+    let mut up_channels = Vec::new();
+
+    for selector in up {
+        if selector.file.is_some() {
+            eprintln!("Error: up channels cannot be written to files with --pty.");
+            return 1;
+        }
+
+        match channels::take_selected(Some(rtt.up_channels()), selector.channel.as_slice()) {
+            Ok(chans) => up_channels.extend(chans.into_iter().flatten()),
+            Err(selector) => {
+                eprintln!("Error: up channel {} does not exist.", selector);
+                return 1;
+            }
+        }
+    }
+
+    if up.is_empty() {
+        up_channels = rtt.up_channels().drain().collect();
+    }
+
+    let mut bridges = Vec::new();
+
+    for chan in up_channels {
+        let number = chan.number();
+
+        let mut pty = match Pty::open() {
+            Ok(pty) => pty,
+            Err(err) => {
+                eprintln!("Error creating pseudo-terminal: {}", err);
+                return 1;
+            }
+        };
+
+        let path = match link {
+            Some(link) => {
+                let mut path = link.as_os_str().to_owned();
+                path.push(number.to_string());
+
+                if let Err(err) = pty.link(Path::new(&path)) {
+                    eprintln!("Error creating link to pseudo-terminal: {}", err);
+                    return 1;
+                }
+
+                format!("{} -> {}", Path::new(&path).display(), pty.path())
+            }
+            None => pty.path().to_string(),
+        };
+
+        println!(
+            "{}: {} {}",
+            number,
+            chan.name().unwrap_or("(no name)"),
+            path
+        );
+
+        bridges.push(PtyBridge {
+            source: recording(core, chan.number(), Box::new(chan), recorder),
+            up_buf: Vec::new(),
+            down_channel: rtt.down_channels().take(number),
+            down_buf: Vec::new(),
+            pty,
+        });
+    }
+
+    let mut buf = [0u8; 1024];
+
+    loop {
+        for bridge in bridges.iter_mut() {
+            // The up channel is only read once the terminal has taken all the previous data, so
+            // that the terminal applies flow control to the target
+            if bridge.up_buf.is_empty() {
+                let count = match bridge.source.read(&mut buf) {
                     Ok(count) => count,
                     Err(err) => {
-                        eprintln!("\nError writing to RTT: {}", err);
+                        eprintln!("Error reading from RTT: {}", err);
                         return 1;
                     }
                 };
 
-                if count > 0 {
-                    down_buf.drain(..count);
+                bridge.up_buf.extend_from_slice(&buf[..count]);
+            }
+
+            if !bridge.up_buf.is_empty() {
+                match bridge.pty.write(&bridge.up_buf) {
+                    Ok(count) => {
+                        bridge.up_buf.drain(..count);
+                    }
+                    Err(err) => {
+                        eprintln!("Error writing to pseudo-terminal: {}", err);
+                        return 1;
+                    }
+                }
+            }
+
+            // Input is only read when there is room for it, so that the terminal applies flow
+            // control to the program writing to it
+            if bridge.down_buf.len() < buf.len() {
+                let count = match bridge.pty.read(&mut buf) {
+                    Ok(count) => count,
+                    Err(err) => {
+                        eprintln!("Error reading from pseudo-terminal: {}", err);
+                        return 1;
+                    }
+                };
+
+                // Input is discarded if the up channel has no down channel
+                if bridge.down_channel.is_some() {
+                    bridge.down_buf.extend_from_slice(&buf[..count]);
+                }
+            }
+
+            if let Some(down_channel) = bridge.down_channel.as_ref() {
+                if let Err(err) = write_down(core, down_channel, &mut bridge.down_buf, recorder) {
+                    eprintln!("{}", err);
+                    return 1;
                 }
             }
         }
     }
 }
 
+#[cfg(not(unix))]
+fn run_pty(
+    _up: &[UpSelector],
+    _link: Option<&Path>,
+    _core: usize,
+    _rtt: &mut Rtt,
+    _recorder: Option<&Rc<RefCell<Recorder>>>,
+) -> i32 {
+    eprintln!("Error: pseudo-terminals are only supported on Unix.");
+    1
+}
+
+/// Reports why rtthost is exiting and returns the exit code.
+fn exit_with(json: bool, exit: Exit) -> i32 {
+    eprintln!("\nExiting: {}.", exit.reason);
+
+    if json {
+        json::print(&json::exit(exit.code, &exit.reason)).ok();
+    }
+
+    exit.code
+}
+
+/// Runs an expect script on an up channel and a down channel, showing the output of the up channel
+/// on stdout. Returns 0 if the script passes and 1 if it fails.
+fn run_expect(
+    script: &Script,
+    up: Option<&UpSelector>,
+    down: Option<&ChannelSelector>,
+    core: usize,
+    rtt: &mut Rtt,
+    recorder: Option<&Rc<RefCell<Recorder>>>,
+) -> i32 {
+    let up_channel = match up.and_then(|selector| selector.channel.as_ref()) {
+        Some(selector) => rtt.up_channels().take_by_selector(selector),
+        None => rtt.up_channels().take(0),
+    };
+
+    let up_channel = match up_channel {
+        Some(chan) => chan,
+        None => {
+            eprintln!(
+                "Error: up channel {} does not exist.",
+                up.map(|selector| selector.to_string())
+                    .unwrap_or_else(|| "0".to_string())
+            );
+            return 1;
+        }
+    };
+
+    let down_channel = match down {
+        Some(selector) => rtt.down_channels().take_by_selector(selector),
+        None => rtt.down_channels().take(0),
+    };
+
+    let sends = script
+        .steps()
+        .iter()
+        .any(|step| matches!(step, Step::Send(_)));
+
+    if down_channel.is_none() && sends {
+        eprintln!(
+            "Error: the script sends data, but down channel {} does not exist.",
+            down.map(|selector| selector.to_string())
+                .unwrap_or_else(|| "0".to_string())
+        );
+        return 1;
+    }
+
+    let input = recording(core, up_channel.number(), Box::new(up_channel), recorder);
+
+    let output = RecordingDownChannel {
+        core,
+        inner: down_channel,
+        recorder: recorder.cloned(),
+    };
+
+    let mut expect = Expect::new(input, output).echo(stdout());
+
+    for step in script.steps() {
+        if let Err(err) = expect.run_step(step) {
+            eprintln!("\nFailed at {}: {}", step, err);
+            return 1;
+        }
+    }
+
+    eprintln!("\nScript passed.");
+
+    0
+}
+
+/// Down channel that records all data written to it. Without a channel all data is discarded.
+struct RecordingDownChannel {
+    core: usize,
+    inner: Option<DownChannel>,
+    recorder: Option<Rc<RefCell<Recorder>>>,
+}
+
+impl Write for RecordingDownChannel {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let chan = match self.inner.as_mut() {
+            Some(chan) => chan,
+            None => return Ok(buf.len()),
+        };
+
+        let count = Write::write(chan, buf)?;
+
+        if count > 0 {
+            if let Some(recorder) = self.recorder.as_ref() {
+                recorder.borrow_mut().data(
+                    self.core,
+                    Direction::Down,
+                    chan.number(),
+                    &buf[..count],
+                )?;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An up channel and its down channel bridged to a pseudo-terminal.
+#[cfg(unix)]
+struct PtyBridge {
+    source: Box<dyn Read>,
+    /// Data read from the up channel that has not been written to the terminal yet.
+    up_buf: Vec<u8>,
+    down_channel: Option<DownChannel>,
+    down_buf: Vec<u8>,
+    pty: Pty,
+}
+
+/// Returns the data read from an up channel as a source, recording it if recording is enabled.
+fn recording(
+    core: usize,
+    number: usize,
+    source: Box<dyn Read>,
+    recorder: Option<&Rc<RefCell<Recorder>>>,
+) -> Box<dyn Read> {
+    match recorder {
+        Some(recorder) => Box::new(RecordingChannel {
+            core,
+            number,
+            inner: source,
+            recorder: recorder.clone(),
+        }),
+        None => source,
+    }
+}
+
+/// Writes as much of the buffered data to a down channel as fits and removes it from the buffer,
+/// recording it if recording is enabled.
+fn write_down(
+    core: usize,
+    down_channel: &DownChannel,
+    down_buf: &mut Vec<u8>,
+    recorder: Option<&Rc<RefCell<Recorder>>>,
+) -> Result<(), String> {
+    if down_buf.is_empty() {
+        return Ok(());
+    }
+
+    let count = down_channel
+        .write(down_buf.as_mut())
+        .map_err(|err| format!("Error writing to RTT: {}", err))?;
+
+    if count > 0 {
+        if let Some(recorder) = recorder {
+            recorder
+                .borrow_mut()
+                .data(
+                    core,
+                    Direction::Down,
+                    down_channel.number(),
+                    &down_buf[..count],
+                )
+                .map_err(|err| format!("Error writing recording: {}", err))?;
+        }
+
+        down_buf.drain(..count);
+    }
+
+    Ok(())
+}
+
+/// Output state of an up channel or virtual terminal.
+struct UpState {
+    source: Box<dyn Read>,
+    /// File to write to instead of stdout.
+    output: Option<Rc<RefCell<LogFile>>>,
+    /// Whether the source only returns complete lines.
+    by_line: bool,
+    prefix: String,
+    color: Option<u8>,
+    /// Whether the previous write ended with a complete line.
+    line_start: bool,
+    ansi: Option<AnsiParser>,
+    decoder: Option<TextDecoder>,
+    exit_line: ExitLine,
+}
+
+impl UpState {
+    /// Applies the ANSI stripping and text decoding options to data read from the source.
+    fn convert(&mut self, data: &[u8]) -> Vec<u8> {
+        let data = match self.ansi.as_mut() {
+            Some(ansi) => ansi.strip(data),
+            None => data.to_vec(),
+        };
+
+        // Characters split between reads are held back by the decoder until complete
+        match self.decoder.as_mut() {
+            Some(decoder) => decoder.decode(&data).into_bytes(),
+            None => data,
+        }
+    }
+}
+
+/// Returns whether to annotate code addresses in the output of an up channel. Only text output is
+/// symbolized, never SystemView events or channel data written to a file as is with CHANNEL=FILE.
+fn symbolizes(raw_file: bool, systemview: bool) -> bool {
+    !raw_file && !systemview
+}
+
+/// Up channel or virtual terminal output as JSON records, a line at a time.
+struct JsonState {
+    lines: LineReader<Box<dyn Read>>,
+    source: json::Source,
+    ansi: Option<AnsiParser>,
+    decoder: Option<TextDecoder>,
+}
+
+impl JsonState {
+    /// Returns the data record for a line, applying the ANSI stripping and text decoding options.
+    fn record(&mut self, line: &Line) -> Value {
+        let mut line = line.clone();
+
+        if let Some(ansi) = self.ansi.as_mut() {
+            line.text = ansi.strip(&line.text);
+        }
+
+        // A partial line continues in the next record, which may complete a split character
+        let text = self.decoder.as_mut().map(|decoder| {
+            let mut text = decoder.decode(&line.text);
+
+            if line.complete {
+                text += &decoder.finish();
+            }
+
+            text
+        });
+
+        json::data(&self.source, &line, text)
+    }
+}
+
+/// Up channel that reports target resets and possible data loss noticed while reading from it as
+/// JSON records.
+struct EventReporter {
+    source: json::Source,
+    /// Whether the channel was in blocking mode at startup.
+    blocking: bool,
+    inner: UpChannel,
+}
+
+impl Read for EventReporter {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = Read::read(&mut self.inner, buf)?;
+        let events = self.inner.take_events();
+
+        if events.reset {
+            json::print(&json::event(&self.source, "reset"))?;
+        }
+
+        // Blocking channels are full whenever the host falls behind, without losing data
+        if events.full && !self.blocking {
+            json::print(&json::event(&self.source, "overflow"))?;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Creates a capture file and writes the control block and channels of each core to it.
+fn start_capture(path: &Path, rtts: &mut [(usize, Rtt)]) -> std::io::Result<CaptureWriter<File>> {
+    let mut capture = CaptureWriter::new(File::create(path)?)?;
+
+    for (core, rtt) in rtts.iter_mut() {
+        capture.control_block(*core, rtt.ptr())?;
+
+        for chan in rtt.up_channels().iter() {
+            capture.channel(ChannelInfo::up(*core, chan))?;
+        }
+
+        for chan in rtt.down_channels().iter() {
+            capture.channel(ChannelInfo::down(*core, chan))?;
+        }
+    }
+
+    Ok(capture)
+}
+
+/// Creates a pcapng file and adds an interface for each channel of each core to it.
+fn start_pcapng(path: &Path, rtts: &mut [(usize, Rtt)]) -> std::io::Result<PcapngWriter<File>> {
+    let mut pcapng = PcapngWriter::new(File::create(path)?)?;
+
+    for (core, rtt) in rtts.iter_mut() {
+        for chan in rtt.up_channels().iter() {
+            pcapng.interface(&ChannelInfo::up(*core, chan))?;
+        }
+
+        for chan in rtt.down_channels().iter() {
+            pcapng.interface(&ChannelInfo::down(*core, chan))?;
+        }
+    }
+
+    Ok(pcapng)
+}
+
+/// Files that the traffic of the session is recorded to.
+#[derive(Default)]
+struct Recorder {
+    capture: Option<CaptureWriter<File>>,
+    pcapng: Option<PcapngWriter<File>>,
+}
+
+impl Recorder {
+    fn is_active(&self) -> bool {
+        self.capture.is_some() || self.pcapng.is_some()
+    }
+
+    fn data(
+        &mut self,
+        core: usize,
+        direction: Direction,
+        number: usize,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        if let Some(capture) = self.capture.as_mut() {
+            capture.data(core, direction, number, data)?;
+        }
+
+        if let Some(pcapng) = self.pcapng.as_mut() {
+            pcapng.data(core, direction, number, data)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Up channel that records all data read from it.
+struct RecordingChannel {
+    core: usize,
+    number: usize,
+    inner: Box<dyn Read>,
+    recorder: Rc<RefCell<Recorder>>,
+}
+
+impl Read for RecordingChannel {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+
+        if count > 0 {
+            self.recorder.borrow_mut().data(
+                self.core,
+                Direction::Up,
+                self.number,
+                &buf[..count],
+            )?;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Decodes SystemView events from an up channel and outputs them as lines of text, optionally
+/// writing them to a Chrome trace as well.
+struct SystemViewReader {
+    inner: Box<dyn Read>,
+    decoder: systemview::Decoder,
+    trace: Option<ChromeTrace<File>>,
+    out: Vec<u8>,
+}
+
+impl Read for SystemViewReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.out.is_empty() {
+            let mut data = [0u8; 1024];
+            let count = self.inner.read(&mut data)?;
+            self.decoder.received(&data[..count]);
+
+            loop {
+                match self.decoder.next_event() {
+                    Ok(Some(event)) => {
+                        if let Some(trace) = self.trace.as_mut() {
+                            trace.write_event(&event)?;
+                        }
+
+                        writeln!(self.out, "{}", event)?;
+                    }
+                    Ok(None) => break,
+                    Err(err) => writeln!(self.out, "({})", err)?,
+                }
+            }
+        }
+
+        let count = self.out.len().min(buf.len());
+
+        buf[..count].copy_from_slice(&self.out[..count]);
+        self.out.drain(..count);
+
+        Ok(count)
+    }
+}
+
+/// Host timestamps for lines of output.
+struct Timestamps {
+    format: TimestampFormat,
+    start: Instant,
+}
+
+impl Timestamps {
+    fn now(&self) -> String {
+        match self.format {
+            TimestampFormat::Iso => {
+                format!("[{}] ", humantime::format_rfc3339_millis(SystemTime::now()))
+            }
+            TimestampFormat::Relative => format!("[{:>12.6}] ", self.start.elapsed().as_secs_f64()),
+        }
+    }
+}
+
+/// Writes data with the timestamp and prefix at the start of each line. The colour is an ANSI SGR
+/// color code, which is reset before each newline and at the end so that it does not carry over
+/// to other output.
+fn write_prefixed(
+    out: &mut impl Write,
+    prefix: &str,
+    timestamps: Option<&Timestamps>,
+    color: Option<u8>,
+    line_start: &mut bool,
+    data: &[u8],
+) -> std::io::Result<()> {
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        let (text, newline) = match line.strip_suffix(b"\n") {
+            Some(text) => (text, true),
+            None => (line, false),
+        };
+
+        if let Some(color) = color {
+            write!(out, "\x1b[{}m", color)?;
+        }
+
+        if *line_start {
+            if let Some(timestamps) = timestamps {
+                out.write_all(timestamps.now().as_bytes())?;
+            }
+
+            out.write_all(prefix.as_bytes())?;
+        }
+
+        out.write_all(text)?;
+
+        if color.is_some() {
+            out.write_all(b"\x1b[0m")?;
+        }
+
+        if newline {
+            out.write_all(b"\n")?;
+        }
+
+        *line_start = newline;
+    }
+
+    Ok(())
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("Invalid size: '{}'", s))
+}
+
 fn list_probes(mut stream: impl std::io::Write, probes: &Vec<DebugProbeInfo>) {
     writeln!(stream, "Available probes:").unwrap();
 
@@ -284,3 +2030,57 @@ fn stdin_channel() -> Receiver<Vec<u8>> {
 
     rx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn line(text: &[u8], complete: bool) -> Line {
+        Line {
+            text: text.to_vec(),
+            first: SystemTime::now(),
+            last: SystemTime::now(),
+            complete,
+        }
+    }
+
+    #[test]
+    fn symbolized_output() {
+        // Text on stdout or in a --log-dir file
+        assert!(symbolizes(false, false));
+
+        // Channel data as is
+        assert!(!symbolizes(true, false));
+
+        // SystemView events, whether on stdout or in a file
+        assert!(!symbolizes(false, true));
+        assert!(!symbolizes(true, true));
+    }
+
+    #[test]
+    fn json_character_split_across_partial_line() {
+        let mut state = JsonState {
+            lines: LineReader::new(Box::new(std::io::empty())),
+            source: json::Source {
+                core: 0,
+                channel: 0,
+                name: None,
+                terminal: None,
+            },
+            ansi: None,
+            decoder: Some(TextDecoder::new(DecodeFallback::Escape)),
+        };
+
+        // "é" is split between a partial line flushed after the idle timeout and the rest of it
+        let first = state.record(&line(b"caf\xc3", false));
+        assert_eq!(first["text"], "caf");
+
+        let second = state.record(&line(b"\xa9\xff", true));
+        assert_eq!(second["text"], "\u{e9}\\xff");
+
+        // An incomplete sequence does not carry over from a complete line
+        state.record(&line(b"a\xc3", true));
+        assert_eq!(state.record(&line(b"\xa9", true))["text"], "\\xa9");
+    }
+}
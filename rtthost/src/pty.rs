@@ -0,0 +1,217 @@
+//! Pseudo-terminals that make RTT channels look like serial devices to other programs.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, OFlag};
+use nix::pty::{self, PtyMaster};
+use nix::sys::stat::Mode;
+use nix::sys::termios::{self, SetArg};
+use nix::unistd;
+
+/// A pseudo-terminal, read and written from the master side.
+///
+/// The slave side is kept open as well, so that the terminal stays configured and data is not lost
+/// while no program has it open. The slave is put in raw mode so that binary data passes through
+/// unchanged, but programs that open it can change the mode like with any serial port.
+pub struct Pty {
+    master: PtyMaster,
+    slave: RawFd,
+    path: String,
+    link: Option<PathBuf>,
+}
+
+impl Pty {
+    /// Opens a new pseudo-terminal.
+    pub fn open() -> io::Result<Pty> {
+        let master = pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).map_err(to_io)?;
+        pty::grantpt(&master).map_err(to_io)?;
+        pty::unlockpt(&master).map_err(to_io)?;
+
+        let path = pty::ptsname_r(&master).map_err(to_io)?;
+
+        let slave = fcntl::open(
+            Path::new(&path),
+            OFlag::O_RDWR | OFlag::O_NOCTTY,
+            Mode::empty(),
+        )
+        .map_err(to_io)?;
+
+        let pty = Pty {
+            master,
+            slave,
+            path,
+            link: None,
+        };
+
+        let mut attrs = termios::tcgetattr(pty.slave).map_err(to_io)?;
+        termios::cfmakeraw(&mut attrs);
+        termios::tcsetattr(pty.slave, SetArg::TCSANOW, &attrs).map_err(to_io)?;
+
+        fcntl::fcntl(pty.master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(to_io)?;
+
+        Ok(pty)
+    }
+
+    /// Returns the path of the slave device, e.g. `/dev/pts/3`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Creates a symbolic link to the slave device, which is removed when the terminal is dropped.
+    /// An existing symbolic link at the path is replaced, but other files are not.
+    pub fn link(&mut self, link: &Path) -> io::Result<()> {
+        if let Ok(meta) = fs::symlink_metadata(link) {
+            if !meta.file_type().is_symlink() {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a symbolic link", link.display()),
+                ));
+            }
+
+            fs::remove_file(link)?;
+        }
+
+        std::os::unix::fs::symlink(&self.path, link)?;
+        self.link = Some(link.to_path_buf());
+
+        Ok(())
+    }
+
+    /// Reads data written to the terminal by other programs. Returns 0 if there is none.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match unistd::read(self.master.as_raw_fd(), buf) {
+            Err(nix::Error::Sys(Errno::EAGAIN)) => Ok(0),
+            result => result.map_err(to_io),
+        }
+    }
+
+    /// Writes data for other programs to read from the terminal. Returns how much of the data fit
+    /// in the terminal buffer, which is 0 if it is full.
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match unistd::write(self.master.as_raw_fd(), buf) {
+            Err(nix::Error::Sys(Errno::EAGAIN)) => Ok(0),
+            result => result.map_err(to_io),
+        }
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(link) = self.link.as_ref() {
+            fs::remove_file(link).ok();
+        }
+
+        unistd::close(self.slave).ok();
+    }
+}
+
+fn to_io(err: nix::Error) -> io::Error {
+    match err {
+        nix::Error::Sys(errno) => errno.into(),
+        err => io::Error::other(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    fn open_slave(pty: &Pty) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(pty.path())
+            .unwrap()
+    }
+
+    /// Reads from the master until some data arrives, as the terminal passes it on asynchronously.
+    fn read_master(pty: &mut Pty) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+
+        for _ in 0..100 {
+            let count = pty.read(&mut buf).unwrap();
+
+            if count > 0 {
+                return buf[..count].to_vec();
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Vec::new()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut pty = Pty::open().unwrap();
+        let mut slave = open_slave(&pty);
+
+        // Raw mode passes binary data and line endings through unchanged
+        assert_eq!(pty.write(b"to\r\n\x03\xff").unwrap(), 6);
+
+        let mut buf = [0u8; 6];
+        slave.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"to\r\n\x03\xff");
+
+        slave.write_all(b"from\n").unwrap();
+        assert_eq!(read_master(&mut pty), b"from\n");
+    }
+
+    #[test]
+    fn would_block() {
+        let mut pty = Pty::open().unwrap();
+
+        // Nothing to read
+        let mut buf = [0u8; 64];
+        assert_eq!(pty.read(&mut buf).unwrap(), 0);
+
+        // Nothing reads the slave, so the terminal buffer eventually fills up
+        let data = [0u8; 4096];
+        let mut full = false;
+
+        for _ in 0..10_000 {
+            if pty.write(&data).unwrap() == 0 {
+                full = true;
+                break;
+            }
+        }
+
+        assert!(full);
+    }
+
+    #[test]
+    fn link() {
+        let dir = std::env::temp_dir().join(format!("rtthost-pty-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let link = dir.join("tty");
+
+        {
+            let mut pty = Pty::open().unwrap();
+
+            // Replaces a stale link
+            std::os::unix::fs::symlink("/nonexistent", &link).unwrap();
+            pty.link(&link).unwrap();
+            assert_eq!(fs::read_link(&link).unwrap(), Path::new(pty.path()));
+        }
+
+        // Removed on drop
+        assert!(fs::symlink_metadata(&link).is_err());
+
+        // Other files are kept
+        fs::write(&link, "").unwrap();
+        let err = Pty::open().unwrap().link(&link).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(link.is_file());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,691 @@
+//! TCP server exposing RTT channels to network clients, like the J-Link RTT telnet server.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+/// Clients on a shared port can select a channel by sending a config string such as
+/// `$$SEGGER_TELNET_ConfigStr=RTTCh;1$$` right after connecting, same as with J-Link. Until then
+/// they receive the default channel.
+const CONFIG_PREFIX: &[u8] = b"$$SEGGER_TELNET_ConfigStr=";
+const CONFIG_SUFFIX: &[u8] = b"$$";
+const MAX_CONFIG_LEN: usize = 128;
+
+/// Output kept for a client that is not reading before further output to it is dropped.
+const MAX_PENDING_OUTPUT: usize = 64 * 1024;
+
+/// TCP server that broadcasts up channel data to clients and collects client input for down
+/// channels. Everything is non-blocking so that the server can be polled along with RTT.
+pub struct Server {
+    listeners: Vec<Listener>,
+    clients: Vec<Client>,
+}
+
+struct Listener {
+    socket: TcpListener,
+    /// Channel of the clients on this port.
+    channel: usize,
+    /// Whether clients can select another channel.
+    select: bool,
+}
+
+impl Server {
+    /// Listens on a single port. Clients start out on the default channel and can select another
+    /// one with a config string.
+    pub fn bind(addr: &str, default_channel: usize) -> io::Result<Server> {
+        let addr = resolve(addr)?;
+
+        Ok(Server {
+            listeners: vec![Listener::bind(addr, default_channel, true)?],
+            clients: Vec::new(),
+        })
+    }
+
+    /// Listens on a port per channel, numbered from the port of the address plus the channel
+    /// number.
+    pub fn bind_per_channel(addr: &str, channels: &[usize]) -> io::Result<Server> {
+        let addr = resolve(addr)?;
+
+        let listeners = channels
+            .iter()
+            .map(|&channel| {
+                let port = (addr.port() as usize)
+                    .checked_add(channel)
+                    .filter(|&port| port <= u16::MAX as usize)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("no port left for channel {}", channel),
+                        )
+                    })?;
+
+                let mut addr = addr;
+                addr.set_port(port as u16);
+
+                Listener::bind(addr, channel, false)
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Server {
+            listeners,
+            clients: Vec::new(),
+        })
+    }
+
+    /// Accepts new clients, sends them any output the socket did not take earlier and reads their
+    /// input. Returns the input by channel number.
+    pub fn poll(&mut self) -> Vec<(usize, Vec<u8>)> {
+        for listener in self.listeners.iter() {
+            loop {
+                match listener.socket.accept() {
+                    Ok((stream, addr)) => match Client::new(stream, addr, listener) {
+                        Ok(client) => {
+                            eprintln!("Client {} connected to channel {}.", addr, client.channel);
+                            self.clients.push(client);
+                        }
+                        Err(err) => eprintln!("Error accepting client {}: {}", addr, err),
+                    },
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        eprintln!("Error accepting client: {}", err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut input = Vec::new();
+
+        for client in self.clients.iter_mut() {
+            client.flush();
+
+            if !client.connected {
+                continue;
+            }
+
+            match client.read() {
+                Ok(data) if !data.is_empty() => input.push((client.channel, data)),
+                Ok(_) => {}
+                Err(err) => client.disconnect(err),
+            }
+        }
+
+        self.remove_disconnected();
+
+        input
+    }
+
+    /// Sends data to all clients of a channel.
+    pub fn broadcast(&mut self, channel: usize, data: &[u8]) {
+        for client in self.clients.iter_mut() {
+            if client.channel == channel {
+                client.send(data);
+            }
+        }
+
+        self.remove_disconnected();
+    }
+
+    fn remove_disconnected(&mut self) {
+        self.clients.retain(|client| client.connected);
+    }
+}
+
+impl Listener {
+    fn bind(addr: SocketAddr, channel: usize, select: bool) -> io::Result<Listener> {
+        let socket = TcpListener::bind(addr)?;
+        socket.set_nonblocking(true)?;
+
+        if select {
+            eprintln!("Listening on {}.", socket.local_addr()?);
+        } else {
+            eprintln!(
+                "Listening on {} for channel {}.",
+                socket.local_addr()?,
+                channel
+            );
+        }
+
+        Ok(Listener {
+            socket,
+            channel,
+            select,
+        })
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    addr: SocketAddr,
+    channel: usize,
+    /// Input received while the client may still be sending a config string.
+    config: Option<Vec<u8>>,
+    telnet: Telnet,
+    output: Vec<u8>,
+    connected: bool,
+}
+
+impl Client {
+    fn new(stream: TcpStream, addr: SocketAddr, listener: &Listener) -> io::Result<Client> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+
+        Ok(Client {
+            stream,
+            addr,
+            channel: listener.channel,
+            config: if listener.select {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            telnet: Telnet::default(),
+            output: Vec::new(),
+            connected: true,
+        })
+    }
+
+    /// Reads the available input, handling telnet commands and config strings.
+    fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = [0u8; 1024];
+        let mut data = Vec::new();
+        let mut reply = Vec::new();
+
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(count) => data.extend(self.telnet.input(&buf[..count], &mut reply)),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        if !reply.is_empty() {
+            self.queue(&reply);
+        }
+
+        Ok(self.received(data))
+    }
+
+    /// Handles input with telnet commands removed, and returns the input for the down channel.
+    fn received(&mut self, data: Vec<u8>) -> Vec<u8> {
+        match self.config.take() {
+            Some(mut config) => {
+                config.extend(data);
+                self.select_channel(config)
+            }
+            None => data,
+        }
+    }
+
+    /// Looks for a config string at the start of the input, and returns the input that follows
+    /// it once the config string is complete or turns out not to be there.
+    fn select_channel(&mut self, mut input: Vec<u8>) -> Vec<u8> {
+        let len = input.len().min(CONFIG_PREFIX.len());
+
+        if input[..len] != CONFIG_PREFIX[..len] {
+            return input;
+        }
+
+        let end = input[CONFIG_PREFIX.len().min(input.len())..]
+            .windows(CONFIG_SUFFIX.len())
+            .position(|w| w == CONFIG_SUFFIX)
+            .map(|pos| CONFIG_PREFIX.len() + pos);
+
+        let end = match end {
+            Some(end) => end,
+            None if input.len() < MAX_CONFIG_LEN => {
+                // Wait for the rest of the config string
+                self.config = Some(input);
+                return Vec::new();
+            }
+            None => return input,
+        };
+
+        let config = String::from_utf8_lossy(&input[CONFIG_PREFIX.len()..end]).into_owned();
+
+        // The config is a list of key;value pairs separated by semicolons
+        let items: Vec<&str> = config.split(';').map(str::trim).collect();
+
+        for pair in items.chunks(2) {
+            if let [key, value] = pair {
+                if key.eq_ignore_ascii_case("RTTCh") {
+                    match value.parse() {
+                        Ok(channel) => {
+                            eprintln!("Client {} selected channel {}.", self.addr, channel);
+                            self.channel = channel;
+                        }
+                        Err(_) => {
+                            eprintln!("Client {} selected invalid channel '{}'.", self.addr, value)
+                        }
+                    }
+                }
+            }
+        }
+
+        input.split_off(end + CONFIG_SUFFIX.len())
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        let data = self.telnet.escape(data);
+        self.write(&data);
+    }
+
+    /// Writes as much of the pending output and the data as the socket takes, keeping the rest
+    /// for later.
+    fn write(&mut self, data: &[u8]) {
+        if self.output.len() + data.len() > MAX_PENDING_OUTPUT {
+            // The client is not keeping up, drop data instead of blocking RTT
+            return;
+        }
+
+        self.queue(data);
+    }
+
+    /// Like [`write`](Client::write), but always keeps the data even if the client is not keeping
+    /// up. Used for telnet replies, which the client may be waiting for and which are small.
+    fn queue(&mut self, data: &[u8]) {
+        self.output.extend_from_slice(data);
+        self.flush();
+    }
+
+    /// Writes as much of the pending output as the socket takes.
+    fn flush(&mut self) {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => {
+                    self.disconnect(ErrorKind::WriteZero.into());
+                    return;
+                }
+                Ok(count) => {
+                    self.output.drain(..count);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    self.disconnect(err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn disconnect(&mut self, err: io::Error) {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            eprintln!("Client {} disconnected.", self.addr);
+        } else {
+            eprintln!("Client {} disconnected: {}", self.addr, err);
+        }
+
+        self.connected = false;
+    }
+}
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const OPT_SUPPRESS_GO_AHEAD: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TelnetState {
+    Data,
+    Iac,
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Minimal telnet protocol handling.
+///
+/// The server never starts option negotiation, so that raw TCP clients such as `nc` do not see any
+/// protocol bytes. Once a client sends a telnet command it is treated as a telnet client: its
+/// option requests are answered, suppressing go ahead is agreed to and everything else, including
+/// echo, is refused, and IAC bytes in the output are escaped.
+struct Telnet {
+    state: TelnetState,
+    active: bool,
+    /// Whether the last data byte was a carriage return, which telnet clients may follow with NUL.
+    cr: bool,
+}
+
+impl Default for Telnet {
+    fn default() -> Self {
+        Telnet {
+            state: TelnetState::Data,
+            active: false,
+            cr: false,
+        }
+    }
+}
+
+impl Telnet {
+    /// Removes telnet commands from input and adds replies to option requests to `reply`.
+    fn input(&mut self, data: &[u8], reply: &mut Vec<u8>) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            self.state = match self.state {
+                TelnetState::Data if byte == IAC => {
+                    self.active = true;
+                    TelnetState::Iac
+                }
+                TelnetState::Data => {
+                    if !(self.active && self.cr && byte == 0) {
+                        out.push(byte);
+                    }
+
+                    self.cr = byte == b'\r';
+                    TelnetState::Data
+                }
+                TelnetState::Iac => match byte {
+                    IAC => {
+                        out.push(IAC);
+                        TelnetState::Data
+                    }
+                    DO | DONT | WILL | WONT => TelnetState::Option(byte),
+                    SB => TelnetState::Subnegotiation,
+                    // Other commands such as NOP and go ahead carry no data
+                    _ => TelnetState::Data,
+                },
+                TelnetState::Option(verb) => {
+                    match verb {
+                        DO if byte == OPT_SUPPRESS_GO_AHEAD => reply.extend(&[IAC, WILL, byte]),
+                        DO => reply.extend(&[IAC, WONT, byte]),
+                        WILL if byte == OPT_SUPPRESS_GO_AHEAD => reply.extend(&[IAC, DO, byte]),
+                        WILL => reply.extend(&[IAC, DONT, byte]),
+                        // Nothing is enabled, so disabling needs no reply
+                        _ => {}
+                    }
+
+                    TelnetState::Data
+                }
+                TelnetState::Subnegotiation if byte == IAC => TelnetState::SubnegotiationIac,
+                TelnetState::Subnegotiation => TelnetState::Subnegotiation,
+                TelnetState::SubnegotiationIac if byte == SE => TelnetState::Data,
+                TelnetState::SubnegotiationIac => TelnetState::Subnegotiation,
+            };
+        }
+
+        out
+    }
+
+    /// Escapes output for the client.
+    fn escape(&self, data: &[u8]) -> Vec<u8> {
+        if !self.active {
+            return data.to_vec();
+        }
+
+        let mut out = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            out.push(byte);
+
+            if byte == IAC {
+                out.push(IAC);
+            }
+        }
+
+        out
+    }
+}
+
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("address '{}' not found", addr),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    const NOP: u8 = 241;
+    const OPT_ECHO: u8 = 1;
+    const OPT_TERMINAL_TYPE: u8 = 24;
+
+    fn input(telnet: &mut Telnet, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut reply = Vec::new();
+        let out = telnet.input(data, &mut reply);
+
+        (out, reply)
+    }
+
+    /// Returns a client on a shared port and the socket of the other end.
+    fn connect() -> (Client, TcpStream) {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(socket.local_addr().unwrap()).unwrap();
+        let (stream, addr) = socket.accept().unwrap();
+
+        let listener = Listener {
+            socket,
+            channel: 0,
+            select: true,
+        };
+
+        (Client::new(stream, addr, &listener).unwrap(), peer)
+    }
+
+    #[test]
+    fn raw_input() {
+        let mut telnet = Telnet::default();
+
+        assert_eq!(
+            input(&mut telnet, b"ab\r\0c\r\n"),
+            (b"ab\r\0c\r\n".to_vec(), vec![])
+        );
+        assert_eq!(telnet.escape(&[1, IAC, 2]), vec![1, IAC, 2]);
+    }
+
+    #[test]
+    fn escaped_iac() {
+        let mut telnet = Telnet::default();
+
+        assert_eq!(
+            input(&mut telnet, &[1, IAC, IAC, 2]),
+            (vec![1, IAC, 2], vec![])
+        );
+
+        // The client is now known to speak telnet
+        assert_eq!(telnet.escape(&[1, IAC, 2]), vec![1, IAC, IAC, 2]);
+    }
+
+    #[test]
+    fn option_replies() {
+        let mut telnet = Telnet::default();
+
+        let (out, reply) = input(
+            &mut telnet,
+            &[
+                IAC,
+                DO,
+                OPT_SUPPRESS_GO_AHEAD,
+                IAC,
+                DO,
+                OPT_ECHO,
+                IAC,
+                WILL,
+                OPT_SUPPRESS_GO_AHEAD,
+                IAC,
+                WILL,
+                OPT_TERMINAL_TYPE,
+                IAC,
+                DONT,
+                OPT_ECHO,
+                IAC,
+                WONT,
+                OPT_ECHO,
+                IAC,
+                NOP,
+                b'x',
+            ],
+        );
+
+        assert_eq!(out, b"x");
+        assert_eq!(
+            reply,
+            vec![
+                IAC,
+                WILL,
+                OPT_SUPPRESS_GO_AHEAD,
+                IAC,
+                WONT,
+                OPT_ECHO,
+                IAC,
+                DO,
+                OPT_SUPPRESS_GO_AHEAD,
+                IAC,
+                DONT,
+                OPT_TERMINAL_TYPE,
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_split_across_reads() {
+        let mut telnet = Telnet::default();
+
+        assert_eq!(input(&mut telnet, &[b'a', IAC]), (b"a".to_vec(), vec![]));
+        assert_eq!(input(&mut telnet, &[DO]), (vec![], vec![]));
+        assert_eq!(
+            input(&mut telnet, &[OPT_ECHO, b'b']),
+            (b"b".to_vec(), vec![IAC, WONT, OPT_ECHO])
+        );
+    }
+
+    #[test]
+    fn subnegotiation() {
+        let mut telnet = Telnet::default();
+
+        // IAC IAC within a subnegotiation does not end it
+        let (out, reply) = input(
+            &mut telnet,
+            &[
+                b'a',
+                IAC,
+                SB,
+                OPT_TERMINAL_TYPE,
+                0,
+                b'x',
+                IAC,
+                IAC,
+                b'y',
+                IAC,
+                SE,
+                b'b',
+            ],
+        );
+
+        assert_eq!(out, b"ab");
+        assert!(reply.is_empty());
+    }
+
+    #[test]
+    fn cr_nul() {
+        let mut telnet = Telnet::default();
+
+        input(&mut telnet, &[IAC, NOP]);
+
+        assert_eq!(input(&mut telnet, b"a\r\0b\r\nc\0").0, b"a\rb\r\nc\0");
+
+        // Split across reads
+        assert_eq!(input(&mut telnet, b"d\r").0, b"d\r");
+        assert_eq!(input(&mut telnet, b"\0e").0, b"e");
+    }
+
+    #[test]
+    fn select_channel() {
+        let (mut client, _peer) = connect();
+
+        assert_eq!(
+            client.received(b"$$SEGGER_TELNET_ConfigStr=RTTCh;1$$hello".to_vec()),
+            b"hello"
+        );
+        assert_eq!(client.channel, 1);
+
+        // Only a config string at the start of the input is recognized
+        assert_eq!(
+            client.received(b"$$SEGGER_TELNET_ConfigStr=RTTCh;2$$".to_vec()),
+            b"$$SEGGER_TELNET_ConfigStr=RTTCh;2$$"
+        );
+        assert_eq!(client.channel, 1);
+    }
+
+    #[test]
+    fn select_channel_split_across_reads() {
+        let (mut client, _peer) = connect();
+
+        assert_eq!(client.received(b"$$SEGGER_TEL".to_vec()), b"");
+        assert_eq!(
+            client.received(b"NET_ConfigStr=SetRTTAddr;0x20000000;".to_vec()),
+            b""
+        );
+        assert_eq!(client.received(b"rttch; 3 $".to_vec()), b"");
+        assert_eq!(client.channel, 0);
+
+        assert_eq!(client.received(b"$x".to_vec()), b"x");
+        assert_eq!(client.channel, 3);
+    }
+
+    #[test]
+    fn no_config_string() {
+        let (mut client, _peer) = connect();
+
+        assert_eq!(client.received(b"$$SEG".to_vec()), b"");
+        assert_eq!(client.received(b"X".to_vec()), b"$$SEGX");
+        assert_eq!(client.received(b"$$SEGGER".to_vec()), b"$$SEGGER");
+        assert_eq!(client.channel, 0);
+
+        let (mut client, _peer) = connect();
+
+        assert_eq!(client.received(b"hello".to_vec()), b"hello");
+    }
+
+    #[test]
+    fn invalid_config_string() {
+        let (mut client, _peer) = connect();
+
+        assert_eq!(
+            client.received(b"$$SEGGER_TELNET_ConfigStr=RTTCh;x$$a".to_vec()),
+            b"a"
+        );
+        assert_eq!(client.channel, 0);
+
+        // Given up on once it's too long to be a config string
+        let (mut client, _peer) = connect();
+        let mut long = CONFIG_PREFIX.to_vec();
+        long.resize(MAX_CONFIG_LEN, b'a');
+
+        assert_eq!(client.received(long.clone()), long);
+    }
+
+    #[test]
+    fn replies_are_queued_when_output_is_full() {
+        let (mut client, mut peer) = connect();
+
+        peer.write_all(&[IAC, DO, OPT_SUPPRESS_GO_AHEAD]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        // Fill the socket buffers so that nothing more can be written right now
+        let chunk = [0u8; 64 * 1024];
+
+        while client.stream.write(&chunk).is_ok() {}
+
+        client.output = vec![0; MAX_PENDING_OUTPUT];
+
+        // Output is dropped...
+        client.send(b"x");
+        assert_eq!(client.output.len(), MAX_PENDING_OUTPUT);
+
+        // ...but replies are not
+        client.read().unwrap();
+        assert!(client.output.ends_with(&[IAC, WILL, OPT_SUPPRESS_GO_AHEAD]));
+    }
+}
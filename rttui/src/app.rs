@@ -1,5 +1,7 @@
 use crate::event::{Event, Events};
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::Arc;
 use termion::{
     cursor::Goto,
     event::Key,
@@ -11,72 +13,104 @@ use tui::{
     backend::TermionBackend,
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
-    widgets::{Block, Borders, List, Paragraph, Tabs, Text},
+    widgets::{Block, Borders, Paragraph, Tabs, Text},
     Terminal,
 };
 use unicode_width::UnicodeWidthStr;
 
-use probe_rs_rtt::{DownChannel, UpChannel};
+use probe_rs_rtt::ansi::{self, AnsiParser, Span};
+use probe_rs_rtt::defmt::{self, DefmtReader, Table};
+use probe_rs_rtt::symbols::Symbols;
+use probe_rs_rtt::terminal::NUM_TERMINALS;
+use probe_rs_rtt::{
+    Channels, DecodeFallback, DownChannel, LineReader, RttChannel, TextDecoder, VirtualTerminal,
+    VirtualTerminals,
+};
 
 struct ChannelState {
-    up_channel: UpChannel,
+    core: usize,
+    name: String,
+    up_channel: LineReader<Box<dyn Read>>,
+    decoder: TextDecoder,
+    ansi: AnsiParser,
+    symbols: Option<Arc<Symbols>>,
     down_channel: Option<DownChannel>,
-    messages: Vec<String>,
-    last_line_done: bool,
+    messages: Vec<Vec<Span>>,
     input: String,
     scroll_offset: usize,
-    rtt_buffer: [u8; 1024],
 }
 
 impl ChannelState {
-    pub fn new(up_channel: UpChannel, down_channel: Option<DownChannel>) -> Self {
+    pub fn new(
+        core: usize,
+        name: String,
+        up_channel: Box<dyn Read>,
+        down_channel: Option<DownChannel>,
+        decode: DecodeFallback,
+        symbols: Option<Arc<Symbols>>,
+    ) -> Self {
         Self {
-            up_channel,
+            core,
+            name,
+            up_channel: LineReader::new(up_channel),
+            decoder: TextDecoder::new(decode),
+            ansi: AnsiParser::new(),
+            symbols,
             down_channel,
             messages: Vec::new(),
-            last_line_done: false,
             input: String::new(),
             scroll_offset: 0,
-            rtt_buffer: [0u8; 1024],
         }
     }
 
     /// Polls the RTT target for new data on the specified channel.
     ///
-    /// Processes all the new data and adds it to the linebuffer of the respective channel.
+    /// Processes all the new data and adds the complete lines to the linebuffer of the respective
+    /// channel.
     fn poll_rtt(&mut self) {
-        // TODO: Proper error handling.
-        let count = match self.up_channel.read(self.rtt_buffer.as_mut()) {
-            Ok(count) => count,
-            Err(err) => {
-                eprintln!("\nError reading from RTT: {}", err);
-                return;
-            }
-        };
+        loop {
+            // TODO: Proper error handling.
+            let line = match self.up_channel.next_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(err) => {
+                    eprintln!("\nError reading from RTT: {}", err);
+                    return;
+                }
+            };
 
-        if count == 0 {
-            return;
-        }
+            // A character cannot continue past a newline, so anything left over is invalid
+            let mut message = self.decoder.decode(&line.text);
+            message += &self.decoder.finish();
 
-        // First, convert the incomming bytes to UTF8.
-        let mut incomming = String::from_utf8_lossy(&self.rtt_buffer[..count]).to_string();
+            if let Some(symbols) = self.symbols.as_ref() {
+                message = symbols.annotate(&message);
+            }
+
+            self.messages.push(self.ansi.parse_line(&message));
 
-        // Then pop the last stored line from our line buffer if possible and append our new line.
-        if !self.last_line_done {
-            if let Some(last_line) = self.messages.pop() {
-                incomming = last_line + &incomming;
+            if self.scroll_offset != 0 {
+                self.scroll_offset += 1;
             }
         }
-        self.last_line_done = incomming.chars().last().unwrap() == '\n';
+    }
 
-        // Then split the entire new contents.
-        let split = incomming.split_terminator('\n');
+    /// Returns the line that is still being received, if any.
+    fn partial_line(&self) -> Option<Vec<Span>> {
+        let partial = self.up_channel.partial();
 
-        // Then add all the splits to the linebuffer.
-        self.messages.extend(split.clone().map(|s| s.to_string()));
+        if partial.is_empty() {
+            None
+        } else {
+            // Decode and parse with copies so that an incomplete character or escape sequence at
+            // the end is held back until the rest of it arrives
+            let mut text = self.decoder.clone().decode(partial);
 
-        if self.scroll_offset != 0 {
-            self.scroll_offset += split.count();
+            if let Some(symbols) = self.symbols.as_ref() {
+                text = symbols.annotate(&text);
+            }
+
+            Some(self.ansi.clone().parse_line(&text))
         }
     }
 
@@ -89,10 +123,23 @@ impl ChannelState {
     }
 }
 
+/// Virtual terminals of a channel that have not received any data yet. The terminals are opened
+/// up front so that no data is lost before their tabs are created.
+struct PendingTerminals {
+    core: usize,
+    name: String,
+    terminals: VirtualTerminals<Box<dyn Read>>,
+    pending: Vec<VirtualTerminal<Box<dyn Read>>>,
+}
+
 /// App holds the state of the application
 pub struct App {
     tabs: Vec<ChannelState>,
     current_tab: usize,
+    spare_down_channels: BTreeMap<usize, Channels<DownChannel>>,
+    pending_terminals: Vec<PendingTerminals>,
+    decode: DecodeFallback,
+    symbols: Option<Arc<Symbols>>,
 
     terminal:
         Terminal<TermionBackend<AlternateScreen<MouseTerminal<RawTerminal<std::io::Stdout>>>>>,
@@ -100,7 +147,16 @@ pub struct App {
 }
 
 impl App {
-    pub fn new(channels: (Vec<UpChannel>, Vec<DownChannel>)) -> Self {
+    /// Creates the app with tabs for the given up channels of each core. The up channels can be
+    /// live channels or channels replayed from a capture file.
+    pub fn new<U: RttChannel + Read + 'static>(
+        cores: Vec<(usize, Vec<U>, Channels<DownChannel>)>,
+        show_core: bool,
+        virtual_terminals: bool,
+        decode: DecodeFallback,
+        defmt_table: Option<Arc<Table>>,
+        symbols: Option<Arc<Symbols>>,
+    ) -> Self {
         let stdout = std::io::stdout().into_raw_mode().unwrap();
         let stdout = MouseTerminal::from(stdout);
         let stdout = AlternateScreen::from(stdout);
@@ -109,26 +165,70 @@ impl App {
 
         let events = Events::new();
 
-        let mut tabs = Vec::with_capacity(channels.0.len());
+        let mut tabs = Vec::new();
+
+        let mut spare_down_channels = BTreeMap::new();
+
+        let mut pending_terminals = Vec::new();
+
+        // Tabs are grouped by core
+        for (core, up_channels, mut down_channels) in cores {
+            for channel in up_channels {
+                let name = channel.name().unwrap_or("Unnamed Channel");
+                let name = if show_core {
+                    format!("{}: {}", core, name)
+                } else {
+                    name.to_string()
+                };
+
+                let down_channel = down_channels.take(channel.number());
+
+                let is_defmt = channel.name() == Some(defmt::CHANNEL_NAME);
+
+                // Channel 0 is split into virtual terminals, with terminal 0 shown in the tab of the
+                // channel itself
+                let up_channel: Box<dyn Read> =
+                    if let (true, Some(table)) = (is_defmt, defmt_table.as_ref()) {
+                        Box::new(DefmtReader::new(channel, table.clone()).colors(true))
+                    } else if virtual_terminals && channel.number() == 0 {
+                        let terminals = VirtualTerminals::new(Box::new(channel) as Box<dyn Read>);
+                        let terminal = terminals.terminal(0).unwrap();
 
-        let up_channels = channels.0;
-        let mut down_channels = channels.1;
-        for channel in up_channels {
-            tabs.push({
-                let c = down_channels.iter().enumerate().find_map(|(i, c)| {
-                    if c.number() == channel.number() {
-                        Some(i)
+                        pending_terminals.push(PendingTerminals {
+                            core,
+                            name: name.clone(),
+                            pending: (1..NUM_TERMINALS)
+                                .map(|id| terminals.terminal(id).unwrap())
+                                .collect(),
+                            terminals,
+                        });
+
+                        Box::new(terminal)
                     } else {
-                        None
-                    }
-                });
-                ChannelState::new(channel, c.map(|c| down_channels.remove(c)))
-            });
+                        Box::new(channel)
+                    };
+
+                tabs.push(ChannelState::new(
+                    core,
+                    name,
+                    up_channel,
+                    down_channel,
+                    decode,
+                    symbols.clone(),
+                ));
+            }
+
+            // Down channels without a matching up channel can be assigned to tabs later
+            spare_down_channels.insert(core, down_channels);
         }
 
         Self {
             tabs,
             current_tab: 0,
+            spare_down_channels,
+            pending_terminals,
+            decode,
+            symbols,
 
             terminal,
             events,
@@ -139,8 +239,13 @@ impl App {
         let input = self.tabs[self.current_tab].input.clone();
         let has_down_channel = self.tabs[self.current_tab].down_channel.is_some();
         let scroll_offset = self.tabs[self.current_tab].scroll_offset;
-        let message_num = self.tabs[self.current_tab].messages.len();
-        let messages = self.tabs[self.current_tab].messages.iter();
+        let partial_line = self.tabs[self.current_tab].partial_line();
+        let message_num =
+            self.tabs[self.current_tab].messages.len() + partial_line.is_some() as usize;
+        let messages = self.tabs[self.current_tab]
+            .messages
+            .iter()
+            .chain(partial_line.as_ref());
         let tabs = &self.tabs;
         let current_tab = self.current_tab;
         let mut height = 0;
@@ -161,10 +266,7 @@ impl App {
                     .constraints(constraints)
                     .split(f.size());
 
-                let tab_names = tabs
-                    .iter()
-                    .map(|t| t.up_channel.name().unwrap_or("Unnamed Channel"))
-                    .collect::<Vec<_>>();
+                let tab_names = tabs.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
                 let mut tabs = Tabs::default()
                     .titles(&tab_names.as_slice())
                     .select(current_tab)
@@ -179,12 +281,26 @@ impl App {
 
                 height = chunks[1].height as usize;
 
-                let messages = messages
-                    .map(|m| Text::raw(m))
+                let mut text = Vec::new();
+
+                for (i, message) in messages
                     .skip(message_num - (height + scroll_offset).min(message_num))
-                    .take(height);
+                    .take(height)
+                    .enumerate()
+                {
+                    if i > 0 {
+                        text.push(Text::raw("\n"));
+                    }
+
+                    text.extend(
+                        message
+                            .iter()
+                            .map(|span| Text::styled(span.text.as_str(), tui_style(&span.style))),
+                    );
+                }
+
                 let mut messages =
-                    List::new(messages).block(Block::default().borders(Borders::NONE));
+                    Paragraph::new(text.iter()).block(Block::default().borders(Borders::NONE));
                 f.render(&mut messages, chunks[1]);
 
                 if has_down_channel {
@@ -196,7 +312,6 @@ impl App {
             })
             .unwrap();
 
-        let message_num = self.tabs[self.current_tab].messages.len();
         let scroll_offset = self.tabs[self.current_tab].scroll_offset;
         if message_num < height + scroll_offset {
             self.tabs[self.current_tab].scroll_offset = message_num - height.min(message_num);
@@ -228,6 +343,10 @@ impl App {
                     }
                     false
                 }
+                Key::Ctrl('d') => {
+                    self.cycle_down_channel();
+                    false
+                }
                 Key::Char('\n') => {
                     self.push_rtt();
                     false
@@ -261,9 +380,121 @@ impl App {
         for channel in &mut self.tabs {
             channel.poll_rtt();
         }
+
+        self.open_terminal_tabs();
+    }
+
+    /// Creates tabs for virtual terminals that have been switched to since the last poll.
+    fn open_terminal_tabs(&mut self) {
+        for group in self.pending_terminals.iter_mut() {
+            let seen = group.terminals.terminals();
+
+            let (new, pending) = group
+                .pending
+                .drain(..)
+                .partition(|terminal| seen.contains(&terminal.id()));
+
+            group.pending = pending;
+
+            for terminal in new {
+                let name = format!("{} [{}]", group.name, terminal.id());
+
+                let mut state = ChannelState::new(
+                    group.core,
+                    name,
+                    Box::new(terminal),
+                    None,
+                    self.decode,
+                    self.symbols.clone(),
+                );
+
+                // Read the data that arrived before the tab was created
+                state.poll_rtt();
+
+                self.tabs.push(state);
+            }
+        }
     }
 
     pub fn push_rtt(&mut self) {
         self.tabs[self.current_tab].push_rtt();
     }
+
+    /// Assigns the next spare down channel of the same core to the current tab, returning the
+    /// previous one to the spare channels. Cycles through having no down channel as well.
+    pub fn cycle_down_channel(&mut self) {
+        let tab = &mut self.tabs[self.current_tab];
+        let spare = self.spare_down_channels.entry(tab.core).or_default();
+
+        let current = tab.down_channel.take().map(|chan| {
+            let number = chan.number();
+            spare.insert(chan);
+            number
+        });
+
+        let next = match current {
+            Some(current) => spare.numbers().find(|&n| n > current),
+            None => spare.numbers().next(),
+        };
+
+        tab.down_channel = next.and_then(|n| spare.take(n));
+    }
+}
+
+/// Converts a style parsed from ANSI escape sequences to a terminal style.
+fn tui_style(style: &ansi::Style) -> Style {
+    let mut modifier = Modifier::empty();
+
+    if style.bold {
+        modifier |= Modifier::BOLD;
+    }
+    if style.dim {
+        modifier |= Modifier::DIM;
+    }
+    if style.italic {
+        modifier |= Modifier::ITALIC;
+    }
+    if style.underline {
+        modifier |= Modifier::UNDERLINED;
+    }
+    if style.reverse {
+        modifier |= Modifier::REVERSED;
+    }
+
+    let mut result = Style::default().modifier(modifier);
+
+    if let Some(fg) = style.fg {
+        result = result.fg(tui_color(fg));
+    }
+
+    if let Some(bg) = style.bg {
+        result = result.bg(tui_color(bg));
+    }
+
+    result
+}
+
+fn tui_color(color: ansi::Color) -> Color {
+    match color {
+        ansi::Color::Named(n) => match n {
+            0 => Color::Black,
+            1 => Color::Red,
+            2 => Color::Green,
+            3 => Color::Yellow,
+            4 => Color::Blue,
+            5 => Color::Magenta,
+            6 => Color::Cyan,
+            7 => Color::Gray,
+            8 => Color::DarkGray,
+            9 => Color::LightRed,
+            10 => Color::LightGreen,
+            11 => Color::LightYellow,
+            12 => Color::LightBlue,
+            13 => Color::LightMagenta,
+            14 => Color::LightCyan,
+            _ => Color::White,
+        },
+        ansi::Color::Indexed(i) => Color::Indexed(i),
+        ansi::Color::Rgb(r, g, b) => Color::Rgb(r, g, b),
+    }
 }
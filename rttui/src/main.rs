@@ -2,9 +2,21 @@ mod app;
 mod event;
 
 use probe_rs::{config::TargetSelector, DebugProbeInfo, Probe};
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
 
-use probe_rs_rtt::{Channels, DownChannel, Rtt, RttChannel, UpChannel};
+use probe_rs_rtt::cache::{self, CacheKey, ControlBlockCache};
+use probe_rs_rtt::capture::Replay;
+use probe_rs_rtt::defmt::Table;
+use probe_rs_rtt::symbols::Symbols;
+use probe_rs_rtt::{
+    channels, parse_region, AttachOptions, ChannelSelector, Channels, DecodeFallback, DownChannel,
+    RttChannel, UpChannel,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -31,17 +43,96 @@ struct Opts {
     #[structopt(long, help = "List all the RTT channels and exit.")]
     list: bool,
 
+    #[structopt(
+        long = "rtt-id",
+        help = "Control block ID string to scan for (up to 16 bytes). Defaults to \"SEGGER RTT\"."
+    )]
+    rtt_id: Option<String>,
+
+    #[structopt(
+        long = "scan-region",
+        parse(try_from_str = parse_region),
+        number_of_values = 1,
+        help = "Additional memory region to scan for the control block, as START..END or START+SIZE. Can be given multiple times."
+    )]
+    scan_regions: Vec<Range<u32>>,
+
+    #[structopt(
+        long,
+        help = "Halt the core while scanning for the control block. The core is resumed afterwards unless it was already halted."
+    )]
+    halt: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Path to the firmware ELF file. Used to tell firmware builds apart in the control block cache, to decode defmt logs, and to annotate code addresses in the output with the function and source location."
+    )]
+    elf: Option<PathBuf>,
+
+    #[structopt(
+        long = "no-symbolize",
+        help = "Do not annotate code addresses in the output with the function and source location from the ELF file."
+    )]
+    no_symbolize: bool,
+
     #[structopt(
         long,
-        help = "All the up channels that should be output. Default is to output all available ones."
+        help = "Cache the control block address on disk and try it first on the next run."
     )]
-    up: Option<Vec<usize>>,
+    cache: bool,
 
     #[structopt(
         long,
-        help = "All the down channels that should be shown. Default is to show all available ones."
+        conflicts_with = "all-cores",
+        help = "Number of the core to attach to. Default is 0."
+    )]
+    core: Option<usize>,
+
+    #[structopt(
+        long = "all-cores",
+        help = "Attach to RTT on all cores. Tabs are grouped by core."
     )]
-    down: Option<Vec<usize>>,
+    all_cores: bool,
+
+    #[structopt(
+        long,
+        help = "All the up channels that should be output, by number or name. Names may contain * and ? wildcards, and names that are numbers can be given as name:NAME. Default is to output all available ones."
+    )]
+    up: Option<Vec<ChannelSelector>>,
+
+    #[structopt(
+        long,
+        help = "All the down channels that should be shown, by number or name. Names may contain * and ? wildcards, and names that are numbers can be given as name:NAME. Default is to show all available ones."
+    )]
+    down: Option<Vec<ChannelSelector>>,
+
+    #[structopt(
+        long,
+        default_value = "replace",
+        help = "How to show output that is not valid UTF-8: replace, escape or latin1."
+    )]
+    decode: DecodeFallback,
+
+    #[structopt(
+        long = "no-virtual-terminals",
+        help = "Show up channel 0 as is instead of splitting it into a tab per SEGGER virtual terminal."
+    )]
+    no_virtual_terminals: bool,
+
+    #[structopt(
+        long,
+        requires = "elf",
+        help = "Decode defmt log frames from the up channel named 'defmt' using the format strings in the ELF file."
+    )]
+    defmt: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Replay a capture file recorded with rtthost --record instead of attaching to a target."
+    )]
+    replay: Option<PathBuf>,
 }
 
 fn main() {
@@ -53,6 +144,72 @@ fn main() {
 fn run() -> i32 {
     let opts = Opts::from_args();
 
+    let elf = match opts.elf.as_ref().map(std::fs::read).transpose() {
+        Ok(elf) => elf,
+        Err(err) => {
+            eprintln!("Error reading ELF file: {}", err);
+            return 1;
+        }
+    };
+
+    let defmt_table = match (opts.defmt, elf.as_ref()) {
+        (true, Some(elf)) => match Table::parse(elf) {
+            Ok(table) => Some(Arc::new(table)),
+            Err(err) => {
+                eprintln!("Error reading defmt data from ELF file: {}", err);
+                return 1;
+            }
+        },
+        _ => None,
+    };
+
+    // Symbols are nice to have, so the output is shown without them if they cannot be read
+    let symbols = match (opts.no_symbolize, elf.as_ref()) {
+        (false, Some(elf)) => match Symbols::parse(elf) {
+            Ok(symbols) => Some(Arc::new(symbols)),
+            Err(err) => {
+                eprintln!("Warning: code addresses will not be symbolized: {}", err);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    if let Some(path) = opts.replay.as_ref() {
+        let replays = match File::open(path).and_then(|file| Replay::load(file, Some(1.0))) {
+            Ok(replays) => replays,
+            Err(err) => {
+                eprintln!("Error reading capture file: {}", err);
+                return 1;
+            }
+        };
+
+        let mut replays: Vec<Replay> = replays
+            .into_iter()
+            .filter(|replay| opts.all_cores || replay.core() == opts.core.unwrap_or(0))
+            .collect();
+
+        let up = match channels::take_selected(
+            replays.iter_mut().map(|replay| replay.up_channels()),
+            opts.up.as_deref().unwrap_or(&[]),
+        ) {
+            Ok(up) => up,
+            Err(selector) => {
+                report_unmatched("up", selector, opts.all_cores);
+                return 1;
+            }
+        };
+
+        // Down channels cannot be written to in a replay
+        let channels = replays
+            .iter()
+            .zip(up)
+            .map(|(replay, up)| (replay.core(), up, Channels::new()))
+            .collect();
+
+        return run_app(channels, &opts, defmt_table, symbols);
+    }
+
     let probes = Probe::list_all();
 
     if probes.len() == 0 {
@@ -100,52 +257,118 @@ fn run() -> i32 {
         }
     };
 
-    let core = match session.attach_to_core(0) {
-        Ok(core) => core,
-        Err(err) => {
-            eprintln!("Error attaching to core 0: {}", err);
-            return 1;
+    let mut attach_options = AttachOptions::new();
+
+    if let Some(rtt_id) = opts.rtt_id.as_ref() {
+        attach_options = attach_options.control_block_id(rtt_id);
+    }
+
+    for range in opts.scan_regions.iter() {
+        attach_options = attach_options.scan_region(range.clone());
+    }
+
+    attach_options = attach_options.halt_core(opts.halt);
+
+    let mut cache = if opts.cache {
+        match ControlBlockCache::open_default() {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                eprintln!("Warning: control block cache disabled: {}", err);
+                None
+            }
         }
+    } else {
+        None
     };
 
+    let probe_serial = probes[opts.probe].serial_number.clone();
+    let chip_name = cache::chip_name(&session, opts.chip.as_deref());
+    let cache_key = |core| CacheKey::new(probe_serial.as_deref(), &chip_name, core, elf.as_deref());
+
     eprintln!("Attaching to RTT...");
 
-    let mut rtt = match Rtt::attach(core, &session) {
-        Ok(rtt) => rtt,
-        Err(err) => {
-            eprintln!("Error attaching to RTT: {}", err);
-            return 1;
-        }
+    let core = if opts.all_cores {
+        None
+    } else {
+        Some(opts.core.unwrap_or(0))
     };
 
+    let mut rtts =
+        match cache::attach_cached(&session, core, &attach_options, cache.as_mut(), cache_key) {
+            Ok(rtts) => rtts,
+            Err(err) => {
+                eprintln!("Error attaching to RTT: {}", err);
+                return 1;
+            }
+        };
+
+    if let Some(cache) = cache.as_ref() {
+        if let Err(err) = cache.save() {
+            eprintln!("Warning: failed to save control block cache: {}", err);
+        }
+    }
+
     if opts.list {
-        println!("Up channels:");
-        list_channels(rtt.up_channels());
+        for (core, rtt) in rtts.iter_mut() {
+            if opts.all_cores {
+                println!("Core {}:", core);
+            }
 
-        println!("Down channels:");
-        list_channels(rtt.down_channels());
+            println!("Up channels:");
+            list_channels(rtt.up_channels());
+
+            println!("Down channels:");
+            list_channels(rtt.down_channels());
+        }
 
         return 0;
     }
 
-    let channels: (Vec<UpChannel>, Vec<DownChannel>) = (
-        opts.up
-            .map(|up| {
-                up.iter()
-                    .filter_map(|n| rtt.up_channels().take(*n))
-                    .collect()
-            })
-            .unwrap_or_else(|| rtt.up_channels().drain().collect()),
-        opts.down
-            .map(|down| {
-                down.iter()
-                    .filter_map(|n| rtt.down_channels().take(*n))
-                    .collect()
-            })
-            .unwrap_or_else(|| rtt.down_channels().drain().collect()),
-    );
+    let up = match channels::take_selected(
+        rtts.iter_mut().map(|(_, rtt)| rtt.up_channels()),
+        opts.up.as_deref().unwrap_or(&[]),
+    ) {
+        Ok(up) => up,
+        Err(selector) => {
+            report_unmatched("up", selector, opts.all_cores);
+            return 1;
+        }
+    };
 
-    let mut app = app::App::new(channels);
+    let down = match channels::take_selected(
+        rtts.iter_mut().map(|(_, rtt)| rtt.down_channels()),
+        opts.down.as_deref().unwrap_or(&[]),
+    ) {
+        Ok(down) => down,
+        Err(selector) => {
+            report_unmatched("down", selector, opts.all_cores);
+            return 1;
+        }
+    };
+
+    let channels: Vec<(usize, Vec<UpChannel>, Channels<DownChannel>)> = rtts
+        .iter()
+        .zip(up.into_iter().zip(down))
+        .map(|((core, _), (up, down))| (*core, up, down.into_iter().collect()))
+        .collect();
+
+    run_app(channels, &opts, defmt_table, symbols)
+}
+
+fn run_app<U: RttChannel + Read + 'static>(
+    channels: Vec<(usize, Vec<U>, Channels<DownChannel>)>,
+    opts: &Opts,
+    defmt_table: Option<Arc<Table>>,
+    symbols: Option<Arc<Symbols>>,
+) -> i32 {
+    let mut app = app::App::new(
+        channels,
+        opts.all_cores,
+        !opts.no_virtual_terminals,
+        opts.decode,
+        defmt_table,
+        symbols,
+    );
     loop {
         app.poll_rtt();
         app.render();
@@ -156,6 +379,18 @@ fn run() -> i32 {
     }
 }
 
+/// Reports a channel selector that matched no channels.
+fn report_unmatched(direction: &str, selector: &ChannelSelector, all_cores: bool) {
+    if all_cores {
+        eprintln!(
+            "Error: {} channel {} does not exist on any core.",
+            direction, selector
+        );
+    } else {
+        eprintln!("Error: {} channel {} does not exist.", direction, selector);
+    }
+}
+
 fn list_probes(mut stream: impl std::io::Write, probes: &Vec<DebugProbeInfo>) {
     writeln!(stream, "Available probes:").unwrap();
 